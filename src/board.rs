@@ -0,0 +1,82 @@
+use crate::storage::{self, Slot, StorageError};
+
+/// Fixed addresses of the protector's parts, which share the bus with the charge channels: its
+/// INA226 and the two GX21M15 temperature sensors.
+pub const PROTECTOR_INA226_ADDRESS: u8 = 0x43;
+pub const GX21M15_ADDRESSES: [u8; 2] = [0x49, 0x48];
+
+/// I2C addresses that depend on the board's address straps.
+///
+/// Stored in flash so one firmware image can drive boards with different layouts. The charge
+/// channel task reads it once at start-up, so a new layout takes effect after a reboot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardLayout {
+    pub mux_addresses: [u8; 2],
+    /// INA226 of each charge channel, in channel order.
+    pub ina226_addresses: [u8; 4],
+}
+
+impl BoardLayout {
+    pub const DEFAULT: Self = Self {
+        mux_addresses: [0x70, 0x71],
+        ina226_addresses: [0x44, 0x41, 0x45, 0x40],
+    };
+
+    const BYTE_SIZE: usize = 6;
+
+    /// Addresses must fit the parts' strap ranges and must not collide. The INA226s sit behind
+    /// different mux ports, but a shared address would still clash when two ports are open, and
+    /// the protector's parts are reachable whichever port is open.
+    pub fn is_valid(&self) -> bool {
+        let [mux_0, mux_1] = self.mux_addresses;
+        let muxes_valid =
+            (0x70..=0x77).contains(&mux_0) && (0x70..=0x77).contains(&mux_1) && mux_0 != mux_1;
+
+        let ina226_valid = self
+            .ina226_addresses
+            .iter()
+            .enumerate()
+            .all(|(index, address)| {
+                (0x40..=0x4f).contains(address)
+                    && *address != PROTECTOR_INA226_ADDRESS
+                    && !GX21M15_ADDRESSES.contains(address)
+                    && !self.ina226_addresses[..index].contains(address)
+            });
+
+        muxes_valid && ina226_valid
+    }
+
+    /// `[mux 0, mux 1, INA226 ch0..ch3]`
+    pub fn to_bytes(&self) -> [u8; Self::BYTE_SIZE] {
+        let [mux_0, mux_1] = self.mux_addresses;
+        let [ina_0, ina_1, ina_2, ina_3] = self.ina226_addresses;
+        [mux_0, mux_1, ina_0, ina_1, ina_2, ina_3]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::BYTE_SIZE {
+            return None;
+        }
+
+        Some(Self {
+            mux_addresses: [bytes[0], bytes[1]],
+            ina226_addresses: [bytes[2], bytes[3], bytes[4], bytes[5]],
+        })
+    }
+}
+
+/// The stored layout, or [`BoardLayout::DEFAULT`] if none (or an invalid one) is stored.
+pub fn load() -> BoardLayout {
+    let mut record = [0u8; BoardLayout::BYTE_SIZE];
+    let layout = storage::read(Slot::Board, &mut record)
+        .and_then(|len| BoardLayout::from_bytes(&record[..len]))
+        .filter(BoardLayout::is_valid)
+        .unwrap_or(BoardLayout::DEFAULT);
+
+    log::info!("Board layout: {:02x?}", layout.to_bytes());
+    layout
+}
+
+pub fn store(layout: &BoardLayout) -> Result<(), StorageError> {
+    storage::write(Slot::Board, &layout.to_bytes())
+}
@@ -0,0 +1,252 @@
+//! Burn-in: switches every charge channel output on and off in a loop for as long as it runs,
+//! watching for faults along the way.
+//!
+//! Statistics go to `burn-in/summary` once a minute, and a final one with the reason when the
+//! run ends: stopped by command or by a critical fault. Only the outputs are switched, through
+//! the charge channel task like `all-on`/`all-off`, so soft fuses, thermal throttling, the input
+//! power ceiling and every VIN cut stay in force throughout. An `all-off` during the run ends it
+//! and leaves the outputs off.
+
+use core::{cell::Cell, fmt::Write};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
+
+use crate::{
+    bus::{publish_status, ChargeChannelAction, CHARGE_CHANNEL_CFG_CHANNEL},
+    protector::ProtectionReasons,
+    settings, snapshot, system,
+};
+
+/// Shortest and longest on or off phase, in seconds. Anything shorter doesn't give the sinks
+/// time to renegotiate.
+pub const MIN_PHASE_SECS: u16 = 5;
+pub const MAX_PHASE_SECS: u16 = 3600;
+
+const MONITOR_INTERVAL: Duration = Duration::from_secs(1);
+const SUMMARY_PERIOD: Duration = Duration::from_secs(60);
+
+/// A channel whose latest reading is older than this has dropped offline.
+const MAX_SAMPLE_AGE: Duration = Duration::from_secs(5);
+
+/// Protection rules that end the run as soon as they trip, with the name reported for each.
+const CRITICAL: [(ProtectionReasons, &str); 4] = [
+    (ProtectionReasons::HARDWARE, "hardware"),
+    (ProtectionReasons::OVER_CURRENT, "over-current"),
+    (ProtectionReasons::REVERSE_CURRENT, "reverse-current"),
+    (ProtectionReasons::FUSE, "fuse"),
+];
+
+#[derive(Debug, Clone, Copy)]
+struct Cycle {
+    on: Duration,
+    off: Duration,
+}
+
+static REQUEST: Signal<CriticalSectionRawMutex, Cycle> = Signal::new();
+static STOP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static RUNNING: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+/// Latest `all-on`/`all-off` received during the run, restored instead of the state before it.
+static USER_OUTPUTS: Mutex<CriticalSectionRawMutex, Cell<Option<bool>>> =
+    Mutex::new(Cell::new(None));
+
+pub fn running() -> bool {
+    RUNNING.lock(|running| running.get())
+}
+
+/// Starts a run with the outputs on for `on_secs` and off for `off_secs` each cycle. Returns
+/// `false` if one is already in progress.
+pub fn start(on_secs: u16, off_secs: u16) -> bool {
+    let started = RUNNING.lock(|running| !running.replace(true));
+    if started {
+        STOP.reset();
+        USER_OUTPUTS.lock(|outputs| outputs.set(None));
+        REQUEST.signal(Cycle {
+            on: Duration::from_secs(on_secs as u64),
+            off: Duration::from_secs(off_secs as u64),
+        });
+    }
+    started
+}
+
+/// Ends the running burn-in. Returns `false` if none is running.
+pub fn stop() -> bool {
+    let running = running();
+    if running {
+        STOP.signal(());
+    }
+    running
+}
+
+/// Records an `all-on`/`all-off` sent while a run is in progress. `all-off` ends the run, so the
+/// next phase can't switch the outputs back on.
+pub fn user_outputs(on: bool) {
+    if !running() {
+        return;
+    }
+    USER_OUTPUTS.lock(|outputs| outputs.set(Some(on)));
+    if !on {
+        STOP.signal(());
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    /// Completed on/off cycles.
+    cycles: u32,
+    /// Times thermal throttling kicked in.
+    thermal: u32,
+    /// Channel samples with a missed INA226 read.
+    stale_reads: u32,
+    /// Times a channel stopped reporting, i.e. went offline.
+    flaps: u32,
+    max_temperature: f32,
+}
+
+impl Stats {
+    fn to_json(&self, elapsed: Duration, reason: Option<&str>) -> String<192> {
+        let mut json = String::new();
+        write!(
+            json,
+            "{{\"running\":{},\"elapsed\":{},\"cycles\":{},\"thermal\":{},\"stale_reads\":{},\"flaps\":{},\"max_temperature\":{:.1}",
+            reason.is_none(),
+            elapsed.as_secs(),
+            self.cycles,
+            self.thermal,
+            self.stale_reads,
+            self.flaps,
+            self.max_temperature
+        )
+        .ok();
+        if let Some(reason) = reason {
+            write!(json, ",\"reason\":\"{}\"", reason).ok();
+        }
+        json.push('}').ok();
+        json
+    }
+}
+
+#[embassy_executor::task]
+pub async fn task() {
+    loop {
+        let cycle = REQUEST.wait().await;
+
+        // Put back whatever `all-off`/`all-on` left in place before the run.
+        let outputs_on = !system::output_holds().all_off;
+        log::info!(
+            "Burn-in started: {}s on, {}s off",
+            cycle.on.as_secs(),
+            cycle.off.as_secs()
+        );
+
+        let started_at = Instant::now();
+        let mut stats = Stats::default();
+        let reason = run(cycle, &mut stats, started_at).await;
+
+        // Read again now: a last phase switch may have raced an `all-off`, which has to win.
+        let outputs_on = USER_OUTPUTS
+            .lock(|outputs| outputs.take())
+            .unwrap_or(outputs_on);
+        set_outputs(outputs_on).await;
+        RUNNING.lock(|running| running.set(false));
+
+        let json = stats.to_json(started_at.elapsed(), Some(reason));
+        log::info!("Burn-in done: {}", json);
+        publish_status("burn-in/summary", json.as_bytes());
+    }
+}
+
+/// Cycles the outputs until stopped, publishing the statistics along the way. Returns why it
+/// ended.
+async fn run(cycle: Cycle, stats: &mut Stats, started_at: Instant) -> &'static str {
+    let mut on = true;
+    set_outputs(on).await;
+    let mut phase_until = Instant::now() + cycle.on;
+    let mut summary_at = Instant::now() + SUMMARY_PERIOD;
+
+    let mut thermal = false;
+    let mut last_channels = [None::<Instant>; 4];
+    let mut online = [false; 4];
+
+    loop {
+        if let Either::First(()) = select(STOP.wait(), Timer::after(MONITOR_INTERVAL)).await {
+            return match USER_OUTPUTS.lock(|outputs| outputs.get()) {
+                Some(false) => "all-off",
+                _ => "stopped",
+            };
+        }
+
+        let now = Instant::now();
+        if now >= phase_until {
+            on = !on;
+            if on {
+                stats.cycles += 1;
+            }
+            set_outputs(on).await;
+            phase_until = now + if on { cycle.on } else { cycle.off };
+        }
+
+        let snapshot = snapshot::snapshot();
+
+        if let Some(sample) = snapshot.protector {
+            let reasons = sample.value.protection;
+            if let Some((_, name)) = CRITICAL
+                .iter()
+                .find(|(reason, _)| reasons.contains(*reason))
+            {
+                log::warn!("Burn-in stopped by {} protection", name);
+                return *name;
+            }
+
+            let throttled = reasons.contains(ProtectionReasons::THERMAL);
+            if throttled && !thermal {
+                stats.thermal += 1;
+            }
+            thermal = throttled;
+
+            let temperature = sample.value.temperature_0.max(sample.value.temperature_1);
+            stats.max_temperature = stats.max_temperature.max(temperature);
+            if temperature >= settings::get().temperature_shutdown_celsius {
+                log::warn!("Burn-in stopped at {:.1}°C", temperature);
+                return "over-temperature";
+            }
+        }
+
+        for (index, sample) in snapshot.channels.iter().enumerate() {
+            let Some(sample) = sample else {
+                continue;
+            };
+            if last_channels[index].is_some_and(|at| sample.at <= at) {
+                // No new reading since the last check.
+                if online[index] && now.saturating_duration_since(sample.at) > MAX_SAMPLE_AGE {
+                    log::warn!("Burn-in: ch{} went offline", index);
+                    online[index] = false;
+                    stats.flaps += 1;
+                }
+                continue;
+            }
+            last_channels[index] = Some(sample.at);
+            online[index] = true;
+            if sample.value.amps_stale || sample.value.watts_stale {
+                stats.stale_reads += 1;
+            }
+        }
+
+        if now >= summary_at {
+            summary_at = now + SUMMARY_PERIOD;
+            let json = stats.to_json(started_at.elapsed(), None);
+            publish_status("burn-in/summary", json.as_bytes());
+        }
+    }
+}
+
+async fn set_outputs(on: bool) {
+    CHARGE_CHANNEL_CFG_CHANNEL
+        .send(ChargeChannelAction::Outputs(on))
+        .await;
+}
@@ -1,9 +1,16 @@
 use core::fmt::Display;
 
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
+use heapless::{String, Vec};
+#[cfg(feature = "json-payload")]
+use serde::Serialize;
 use sw3526::{AbnormalCaseResponse, ProtocolIndicationResponse, SystemStatusResponse};
 
-use crate::protector::VinState;
+use crate::{
+    protector::{CurrentDirection, ProtectionReasons, ProtectorCommand, VinState},
+    telemetry_layout::{self, ByteOrder},
+    wifi::WifiCredentials,
+};
 
 #[derive(Debug, Clone, Copy)]
 pub enum WiFiConnectStatus {
@@ -20,42 +27,168 @@ impl Display for WiFiConnectStatus {
 pub static WIFI_CONNECT_STATUS: Mutex<CriticalSectionRawMutex, WiFiConnectStatus> =
     Mutex::new(WiFiConnectStatus::Connecting);
 
+/// The access point the station is associated with, as last seen by a scan.
+#[derive(Debug, Clone, Copy)]
+pub struct WiFiLinkInfo {
+    /// Signal strength in dBm.
+    pub rssi: i8,
+    pub channel: u8,
+    pub bssid: [u8; 6],
+}
+
+/// Refreshed by the Wi-Fi connection task while connected, `None` otherwise.
+pub static WIFI_LINK_INFO: Mutex<CriticalSectionRawMutex, Option<WiFiLinkInfo>> = Mutex::new(None);
+
+/// Encodes a series field in the configured [`telemetry_layout::byte_order`].
+macro_rules! wire_bytes {
+    ($value:expr) => {
+        match telemetry_layout::byte_order() {
+            ByteOrder::Little => $value.to_le_bytes(),
+            ByteOrder::Big => $value.to_be_bytes(),
+        }
+    };
+}
+
+/// Writes a series field at its offset in `layout`. The name is looked up, and the encoded size
+/// checked against the table, at compile time.
+macro_rules! put_field {
+    ($buffer:expr, $layout:expr, $name:literal, $value:expr) => {{
+        const OFFSET: usize = telemetry_layout::offset($layout, $name);
+        const SIZE: usize = telemetry_layout::size($layout, $name);
+        let bytes: [u8; SIZE] = wire_bytes!($value);
+        $buffer[OFFSET..OFFSET + SIZE].copy_from_slice(&bytes);
+    }};
+}
+
+/// Input-side telemetry. `amps`, `raw_amps` and `watts` are signed so that positive values
+/// mean power drawn from the supply; see [`CurrentDirection`].
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct ProtectorSeriesItem {
     pub temperature_0: f32,
     pub temperature_1: f32,
     pub millivolts: f64,
+    /// Input current averaged over the configured window; used for protection decisions.
     pub amps: f64,
+    /// Latest instantaneous input current.
+    pub raw_amps: f64,
+    /// Power from the INA226 power register.
     pub watts: f64,
+    /// Power computed as bus voltage × instantaneous current, 0 unless `power_cross_check` is
+    /// on.
+    pub computed_watts: f64,
     pub vin_status: VinState,
+    pub direction: CurrentDirection,
+    /// `watts` and `computed_watts` disagree beyond the configured tolerance, which usually
+    /// means the INA226 calibration is wrong. Only checked with `power_cross_check` on.
+    pub power_mismatch: bool,
+    /// The INA226 returned no current this sample: `amps`, `raw_amps` and `computed_watts` hold
+    /// the previous reading.
+    pub amps_stale: bool,
+    /// The INA226 returned no power this sample: `watts` holds the previous reading.
+    pub watts_stale: bool,
+    /// Every protection rule that is active this sample.
+    pub protection: ProtectionReasons,
+    /// Position of this sample in the series, counting from 0 at boot and wrapping at `u16::MAX`,
+    /// so consumers can spot missing or reordered messages.
+    pub seq: u16,
+    /// Duty of the cooling fan in percent; 0 without one.
+    pub fan_duty: u8,
 }
 
+/// Encoded as described by [`telemetry_layout::PROTECTOR_SERIES_ITEM`]; keep the two in sync.
 impl ProtectorSeriesItem {
-    const BYTE_SIZE: usize = size_of::<f32>() * 2 + size_of::<f64>() * 3 + size_of::<u8>();
+    const BYTE_SIZE: usize =
+        size_of::<f32>() * 2 + size_of::<f64>() * 5 + size_of::<u8>() * 6 + size_of::<u16>() * 2;
     pub fn to_bytes(&self) -> [u8; Self::BYTE_SIZE] {
         let mut buffer = [0u8; Self::BYTE_SIZE];
-        let mut offset = 0;
 
-        fn copy_into_slice(buffer: &mut [u8], offset: &mut usize, bytes: &[u8]) {
-            let end = *offset + bytes.len();
-            buffer[*offset..end].copy_from_slice(bytes);
-            *offset = end;
+        macro_rules! put {
+            ($name:literal, $value:expr) => {
+                put_field!(
+                    buffer,
+                    telemetry_layout::PROTECTOR_SERIES_ITEM,
+                    $name,
+                    $value
+                )
+            };
         }
 
-        copy_into_slice(&mut buffer, &mut offset, &self.temperature_0.to_le_bytes());
-        copy_into_slice(&mut buffer, &mut offset, &self.temperature_1.to_le_bytes());
-        copy_into_slice(&mut buffer, &mut offset, &self.millivolts.to_le_bytes());
-        copy_into_slice(&mut buffer, &mut offset, &self.amps.to_le_bytes());
-        copy_into_slice(&mut buffer, &mut offset, &self.watts.to_le_bytes());
-        copy_into_slice(
-            &mut buffer,
-            &mut offset,
-            &(self.vin_status as u8).to_le_bytes(),
-        );
+        put!("temperature_0", self.temperature_0);
+        put!("temperature_1", self.temperature_1);
+        put!("millivolts", self.millivolts);
+        put!("amps", self.amps);
+        put!("watts", self.watts);
+        put!("vin_status", self.vin_status as u8);
+        put!("direction", self.direction as u8);
+        put!("power_mismatch", self.power_mismatch as u8);
+        put!("amps_stale", self.amps_stale as u8);
+        put!("watts_stale", self.watts_stale as u8);
+        put!("protection", self.protection.bits());
+        put!("seq", self.seq);
+        put!("fan_duty", self.fan_duty);
+        put!("raw_amps", self.raw_amps);
+        put!("computed_watts", self.computed_watts);
         buffer
     }
 }
 
+const _: () = assert!(
+    telemetry_layout::byte_size(telemetry_layout::PROTECTOR_SERIES_ITEM)
+        == ProtectorSeriesItem::BYTE_SIZE
+);
+
+/// Longest series payload in JSON; the charge channel's comes to about 450 bytes.
+#[cfg(feature = "json-payload")]
+pub(crate) const MAX_JSON_PAYLOAD_LEN: usize = 512;
+
+#[cfg(feature = "json-payload")]
+pub(crate) type JsonPayload = String<MAX_JSON_PAYLOAD_LEN>;
+
+/// The fields of [`ProtectorSeriesItem`] by name, with the enums and flags as their wire values.
+/// Readings are narrowed to `f32`, still finer than the INA226 resolves, to keep it short.
+#[cfg(feature = "json-payload")]
+#[derive(Serialize)]
+struct ProtectorJson {
+    temperature_0: f32,
+    temperature_1: f32,
+    millivolts: f32,
+    amps: f32,
+    watts: f32,
+    vin_status: u8,
+    direction: u8,
+    power_mismatch: bool,
+    amps_stale: bool,
+    watts_stale: bool,
+    protection: u16,
+    seq: u16,
+    fan_duty: u8,
+    raw_amps: f32,
+    computed_watts: f32,
+}
+
+#[cfg(feature = "json-payload")]
+impl ProtectorSeriesItem {
+    pub fn to_json(&self) -> serde_json_core::ser::Result<JsonPayload> {
+        serde_json_core::to_string(&ProtectorJson {
+            temperature_0: self.temperature_0,
+            temperature_1: self.temperature_1,
+            millivolts: self.millivolts as f32,
+            amps: self.amps as f32,
+            watts: self.watts as f32,
+            vin_status: self.vin_status as u8,
+            direction: self.direction as u8,
+            power_mismatch: self.power_mismatch,
+            amps_stale: self.amps_stale,
+            watts_stale: self.watts_stale,
+            protection: self.protection.bits(),
+            seq: self.seq,
+            fan_duty: self.fan_duty,
+            raw_amps: self.raw_amps as f32,
+            computed_watts: self.computed_watts as f32,
+        })
+    }
+}
+
 impl Default for ProtectorSeriesItem {
     fn default() -> Self {
         Self {
@@ -63,8 +196,17 @@ impl Default for ProtectorSeriesItem {
             temperature_1: 0.0,
             millivolts: 0.0,
             amps: 0.0,
+            raw_amps: 0.0,
             watts: 0.0,
+            computed_watts: 0.0,
             vin_status: VinState::Normal,
+            direction: CurrentDirection::Idle,
+            power_mismatch: false,
+            amps_stale: false,
+            watts_stale: false,
+            protection: ProtectionReasons::NONE,
+            seq: 0,
+            fan_duty: 0,
         }
     }
 }
@@ -78,78 +220,230 @@ pub(crate) static PROTECTOR_SERIES_ITEM_CHANNEL: ProtectorSeriesItemChannel = Ch
 pub(crate) struct ChargeChannelSeriesItem {
     pub millivolts: f64,
     pub amps: f64,
+    /// Power from the INA226 power register.
     pub watts: f64,
+    /// Power computed as bus voltage × current, 0 unless `power_cross_check` is on.
+    pub computed_watts: f64,
     pub protocol: ProtocolIndicationResponse,
     pub system_status: SystemStatusResponse,
     pub abnormal_case: AbnormalCaseResponse,
     pub buck_output_millivolts: u16,
     pub buck_output_limit_milliamps: u16,
+    /// Output limit read back from the SW3526, i.e. the effective, clamped value.
     pub limit_watts: u8,
+    /// Output limit as last commanded, before clamping to the configured range.
+    pub requested_limit_watts: u8,
+    /// `watts` and `computed_watts` disagree beyond the configured tolerance. Only checked with
+    /// `power_cross_check` on.
+    pub power_mismatch: bool,
+    /// The channel is delivering power; see `ChargeChannel::is_active`.
+    pub active: bool,
+    /// Time since the previous sample of this channel, 0 for the first one after init.
+    pub sample_interval_ms: u32,
+    /// The interval exceeded twice the loop period, i.e. at least one sample was missed.
+    pub sample_overrun: bool,
+    /// The INA226 returned no current this sample: `amps` and `computed_watts` hold the
+    /// previous reading.
+    pub amps_stale: bool,
+    /// The INA226 returned no power this sample: `watts` holds the previous reading.
+    pub watts_stale: bool,
+    /// Output current as measured by the SW3526's own ADC, in 40mA steps. Independent of the
+    /// INA226, so the two can be checked against each other.
+    pub buck_output_milliamps: u16,
+    /// Position of this sample in the channel's series; see [`ProtectorSeriesItem::seq`].
+    pub seq: u16,
+    /// Raw INA226 shunt voltage, uncalibrated. Divided by the shunt resistance it should match
+    /// `amps`; a mismatch points at a wrong resistance in the calibration.
+    pub shunt_microvolts: f64,
+    /// `ChargeChannelOnlineStatus` as its bits: 1 INA226, 2 SW3526, 3 both. Anything but 3
+    /// means the channel isn't sampled and only this field and `seq` carry information.
+    pub online_status: u8,
 }
 
+/// Encoded as described by [`telemetry_layout::CHARGE_CHANNEL_SERIES_ITEM`]; keep the two in
+/// sync.
 impl ChargeChannelSeriesItem {
     const BYTE_SIZE: usize = size_of::<f64>() * 3
         + size_of::<ProtocolIndicationResponse>()
         + size_of::<SystemStatusResponse>()
         + size_of::<AbnormalCaseResponse>()
         + size_of::<u16>() * 2
-        + size_of::<u8>();
+        + size_of::<u8>() * 4
+        + size_of::<u32>()
+        + size_of::<u8>()
+        + size_of::<u16>() * 2
+        + size_of::<f64>()
+        + size_of::<u8>()
+        + size_of::<f64>();
 
     pub fn to_bytes(&self) -> [u8; Self::BYTE_SIZE] {
         let mut buffer = [0u8; Self::BYTE_SIZE];
-        let mut offset = 0;
 
-        // Helper function to copy bytes into the buffer
-        fn copy_into_slice(buffer: &mut [u8], offset: &mut usize, bytes: &[u8]) {
-            let end = *offset + bytes.len();
-            buffer[*offset..end].copy_from_slice(bytes);
-            *offset = end;
+        macro_rules! put {
+            ($name:literal, $value:expr) => {
+                put_field!(
+                    buffer,
+                    telemetry_layout::CHARGE_CHANNEL_SERIES_ITEM,
+                    $name,
+                    $value
+                )
+            };
         }
 
-        copy_into_slice(&mut buffer, &mut offset, &self.millivolts.to_le_bytes());
-        copy_into_slice(&mut buffer, &mut offset, &self.amps.to_le_bytes());
-        copy_into_slice(&mut buffer, &mut offset, &self.watts.to_le_bytes());
-
-        let protocol: u8 = self.protocol.into();
-        let system_status: u8 = self.system_status.into();
-        let abnormal_case: u8 = self.abnormal_case.into();
-        copy_into_slice(&mut buffer, &mut offset, &protocol.to_le_bytes());
-        copy_into_slice(&mut buffer, &mut offset, &system_status.to_le_bytes());
-        copy_into_slice(&mut buffer, &mut offset, &abnormal_case.to_le_bytes());
-
-        copy_into_slice(
-            &mut buffer,
-            &mut offset,
-            &self.buck_output_millivolts.to_le_bytes(),
-        );
-        copy_into_slice(
-            &mut buffer,
-            &mut offset,
-            &self.buck_output_limit_milliamps.to_le_bytes(),
+        put!("millivolts", self.millivolts);
+        put!("amps", self.amps);
+        put!("watts", self.watts);
+        put!("protocol", u8::from(self.protocol));
+        put!("system_status", u8::from(self.system_status));
+        put!("abnormal_case", u8::from(self.abnormal_case));
+        put!("buck_output_millivolts", self.buck_output_millivolts);
+        put!(
+            "buck_output_limit_milliamps",
+            self.buck_output_limit_milliamps
         );
-
-        copy_into_slice(&mut buffer, &mut offset, &self.limit_watts.to_le_bytes());
+        put!("limit_watts", self.limit_watts);
+        put!("requested_limit_watts", self.requested_limit_watts);
+        put!("power_mismatch", self.power_mismatch as u8);
+        put!("active", self.active as u8);
+        put!("sample_interval_ms", self.sample_interval_ms);
+        put!("sample_overrun", self.sample_overrun as u8);
+        put!("amps_stale", self.amps_stale as u8);
+        put!("watts_stale", self.watts_stale as u8);
+        put!("buck_output_milliamps", self.buck_output_milliamps);
+        put!("seq", self.seq);
+        put!("shunt_microvolts", self.shunt_microvolts);
+        put!("online_status", self.online_status);
+        put!("computed_watts", self.computed_watts);
 
         buffer
     }
 }
 
+const _: () = assert!(
+    telemetry_layout::byte_size(telemetry_layout::CHARGE_CHANNEL_SERIES_ITEM)
+        == ChargeChannelSeriesItem::BYTE_SIZE
+);
+
+/// The fields of [`ChargeChannelSeriesItem`] by name; see [`ProtectorJson`].
+#[cfg(feature = "json-payload")]
+#[derive(Serialize)]
+struct ChargeChannelJson {
+    millivolts: f32,
+    amps: f32,
+    watts: f32,
+    protocol: u8,
+    system_status: u8,
+    abnormal_case: u8,
+    buck_output_millivolts: u16,
+    buck_output_limit_milliamps: u16,
+    limit_watts: u8,
+    requested_limit_watts: u8,
+    power_mismatch: bool,
+    active: bool,
+    sample_interval_ms: u32,
+    sample_overrun: bool,
+    amps_stale: bool,
+    watts_stale: bool,
+    buck_output_milliamps: u16,
+    seq: u16,
+    shunt_microvolts: f32,
+    online_status: u8,
+    computed_watts: f32,
+}
+
+#[cfg(feature = "json-payload")]
+impl ChargeChannelSeriesItem {
+    pub fn to_json(&self) -> serde_json_core::ser::Result<JsonPayload> {
+        serde_json_core::to_string(&ChargeChannelJson {
+            millivolts: self.millivolts as f32,
+            amps: self.amps as f32,
+            watts: self.watts as f32,
+            protocol: self.protocol.into(),
+            system_status: self.system_status.into(),
+            abnormal_case: self.abnormal_case.into(),
+            buck_output_millivolts: self.buck_output_millivolts,
+            buck_output_limit_milliamps: self.buck_output_limit_milliamps,
+            limit_watts: self.limit_watts,
+            requested_limit_watts: self.requested_limit_watts,
+            power_mismatch: self.power_mismatch,
+            active: self.active,
+            sample_interval_ms: self.sample_interval_ms,
+            sample_overrun: self.sample_overrun,
+            amps_stale: self.amps_stale,
+            watts_stale: self.watts_stale,
+            buck_output_milliamps: self.buck_output_milliamps,
+            seq: self.seq,
+            shunt_microvolts: self.shunt_microvolts as f32,
+            online_status: self.online_status,
+            computed_watts: self.computed_watts as f32,
+        })
+    }
+}
+
 impl Default for ChargeChannelSeriesItem {
     fn default() -> Self {
         Self {
             millivolts: 0.0,
             amps: 0.0,
             watts: 0.0,
+            computed_watts: 0.0,
             protocol: 0.into(),
             system_status: 0.into(),
             abnormal_case: 0.into(),
             buck_output_millivolts: 0,
             buck_output_limit_milliamps: 0,
             limit_watts: 0,
+            requested_limit_watts: 0,
+            power_mismatch: false,
+            active: false,
+            sample_interval_ms: 0,
+            sample_overrun: false,
+            amps_stale: false,
+            watts_stale: false,
+            buck_output_milliamps: 0,
+            seq: 0,
+            shunt_microvolts: 0.0,
+            online_status: 0,
         }
     }
 }
 
+/// The power contract a charge channel's sink negotiated.
+///
+/// The SW3526 doesn't expose the PD request itself, so this is derived from the buck output
+/// target and its current limit, which the chip programs from the accepted request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PdContract {
+    pub protocol: u8,
+    pub millivolts: u16,
+    pub milliamps: u16,
+}
+
+impl PdContract {
+    pub fn from_series_item(item: &ChargeChannelSeriesItem) -> Self {
+        Self {
+            protocol: item.protocol.into(),
+            millivolts: item.buck_output_millivolts,
+            milliamps: item.buck_output_limit_milliamps,
+        }
+    }
+
+    pub fn milliwatts(&self) -> u32 {
+        self.millivolts as u32 * self.milliamps as u32 / 1000
+    }
+
+    /// `[protocol: u8][millivolts: u16][milliamps: u16][milliwatts: u32]`, in the
+    /// [`telemetry_layout::byte_order`] the series use.
+    pub fn to_bytes(&self) -> [u8; 9] {
+        let mut buffer = [0u8; 9];
+        buffer[0] = self.protocol;
+        buffer[1..3].copy_from_slice(&wire_bytes!(self.millivolts));
+        buffer[3..5].copy_from_slice(&wire_bytes!(self.milliamps));
+        buffer[5..].copy_from_slice(&wire_bytes!(self.milliwatts()));
+        buffer
+    }
+}
+
 pub(crate) type ChargeChannelSeriesItemChannel =
     Channel<CriticalSectionRawMutex, ChargeChannelSeriesItem, 10>;
 
@@ -160,4 +454,210 @@ pub(crate) static CHARGE_CHANNEL_SERIES_ITEM_CHANNELS: [ChargeChannelSeriesItemC
     Channel::new(),
 ];
 
-pub(crate) static VIN_STATUS_CFG_CHANNEL: Channel<CriticalSectionRawMutex, VinState, 1> = Channel::new();
\ No newline at end of file
+/// Depth of the per-target config command queues. A burst of commands larger than this is
+/// rejected with [`CommandResult::Busy`] instead of blocking the MQTT task.
+pub(crate) const CFG_QUEUE_SIZE: usize = 4;
+
+/// Requests for the protector's VIN control.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum VinAction {
+    /// Sets the state VIN is kept in, ending any override. Turning VIN on is refused while an
+    /// over-current trip is latched.
+    Set(VinState),
+    /// Clears a latched over-current trip, then sets VIN on like `Set`. Only `vin-status` sends
+    /// it.
+    Rearm,
+    /// Cuts VIN at once, past the minimum on time, ending any override. Sent by the offline
+    /// guard.
+    Cut,
+    /// Holds VIN in a state for a number of seconds, then returns it to the state it was set
+    /// to. `0` seconds ends a running override early.
+    Override(VinState, u16),
+}
+
+pub(crate) static VIN_STATUS_CFG_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    VinAction,
+    CFG_QUEUE_SIZE,
+> = Channel::new();
+
+pub(crate) static PROTECTOR_CFG_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    ProtectorCommand,
+    CFG_QUEUE_SIZE,
+> = Channel::new();
+
+/// Which charge channels a `reinit` command re-runs the init path for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ReinitTarget {
+    /// Both muxes and every channel, as after boot.
+    All,
+    Channel(u8),
+}
+
+/// What `ch<n>/reset-device` resets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum ChannelReset {
+    /// The INA226, to its power-on defaults through its RST bit.
+    Ina226 = 0,
+    /// A PD hard reset sent by the SW3526. Only the PD link restarts and the sink renegotiates;
+    /// the SW3526 has no soft reset and keeps its registers.
+    PdHardReset = 1,
+}
+
+impl TryFrom<u8> for ChannelReset {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ChannelReset::Ina226),
+            1 => Ok(ChannelReset::PdHardReset),
+            _ => Err(value),
+        }
+    }
+}
+
+/// One-off actions for the charge channel task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChargeChannelAction {
+    Reinit(ReinitTarget),
+    /// Resets the INA226 or the PD link of a channel and re-runs the channel's init.
+    Reset(u8, ChannelReset),
+    /// Reads a channel's chip ids again and republishes them.
+    ReadIds(u8),
+    /// Resets a channel's blown soft fuse.
+    ResetFuse(u8),
+    /// Switches every channel's output on (`true`) or holds them all off (`false`).
+    Outputs(bool),
+    /// Samples every channel each `period_ms` for `secs` seconds; `0` seconds ends a running
+    /// high-rate window.
+    HighRate {
+        period_ms: u16,
+        secs: u16,
+    },
+}
+
+pub(crate) static CHARGE_CHANNEL_CFG_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    ChargeChannelAction,
+    CFG_QUEUE_SIZE,
+> = Channel::new();
+
+/// New Wi-Fi credentials to switch to. Only one switch can be in flight.
+pub(crate) static WIFI_CREDENTIALS_CHANNEL: Channel<CriticalSectionRawMutex, WifiCredentials, 1> =
+    Channel::new();
+
+/// Max length of the optional request id trailing a command payload.
+pub(crate) const MAX_REQUEST_ID_LEN: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CommandResult {
+    Applied = 0,
+    InvalidPayload = 1,
+    InvalidValue = 2,
+    Busy = 3,
+    UnknownField = 4,
+    /// The command was valid but applying it failed, e.g. a flash write error.
+    Failed = 5,
+}
+
+/// Max length of the config field a command ack is published under.
+pub(crate) const MAX_CFG_FIELD_LEN: usize = 32;
+
+/// Result of a config command, published to `cfg-ack/<field>`.
+///
+/// Payload layout: `[result: u8][request id: 0..=MAX_REQUEST_ID_LEN bytes]`. The request id is
+/// whatever the sender appended after the command value, echoed back verbatim.
+#[derive(Debug, Clone)]
+pub(crate) struct CommandAck {
+    pub field: String<MAX_CFG_FIELD_LEN>,
+    pub result: CommandResult,
+    pub request_id: Vec<u8, MAX_REQUEST_ID_LEN>,
+}
+
+impl CommandAck {
+    pub fn new(field: &str) -> Self {
+        let mut name = String::new();
+        if name.push_str(field).is_err() {
+            log::warn!("cfg field name too long for ack: {:?}", field);
+        }
+
+        Self {
+            field: name,
+            result: CommandResult::Applied,
+            request_id: Vec::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8, { MAX_REQUEST_ID_LEN + 1 }> {
+        let mut buffer = Vec::new();
+        buffer.push(self.result as u8).ok();
+        buffer.extend_from_slice(&self.request_id).ok();
+        buffer
+    }
+}
+
+pub(crate) static CMD_ACK_CHANNEL: Channel<CriticalSectionRawMutex, CommandAck, 8> = Channel::new();
+
+pub(crate) const MAX_SYSTEM_MESSAGE_LEN: usize = 256;
+/// Max length of a system message topic, below the device prefix.
+pub(crate) const MAX_SYSTEM_TOPIC_LEN: usize = 32;
+
+/// Retained status message published to `<topic>` under the device prefix, for reports that
+/// don't warrant their own series type.
+#[derive(Debug, Clone)]
+pub(crate) struct SystemMessage {
+    pub topic: String<MAX_SYSTEM_TOPIC_LEN>,
+    pub payload: Vec<u8, MAX_SYSTEM_MESSAGE_LEN>,
+}
+
+/// Deep enough for a whole `cfg/get` reply.
+pub(crate) static SYSTEM_MESSAGE_CHANNEL: Channel<CriticalSectionRawMutex, SystemMessage, 5> =
+    Channel::new();
+
+/// Queues a retained message for `<topic>`. Dropped with a warning if it doesn't fit or the
+/// queue is full, so it is safe to call from any task.
+pub(crate) fn publish_status(topic: &str, payload: &[u8]) {
+    let mut message = SystemMessage {
+        topic: String::new(),
+        payload: Vec::new(),
+    };
+    if message.topic.push_str(topic).is_err() || message.payload.extend_from_slice(payload).is_err()
+    {
+        log::warn!("System message for {:?} too long, dropping", topic);
+        return;
+    }
+
+    if SYSTEM_MESSAGE_CHANNEL.try_send(message).is_err() {
+        log::warn!("System message queue full, dropping {:?}", topic);
+    }
+}
+
+/// Like [`publish_status`], but waits for room in the queue instead of dropping the message.
+/// For tasks sending more messages in a row than the queue holds.
+pub(crate) async fn send_status(topic: &str, payload: &[u8]) {
+    let mut message = SystemMessage {
+        topic: String::new(),
+        payload: Vec::new(),
+    };
+    if message.topic.push_str(topic).is_err() || message.payload.extend_from_slice(payload).is_err()
+    {
+        log::warn!("System message for {:?} too long, dropping", topic);
+        return;
+    }
+
+    SYSTEM_MESSAGE_CHANNEL.send(message).await;
+}
+
+/// Queues a retained message for `system/<topic>`; see [`publish_status`].
+pub(crate) fn publish_system_message(topic: &str, payload: &[u8]) {
+    let mut system_topic = String::<MAX_SYSTEM_TOPIC_LEN>::new();
+    if system_topic.push_str("system/").is_err() || system_topic.push_str(topic).is_err() {
+        log::warn!("System topic {:?} too long, dropping", topic);
+        return;
+    }
+
+    publish_status(&system_topic, payload);
+}
@@ -0,0 +1,248 @@
+use core::{cell::RefCell, fmt::Write};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Duration};
+use heapless::String;
+
+use crate::{
+    bus::publish_system_message,
+    snapshot,
+    storage::{self, Slot},
+};
+
+/// Calibrated INA226s: the four charge channels, then the protector.
+pub const CALIBRATION_DEVICES: usize = 5;
+pub const PROTECTOR_DEVICE: usize = 4;
+
+/// Corrections beyond this range point at a wrong reference or a broken sensor, not drift.
+const MIN_GAIN: f32 = 0.8;
+const MAX_GAIN: f32 = 1.2;
+
+/// Readings below these are too close to the noise floor to calibrate against.
+const MIN_CAL_MILLIVOLTS: f64 = 1000.0;
+const MIN_CAL_AMPS: f64 = 0.1;
+
+const RECORD_LEN: usize = CALIBRATION_DEVICES * 2 * size_of::<f32>();
+
+/// Quiet time after the last change before [`task`] writes the factors, so calibrating several
+/// devices in a row costs one flash write.
+const SAVE_DELAY: Duration = Duration::from_secs(5);
+
+/// Shunt and current range an INA226 is programmed with. Unlike the correction factors, this
+/// is a property of the board revision, not of the individual part.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationConfig {
+    pub shunt_ohms: f64,
+    /// Highest current to resolve; sets the current register's LSB.
+    pub max_current_amps: f64,
+}
+
+impl Default for CalibrationConfig {
+    /// The 10mΩ shunts and 5A range every board so far uses.
+    fn default() -> Self {
+        Self {
+            shunt_ohms: 0.01,
+            max_current_amps: 5.0,
+        }
+    }
+}
+
+/// Correction factors applied to one INA226's readings.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    pub voltage_gain: f32,
+    pub current_gain: f32,
+}
+
+impl Calibration {
+    pub const IDENTITY: Self = Self {
+        voltage_gain: 1.0,
+        current_gain: 1.0,
+    };
+
+    pub fn millivolts(&self, millivolts: f64) -> f64 {
+        millivolts * self.voltage_gain as f64
+    }
+
+    pub fn amps(&self, amps: f64) -> f64 {
+        amps * self.current_gain as f64
+    }
+
+    pub fn watts(&self, watts: f64) -> f64 {
+        watts * self.voltage_gain as f64 * self.current_gain as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    Voltage,
+    Current,
+}
+
+#[derive(Debug)]
+pub enum CalibrationError {
+    /// The device hasn't produced a reading yet.
+    NoReading,
+    /// The reading is too small to calibrate against.
+    ReadingTooLow,
+    /// The resulting factor is outside `MIN_GAIN..=MAX_GAIN`.
+    OutOfRange,
+}
+
+static CALIBRATION: Mutex<CriticalSectionRawMutex, RefCell<[Calibration; CALIBRATION_DEVICES]>> =
+    Mutex::new(RefCell::new([Calibration::IDENTITY; CALIBRATION_DEVICES]));
+
+/// Raised when the factors in force differ from the stored ones.
+static SAVE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn get(device: usize) -> Calibration {
+    CALIBRATION.lock(|calibration| calibration.borrow()[device])
+}
+
+/// Loads the stored factors, if any, and publishes them. Call once at boot.
+pub fn load() {
+    let mut record = [0u8; RECORD_LEN];
+    match storage::read(Slot::Calibration, &mut record) {
+        Some(RECORD_LEN) => {
+            let mut factors = [Calibration::IDENTITY; CALIBRATION_DEVICES];
+            for (factor, bytes) in factors.iter_mut().zip(record.chunks_exact(8)) {
+                let voltage_gain = f32::from_le_bytes(bytes[..4].try_into().unwrap());
+                let current_gain = f32::from_le_bytes(bytes[4..].try_into().unwrap());
+                if is_valid_gain(voltage_gain) && is_valid_gain(current_gain) {
+                    *factor = Calibration {
+                        voltage_gain,
+                        current_gain,
+                    };
+                }
+            }
+
+            CALIBRATION.lock(|calibration| *calibration.borrow_mut() = factors);
+            log::info!("Calibration loaded");
+        }
+        Some(len) => log::warn!("Ignoring calibration record of {} bytes", len),
+        None => log::info!("No stored calibration"),
+    }
+
+    publish(&all());
+}
+
+/// Captures the device's latest reading against `reference` (millivolts or amps) and applies the
+/// resulting correction factor to subsequent readings. [`task`] stores it once calibration
+/// settles. A reference of 0 resets the factor to 1.
+pub fn calibrate(
+    device: usize,
+    quantity: Quantity,
+    reference: f32,
+) -> Result<Calibration, CalibrationError> {
+    let current = get(device);
+
+    let gain = if reference == 0.0 {
+        1.0
+    } else {
+        // The snapshot holds corrected readings; undo the factor in force to get the raw one.
+        let reading = latest_reading(device, quantity).ok_or(CalibrationError::NoReading)?;
+        let (raw, floor) = match quantity {
+            Quantity::Voltage => (reading / current.voltage_gain as f64, MIN_CAL_MILLIVOLTS),
+            Quantity::Current => (reading / current.current_gain as f64, MIN_CAL_AMPS),
+        };
+        if raw < floor {
+            return Err(CalibrationError::ReadingTooLow);
+        }
+
+        (reference as f64 / raw) as f32
+    };
+
+    if !is_valid_gain(gain) {
+        return Err(CalibrationError::OutOfRange);
+    }
+
+    let mut factors = all();
+    match quantity {
+        Quantity::Voltage => factors[device].voltage_gain = gain,
+        Quantity::Current => factors[device].current_gain = gain,
+    }
+
+    CALIBRATION.lock(|calibration| *calibration.borrow_mut() = factors);
+    log::info!("Calibrated device {} {:?}: {}", device, quantity, gain);
+    publish(&factors);
+    SAVE.signal(());
+
+    Ok(factors[device])
+}
+
+fn is_valid_gain(gain: f32) -> bool {
+    (MIN_GAIN..=MAX_GAIN).contains(&gain)
+}
+
+fn all() -> [Calibration; CALIBRATION_DEVICES] {
+    CALIBRATION.lock(|calibration| *calibration.borrow())
+}
+
+/// Magnitude of the latest reading of `quantity`; the protector's current is signed.
+fn latest_reading(device: usize, quantity: Quantity) -> Option<f64> {
+    let snapshot = snapshot::snapshot();
+    let (millivolts, amps) = if device == PROTECTOR_DEVICE {
+        let sample = snapshot.protector?;
+        (sample.value.millivolts, sample.value.amps)
+    } else {
+        let sample = snapshot.channels[device]?;
+        (sample.value.millivolts, sample.value.amps)
+    };
+
+    Some(match quantity {
+        Quantity::Voltage => millivolts,
+        Quantity::Current if amps < 0.0 => -amps,
+        Quantity::Current => amps,
+    })
+}
+
+/// Writes the factors to flash after changes have settled for [`SAVE_DELAY`]. The sector erase
+/// blocks, so it is kept out of the command path that runs in the MQTT task.
+#[embassy_executor::task]
+pub async fn task() {
+    loop {
+        SAVE.wait().await;
+        while with_timeout(SAVE_DELAY, SAVE.wait()).await.is_ok() {}
+
+        save(&all());
+    }
+}
+
+fn save(factors: &[Calibration; CALIBRATION_DEVICES]) {
+    let mut record = [0u8; RECORD_LEN];
+    for (factor, bytes) in factors.iter().zip(record.chunks_exact_mut(8)) {
+        bytes[..4].copy_from_slice(&factor.voltage_gain.to_le_bytes());
+        bytes[4..].copy_from_slice(&factor.current_gain.to_le_bytes());
+    }
+
+    match storage::write(Slot::Calibration, &record) {
+        Ok(()) => log::info!("Calibration saved"),
+        Err(err) => log::error!("Cannot store calibration: {:?}", err),
+    }
+}
+
+/// Publishes the applied factors to `system/calibration` as
+/// `{"ch0":[voltage_gain,current_gain],...,"protector":[...]}`.
+fn publish(factors: &[Calibration; CALIBRATION_DEVICES]) {
+    let mut json = String::<192>::new();
+    for (device, factor) in factors.iter().enumerate() {
+        let separator = if device == 0 { "{" } else { "," };
+        if device == PROTECTOR_DEVICE {
+            write!(json, "{}\"protector\"", separator).ok();
+        } else {
+            write!(json, "{}\"ch{}\"", separator, device).ok();
+        }
+        write!(
+            json,
+            ":[{:.4},{:.4}]",
+            factor.voltage_gain, factor.current_gain
+        )
+        .ok();
+    }
+    json.push('}').ok();
+
+    publish_system_message("calibration", json.as_bytes());
+}
@@ -1,30 +1,108 @@
-use core::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not};
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
-use embassy_futures::select::{self, select};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Ticker};
-use embedded_hal_async::i2c::{I2c, SevenBitAddress};
+use core::{
+    cell::Cell,
+    fmt::Write,
+    ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not},
+};
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+};
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
+use embedded_hal_async::i2c::I2c;
 use esp_hal::{peripherals::I2C0, Async};
-use ina226::INA226;
+use heapless::String;
+use ina226::{MaskEnableFlags, INA226};
 use pca9546a::PCA9546A;
-use sw3526::{FastChargeConfig1, SW3526};
+use sw3526::{
+    BuckForceOff, BuckForceOffConfig, CCUnDrivenDurationBuckForceOff, FastChargeConfig1, SW3526,
+};
 
 use crate::{
+    board,
     bus::{
-        ChargeChannelSeriesItem, ChargeChannelSeriesItemChannel,
-        CHARGE_CHANNEL_SERIES_ITEM_CHANNELS,
+        publish_status, publish_system_message, ChannelReset, ChargeChannelAction,
+        ChargeChannelSeriesItem, ChargeChannelSeriesItemChannel, PdContract, ReinitTarget,
+        CHARGE_CHANNEL_CFG_CHANNEL, CHARGE_CHANNEL_SERIES_ITEM_CHANNELS,
     },
+    calibration::{self, CalibrationConfig},
     error::ChargeChannelError,
+    helper::{
+        abs, diverges, exceeds_with_hysteresis, MissedReads, NoiseTuner, SoftFuse,
+        POWER_MISMATCH_FLOOR_WATTS,
+    },
+    history,
+    i2c_bus::{ErrorClass, Priority, SharedI2c},
     i2c_mux::{ChargeChannelIndex, I2cMux},
+    i2c_trace::TracedI2c,
+    lifetime, peaks, protector, schema,
+    settings::{
+        self, Settings, MAX_INA226_AVG_STEP, PD_PROFILE_12V, PD_PROFILE_15V, PD_PROFILE_20V,
+        PD_PROFILE_9V, PD_PROFILE_PPS0, PD_PROFILE_PPS1,
+    },
+    snapshot,
+    system::{self, OutputHolds},
 };
 
-const PCA9546A_ADDRESS_0: SevenBitAddress = 0x70;
-const PCA9546A_ADDRESS_1: SevenBitAddress = 0x71;
+const INA226_CONFIGURATION_REGISTER: u8 = 0x00;
+/// Setting bit 15 of the configuration register resets the INA226 to power-on defaults.
+const INA226_RESET: u16 = 0x8000;
+
+/// Period of the charge channel loop. Each channel is sampled once per period.
+const SAMPLE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Pause between two charge channel loop cycles with `poll_conversion_ready`, so the telemetry
+/// rate stays bounded even when the bus is fast.
+const POLLED_SAMPLE_PERIOD: Duration = Duration::from_millis(50);
+
+/// Delay between two reads of the INA226 conversion-ready flag.
+const CONVERSION_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How often the achieved loop rate is published to `system/sample-rate`.
+const SAMPLE_RATE_REPORT_PERIOD: Duration = Duration::from_secs(30);
+
+/// How often [`force_off_task`] repeats the force-off while outputs are held off. The SW3526
+/// releases it after one second.
+const FORCE_OFF_REFRESH_PERIOD: Duration = Duration::from_millis(500);
+
+/// Buck off for the next second, with CC undriven so the sink sees a detach rather than a
+/// brown-out.
+const FORCE_OFF: BuckForceOffConfig = BuckForceOffConfig {
+    force_off: BuckForceOff::TurnOffOneSecond,
+    cc_un_driven_duration_buck_force_off: CCUnDrivenDurationBuckForceOff::UnDriven,
+};
+
+/// How often a changing soft fuse heat is published.
+const FUSE_REPORT_PERIOD: Duration = Duration::from_secs(10);
+
+/// Wait before re-initializing an SW3526 that is still offline after its timeouts, doubled after
+/// every failed attempt up to [`SW3526_RETRY_MAX`].
+const SW3526_RETRY_MIN: Duration = Duration::from_secs(5);
+const SW3526_RETRY_MAX: Duration = Duration::from_secs(300);
+
+/// Bounds of a `high-rate` window: the shortest sample period, in milliseconds, and the
+/// longest window, in seconds.
+pub const MIN_HIGH_RATE_PERIOD_MS: u16 = 50;
+pub const MAX_HIGH_RATE_SECS: u16 = 300;
+
+/// A running `high-rate` window.
+#[derive(Debug, Clone, Copy)]
+struct HighRate {
+    period: Duration,
+    until: Instant,
+}
+
+static HIGH_RATE: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<HighRate>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+
+/// Sample period of the running `high-rate` window, if any.
+fn high_rate_period() -> Option<Duration> {
+    HIGH_RATE.lock(|high_rate| high_rate.get().map(|high_rate| high_rate.period))
+}
 
-const INA226_0: SevenBitAddress = 0x44;
-const INA226_1: SevenBitAddress = 0x41;
-const INA226_2: SevenBitAddress = 0x45;
-const INA226_3: SevenBitAddress = 0x40;
+/// An active channel goes idle again once its current drops below this share of the
+/// configured threshold, so a load hovering around the threshold doesn't flap.
+const ACTIVE_RELEASE_PERCENT: u8 = 50;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum ChargeChannelOnlineStatus {
@@ -96,11 +174,37 @@ impl BitXorAssign for ChargeChannelOnlineStatus {
 }
 
 pub struct ChargeChannel<I2C> {
+    index: usize,
     ina226: INA226<I2C>,
+    ina226_address: u8,
+    calibration_config: CalibrationConfig,
+    /// For the INA226 RST write, which the driver doesn't expose. A shared-bus handle at the
+    /// channel's priority, like the drivers' own.
+    ina226_reset_i2c: I2C,
     sw3526: SW3526<I2C>,
     charge_channel: &'static ChargeChannelSeriesItemChannel,
     online_status: ChargeChannelOnlineStatus,
     current_channel_state: ChargeChannelSeriesItem,
+    contract: Option<PdContract>,
+    programmed_limit_watts: Option<u8>,
+    programmed_pd_profiles: Option<u8>,
+    last_sample_at: Option<Instant>,
+    missed_reads: MissedReads,
+    /// INA226 averaging step programmed on the next (re)configuration.
+    avg_step: u8,
+    noise_tuner: NoiseTuner,
+    fuse: SoftFuse,
+    /// Heat in the last `ch<n>/fuse` message, and when it was published.
+    fuse_reported: (f64, Instant),
+    /// Chip ids as last read, `None` while the chip doesn't answer.
+    sw3526_version: Option<u8>,
+    ina226_die_id: Option<u16>,
+    /// SW3526 timeouts in a row, and since boot.
+    sw3526_timeouts: u32,
+    sw3526_timeouts_total: u32,
+    /// When to re-initialize an SW3526 lost to timeouts next, and the wait after that if it
+    /// fails again.
+    sw3526_retry: Option<(Instant, Duration)>,
 }
 
 impl<I2C, E> ChargeChannel<I2C>
@@ -109,23 +213,96 @@ where
     E: embedded_hal_async::i2c::Error + 'static,
 {
     pub fn new(
+        index: usize,
         ina226: INA226<I2C>,
+        ina226_address: u8,
+        ina226_reset_i2c: I2C,
         sw3526: SW3526<I2C>,
         charge_channel: &'static ChargeChannelSeriesItemChannel,
+        calibration_config: CalibrationConfig,
     ) -> Self {
         Self {
+            index,
             ina226,
+            calibration_config,
+            ina226_address,
+            ina226_reset_i2c,
             sw3526,
             charge_channel,
             online_status: ChargeChannelOnlineStatus::Offline,
             current_channel_state: ChargeChannelSeriesItem::default(),
+            contract: None,
+            programmed_limit_watts: None,
+            programmed_pd_profiles: None,
+            last_sample_at: None,
+            missed_reads: MissedReads::new(),
+            avg_step: settings::get().ina226_avg_step,
+            noise_tuner: NoiseTuner::new(),
+            fuse: SoftFuse::new(),
+            fuse_reported: (0.0, Instant::from_ticks(0)),
+            sw3526_version: None,
+            ina226_die_id: None,
+            sw3526_timeouts: 0,
+            sw3526_timeouts_total: 0,
+            sw3526_retry: None,
+        }
+    }
+
+    pub fn online_status(&self) -> ChargeChannelOnlineStatus {
+        self.online_status
+    }
+
+    /// Whether the channel is delivering power, i.e. its output current crossed the configured
+    /// `channel_active_milliamps` threshold (with hysteresis). Features that care about channel
+    /// activity should use this instead of their own current checks.
+    pub fn is_active(&self) -> bool {
+        self.current_channel_state.active
+    }
+
+    fn update_active(&mut self, active_milliamps: u16) {
+        let was_active = self.is_active();
+        let active = exceeds_with_hysteresis(
+            was_active,
+            self.current_channel_state.amps * 1000.0,
+            active_milliamps as f64,
+            ACTIVE_RELEASE_PERCENT,
+        );
+
+        if active != was_active {
+            log::info!(
+                "ch{} {}",
+                self.index,
+                if active { "active" } else { "idle" }
+            );
+            self.current_channel_state.active = active;
         }
     }
 
+    /// Publishes the negotiated contract to `ch<n>/contract` whenever it changes.
+    fn update_contract(&mut self) {
+        let contract = PdContract::from_series_item(&self.current_channel_state);
+        if self.contract == Some(contract) {
+            return;
+        }
+
+        log::info!(
+            "ch{} contract: protocol {}, {}mV {}mA",
+            self.index,
+            contract.protocol,
+            contract.millivolts,
+            contract.milliamps
+        );
+
+        let mut topic = String::<16>::new();
+        write!(topic, "ch{}/contract", self.index).ok();
+        publish_status(&topic, &contract.to_bytes());
+        self.contract = Some(contract);
+    }
+
     async fn config_ina226(&mut self) -> Result<(), ChargeChannelError<E>> {
         let config = ina226::Config {
             mode: ina226::MODE::ShuntBusVoltageContinuous,
-            avg: ina226::AVG::_4,
+            avg: avg_for_step(self.avg_step),
             vbusct: ina226::VBUSCT::_588us,
             vshct: ina226::VSHCT::_588us,
         };
@@ -135,7 +312,10 @@ where
             .await
             .map_err(|err| ChargeChannelError::I2CError(err))?;
         self.ina226
-            .callibrate(0.01, 5.0)
+            .callibrate(
+                self.calibration_config.shunt_ohms,
+                self.calibration_config.max_current_amps,
+            )
             .await
             .map_err(|err| ChargeChannelError::I2CError(err))?;
 
@@ -144,12 +324,17 @@ where
 
     async fn init_ina226(&mut self) -> Result<(), ChargeChannelError<E>> {
         match self.ina226.die_id().await {
-            Ok(_) => {
+            Ok(die_id) => {
+                self.ina226_die_id = Some(die_id);
                 self.online_status |= ChargeChannelOnlineStatus::INA226Online;
 
                 self.config_ina226().await?;
+                self.publish_averaging(None);
+            }
+            Err(_) => {
+                self.ina226_die_id = None;
+                self.online_status &= !ChargeChannelOnlineStatus::INA226Online;
             }
-            Err(_) => self.online_status &= !ChargeChannelOnlineStatus::INA226Online,
         }
 
         Ok(())
@@ -158,6 +343,7 @@ where
     async fn init_sw3526(&mut self) -> Result<(), ChargeChannelError<E>> {
         match self.sw3526.get_chip_version().await {
             Ok(value) => {
+                self.sw3526_version = Some(value);
                 self.online_status |= ChargeChannelOnlineStatus::SW3526Online;
                 log::info!("sw3526 Chip version: {}", value);
 
@@ -166,25 +352,14 @@ where
                     .await
                     .map_err(|err| ChargeChannelError::I2CError(err))?;
 
-                self.sw3526
-                    .set_fast_charge_config_1(FastChargeConfig1 {
-                        pps1_disabled: false,
-                        pps0_disabled: false,
-                        pd_20v_disabled: false,
-                        pd_15v_disabled: false,
-                        pd_12v_disabled: false,
-                        pd_9v_disabled: false,
-                        pd_disabled: false,
-                    })
-                    .await
-                    .map_err(|err| ChargeChannelError::I2CError(err))?;
+                self.programmed_pd_profiles = None;
+                self.apply_pd_profiles().await?;
 
-                self.sw3526
-                    .set_output_limit_watts(65)
-                    .await
-                    .map_err(|err| ChargeChannelError::I2CError(err))?;
+                self.programmed_limit_watts = None;
+                self.apply_output_limit().await?;
             }
             Err(_) => {
+                self.sw3526_version = None;
                 self.online_status &= !ChargeChannelOnlineStatus::SW3526Online;
             }
         };
@@ -192,7 +367,82 @@ where
         Ok(())
     }
 
+    /// Programs the requested output limit, clamped to the configured range, whenever either
+    /// changed. Every write of the limit goes through here.
+    async fn apply_output_limit(&mut self) -> Result<(), ChargeChannelError<E>> {
+        let settings = settings::get();
+        let requested = settings.limit_watts[self.index];
+        let configured = settings.effective_limit_watts(self.index);
+        let effective = [
+            protector::thermal_limit_watts(),
+            protector::input_limit_watts(),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(configured, u8::min);
+        self.current_channel_state.requested_limit_watts = requested;
+
+        if self.programmed_limit_watts == Some(effective) {
+            return Ok(());
+        }
+
+        if effective != configured {
+            log::warn!(
+                "ch{} output limit {}W throttled to {}W",
+                self.index,
+                configured,
+                effective
+            );
+        } else if effective != requested {
+            log::warn!(
+                "ch{} output limit {}W clamped to {}W",
+                self.index,
+                requested,
+                effective
+            );
+        }
+
+        self.sw3526
+            .set_output_limit_watts(effective)
+            .await
+            .map_err(|err| ChargeChannelError::I2CError(err))?;
+        self.programmed_limit_watts = Some(effective);
+
+        Ok(())
+    }
+
+    /// Programs the configured PD profiles whenever they changed. Taking effect between cycles
+    /// means a running negotiation completes against the old set first.
+    async fn apply_pd_profiles(&mut self) -> Result<(), ChargeChannelError<E>> {
+        let profiles = settings::get().pd_profiles[self.index];
+        if self.programmed_pd_profiles == Some(profiles) {
+            return Ok(());
+        }
+
+        let disabled = |profile: u8| profiles & profile == 0;
+        self.sw3526
+            .set_fast_charge_config_1(FastChargeConfig1 {
+                pps1_disabled: disabled(PD_PROFILE_PPS1),
+                pps0_disabled: disabled(PD_PROFILE_PPS0),
+                pd_20v_disabled: disabled(PD_PROFILE_20V),
+                pd_15v_disabled: disabled(PD_PROFILE_15V),
+                pd_12v_disabled: disabled(PD_PROFILE_12V),
+                pd_9v_disabled: disabled(PD_PROFILE_9V),
+                pd_disabled: false,
+            })
+            .await
+            .map_err(|err| ChargeChannelError::I2CError(err))?;
+        if self.programmed_pd_profiles.is_some() {
+            log::info!("ch{} PD profiles set to {:#04x}", self.index, profiles);
+        }
+        self.programmed_pd_profiles = Some(profiles);
+
+        Ok(())
+    }
+
     pub async fn init(&mut self) -> Result<(), ChargeChannelError<E>> {
+        self.last_sample_at = None;
+
         match self.init_sw3526().await {
             Ok(_) => {
                 log::info!("SW3526 init success");
@@ -213,34 +463,192 @@ where
             }
         }
 
+        self.publish_ids();
         Ok(())
     }
 
+    /// Reads both chip ids again, e.g. after a part was swapped, and republishes them.
+    pub async fn read_ids(&mut self) -> Result<(), ChargeChannelError<E>> {
+        self.sw3526_version = self.sw3526.get_chip_version().await.ok();
+        self.ina226_die_id = self.ina226.die_id().await.ok();
+        self.publish_ids();
+        Ok(())
+    }
+
+    /// Publishes `{"sw3526_version":<u8>|null,"ina226_die_id":<u16>|null}` to `ch<n>/ids`, with
+    /// `null` for a chip that didn't answer.
+    fn publish_ids(&self) {
+        let mut topic = String::<16>::new();
+        write!(topic, "ch{}/ids", self.index).ok();
+        let mut json = String::<64>::new();
+        json.push_str("{\"sw3526_version\":").ok();
+        match self.sw3526_version {
+            Some(version) => write!(json, "{}", version).ok(),
+            None => json.push_str("null").ok(),
+        };
+        json.push_str(",\"ina226_die_id\":").ok();
+        match self.ina226_die_id {
+            Some(die_id) => write!(json, "{}", die_id).ok(),
+            None => json.push_str("null").ok(),
+        };
+        json.push('}').ok();
+
+        publish_status(&topic, json.as_bytes());
+    }
+
+    /// Resets the INA226 or the PD link, see [`ChannelReset`], and re-runs the channel's init.
+    pub async fn reset(&mut self, reset: ChannelReset) -> Result<(), ChargeChannelError<E>> {
+        log::info!("ch{} reset {:?}", self.index, reset);
+
+        match reset {
+            ChannelReset::Ina226 => {
+                let [high, low] = INA226_RESET.to_be_bytes();
+                self.ina226_reset_i2c
+                    .write(
+                        self.ina226_address,
+                        &[INA226_CONFIGURATION_REGISTER, high, low],
+                    )
+                    .await
+                    .map_err(|err| ChargeChannelError::I2CError(err))?;
+            }
+            ChannelReset::PdHardReset => {
+                self.sw3526
+                    .send_pd_hard_reset()
+                    .await
+                    .map_err(|err| ChargeChannelError::I2CError(err))?;
+            }
+        }
+
+        self.init().await
+    }
+
+    pub fn fuse_blown(&self) -> bool {
+        self.fuse.blown()
+    }
+
+    /// Forces the output off while all outputs are switched off (`all_off`) or the soft fuse is
+    /// blown. Returns whether the output is being held off.
+    pub async fn hold_output_off(&mut self, all_off: bool) -> Result<bool, ChargeChannelError<E>> {
+        if !all_off && !self.fuse.blown() {
+            return Ok(false);
+        }
+
+        self.force_off().await
+    }
+
+    /// Applies [`FORCE_OFF`]. Returns `false` if the SW3526 is offline and the output is out of
+    /// reach.
+    async fn force_off(&mut self) -> Result<bool, ChargeChannelError<E>> {
+        if self.online_status & ChargeChannelOnlineStatus::SW3526Online
+            == ChargeChannelOnlineStatus::Offline
+        {
+            return Ok(false);
+        }
+
+        self.sw3526
+            .set_buck_force_off(FORCE_OFF)
+            .await
+            .map_err(|err| ChargeChannelError::I2CError(err))?;
+
+        Ok(true)
+    }
+
+    /// Clears a blown soft fuse; the output comes back once the last force-off lapses.
+    pub async fn reset_fuse(&mut self) -> Result<(), ChargeChannelError<E>> {
+        log::info!("ch{} soft fuse reset", self.index);
+        self.fuse.reset();
+        self.publish_fuse();
+        Ok(())
+    }
+
+    /// Feeds this sample's current to the soft fuse and cuts the output right away if it blows.
+    async fn update_fuse(&mut self, settings: &Settings) -> Result<(), ChargeChannelError<E>> {
+        let state = &self.current_channel_state;
+        if settings.fuse_rated_milliamps != 0 && !state.amps_stale {
+            let blew = self.fuse.record(
+                abs(state.amps),
+                state.sample_interval_ms as f64 / 1000.0,
+                settings.fuse_rated_milliamps as f64 / 1000.0,
+                settings.fuse_limit_amp2_secs as f64,
+            );
+            if blew {
+                log::warn!("ch{} soft fuse blown, cutting the output", self.index);
+                self.publish_fuse();
+                self.force_off().await?;
+                return Ok(());
+            }
+        }
+
+        let (reported_heat, reported_at) = self.fuse_reported;
+        if self.fuse.heat() != reported_heat && reported_at.elapsed() >= FUSE_REPORT_PERIOD {
+            self.publish_fuse();
+        }
+
+        Ok(())
+    }
+
+    /// Publishes `{"blown":<bool>,"heat":<A²s>}` to `ch<n>/fuse`.
+    fn publish_fuse(&mut self) {
+        let mut topic = String::<16>::new();
+        write!(topic, "ch{}/fuse", self.index).ok();
+        let mut json = String::<48>::new();
+        write!(
+            json,
+            "{{\"blown\":{},\"heat\":{:.2}}}",
+            self.fuse.blown(),
+            self.fuse.heat()
+        )
+        .ok();
+
+        publish_status(&topic, json.as_bytes());
+        self.fuse_reported = (self.fuse.heat(), Instant::now());
+    }
+
     pub async fn task_once(&mut self) -> Result<(), ChargeChannelError<E>> {
+        // Bound each device separately, so one stuck chip can't stall the whole channel loop.
+        let timeout = Duration::from_millis(settings::get().channel_op_timeout_ms as u64);
+
+        self.retry_sw3526(timeout).await;
+        self.current_channel_state.online_status = self.online_status as u8;
         if self.online_status != ChargeChannelOnlineStatus::Online {
+            self.publish_offline().await;
             return Ok(());
         }
 
-        let mut timeout = Ticker::every(Duration::from_secs(1));
-
-        match self.ina226_task_once().await {
-            Ok(_) => {}
-            Err(err) => {
+        match with_timeout(timeout, self.ina226_task_once()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => {
                 log::error!("INA226 task error.");
                 return Err(err);
             }
+            Err(_) => {
+                log::warn!("ina226 task time out");
+                return Err(ChargeChannelError::Timeout);
+            }
         }
 
-        let future = select(timeout.next(), self.sw3526_task_once()).await;
-
-        match future {
-            select::Either::First(_) => {
+        match with_timeout(timeout, self.sw3526_task_once()).await {
+            Err(_) => {
                 log::warn!("sw3526 task time out");
+                self.on_sw3526_timeout(timeout).await;
             }
-            select::Either::Second(result) => match result {
+            Ok(result) => match result {
                 Ok(_) => {
                     log::info!("SW3526 task success");
-                    self.charge_channel.send(self.current_channel_state.clone()).await;
+                    if self.sw3526_timeouts > 0 {
+                        self.sw3526_timeouts = 0;
+                        self.publish_sw3526_timeouts();
+                    }
+                    snapshot::record_charge_channel(self.index, self.current_channel_state);
+                    peaks::record_charge_channel(self.index, &self.current_channel_state);
+                    history::record_charge_channel(self.index, &self.current_channel_state);
+                    lifetime::record_charge_channel(self.index, &self.current_channel_state);
+                    self.update_contract();
+                    self.charge_channel
+                        .send(self.current_channel_state.clone())
+                        .await;
+                    let state = &mut self.current_channel_state;
+                    state.seq = state.seq.wrapping_add(1);
                 }
                 Err(err) => {
                     log::error!("SW3526 task error.");
@@ -252,28 +660,169 @@ where
         Ok(())
     }
 
+    /// Sends a sample carrying only the online status and `seq`, so a channel that is partly
+    /// offline (`online_status` 1 or 2) or entirely offline (`online_status` 0) shows up as such
+    /// instead of going quiet.
+    async fn publish_offline(&mut self) {
+        let state = &mut self.current_channel_state;
+        let sample = ChargeChannelSeriesItem {
+            online_status: state.online_status,
+            seq: state.seq,
+            ..Default::default()
+        };
+        state.seq = state.seq.wrapping_add(1);
+        self.charge_channel.send(sample).await;
+    }
+
+    /// Counts a timed-out SW3526 cycle. Past the configured limit the chip is marked offline,
+    /// which stops the channel's series, and re-initialized until it answers; see
+    /// [`Self::retry_sw3526`].
+    async fn on_sw3526_timeout(&mut self, timeout: Duration) {
+        self.sw3526_timeouts += 1;
+        self.sw3526_timeouts_total += 1;
+        self.publish_sw3526_timeouts();
+
+        if self.sw3526_timeouts < settings::get().sw3526_timeout_limit as u32 {
+            return;
+        }
+
+        log::error!(
+            "ch{}: SW3526 timed out {} times in a row, re-initializing",
+            self.index,
+            self.sw3526_timeouts
+        );
+        self.online_status &= !ChargeChannelOnlineStatus::SW3526Online;
+        self.sw3526_timeouts = 0;
+        self.sw3526_retry = Some((Instant::now(), SW3526_RETRY_MIN));
+        self.retry_sw3526(timeout).await;
+    }
+
+    /// Re-initializes an SW3526 lost to timeouts when its retry is due, and backs off further
+    /// if it still doesn't come back. Stops once it is online again.
+    async fn retry_sw3526(&mut self, timeout: Duration) {
+        let Some((due, backoff)) = self.sw3526_retry else {
+            return;
+        };
+        if self.online_status & ChargeChannelOnlineStatus::SW3526Online
+            == ChargeChannelOnlineStatus::SW3526Online
+        {
+            self.sw3526_retry = None;
+            return;
+        }
+        if Instant::now() < due {
+            return;
+        }
+
+        match with_timeout(timeout, self.init_sw3526()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => log::error!("ch{}: SW3526 re-init error. {:?}", self.index, err),
+            Err(_) => log::error!("ch{}: SW3526 re-init timed out", self.index),
+        }
+
+        if self.online_status & ChargeChannelOnlineStatus::SW3526Online
+            == ChargeChannelOnlineStatus::SW3526Online
+        {
+            log::info!("ch{}: SW3526 back online", self.index);
+            self.sw3526_retry = None;
+        } else {
+            log::warn!(
+                "ch{}: SW3526 still offline, next re-init in {}s",
+                self.index,
+                backoff.as_secs()
+            );
+            self.sw3526_retry = Some((
+                Instant::now() + backoff,
+                (backoff * 2).min(SW3526_RETRY_MAX),
+            ));
+        }
+    }
+
+    /// Publishes `{"consecutive":n,"total":m}` to `ch<n>/sw3526-timeouts`.
+    fn publish_sw3526_timeouts(&self) {
+        let mut topic = String::<24>::new();
+        write!(topic, "ch{}/sw3526-timeouts", self.index).ok();
+        let mut json = String::<48>::new();
+        write!(
+            json,
+            "{{\"consecutive\":{},\"total\":{}}}",
+            self.sw3526_timeouts, self.sw3526_timeouts_total
+        )
+        .ok();
+        publish_status(&topic, json.as_bytes());
+    }
+
+    /// Records the time since the previous sample, so consumers integrating over time use the
+    /// real interval instead of assuming the nominal `period`.
+    fn update_sample_interval(&mut self, period: Duration) {
+        let now = Instant::now();
+        let interval = self.last_sample_at.map(|last| now - last);
+        self.last_sample_at = Some(now);
+
+        let state = &mut self.current_channel_state;
+        state.sample_interval_ms = interval.map_or(0, |interval| interval.as_millis() as u32);
+        state.sample_overrun = interval.is_some_and(|interval| interval > period * 2);
+        if state.sample_overrun {
+            log::warn!(
+                "ch{} sample overrun: {}ms",
+                self.index,
+                state.sample_interval_ms
+            );
+        }
+    }
+
+    /// Waits for the INA226 to finish its current conversion. Reading the Mask/Enable register
+    /// clears the flag, so each conversion is picked up once. Bounded by the caller's timeout.
+    async fn wait_conversion_ready(&mut self) -> Result<(), ChargeChannelError<E>> {
+        loop {
+            let flags = self
+                .ina226
+                .mask_enable()
+                .await
+                .map_err(|err| ChargeChannelError::I2CError(err))?;
+            if flags.contains(MaskEnableFlags::CVRF) {
+                return Ok(());
+            }
+
+            Timer::after(CONVERSION_POLL_INTERVAL).await;
+        }
+    }
+
     pub async fn ina226_task_once(&mut self) -> Result<(), ChargeChannelError<E>> {
+        let settings = settings::get();
+        let calibration = calibration::get(self.index);
+
+        let period = if let Some(period) = high_rate_period() {
+            period
+        } else if settings.poll_conversion_ready {
+            self.wait_conversion_ready().await?;
+            POLLED_SAMPLE_PERIOD
+        } else {
+            SAMPLE_PERIOD
+        };
+        self.update_sample_interval(period);
+
         match self.ina226.bus_voltage_millivolts().await {
             Ok(value) => {
                 // log::info!("Bus voltage: {}", value);
-                self.current_channel_state.millivolts = value;
+                self.current_channel_state.millivolts = calibration.millivolts(value);
             }
             Err(err) => return Err(ChargeChannelError::I2CError(err)),
         };
 
-        // match self.ina226.shunt_voltage_microvolts().await {
-        //     Ok(value) => {
-        //         // log::info!("Shunt voltage: {}", value);
-        //     }
-        //     Err(err) => return Err(ChargeChannelError::I2CError(err)),
-        // };
+        match self.ina226.shunt_voltage_microvolts().await {
+            Ok(value) => {
+                self.current_channel_state.shunt_microvolts = value;
+            }
+            Err(err) => return Err(ChargeChannelError::I2CError(err)),
+        };
 
         match self.ina226.current_amps().await {
             Ok(value) => {
                 // log::info!("Current: {:?}", value);
                 if let Some(value) = value {
-                    self.current_channel_state.amps = value;
+                    self.current_channel_state.amps = calibration.amps(value);
                 }
+                self.current_channel_state.amps_stale = value.is_none();
             }
             Err(err) => return Err(ChargeChannelError::I2CError(err)),
         };
@@ -282,16 +831,101 @@ where
             Ok(value) => {
                 // log::info!("Power: {:?}", value);
                 if let Some(value) = value {
-                    self.current_channel_state.watts = value;
+                    self.current_channel_state.watts = calibration.watts(value);
                 }
+                self.current_channel_state.watts_stale = value.is_none();
             }
             Err(err) => return Err(ChargeChannelError::I2CError(err)),
         };
 
+        let state = &mut self.current_channel_state;
+        if let Some((samples, amps, watts)) = self
+            .missed_reads
+            .record(state.amps_stale, state.watts_stale)
+        {
+            log::warn!(
+                "ch{}: INA226 returned no current in {} and no power in {} of {} samples",
+                self.index,
+                amps,
+                watts,
+                samples
+            );
+        }
+        if settings.power_cross_check {
+            state.computed_watts = state.millivolts / 1000.0 * state.amps;
+            state.power_mismatch = diverges(
+                state.watts,
+                state.computed_watts,
+                settings.power_mismatch_percent,
+                POWER_MISMATCH_FLOOR_WATTS,
+            );
+        } else {
+            state.computed_watts = 0.0;
+            state.power_mismatch = false;
+        }
+
+        self.update_active(settings.channel_active_milliamps[self.index]);
+        self.update_fuse(&settings).await?;
+        self.update_averaging(&settings).await?;
+
+        Ok(())
+    }
+
+    /// Follows `ina226_avg_step`, or the noise tuning while it is on, and reprograms the INA226
+    /// when the step changes.
+    async fn update_averaging(&mut self, settings: &Settings) -> Result<(), ChargeChannelError<E>> {
+        let target = settings.ina226_noise_target_microamps;
+        let (step, noise) = if target == 0 {
+            self.noise_tuner.reset();
+            (settings.ina226_avg_step, None)
+        } else if self.current_channel_state.amps_stale {
+            return Ok(());
+        } else {
+            match self.noise_tuner.push(
+                self.current_channel_state.amps * 1_000_000.0,
+                self.avg_step,
+                MAX_INA226_AVG_STEP,
+                target as f64,
+            ) {
+                Some((step, noise)) => (step, Some(noise)),
+                None => return Ok(()),
+            }
+        };
+
+        if step == self.avg_step {
+            return Ok(());
+        }
+
+        self.avg_step = step;
+        self.noise_tuner.reset();
+        self.config_ina226().await?;
+        self.publish_averaging(noise);
+
         Ok(())
     }
 
+    /// Publishes `{"samples":n,"auto":<bool>}` to `ch<n>/ina226-avg`, plus `"noise_microamps"`
+    /// when the tuning picked the step.
+    fn publish_averaging(&self, noise: Option<f64>) {
+        let samples = AVG_SAMPLES[self.avg_step as usize];
+        let auto = settings::get().ina226_noise_target_microamps != 0;
+        log::info!("ch{} INA226 averaging: {} samples", self.index, samples);
+
+        let mut topic = String::<16>::new();
+        write!(topic, "ch{}/ina226-avg", self.index).ok();
+        let mut json = String::<64>::new();
+        write!(json, "{{\"samples\":{},\"auto\":{}", samples, auto).ok();
+        if let Some(noise) = noise {
+            write!(json, ",\"noise_microamps\":{:.0}", noise).ok();
+        }
+        json.push('}').ok();
+
+        publish_status(&topic, json.as_bytes());
+    }
+
     pub async fn sw3526_task_once(&mut self) -> Result<(), ChargeChannelError<E>> {
+        self.apply_pd_profiles().await?;
+        self.apply_output_limit().await?;
         self.report_sw3526_limits().await?;
         self.report_sw3526_status().await?;
 
@@ -340,6 +974,15 @@ where
             }
         }
 
+        match self.sw3526.get_adc_output_milliamps().await {
+            Ok(milliamps) => {
+                self.current_channel_state.buck_output_milliamps = milliamps as u16;
+            }
+            Err(err) => {
+                return Err(ChargeChannelError::I2CError(err));
+            }
+        }
+
         Ok(())
     }
 
@@ -378,28 +1021,53 @@ where
     }
 }
 
+/// Samples averaged per INA226 averaging step.
+const AVG_SAMPLES: [u16; 8] = [1, 4, 16, 64, 128, 256, 512, 1024];
+
+fn avg_for_step(step: u8) -> ina226::AVG {
+    match step {
+        0 => ina226::AVG::_1,
+        1 => ina226::AVG::_4,
+        2 => ina226::AVG::_16,
+        3 => ina226::AVG::_64,
+        4 => ina226::AVG::_128,
+        5 => ina226::AVG::_256,
+        6 => ina226::AVG::_512,
+        _ => ina226::AVG::_1024,
+    }
+}
+
 macro_rules! create_channel {
-    ($i2c_mutex:expr, $ina226_addr:expr, $charge_channel:expr) => {{
-        let ina226_i2c_dev = I2cDevice::new($i2c_mutex);
-        let sw3526_i2c_dev = I2cDevice::new($i2c_mutex);
+    ($i2c_mutex:expr, $index:expr, $ina226_addr:expr) => {{
+        let ina226_i2c_dev = TracedI2c::new(SharedI2c::new($i2c_mutex, Priority::Normal));
+        let sw3526_i2c_dev = TracedI2c::new(SharedI2c::new($i2c_mutex, Priority::Normal));
+        let ina226_reset_i2c_dev = TracedI2c::new(SharedI2c::new($i2c_mutex, Priority::Normal));
 
         let ina226 = INA226::new(ina226_i2c_dev, $ina226_addr);
         let sw3526 = SW3526::new(sw3526_i2c_dev);
 
-        ChargeChannel::new(ina226, sw3526, $charge_channel)
+        ChargeChannel::new(
+            $index,
+            ina226,
+            $ina226_addr,
+            ina226_reset_i2c_dev,
+            sw3526,
+            &CHARGE_CHANNEL_SERIES_ITEM_CHANNELS[$index],
+            CalibrationConfig::default(),
+        )
     }};
 }
 
 macro_rules! init_charge_channel {
     ($mux:expr, $channel:expr, $charge_channel:expr) => {{
         if $mux.get_channel_available($channel) {
-            match $mux.set_channel($channel).await {
-                Ok(_) => {}
+            let _ports = match $mux.set_channel($channel).await {
+                Ok(ports) => ports,
                 Err(err) => {
                     log::error!("set channel#{} error. {:?}", $channel as u8, err);
                     continue;
                 }
-            }
+            };
             match $charge_channel.init().await {
                 Ok(_) => {
                     log::info!("init charge channel#{} success.", $channel as u8);
@@ -413,15 +1081,57 @@ macro_rules! init_charge_channel {
     }};
 }
 
+/// Selects the channel's mux port and runs `$op` on the channel outside the regular loop.
+/// Channels are picked by runtime index, so every channel variable has to be passed in.
+macro_rules! run_on_channel {
+    ($mux:expr, $index:expr, [$ch0:expr, $ch1:expr, $ch2:expr, $ch3:expr], $op:ident($($arg:expr),*)) => {{
+        let channel = match $index {
+            0 => ChargeChannelIndex::Ch0,
+            1 => ChargeChannelIndex::Ch1,
+            2 => ChargeChannelIndex::Ch2,
+            _ => ChargeChannelIndex::Ch3,
+        };
+        match $mux.set_channel(channel).await {
+            Ok(_ports) => match $index {
+                0 => $ch0.$op($($arg),*).await,
+                1 => $ch1.$op($($arg),*).await,
+                2 => $ch2.$op($($arg),*).await,
+                _ => $ch3.$op($($arg),*).await,
+            },
+            Err(err) => Err(err),
+        }
+    }};
+}
+
+/// Forces off every channel that has to stay off, right away, and returns which ones are held
+/// off. [`force_off_task`] keeps them off from then on.
+macro_rules! hold_outputs_off {
+    ($mux:expr, $all_off:expr, [$ch0:expr, $ch1:expr, $ch2:expr, $ch3:expr]) => {{
+        let mut held = [false; 4];
+        for index in 0..held.len() as u8 {
+            match run_on_channel!(
+                $mux,
+                index,
+                [$ch0, $ch1, $ch2, $ch3],
+                hold_output_off($all_off)
+            ) {
+                Ok(applied) => held[index as usize] = applied,
+                Err(err) => log::error!("hold charge channel#{} off error. {:?}", index, err),
+            }
+        }
+        held
+    }};
+}
+
 macro_rules! do_channel_task {
     ($mux:expr, $channel:expr, $charge_channel:expr, $task_name:ident) => {{
-        match $mux.set_channel($channel).await {
-            Ok(_) => {}
+        let _ports = match $mux.set_channel($channel).await {
+            Ok(ports) => ports,
             Err(err) => {
                 log::error!("set channel#{} error. {:?}", $channel as u8, err);
                 continue;
             }
-        }
+        };
         match $charge_channel.$task_name().await {
             Ok(_) => {}
             Err(err) => {
@@ -430,6 +1140,22 @@ macro_rules! do_channel_task {
                     $channel as u8,
                     err
                 );
+                match err.i2c_error().map(ErrorClass::of) {
+                    // The chip didn't answer: re-init the channel, which takes it offline if it
+                    // stays silent.
+                    Some(ErrorClass::Device) => {
+                        CHARGE_CHANNEL_CFG_CHANNEL
+                            .try_send(ChargeChannelAction::Reinit(ReinitTarget::Channel(
+                                $channel as u8,
+                            )))
+                            .ok();
+                    }
+                    // The bus was disturbed and the controller has reset itself; the chip did
+                    // nothing wrong. Leave the other channels to the next cycle so the bus can
+                    // settle.
+                    Some(ErrorClass::Bus) => continue,
+                    _ => {}
+                }
             }
         }
     }};
@@ -439,25 +1165,25 @@ macro_rules! do_channel_task {
 pub(crate) async fn task(
     i2c_mutex: &'static Mutex<CriticalSectionRawMutex, esp_hal::i2c::I2c<'static, I2C0, Async>>,
 ) {
-    let pca9546a_i2c_dev = I2cDevice::new(i2c_mutex);
-    let mux_chip_0: PCA9546A<
-        I2cDevice<CriticalSectionRawMutex, esp_hal::i2c::I2c<'_, I2C0, Async>>,
-    > = PCA9546A::new(pca9546a_i2c_dev, PCA9546A_ADDRESS_0);
-    let pca9546a_i2c_dev = I2cDevice::new(i2c_mutex);
-    let mux_chip_1 = PCA9546A::new(pca9546a_i2c_dev, PCA9546A_ADDRESS_1);
-
-    let mut mux = I2cMux::new(mux_chip_0, mux_chip_1);
-
-    let mut charge_channel_0 =
-        create_channel!(i2c_mutex, INA226_0, &CHARGE_CHANNEL_SERIES_ITEM_CHANNELS[0]);
-    let mut charge_channel_1 =
-        create_channel!(i2c_mutex, INA226_1, &CHARGE_CHANNEL_SERIES_ITEM_CHANNELS[1]);
-    let mut charge_channel_2 =
-        create_channel!(i2c_mutex, INA226_2, &CHARGE_CHANNEL_SERIES_ITEM_CHANNELS[2]);
-    let mut charge_channel_3 =
-        create_channel!(i2c_mutex, INA226_3, &CHARGE_CHANNEL_SERIES_ITEM_CHANNELS[3]);
-
-    let mut ticker = Ticker::every(Duration::from_secs(1));
+    let layout = board::load();
+
+    let pca9546a_i2c_dev = TracedI2c::new(SharedI2c::new(i2c_mutex, Priority::Normal));
+    let mux_chip_0: PCA9546A<TracedI2c<SharedI2c<esp_hal::i2c::I2c<'_, I2C0, Async>>>> =
+        PCA9546A::new(pca9546a_i2c_dev, layout.mux_addresses[0]);
+    let pca9546a_i2c_dev = TracedI2c::new(SharedI2c::new(i2c_mutex, Priority::Normal));
+    let mux_chip_1 = PCA9546A::new(pca9546a_i2c_dev, layout.mux_addresses[1]);
+
+    let mut mux = I2cMux::new(mux_chip_0, mux_chip_1, Priority::Normal);
+
+    let mut charge_channel_0 = create_channel!(i2c_mutex, 0, layout.ina226_addresses[0]);
+    let mut charge_channel_1 = create_channel!(i2c_mutex, 1, layout.ina226_addresses[1]);
+    let mut charge_channel_2 = create_channel!(i2c_mutex, 2, layout.ina226_addresses[2]);
+    let mut charge_channel_3 = create_channel!(i2c_mutex, 3, layout.ina226_addresses[3]);
+
+    let mut ticker = Ticker::every(SAMPLE_PERIOD);
+    let mut reinit_requested = false;
+    let mut rate = SampleRate::new();
+    let mut outputs_off = false;
 
     loop {
         ticker.next().await;
@@ -471,10 +1197,209 @@ pub(crate) async fn task(
         init_charge_channel!(mux, ChargeChannelIndex::Ch2, &mut charge_channel_2);
         init_charge_channel!(mux, ChargeChannelIndex::Ch3, &mut charge_channel_3);
 
+        let online_statuses = [
+            charge_channel_0.online_status(),
+            charge_channel_1.online_status(),
+            charge_channel_2.online_status(),
+            charge_channel_3.online_status(),
+        ];
+        system::record_mux_online(mux.online());
+        system::record_channels(online_statuses);
+        schema::record_channels(online_statuses);
+
+        let fuse_blown = [
+            charge_channel_0.fuse_blown(),
+            charge_channel_1.fuse_blown(),
+            charge_channel_2.fuse_blown(),
+            charge_channel_3.fuse_blown(),
+        ];
+        if outputs_off || fuse_blown.contains(&true) {
+            hold_outputs_off!(
+                mux,
+                outputs_off,
+                [
+                    charge_channel_0,
+                    charge_channel_1,
+                    charge_channel_2,
+                    charge_channel_3
+                ]
+            );
+        }
+
+        if reinit_requested {
+            publish_reinit_result(ReinitTarget::All, online_statuses);
+            reinit_requested = false;
+        }
+
         log::info!("loop charge channels task...");
 
         loop {
-            ticker.next().await;
+            if end_expired_high_rate() {
+                ticker.reset();
+                rate = SampleRate::new();
+            }
+
+            let poll = settings::get().poll_conversion_ready;
+            if poll != rate.poll {
+                // The ticker would otherwise catch up on every period missed while polling.
+                ticker.reset();
+                rate = SampleRate::new();
+                rate.poll = poll;
+            }
+
+            let high_rate = high_rate_period();
+            let next_cycle = async {
+                if let Some(period) = high_rate {
+                    Timer::after(period).await
+                } else if poll {
+                    Timer::after(POLLED_SAMPLE_PERIOD).await
+                } else {
+                    ticker.next().await
+                }
+            };
+            let fuse_blown = [
+                charge_channel_0.fuse_blown(),
+                charge_channel_1.fuse_blown(),
+                charge_channel_2.fuse_blown(),
+                charge_channel_3.fuse_blown(),
+            ];
+            system::record_output_holds(OutputHolds {
+                all_off: outputs_off,
+                fuse_blown,
+            });
+            let action = match select(next_cycle, CHARGE_CHANNEL_CFG_CHANNEL.receive()).await {
+                Either::First(_) => None,
+                Either::Second(action) => Some(action),
+            };
+
+            match action {
+                None => {}
+                Some(ChargeChannelAction::Reinit(ReinitTarget::All)) => {
+                    log::info!("reinit all charge channels");
+                    reinit_requested = true;
+                    break;
+                }
+                Some(ChargeChannelAction::Reinit(ReinitTarget::Channel(index))) => {
+                    log::info!("reinit charge channel#{}", index);
+                    let result = run_on_channel!(
+                        mux,
+                        index,
+                        [
+                            charge_channel_0,
+                            charge_channel_1,
+                            charge_channel_2,
+                            charge_channel_3
+                        ],
+                        init()
+                    );
+                    if let Err(err) = result {
+                        log::error!("reinit charge channel#{} error. {:?}", index, err);
+                    }
+
+                    let online_statuses = [
+                        charge_channel_0.online_status(),
+                        charge_channel_1.online_status(),
+                        charge_channel_2.online_status(),
+                        charge_channel_3.online_status(),
+                    ];
+                    schema::record_channels(online_statuses);
+                    publish_reinit_result(ReinitTarget::Channel(index), online_statuses);
+                    continue;
+                }
+                Some(ChargeChannelAction::Reset(index, reset)) => {
+                    let result = run_on_channel!(
+                        mux,
+                        index,
+                        [
+                            charge_channel_0,
+                            charge_channel_1,
+                            charge_channel_2,
+                            charge_channel_3
+                        ],
+                        reset(reset)
+                    );
+                    if let Err(err) = &result {
+                        log::error!("reset charge channel#{} error. {:?}", index, err);
+                    }
+
+                    let online_statuses = [
+                        charge_channel_0.online_status(),
+                        charge_channel_1.online_status(),
+                        charge_channel_2.online_status(),
+                        charge_channel_3.online_status(),
+                    ];
+                    schema::record_channels(online_statuses);
+                    publish_reset_result(
+                        index,
+                        reset,
+                        result.is_ok(),
+                        online_statuses[index as usize],
+                    );
+                    continue;
+                }
+                Some(ChargeChannelAction::ReadIds(index)) => {
+                    let result = run_on_channel!(
+                        mux,
+                        index,
+                        [
+                            charge_channel_0,
+                            charge_channel_1,
+                            charge_channel_2,
+                            charge_channel_3
+                        ],
+                        read_ids()
+                    );
+                    if let Err(err) = result {
+                        log::error!("read charge channel#{} ids error. {:?}", index, err);
+                    }
+                    continue;
+                }
+                Some(ChargeChannelAction::ResetFuse(index)) => {
+                    let result = run_on_channel!(
+                        mux,
+                        index,
+                        [
+                            charge_channel_0,
+                            charge_channel_1,
+                            charge_channel_2,
+                            charge_channel_3
+                        ],
+                        reset_fuse()
+                    );
+                    if let Err(err) = result {
+                        log::error!("reset charge channel#{} fuse error. {:?}", index, err);
+                    }
+                    continue;
+                }
+                Some(ChargeChannelAction::Outputs(on)) => {
+                    log::info!(
+                        "all charge channel outputs {}",
+                        if on { "on" } else { "off" }
+                    );
+                    outputs_off = !on;
+                    // Released outputs come back by themselves once the last force-off lapses,
+                    // except for channels whose soft fuse is blown.
+                    let held = hold_outputs_off!(
+                        mux,
+                        outputs_off,
+                        [
+                            charge_channel_0,
+                            charge_channel_1,
+                            charge_channel_2,
+                            charge_channel_3
+                        ]
+                    );
+                    publish_outputs(on, held);
+                    continue;
+                }
+                Some(ChargeChannelAction::HighRate { period_ms, secs }) => {
+                    set_high_rate(period_ms, secs);
+                    ticker.reset();
+                    rate = SampleRate::new();
+                    rate.poll = poll;
+                    continue;
+                }
+            }
 
             do_channel_task!(
                 mux,
@@ -500,6 +1425,229 @@ pub(crate) async fn task(
                 &mut charge_channel_3,
                 task_once
             );
+
+            // A channel can drop out mid-loop, e.g. after repeated SW3526 timeouts.
+            schema::record_channels([
+                charge_channel_0.online_status(),
+                charge_channel_1.online_status(),
+                charge_channel_2.online_status(),
+                charge_channel_3.online_status(),
+            ]);
+
+            rate.cycle_done();
+        }
+    }
+}
+
+/// Counts completed channel loop cycles and periodically publishes the achieved rate as
+/// `{"mode":"fixed"|"conversion-ready","hz":<rate>}` to `system/sample-rate`.
+struct SampleRate {
+    poll: bool,
+    cycles: u32,
+    since: Instant,
+}
+
+impl SampleRate {
+    fn new() -> Self {
+        Self {
+            poll: false,
+            cycles: 0,
+            since: Instant::now(),
+        }
+    }
+
+    fn cycle_done(&mut self) {
+        self.cycles += 1;
+
+        let elapsed = self.since.elapsed();
+        if elapsed < SAMPLE_RATE_REPORT_PERIOD {
+            return;
         }
+
+        let hz = self.cycles as f64 * 1000.0 / elapsed.as_millis() as f64;
+        let mode = if high_rate_period().is_some() {
+            "high-rate"
+        } else if self.poll {
+            "conversion-ready"
+        } else {
+            "fixed"
+        };
+        log::info!("charge channel sample rate: {:.2}Hz ({})", hz, mode);
+
+        let mut json = String::<64>::new();
+        write!(json, "{{\"mode\":\"{}\",\"hz\":{:.2}}}", mode, hz).ok();
+        publish_system_message("sample-rate", json.as_bytes());
+
+        self.cycles = 0;
+        self.since = Instant::now();
     }
 }
+
+/// Starts, restarts or (with `secs` = 0) ends the high-rate window.
+fn set_high_rate(period_ms: u16, secs: u16) {
+    if secs == 0 {
+        if HIGH_RATE.lock(|high_rate| high_rate.take()).is_some() {
+            log::info!("high-rate sampling cancelled");
+            publish_high_rate(None);
+        }
+        return;
+    }
+
+    let high_rate = HighRate {
+        period: Duration::from_millis(period_ms as u64),
+        until: Instant::now() + Duration::from_secs(secs as u64),
+    };
+    HIGH_RATE.lock(|current| current.set(Some(high_rate)));
+    log::info!("high-rate sampling every {}ms for {}s", period_ms, secs);
+    publish_high_rate(Some((period_ms, secs)));
+}
+
+/// Ends the high-rate window once its time is up. Returns whether it did.
+fn end_expired_high_rate() -> bool {
+    let expired = HIGH_RATE.lock(|high_rate| {
+        let expired = high_rate
+            .get()
+            .is_some_and(|high_rate| Instant::now() >= high_rate.until);
+        if expired {
+            high_rate.set(None);
+        }
+        expired
+    });
+    if expired {
+        log::info!("high-rate sampling ended");
+        publish_high_rate(None);
+    }
+    expired
+}
+
+/// Publishes `{"active":true,"period_ms":<ms>,"secs":<s>}` to `system/high-rate` when a
+/// window starts and `{"active":false}` when it ends.
+fn publish_high_rate(started: Option<(u16, u16)>) {
+    let mut json = String::<64>::new();
+    match started {
+        Some((period_ms, secs)) => write!(
+            json,
+            "{{\"active\":true,\"period_ms\":{},\"secs\":{}}}",
+            period_ms, secs
+        )
+        .ok(),
+        None => json.push_str("{\"active\":false}").ok(),
+    };
+    publish_system_message("high-rate", json.as_bytes());
+}
+
+/// Publishes `{"target":"all"|"ch<n>","channels":[<online status>; 4]}` to `system/reinit`.
+/// Repeats the force-off of every held output on its own timer, so a charge channel task busy
+/// with a slow or stuck chip can't let the SW3526 release it. The holds come from
+/// [`system::output_holds`]; the charge channel task applies each one the first time itself.
+#[embassy_executor::task]
+pub(crate) async fn force_off_task(
+    i2c_mutex: &'static Mutex<CriticalSectionRawMutex, esp_hal::i2c::I2c<'static, I2C0, Async>>,
+) {
+    let layout = board::load();
+
+    let pca9546a_i2c_dev = TracedI2c::new(SharedI2c::new(i2c_mutex, Priority::High));
+    let mux_chip_0 = PCA9546A::new(pca9546a_i2c_dev, layout.mux_addresses[0]);
+    let pca9546a_i2c_dev = TracedI2c::new(SharedI2c::new(i2c_mutex, Priority::High));
+    let mux_chip_1 = PCA9546A::new(pca9546a_i2c_dev, layout.mux_addresses[1]);
+    let mut mux = I2cMux::new(mux_chip_0, mux_chip_1, Priority::High);
+
+    // Every SW3526 answers at the same address; the mux picks which one.
+    let mut sw3526 = SW3526::new(TracedI2c::new(SharedI2c::new(i2c_mutex, Priority::High)));
+
+    let mut ticker = Ticker::every(FORCE_OFF_REFRESH_PERIOD);
+    loop {
+        ticker.next().await;
+
+        let holds = system::output_holds();
+        if !holds.all_off && !holds.fuse_blown.contains(&true) {
+            continue;
+        }
+
+        // Picks up a mux that came back since the last round.
+        mux.init().await;
+        for (index, fuse_blown) in holds.fuse_blown.into_iter().enumerate() {
+            if !holds.all_off && !fuse_blown {
+                continue;
+            }
+
+            let channel = match index {
+                0 => ChargeChannelIndex::Ch0,
+                1 => ChargeChannelIndex::Ch1,
+                2 => ChargeChannelIndex::Ch2,
+                _ => ChargeChannelIndex::Ch3,
+            };
+            if !mux.get_channel_available(channel) {
+                continue;
+            }
+
+            let _ports = match mux.set_channel(channel).await {
+                Ok(ports) => ports,
+                Err(err) => {
+                    log::error!("force-off: set channel#{} error. {:?}", index, err);
+                    continue;
+                }
+            };
+            if let Err(err) = sw3526.set_buck_force_off(FORCE_OFF).await {
+                log::warn!("force-off: charge channel#{} error. {:?}", index, err);
+            }
+        }
+    }
+}
+
+fn publish_reinit_result(target: ReinitTarget, channels: [ChargeChannelOnlineStatus; 4]) {
+    let mut json = String::<64>::new();
+    match target {
+        ReinitTarget::All => write!(json, "{{\"target\":\"all\"").ok(),
+        ReinitTarget::Channel(index) => write!(json, "{{\"target\":\"ch{}\"", index).ok(),
+    };
+    write!(
+        json,
+        ",\"channels\":[{},{},{},{}]}}",
+        channels[0] as u8, channels[1] as u8, channels[2] as u8, channels[3] as u8
+    )
+    .ok();
+
+    publish_system_message("reinit", json.as_bytes());
+}
+
+/// Publishes `{"on":<bool>,"held":[<bool>; 4]}` to `system/outputs`. `held` tells which
+/// channels' SW3526 took the force-off; after switching back on, only channels with a blown
+/// soft fuse stay held.
+fn publish_outputs(on: bool, held: [bool; 4]) {
+    let mut json = String::<64>::new();
+    write!(
+        json,
+        "{{\"on\":{},\"held\":[{},{},{},{}]}}",
+        on, held[0], held[1], held[2], held[3]
+    )
+    .ok();
+
+    publish_system_message("outputs", json.as_bytes());
+}
+
+/// Publishes `{"reset":"ina226"|"pd-hard-reset","ok":<bool>,"online":<online status>}` to
+/// `ch<n>/reset-device`.
+fn publish_reset_result(
+    index: u8,
+    reset: ChannelReset,
+    ok: bool,
+    online_status: ChargeChannelOnlineStatus,
+) {
+    let reset = match reset {
+        ChannelReset::Ina226 => "ina226",
+        ChannelReset::PdHardReset => "pd-hard-reset",
+    };
+
+    let mut topic = String::<24>::new();
+    write!(topic, "ch{}/reset-device", index).ok();
+    let mut json = String::<64>::new();
+    write!(
+        json,
+        "{{\"reset\":\"{}\",\"ok\":{},\"online\":{}}}",
+        reset, ok, online_status as u8
+    )
+    .ok();
+
+    publish_status(&topic, json.as_bytes());
+}
@@ -0,0 +1,1911 @@
+use core::fmt::Write;
+
+use heapless::String;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::{self, BoardLayout},
+    burn_in,
+    bus::{
+        publish_system_message, ChannelReset, ChargeChannelAction, CommandAck, CommandResult,
+        ReinitTarget, CHARGE_CHANNEL_CFG_CHANNEL, CHARGE_CHANNEL_SERIES_ITEM_CHANNELS,
+        MAX_REQUEST_ID_LEN, MAX_SYSTEM_MESSAGE_LEN, SYSTEM_MESSAGE_CHANNEL,
+        WIFI_CREDENTIALS_CHANNEL,
+    },
+    calibration::{self, CalibrationError, Quantity, PROTECTOR_DEVICE},
+    charge_channel::{MAX_HIGH_RATE_SECS, MIN_HIGH_RATE_PERIOD_MS},
+    commission, device_name,
+    history::{self, Source},
+    i2c_trace, lifetime, logger, peaks,
+    settings::{self, Delivery, Settings, Stream, TemperatureReduction},
+    system,
+    wifi::WifiCredentials,
+};
+#[cfg(not(no_protector))]
+use crate::{
+    bus::{VinAction, PROTECTOR_CFG_CHANNEL, VIN_STATUS_CFG_CHANNEL},
+    protector::{self, ProtectorCommand, VinState, MAX_VIN_OVERRIDE_SECS},
+};
+
+/// Max number of topic segments after `cfg/`, e.g. `ch0/limit-watts`.
+const MAX_CFG_DEPTH: usize = 2;
+
+/// What a config command addresses, taken from the topic segments after `cfg/`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfgTarget {
+    /// `cfg/<param>`
+    Global,
+    /// `cfg/ch<n>/<param>`
+    Channel(u8),
+    /// `cfg/protector/<param>`
+    Protector,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CfgScope {
+    Global,
+    Channel,
+    Protector,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CfgValue {
+    /// Fixed-length binary value. Anything after it is the optional request id.
+    Bytes(usize),
+    /// The whole payload is a UTF-8 string; no request id.
+    Text,
+}
+
+type CfgApply = fn(CfgTarget, &[u8]) -> Result<(), CommandResult>;
+
+struct CfgCommand {
+    param: &'static str,
+    scope: CfgScope,
+    value: CfgValue,
+    apply: CfgApply,
+}
+
+/// Every supported config command. Adding a command is a new entry here plus its apply fn.
+const CFG_COMMANDS: &[CfgCommand] = &[
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "vin-status",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_vin_status,
+    },
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "vin-override",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(3),
+        apply: apply_vin_override,
+    },
+    CfgCommand {
+        param: "all-off",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_all_off,
+    },
+    CfgCommand {
+        param: "all-on",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_all_on,
+    },
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "current-avg-window",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_current_avg_window,
+    },
+    CfgCommand {
+        param: "power-mismatch-percent",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_power_mismatch_percent,
+    },
+    CfgCommand {
+        param: "power-cross-check",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_power_cross_check,
+    },
+    CfgCommand {
+        param: "active-milliamps",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(2),
+        apply: apply_channel_active_milliamps,
+    },
+    CfgCommand {
+        param: "cal-millivolts",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(4),
+        apply: apply_cal_millivolts,
+    },
+    CfgCommand {
+        param: "cal-amps",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(4),
+        apply: apply_cal_amps,
+    },
+    CfgCommand {
+        param: "cal-millivolts",
+        scope: CfgScope::Protector,
+        value: CfgValue::Bytes(4),
+        apply: apply_cal_millivolts,
+    },
+    CfgCommand {
+        param: "cal-amps",
+        scope: CfgScope::Protector,
+        value: CfgValue::Bytes(4),
+        apply: apply_cal_amps,
+    },
+    CfgCommand {
+        param: "reinit",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_reinit,
+    },
+    CfgCommand {
+        param: "reinit",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(1),
+        apply: apply_reinit,
+    },
+    CfgCommand {
+        param: "mqtt-ping-interval",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_mqtt_ping_interval,
+    },
+    CfgCommand {
+        param: "socket-timeout",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_socket_timeout,
+    },
+    CfgCommand {
+        param: "heartbeat-interval",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_heartbeat_interval,
+    },
+    CfgCommand {
+        param: "mqtt-reconnect",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(4),
+        apply: apply_mqtt_reconnect,
+    },
+    CfgCommand {
+        param: "mqtt-subscribe-retries",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_mqtt_subscribe_retries,
+    },
+    CfgCommand {
+        param: "channel-op-timeout",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_channel_op_timeout,
+    },
+    CfgCommand {
+        param: "ina226-avg",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_ina226_avg,
+    },
+    CfgCommand {
+        param: "ina226-noise-target",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_ina226_noise_target,
+    },
+    CfgCommand {
+        param: "sw3526-timeout-limit",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_sw3526_timeout_limit,
+    },
+    CfgCommand {
+        param: "poll-conversion-ready",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_poll_conversion_ready,
+    },
+    CfgCommand {
+        param: "retain-telemetry",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_retain_telemetry,
+    },
+    CfgCommand {
+        param: "delivery",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(3),
+        apply: apply_delivery,
+    },
+    CfgCommand {
+        param: "thermal-throttle",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_thermal_throttle,
+    },
+    CfgCommand {
+        param: "temperature-reduction",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_temperature_reduction,
+    },
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "os-fail-queue",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_os_fail_queue,
+    },
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "temp-shutdown",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(4),
+        apply: apply_temp_shutdown,
+    },
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "temp-hysteresis",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(4),
+        apply: apply_temp_hysteresis,
+    },
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "fan",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_fan,
+    },
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "over-current",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_over_current,
+    },
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "input-power-limit",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_input_power_limit,
+    },
+    CfgCommand {
+        param: "fuse",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(4),
+        apply: apply_fuse,
+    },
+    CfgCommand {
+        param: "fuse-reset",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(1),
+        apply: apply_fuse_reset,
+    },
+    CfgCommand {
+        param: "high-rate",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(4),
+        apply: apply_high_rate,
+    },
+    CfgCommand {
+        param: "limit-watts",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(1),
+        apply: apply_limit_watts,
+    },
+    CfgCommand {
+        param: "pd-profiles",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(1),
+        apply: apply_pd_profiles,
+    },
+    CfgCommand {
+        param: "limit-watts-range",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_limit_watts_range,
+    },
+    CfgCommand {
+        param: "i2c-trace",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_i2c_trace,
+    },
+    CfgCommand {
+        param: "board-layout",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(6),
+        apply: apply_board_layout,
+    },
+    CfgCommand {
+        param: "reset-device",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(1),
+        apply: apply_reset_device,
+    },
+    CfgCommand {
+        param: "ids",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(1),
+        apply: apply_ids,
+    },
+    CfgCommand {
+        param: "offline-shutdown",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_offline_shutdown,
+    },
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "vin-min-on",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_vin_min_on,
+    },
+    #[cfg(not(no_protector))]
+    CfgCommand {
+        param: "vin-min-off",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(2),
+        apply: apply_vin_min_off,
+    },
+    CfgCommand {
+        param: "energy-reset",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(0),
+        apply: apply_energy_reset,
+    },
+    CfgCommand {
+        param: "energy-reset",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(0),
+        apply: apply_energy_reset,
+    },
+    CfgCommand {
+        param: "reset-peaks",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_reset_peaks,
+    },
+    CfgCommand {
+        param: "reset-peaks",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(1),
+        apply: apply_reset_peaks,
+    },
+    CfgCommand {
+        param: "reset-peaks",
+        scope: CfgScope::Protector,
+        value: CfgValue::Bytes(1),
+        apply: apply_reset_peaks,
+    },
+    CfgCommand {
+        param: "history",
+        scope: CfgScope::Channel,
+        value: CfgValue::Bytes(1),
+        apply: apply_history,
+    },
+    CfgCommand {
+        param: "history",
+        scope: CfgScope::Protector,
+        value: CfgValue::Bytes(1),
+        apply: apply_history,
+    },
+    CfgCommand {
+        param: "history-len",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_history_len,
+    },
+    CfgCommand {
+        param: "commission-watts",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_commission_watts,
+    },
+    CfgCommand {
+        param: "commission-tolerance",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_commission_tolerance,
+    },
+    CfgCommand {
+        param: "consistency-tolerance",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_consistency_tolerance,
+    },
+    CfgCommand {
+        param: "commission",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_commission,
+    },
+    CfgCommand {
+        param: "burn-in",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(4),
+        apply: apply_burn_in,
+    },
+    CfgCommand {
+        param: "bulk",
+        scope: CfgScope::Global,
+        value: CfgValue::Text,
+        apply: apply_bulk,
+    },
+    CfgCommand {
+        param: "get",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(0),
+        apply: apply_get,
+    },
+    CfgCommand {
+        param: "wifi",
+        scope: CfgScope::Global,
+        value: CfgValue::Text,
+        apply: apply_wifi,
+    },
+    CfgCommand {
+        param: "device-name",
+        scope: CfgScope::Global,
+        value: CfgValue::Text,
+        apply: apply_device_name,
+    },
+    CfgCommand {
+        param: "log-level",
+        scope: CfgScope::Global,
+        value: CfgValue::Text,
+        apply: apply_log_level,
+    },
+    CfgCommand {
+        param: "reboot",
+        scope: CfgScope::Global,
+        value: CfgValue::Bytes(1),
+        apply: apply_reboot,
+    },
+];
+
+fn parse_channel_segment(segment: &str) -> Option<u8> {
+    let index: u8 = segment.strip_prefix("ch")?.parse().ok()?;
+    if (index as usize) < CHARGE_CHANNEL_SERIES_ITEM_CHANNELS.len() {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+/// Splits `field` (the topic after `cfg/`) into its target and parameter name.
+fn parse_route(field: &str) -> Option<(CfgTarget, &str)> {
+    if field.is_empty() || field.split('/').count() > MAX_CFG_DEPTH {
+        return None;
+    }
+
+    let (target, param) = match field.split_once('/') {
+        Some(("protector", param)) => (CfgTarget::Protector, param),
+        Some((channel, param)) => (CfgTarget::Channel(parse_channel_segment(channel)?), param),
+        None => (CfgTarget::Global, field),
+    };
+
+    if param.is_empty() {
+        return None;
+    }
+
+    Some((target, param))
+}
+
+/// Splits a command payload into its `value_len`-byte value and the optional trailing request
+/// id, which is echoed back in the ack so the sender can correlate it.
+fn split_request_id<'a>(
+    message: &'a [u8],
+    value_len: usize,
+    ack: &mut CommandAck,
+) -> Result<&'a [u8], CommandResult> {
+    if message.len() < value_len {
+        return Err(CommandResult::InvalidPayload);
+    }
+
+    let (value, request_id) = message.split_at(value_len);
+    if request_id.len() > MAX_REQUEST_ID_LEN {
+        return Err(CommandResult::InvalidPayload);
+    }
+    ack.request_id.extend_from_slice(request_id).ok();
+
+    Ok(value)
+}
+
+fn dispatch(field: &str, message: &[u8], ack: &mut CommandAck) -> Result<(), CommandResult> {
+    let (target, param) = parse_route(field).ok_or(CommandResult::UnknownField)?;
+
+    let scope = match target {
+        CfgTarget::Global => CfgScope::Global,
+        CfgTarget::Channel(_) => CfgScope::Channel,
+        CfgTarget::Protector => CfgScope::Protector,
+    };
+    let command = CFG_COMMANDS
+        .iter()
+        .find(|command| command.param == param && command.scope == scope)
+        .ok_or(CommandResult::UnknownField)?;
+
+    let value = match command.value {
+        CfgValue::Bytes(value_len) => split_request_id(message, value_len, ack)?,
+        CfgValue::Text => message,
+    };
+    (command.apply)(target, value)
+}
+
+/// Applies a message received on `cfg/<field>` and returns the ack to publish for it.
+pub fn handle(field: &str, message: &[u8]) -> CommandAck {
+    let mut ack = CommandAck::new(field);
+
+    ack.result = match dispatch(field, message, &mut ack) {
+        Ok(_) => CommandResult::Applied,
+        Err(result) => {
+            log::warn!("Rejected cfg {:?}: {:?}", field, result);
+            result
+        }
+    };
+
+    ack
+}
+
+/// Turning VIN on this way also clears a latched over-current trip; it is the only command that
+/// does.
+#[cfg(not(no_protector))]
+fn apply_vin_status(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let vin_state = VinState::try_from(value[0]).map_err(|_| CommandResult::InvalidValue)?;
+    let action = match vin_state {
+        VinState::Normal => VinAction::Rearm,
+        state => VinAction::Set(state),
+    };
+    send_vin_action(action, vin_state)
+}
+
+#[cfg(not(no_protector))]
+fn send_vin_action(action: VinAction, vin_state: VinState) -> Result<(), CommandResult> {
+    VIN_STATUS_CFG_CHANNEL
+        .try_send(action)
+        .map_err(|_| CommandResult::Busy)?;
+
+    if matches!(vin_state, VinState::Normal) && system::offline_shutdown() {
+        system::set_offline_shutdown(false);
+        publish_system_message("offline-shutdown", b"0");
+    }
+    Ok(())
+}
+
+/// Value: VIN state (`0` on, `1` off), then the duration in seconds as u16 LE, up to
+/// [`MAX_VIN_OVERRIDE_SECS`]. A duration of `0` ends a running override. Holding VIN on is
+/// refused with `InvalidValue` while an over-current trip is latched.
+#[cfg(not(no_protector))]
+fn apply_vin_override(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let vin_state = match value[0] {
+        0 => VinState::Normal,
+        1 => VinState::Shutdown,
+        _ => return Err(CommandResult::InvalidValue),
+    };
+    let secs = u16::from_le_bytes([value[1], value[2]]);
+    if secs > MAX_VIN_OVERRIDE_SECS {
+        return Err(CommandResult::InvalidValue);
+    }
+    if matches!(vin_state, VinState::Normal) && secs > 0 && protector::over_current_latched() {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    VIN_STATUS_CFG_CHANNEL
+        .try_send(VinAction::Override(vin_state, secs))
+        .map_err(|_| CommandResult::Busy)
+}
+
+/// Value: `0` for the charge channel outputs only, `1` to cut VIN as well. The outputs stay
+/// off until `all-on`.
+fn apply_all_off(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    set_all_outputs(false, with_vin(value[0])?)
+}
+
+/// Value: `0` for the charge channel outputs only, `1` to switch VIN back on as well. `1` is
+/// refused with `InvalidValue` while an over-current trip is latched; only `vin-status` clears it.
+fn apply_all_on(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    set_all_outputs(true, with_vin(value[0])?)
+}
+
+fn with_vin(value: u8) -> Result<bool, CommandResult> {
+    match value {
+        0 => Ok(false),
+        1 if cfg!(not(no_protector)) => Ok(true),
+        _ => Err(CommandResult::InvalidValue),
+    }
+}
+
+/// Every queue involved is checked before anything is sent, so the command is applied either
+/// completely or not at all.
+#[cfg_attr(no_protector, allow(unused_variables))]
+fn set_all_outputs(on: bool, with_vin: bool) -> Result<(), CommandResult> {
+    if CHARGE_CHANNEL_CFG_CHANNEL.free_capacity() == 0 {
+        return Err(CommandResult::Busy);
+    }
+    #[cfg(not(no_protector))]
+    if with_vin && VIN_STATUS_CFG_CHANNEL.free_capacity() == 0 {
+        return Err(CommandResult::Busy);
+    }
+    #[cfg(not(no_protector))]
+    if on && with_vin && protector::over_current_latched() {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    CHARGE_CHANNEL_CFG_CHANNEL
+        .try_send(ChargeChannelAction::Outputs(on))
+        .map_err(|_| CommandResult::Busy)?;
+    burn_in::user_outputs(on);
+
+    #[cfg(not(no_protector))]
+    if with_vin {
+        let vin_state = if on {
+            VinState::Normal
+        } else {
+            VinState::Shutdown
+        };
+        send_vin_action(VinAction::Set(vin_state), vin_state)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(no_protector))]
+fn apply_current_avg_window(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_current_avg_window(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    PROTECTOR_CFG_CHANNEL
+        .try_send(ProtectorCommand::CurrentAvgWindow(value[0]))
+        .map_err(|_| CommandResult::Busy)
+}
+
+fn apply_power_mismatch_percent(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_power_mismatch_percent(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.power_mismatch_percent = value[0]);
+    Ok(())
+}
+
+/// Value: `1` also reports the power computed as V×I and flags `power_mismatch` when it strays
+/// from the INA226 power register, `0` (default) reports the register value alone.
+fn apply_power_cross_check(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let cross_check = match value[0] {
+        0 => false,
+        1 => true,
+        _ => return Err(CommandResult::InvalidValue),
+    };
+
+    settings::update(|settings| settings.power_cross_check = cross_check);
+    Ok(())
+}
+
+fn apply_channel_active_milliamps(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let CfgTarget::Channel(index) = target else {
+        return Err(CommandResult::UnknownField);
+    };
+    let milliamps = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_channel_active_milliamps(milliamps) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.channel_active_milliamps[index as usize] = milliamps);
+    Ok(())
+}
+
+fn calibrate(target: CfgTarget, quantity: Quantity, value: &[u8]) -> Result<(), CommandResult> {
+    let device = match target {
+        CfgTarget::Channel(index) => index as usize,
+        CfgTarget::Protector => PROTECTOR_DEVICE,
+        CfgTarget::Global => return Err(CommandResult::UnknownField),
+    };
+    let reference = f32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+    if reference.is_nan() || reference < 0.0 {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    match calibration::calibrate(device, quantity, reference) {
+        Ok(_) => Ok(()),
+        Err(CalibrationError::NoReading) => Err(CommandResult::Busy),
+        Err(_) => Err(CommandResult::InvalidValue),
+    }
+}
+
+/// Value: reference bus voltage in millivolts as `f32` LE; 0 resets the factor.
+fn apply_cal_millivolts(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    calibrate(target, Quantity::Voltage, value)
+}
+
+/// Value: reference current in amps as `f32` LE; 0 resets the factor.
+fn apply_cal_amps(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    calibrate(target, Quantity::Current, value)
+}
+
+/// Takes `1` as the value. The result is published to `system/reinit` once the init path ran.
+fn apply_reinit(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if value[0] != 1 {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    let target = match target {
+        CfgTarget::Channel(index) => ReinitTarget::Channel(index),
+        _ => ReinitTarget::All,
+    };
+
+    CHARGE_CHANNEL_CFG_CHANNEL
+        .try_send(ChargeChannelAction::Reinit(target))
+        .map_err(|_| CommandResult::Busy)
+}
+
+/// Value: `0` resets the INA226, `1` sends a PD hard reset through the SW3526. The outcome is
+/// published to `ch<n>/reset-device`.
+fn apply_reset_device(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let CfgTarget::Channel(index) = target else {
+        return Err(CommandResult::UnknownField);
+    };
+    let reset = ChannelReset::try_from(value[0]).map_err(|_| CommandResult::InvalidValue)?;
+
+    CHARGE_CHANNEL_CFG_CHANNEL
+        .try_send(ChargeChannelAction::Reset(index, reset))
+        .map_err(|_| CommandResult::Busy)
+}
+
+/// Value must be `1`. Re-reads the channel's chip ids and republishes `ch<n>/ids`.
+fn apply_ids(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let CfgTarget::Channel(index) = target else {
+        return Err(CommandResult::UnknownField);
+    };
+    if value[0] != 1 {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    CHARGE_CHANNEL_CFG_CHANNEL
+        .try_send(ChargeChannelAction::ReadIds(index))
+        .map_err(|_| CommandResult::Busy)
+}
+
+/// Value: seconds as `u16` LE.
+fn apply_mqtt_ping_interval(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let secs = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_mqtt_timing(secs, settings::get().socket_timeout_secs) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.mqtt_ping_interval_secs = secs);
+    Ok(())
+}
+
+/// Value: seconds as `u16` LE.
+fn apply_socket_timeout(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let secs = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_mqtt_timing(settings::get().mqtt_ping_interval_secs, secs) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.socket_timeout_secs = secs);
+    Ok(())
+}
+
+/// Value: seconds as `u16` LE, 0 disables the heartbeat.
+fn apply_heartbeat_interval(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let secs = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_heartbeat_interval_secs(secs) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.heartbeat_interval_secs = secs);
+    Ok(())
+}
+
+/// Value: minimum interval and random spread, both seconds as `u16` LE.
+fn apply_mqtt_reconnect(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let min_secs = u16::from_le_bytes([value[0], value[1]]);
+    let spread_secs = u16::from_le_bytes([value[2], value[3]]);
+    if !settings::is_valid_mqtt_reconnect(min_secs, spread_secs) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| {
+        settings.mqtt_reconnect_min_secs = min_secs;
+        settings.mqtt_reconnect_spread_secs = spread_secs;
+    });
+    Ok(())
+}
+
+/// Value: retries of a refused subscription before reconnecting, up to 10. Applies from the
+/// next connect.
+fn apply_mqtt_subscribe_retries(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_mqtt_subscribe_retries(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.mqtt_subscribe_retries = value[0]);
+    Ok(())
+}
+
+/// Value: averaging step, 0 = 1 sample up to 5 = 256 samples.
+fn apply_ina226_avg(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_ina226_avg_step(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.ina226_avg_step = value[0]);
+    Ok(())
+}
+
+/// Value: µA as `u16` LE, 0 turns the averaging auto-tuning off.
+fn apply_ina226_noise_target(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let microamps = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_ina226_noise_target_microamps(microamps) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.ina226_noise_target_microamps = microamps);
+    Ok(())
+}
+
+fn apply_sw3526_timeout_limit(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_sw3526_timeout_limit(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.sw3526_timeout_limit = value[0]);
+    Ok(())
+}
+
+/// Value: milliseconds as `u16` LE.
+fn apply_channel_op_timeout(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let timeout_ms = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_channel_op_timeout_ms(timeout_ms) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.channel_op_timeout_ms = timeout_ms);
+    Ok(())
+}
+
+/// Value: `0` samples the charge channels on the fixed 1s cadence, `1` as soon as their
+/// conversions are ready. The achieved rate is published to `system/sample-rate`.
+fn apply_poll_conversion_ready(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let poll = match value[0] {
+        0 => false,
+        1 => true,
+        _ => return Err(CommandResult::InvalidValue),
+    };
+
+    if !settings::is_valid_delivery(&settings::get().delivery, poll) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.poll_conversion_ready = poll);
+    Ok(())
+}
+
+/// Value: `1` publishes the telemetry series retained, `0` (default) does not. Shorthand for
+/// setting the retain flag of both telemetry streams with `delivery`.
+fn apply_retain_telemetry(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let retain = match value[0] {
+        0 => false,
+        1 => true,
+        _ => return Err(CommandResult::InvalidValue),
+    };
+
+    settings::update(|settings| {
+        settings.delivery[Stream::Protector as usize].retain = retain;
+        settings.delivery[Stream::ChargeChannel as usize].retain = retain;
+    });
+    Ok(())
+}
+
+/// Value: `[stream, qos, retain]`, with the stream as in [`Stream`] and retain `0` or `1`.
+fn apply_delivery(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let stream = Stream::try_from(value[0]).map_err(|_| CommandResult::InvalidValue)?;
+    let retain = match value[2] {
+        0 => false,
+        1 => true,
+        _ => return Err(CommandResult::InvalidValue),
+    };
+
+    let current = settings::get();
+    let mut delivery = current.delivery;
+    delivery[stream as usize] = Delivery {
+        qos: value[1],
+        retain,
+    };
+    if !settings::is_valid_delivery(&delivery, current.poll_conversion_ready) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.delivery = delivery);
+    Ok(())
+}
+
+/// Value: the throttling temperature in °C, or `0` to turn throttling off.
+fn apply_thermal_throttle(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let shutdown_celsius = settings::get().temperature_shutdown_celsius;
+    if !settings::is_valid_thermal_throttle_celsius(value[0], shutdown_celsius) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.thermal_throttle_celsius = value[0]);
+    Ok(())
+}
+
+/// Value: `0` the hotter sensor, `1` the mean of both, `2` sensor 0 only, `3` sensor 1 only.
+fn apply_temperature_reduction(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let reduction =
+        TemperatureReduction::try_from(value[0]).map_err(|_| CommandResult::InvalidValue)?;
+
+    settings::update(|settings| settings.temperature_reduction = reduction);
+    Ok(())
+}
+
+/// Value: 1, 2, 4 or 8 readings. The protector reconfigures both sensors on its next cycle.
+#[cfg(not(no_protector))]
+fn apply_os_fail_queue(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_os_fail_queue_size(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.os_fail_queue_size = value[0]);
+    Ok(())
+}
+
+/// Checks a new pair of sensor thresholds against each other and the throttling temperature,
+/// warning about an inverted pair since the sensors would then never let VIN back on.
+#[cfg(not(no_protector))]
+fn check_temperature_limits(
+    hysteresis_celsius: f32,
+    shutdown_celsius: f32,
+    settings: &Settings,
+) -> Result<(), CommandResult> {
+    if hysteresis_celsius >= shutdown_celsius {
+        log::warn!(
+            "Rejected temperature limits: hysteresis {:.1}°C not below shutdown {:.1}°C",
+            hysteresis_celsius,
+            shutdown_celsius
+        );
+        return Err(CommandResult::InvalidValue);
+    }
+    if !settings::is_valid_temperature_limits(hysteresis_celsius, shutdown_celsius)
+        || !settings::is_valid_thermal_throttle_celsius(
+            settings.thermal_throttle_celsius,
+            shutdown_celsius,
+        )
+    {
+        return Err(CommandResult::InvalidValue);
+    }
+    Ok(())
+}
+
+/// Value: the temperature in °C at which the sensors cut VIN, as `f32` LE. It has to stay above
+/// `temp-hysteresis` and at least 5°C above `thermal-throttle`. The protector reprograms both
+/// sensors on its next cycle.
+#[cfg(not(no_protector))]
+fn apply_temp_shutdown(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let celsius = f32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+    let current = settings::get();
+    check_temperature_limits(current.temperature_hysteresis_celsius, celsius, &current)?;
+
+    settings::update(|settings| settings.temperature_shutdown_celsius = celsius);
+    Ok(())
+}
+
+/// Value: the temperature in °C both sensors have to fall below before VIN comes back on after
+/// an over-temperature cut, as `f32` LE. It has to stay below `temp-shutdown`.
+#[cfg(not(no_protector))]
+fn apply_temp_hysteresis(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let celsius = f32::from_le_bytes([value[0], value[1], value[2], value[3]]);
+    let current = settings::get();
+    check_temperature_limits(celsius, current.temperature_shutdown_celsius, &current)?;
+
+    settings::update(|settings| settings.temperature_hysteresis_celsius = celsius);
+    Ok(())
+}
+
+/// Value: `[start, full]` in °C, the temperatures at which the fan starts and reaches full speed.
+#[cfg(not(no_protector))]
+fn apply_fan(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_fan_celsius(value[0], value[1]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| {
+        settings.fan_start_celsius = value[0];
+        settings.fan_full_celsius = value[1];
+    });
+    Ok(())
+}
+
+/// Value: the input current in mA as `u16` LE that cuts VIN, or `0` to turn the protection off.
+/// Only `vin-status` turns VIN back on after a trip.
+#[cfg(not(no_protector))]
+fn apply_over_current(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let milliamps = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_over_current_milliamps(milliamps) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.over_current_milliamps = milliamps);
+    Ok(())
+}
+
+/// Value: the supply's power in watts as `u16` LE, or `0` to turn the ceiling off.
+#[cfg(not(no_protector))]
+fn apply_input_power_limit(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let watts = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_input_power_limit_watts(watts) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.input_power_limit_watts = watts);
+    Ok(())
+}
+
+/// Values outside the configured range are accepted and clamped when programmed.
+fn apply_limit_watts(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let CfgTarget::Channel(index) = target else {
+        return Err(CommandResult::UnknownField);
+    };
+    if !settings::is_valid_limit_watts(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+    // The commission test owns the limits until it restores them.
+    if commission::running() {
+        return Err(CommandResult::Busy);
+    }
+
+    settings::update(|settings| settings.limit_watts[index as usize] = value[0]);
+    Ok(())
+}
+
+/// Value: mask of the PD profiles to offer, bit 0 to 5 for 9V, 12V, 15V, 20V, PPS0 and PPS1.
+fn apply_pd_profiles(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let CfgTarget::Channel(index) = target else {
+        return Err(CommandResult::UnknownField);
+    };
+    if !settings::is_valid_pd_profiles(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.pd_profiles[index as usize] = value[0]);
+    Ok(())
+}
+
+/// Value: `[min, max]` in watts.
+fn apply_limit_watts_range(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let (min, max) = (value[0], value[1]);
+    if !settings::is_valid_limit_watts(min) || !settings::is_valid_limit_watts(max) || min > max {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| {
+        settings.min_limit_watts = min;
+        settings.max_limit_watts = max;
+    });
+    Ok(())
+}
+
+/// Value: `[7-bit address, transaction count]`. Address 0 stops a running trace.
+fn apply_i2c_trace(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let (address, count) = (value[0], value[1]);
+    if address == 0 {
+        i2c_trace::stop();
+        return Ok(());
+    }
+    if address > 0x7f || count == 0 {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    i2c_trace::start(address, count);
+    Ok(())
+}
+
+/// Value: `[mux 0, mux 1, INA226 ch0..ch3]` addresses. Stored only; applies after a reboot.
+fn apply_board_layout(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let layout = BoardLayout::from_bytes(value)
+        .filter(BoardLayout::is_valid)
+        .ok_or(CommandResult::InvalidValue)?;
+
+    board::store(&layout).map_err(|err| {
+        log::error!("Cannot store board layout: {:?}", err);
+        CommandResult::Failed
+    })
+}
+
+/// Value: seconds as `u16` LE, 0 to keep running while offline.
+fn apply_offline_shutdown(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let secs = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_offline_shutdown_secs(secs) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.offline_shutdown_secs = secs);
+    Ok(())
+}
+
+/// Value: minimum on time of VIN in seconds, `u16` LE; 0 disables it. Holds back cuts by
+/// command or an expiring override, not those made for protection.
+#[cfg(not(no_protector))]
+fn apply_vin_min_on(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let secs = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_vin_min_secs(secs) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.vin_min_on_secs = secs);
+    Ok(())
+}
+
+/// Value: minimum off time of VIN after a cut in seconds, `u16` LE; 0 disables it.
+#[cfg(not(no_protector))]
+fn apply_vin_min_off(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let secs = u16::from_le_bytes([value[0], value[1]]);
+    if !settings::is_valid_vin_min_secs(secs) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.vin_min_off_secs = secs);
+    Ok(())
+}
+
+/// Value: rated current in mA and limit in A²s, both `u16` LE. A rated current of 0 disables
+/// the soft fuse.
+fn apply_fuse(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let rated_milliamps = u16::from_le_bytes([value[0], value[1]]);
+    let limit_amp2_secs = u16::from_le_bytes([value[2], value[3]]);
+    if !settings::is_valid_fuse(rated_milliamps, limit_amp2_secs) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| {
+        settings.fuse_rated_milliamps = rated_milliamps;
+        settings.fuse_limit_amp2_secs = limit_amp2_secs;
+    });
+    Ok(())
+}
+
+/// Value must be `1`. Re-arms the channel's blown soft fuse.
+fn apply_fuse_reset(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let CfgTarget::Channel(index) = target else {
+        return Err(CommandResult::UnknownField);
+    };
+    if value[0] != 1 {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    CHARGE_CHANNEL_CFG_CHANNEL
+        .try_send(ChargeChannelAction::ResetFuse(index))
+        .map_err(|_| CommandResult::Busy)
+}
+
+/// Value: sample period in ms, then the window in seconds, both `u16` LE. The period has to
+/// be at least [`MIN_HIGH_RATE_PERIOD_MS`] and shorter than the normal one second, the window
+/// at most [`MAX_HIGH_RATE_SECS`]; a window of `0` ends a running one. Like the polled sample
+/// rate, it needs the charge channel series at QoS 0.
+fn apply_high_rate(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let period_ms = u16::from_le_bytes([value[0], value[1]]);
+    let secs = u16::from_le_bytes([value[2], value[3]]);
+    if secs != 0
+        && (!(MIN_HIGH_RATE_PERIOD_MS..1000).contains(&period_ms)
+            || secs > MAX_HIGH_RATE_SECS
+            || settings::get().delivery(Stream::ChargeChannel).qos > 0)
+    {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    CHARGE_CHANNEL_CFG_CHANNEL
+        .try_send(ChargeChannelAction::HighRate { period_ms, secs })
+        .map_err(|_| CommandResult::Busy)
+}
+
+fn apply_commission_watts(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_limit_watts(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.commission_watts = value[0]);
+    Ok(())
+}
+
+/// Value: percent.
+fn apply_commission_tolerance(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_commission_tolerance_percent(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.commission_tolerance_percent = value[0]);
+    Ok(())
+}
+
+/// Value: percent, 0 disables the consistency check.
+fn apply_consistency_tolerance(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_consistency_tolerance_percent(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.consistency_tolerance_percent = value[0]);
+    Ok(())
+}
+
+/// Value must be `1`. Starts the commission test; busy while one is running.
+fn apply_commission(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if value[0] != 1 {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    // Both drive the outputs; they'd spoil each other's readings.
+    if burn_in::running() || !commission::start() {
+        return Err(CommandResult::Busy);
+    }
+    Ok(())
+}
+
+/// Value: seconds on, seconds off, as u16 LE each, in `5..=3600`. Both `0` ends the running
+/// burn-in; busy while one (or the commission test) is running.
+fn apply_burn_in(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let on_secs = u16::from_le_bytes([value[0], value[1]]);
+    let off_secs = u16::from_le_bytes([value[2], value[3]]);
+    if on_secs == 0 && off_secs == 0 {
+        return if burn_in::stop() {
+            Ok(())
+        } else {
+            Err(CommandResult::InvalidValue)
+        };
+    }
+
+    let phase = burn_in::MIN_PHASE_SECS..=burn_in::MAX_PHASE_SECS;
+    if !phase.contains(&on_secs) || !phase.contains(&off_secs) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    if commission::running() || !burn_in::start(on_secs, off_secs) {
+        return Err(CommandResult::Busy);
+    }
+    Ok(())
+}
+
+/// Value must be `1`. Dumps the recent samples of the channel or the protector to
+/// `history/...`; busy while a dump is running.
+fn apply_history(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if value[0] != 1 {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    let source = match target {
+        CfgTarget::Channel(index) => Source::Channel(index),
+        _ => Source::Protector,
+    };
+    if !history::request(source) {
+        return Err(CommandResult::Busy);
+    }
+    Ok(())
+}
+
+/// Value: samples per series a `history` dump returns.
+fn apply_history_len(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if !settings::is_valid_history_len(value[0]) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    settings::update(|settings| settings.history_len = value[0]);
+    Ok(())
+}
+
+/// Value: none, so an empty payload does it; whatever is sent is the request id, like for `get`.
+/// Zeroes the session energy of one channel, or of all for the global command. The lifetime
+/// counters are left alone.
+fn apply_energy_reset(target: CfgTarget, _: &[u8]) -> Result<(), CommandResult> {
+    match target {
+        CfgTarget::Channel(index) => lifetime::reset_session(Some(index as usize)),
+        CfgTarget::Global => lifetime::reset_session(None),
+        CfgTarget::Protector => return Err(CommandResult::UnknownField),
+    }
+
+    Ok(())
+}
+
+/// Clears the peak values of one channel, the protector, or everything for the global command.
+fn apply_reset_peaks(target: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if value[0] != 1 {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    match target {
+        CfgTarget::Channel(index) => peaks::reset_charge_channel(index as usize),
+        CfgTarget::Protector => peaks::reset_protector(),
+        CfgTarget::Global => {
+            for index in 0..CHARGE_CHANNEL_SERIES_ITEM_CHANNELS.len() {
+                peaks::reset_charge_channel(index);
+            }
+            if cfg!(not(no_protector)) {
+                peaks::reset_protector();
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Document accepted by `cfg/bulk`. Every field is optional and uses the same units and ranges
+/// as the matching single-parameter command. `cfg/get` publishes the current settings in the
+/// same format, so a snapshot can be sent back as is.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct BulkConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_avg_window: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power_mismatch_percent: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power_cross_check: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel_active_milliamps: Option<[u16; 4]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit_watts: Option<[u8; 4]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    limit_watts_range: Option<[u8; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mqtt_ping_interval: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    socket_timeout: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heartbeat_interval: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mqtt_reconnect: Option<[u16; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mqtt_subscribe_retries: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    channel_op_timeout: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ina226_avg: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ina226_noise_target: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sw3526_timeout_limit: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    offline_shutdown: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vin_min_on: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vin_min_off: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pd_profiles: Option<[u8; 4]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    poll_conversion_ready: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history_len: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retain_telemetry: Option<bool>,
+    /// `[qos, retain]` per stream, in [`Stream`] order.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delivery: Option<[[u8; 2]; 4]>,
+    /// `[rated milliamps, limit A²s]`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fuse: Option<[u16; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thermal_throttle: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    os_fail_queue: Option<u8>,
+    /// `[hysteresis, shutdown]` in °C.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature_limits: Option<[f32; 2]>,
+    /// `[start, full]` in °C.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fan: Option<[u8; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    over_current: Option<u16>,
+    /// `0` max, `1` mean, `2`/`3` one sensor only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature_reduction: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_power_limit: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commission_watts: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commission_tolerance: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    consistency_tolerance: Option<u8>,
+}
+
+impl BulkConfig {
+    /// The current settings as `cfg/bulk` documents, grouped by what they tune. Each group has
+    /// to fit a status message even with every value at its longest; [`apply_get`] answers
+    /// `Failed` rather than send one cut short.
+    fn snapshot(settings: &Settings) -> [(&'static str, BulkConfig); CFG_GET_PARTS] {
+        [
+            (
+                "cfg/limits",
+                BulkConfig {
+                    limit_watts: Some(settings.limit_watts),
+                    limit_watts_range: Some([settings.min_limit_watts, settings.max_limit_watts]),
+                    pd_profiles: Some(settings.pd_profiles),
+                    channel_active_milliamps: Some(settings.channel_active_milliamps),
+                    fuse: Some([settings.fuse_rated_milliamps, settings.fuse_limit_amp2_secs]),
+                    input_power_limit: Some(settings.input_power_limit_watts),
+                    ..Default::default()
+                },
+            ),
+            (
+                "cfg/protection",
+                BulkConfig {
+                    temperature_limits: Some([
+                        settings.temperature_hysteresis_celsius,
+                        settings.temperature_shutdown_celsius,
+                    ]),
+                    temperature_reduction: Some(settings.temperature_reduction as u8),
+                    thermal_throttle: Some(settings.thermal_throttle_celsius),
+                    os_fail_queue: Some(settings.os_fail_queue_size),
+                    fan: Some([settings.fan_start_celsius, settings.fan_full_celsius]),
+                    over_current: Some(settings.over_current_milliamps),
+                    offline_shutdown: Some(settings.offline_shutdown_secs),
+                    vin_min_on: Some(settings.vin_min_on_secs),
+                    vin_min_off: Some(settings.vin_min_off_secs),
+                    ..Default::default()
+                },
+            ),
+            (
+                "cfg/sampling",
+                BulkConfig {
+                    current_avg_window: Some(settings.current_avg_window),
+                    power_mismatch_percent: Some(settings.power_mismatch_percent),
+                    power_cross_check: Some(settings.power_cross_check),
+                    channel_op_timeout: Some(settings.channel_op_timeout_ms),
+                    ina226_avg: Some(settings.ina226_avg_step),
+                    ina226_noise_target: Some(settings.ina226_noise_target_microamps),
+                    sw3526_timeout_limit: Some(settings.sw3526_timeout_limit),
+                    poll_conversion_ready: Some(settings.poll_conversion_ready),
+                    ..Default::default()
+                },
+            ),
+            (
+                "cfg/mqtt",
+                BulkConfig {
+                    mqtt_ping_interval: Some(settings.mqtt_ping_interval_secs),
+                    socket_timeout: Some(settings.socket_timeout_secs),
+                    heartbeat_interval: Some(settings.heartbeat_interval_secs),
+                    mqtt_reconnect: Some([
+                        settings.mqtt_reconnect_min_secs,
+                        settings.mqtt_reconnect_spread_secs,
+                    ]),
+                    mqtt_subscribe_retries: Some(settings.mqtt_subscribe_retries),
+                    delivery: Some(
+                        settings
+                            .delivery
+                            .map(|delivery| [delivery.qos, delivery.retain as u8]),
+                    ),
+                    ..Default::default()
+                },
+            ),
+            (
+                "cfg/diagnostics",
+                BulkConfig {
+                    history_len: Some(settings.history_len),
+                    consistency_tolerance: Some(settings.consistency_tolerance_percent),
+                    commission_watts: Some(settings.commission_watts),
+                    commission_tolerance: Some(settings.commission_tolerance_percent),
+                    ..Default::default()
+                },
+            ),
+        ]
+    }
+
+    /// Applies the document to `settings`, returning the name of the first invalid field.
+    fn apply_to(&self, settings: &mut Settings) -> Result<(), &'static str> {
+        fn set<T: Copy>(
+            target: &mut T,
+            value: Option<T>,
+            valid: bool,
+            name: &'static str,
+        ) -> Result<(), &'static str> {
+            match value {
+                Some(_) if !valid => Err(name),
+                Some(value) => {
+                    *target = value;
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        }
+
+        set(
+            &mut settings.current_avg_window,
+            self.current_avg_window,
+            self.current_avg_window
+                .map_or(true, settings::is_valid_current_avg_window),
+            "current_avg_window",
+        )?;
+        set(
+            &mut settings.power_mismatch_percent,
+            self.power_mismatch_percent,
+            self.power_mismatch_percent
+                .map_or(true, settings::is_valid_power_mismatch_percent),
+            "power_mismatch_percent",
+        )?;
+        if let Some(cross_check) = self.power_cross_check {
+            settings.power_cross_check = cross_check;
+        }
+        set(
+            &mut settings.channel_active_milliamps,
+            self.channel_active_milliamps,
+            self.channel_active_milliamps.map_or(true, |values| {
+                values
+                    .into_iter()
+                    .all(settings::is_valid_channel_active_milliamps)
+            }),
+            "channel_active_milliamps",
+        )?;
+        set(
+            &mut settings.limit_watts,
+            self.limit_watts,
+            self.limit_watts.map_or(true, |values| {
+                values.into_iter().all(settings::is_valid_limit_watts)
+            }),
+            "limit_watts",
+        )?;
+        if let Some([min, max]) = self.limit_watts_range {
+            if !settings::is_valid_limit_watts(min)
+                || !settings::is_valid_limit_watts(max)
+                || min > max
+            {
+                return Err("limit_watts_range");
+            }
+            settings.min_limit_watts = min;
+            settings.max_limit_watts = max;
+        }
+        set(
+            &mut settings.heartbeat_interval_secs,
+            self.heartbeat_interval,
+            self.heartbeat_interval
+                .map_or(true, settings::is_valid_heartbeat_interval_secs),
+            "heartbeat_interval",
+        )?;
+        if let Some([min_secs, spread_secs]) = self.mqtt_reconnect {
+            if !settings::is_valid_mqtt_reconnect(min_secs, spread_secs) {
+                return Err("mqtt_reconnect");
+            }
+            settings.mqtt_reconnect_min_secs = min_secs;
+            settings.mqtt_reconnect_spread_secs = spread_secs;
+        }
+        set(
+            &mut settings.mqtt_subscribe_retries,
+            self.mqtt_subscribe_retries,
+            self.mqtt_subscribe_retries
+                .map_or(true, settings::is_valid_mqtt_subscribe_retries),
+            "mqtt_subscribe_retries",
+        )?;
+        set(
+            &mut settings.channel_op_timeout_ms,
+            self.channel_op_timeout,
+            self.channel_op_timeout
+                .map_or(true, settings::is_valid_channel_op_timeout_ms),
+            "channel_op_timeout",
+        )?;
+        set(
+            &mut settings.ina226_avg_step,
+            self.ina226_avg,
+            self.ina226_avg
+                .map_or(true, settings::is_valid_ina226_avg_step),
+            "ina226_avg",
+        )?;
+        set(
+            &mut settings.ina226_noise_target_microamps,
+            self.ina226_noise_target,
+            self.ina226_noise_target
+                .map_or(true, settings::is_valid_ina226_noise_target_microamps),
+            "ina226_noise_target",
+        )?;
+        set(
+            &mut settings.sw3526_timeout_limit,
+            self.sw3526_timeout_limit,
+            self.sw3526_timeout_limit
+                .map_or(true, settings::is_valid_sw3526_timeout_limit),
+            "sw3526_timeout_limit",
+        )?;
+        set(
+            &mut settings.offline_shutdown_secs,
+            self.offline_shutdown,
+            self.offline_shutdown
+                .map_or(true, settings::is_valid_offline_shutdown_secs),
+            "offline_shutdown",
+        )?;
+        set(
+            &mut settings.vin_min_on_secs,
+            self.vin_min_on,
+            self.vin_min_on
+                .map_or(true, settings::is_valid_vin_min_secs),
+            "vin_min_on",
+        )?;
+        set(
+            &mut settings.vin_min_off_secs,
+            self.vin_min_off,
+            self.vin_min_off
+                .map_or(true, settings::is_valid_vin_min_secs),
+            "vin_min_off",
+        )?;
+        set(
+            &mut settings.pd_profiles,
+            self.pd_profiles,
+            self.pd_profiles.map_or(true, |values| {
+                values.into_iter().all(settings::is_valid_pd_profiles)
+            }),
+            "pd_profiles",
+        )?;
+
+        if let Some([rated_milliamps, limit_amp2_secs]) = self.fuse {
+            if !settings::is_valid_fuse(rated_milliamps, limit_amp2_secs) {
+                return Err("fuse");
+            }
+            settings.fuse_rated_milliamps = rated_milliamps;
+            settings.fuse_limit_amp2_secs = limit_amp2_secs;
+        }
+        if let Some([hysteresis_celsius, shutdown_celsius]) = self.temperature_limits {
+            if !settings::is_valid_temperature_limits(hysteresis_celsius, shutdown_celsius) {
+                return Err("temperature_limits");
+            }
+            settings.temperature_hysteresis_celsius = hysteresis_celsius;
+            settings.temperature_shutdown_celsius = shutdown_celsius;
+        }
+        if let Some([start_celsius, full_celsius]) = self.fan {
+            if !settings::is_valid_fan_celsius(start_celsius, full_celsius) {
+                return Err("fan");
+            }
+            settings.fan_start_celsius = start_celsius;
+            settings.fan_full_celsius = full_celsius;
+        }
+        // Throttling has to start below the cut-off, so check it once both are final.
+        settings.thermal_throttle_celsius = self
+            .thermal_throttle
+            .unwrap_or(settings.thermal_throttle_celsius);
+        if !settings::is_valid_thermal_throttle_celsius(
+            settings.thermal_throttle_celsius,
+            settings.temperature_shutdown_celsius,
+        ) {
+            return Err("thermal_throttle");
+        }
+        set(
+            &mut settings.os_fail_queue_size,
+            self.os_fail_queue,
+            self.os_fail_queue
+                .map_or(true, settings::is_valid_os_fail_queue_size),
+            "os_fail_queue",
+        )?;
+        if let Some(reduction) = self.temperature_reduction {
+            settings.temperature_reduction =
+                TemperatureReduction::try_from(reduction).map_err(|_| "temperature_reduction")?;
+        }
+        set(
+            &mut settings.over_current_milliamps,
+            self.over_current,
+            self.over_current
+                .map_or(true, settings::is_valid_over_current_milliamps),
+            "over_current",
+        )?;
+        set(
+            &mut settings.input_power_limit_watts,
+            self.input_power_limit,
+            self.input_power_limit
+                .map_or(true, settings::is_valid_input_power_limit_watts),
+            "input_power_limit",
+        )?;
+        set(
+            &mut settings.commission_watts,
+            self.commission_watts,
+            self.commission_watts
+                .map_or(true, settings::is_valid_limit_watts),
+            "commission_watts",
+        )?;
+        set(
+            &mut settings.commission_tolerance_percent,
+            self.commission_tolerance,
+            self.commission_tolerance
+                .map_or(true, settings::is_valid_commission_tolerance_percent),
+            "commission_tolerance",
+        )?;
+        set(
+            &mut settings.consistency_tolerance_percent,
+            self.consistency_tolerance,
+            self.consistency_tolerance
+                .map_or(true, settings::is_valid_consistency_tolerance_percent),
+            "consistency_tolerance",
+        )?;
+        set(
+            &mut settings.history_len,
+            self.history_len,
+            self.history_len
+                .map_or(true, settings::is_valid_history_len),
+            "history_len",
+        )?;
+        if let Some(poll) = self.poll_conversion_ready {
+            settings.poll_conversion_ready = poll;
+        }
+        if let Some(retain) = self.retain_telemetry {
+            settings.delivery[Stream::Protector as usize].retain = retain;
+            settings.delivery[Stream::ChargeChannel as usize].retain = retain;
+        }
+        if let Some(delivery) = self.delivery {
+            for (target, [qos, retain]) in settings.delivery.iter_mut().zip(delivery) {
+                if retain > 1 {
+                    return Err("delivery");
+                }
+                *target = Delivery {
+                    qos,
+                    retain: retain == 1,
+                };
+            }
+        }
+        // Checked once both the delivery and the sampling mode are final.
+        if !settings::is_valid_delivery(&settings.delivery, settings.poll_conversion_ready) {
+            return Err("delivery");
+        }
+
+        // The MQTT timings depend on each other, so check them after both are set.
+        settings.mqtt_ping_interval_secs = self
+            .mqtt_ping_interval
+            .unwrap_or(settings.mqtt_ping_interval_secs);
+        settings.socket_timeout_secs = self.socket_timeout.unwrap_or(settings.socket_timeout_secs);
+        if !settings::is_valid_mqtt_timing(
+            settings.mqtt_ping_interval_secs,
+            settings.socket_timeout_secs,
+        ) {
+            return Err("mqtt_ping_interval/socket_timeout");
+        }
+
+        Ok(())
+    }
+}
+
+/// Applies a JSON document of several settings at once. The whole document is validated before
+/// anything is applied; on rejection the reason is published to `system/cfg-bulk`.
+fn apply_bulk(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let result = match serde_json_core::from_slice::<BulkConfig>(value) {
+        // The commission test owns the limits until it restores them.
+        Ok((config, _)) if config.limit_watts.is_some() && commission::running() => {
+            let mut detail = String::<128>::new();
+            detail
+                .push_str("{\"ok\":false,\"error\":\"commission test running\"}")
+                .ok();
+            Err((CommandResult::Busy, detail))
+        }
+        // The protector takes the averaging window through its own queue.
+        #[cfg(not(no_protector))]
+        Ok((config, _))
+            if config.current_avg_window.is_some()
+                && PROTECTOR_CFG_CHANNEL.free_capacity() == 0 =>
+        {
+            let mut detail = String::<128>::new();
+            detail.push_str("{\"ok\":false,\"error\":\"busy\"}").ok();
+            Err((CommandResult::Busy, detail))
+        }
+        Ok((config, _)) => {
+            let mut settings = settings::get();
+            match config.apply_to(&mut settings) {
+                Ok(_) => {
+                    settings::update(|current| *current = settings);
+                    #[cfg(not(no_protector))]
+                    if let Some(window) = config.current_avg_window {
+                        PROTECTOR_CFG_CHANNEL
+                            .try_send(ProtectorCommand::CurrentAvgWindow(window))
+                            .ok();
+                    }
+                    Ok(())
+                }
+                Err(field) => {
+                    let mut detail = String::<128>::new();
+                    write!(detail, "{{\"ok\":false,\"error\":\"invalid {}\"}}", field).ok();
+                    Err((CommandResult::InvalidValue, detail))
+                }
+            }
+        }
+        Err(err) => {
+            let mut detail = String::<128>::new();
+            write!(detail, "{{\"ok\":false,\"error\":\"{}\"}}", err).ok();
+            Err((CommandResult::InvalidPayload, detail))
+        }
+    };
+
+    match result {
+        Ok(_) => {
+            publish_system_message("cfg-bulk", b"{\"ok\":true}");
+            Ok(())
+        }
+        Err((result, detail)) => {
+            publish_system_message("cfg-bulk", detail.as_bytes());
+            Err(result)
+        }
+    }
+}
+
+/// Number of messages a `cfg/get` reply is split into.
+const CFG_GET_PARTS: usize = 5;
+
+/// Publishes the current settings to `system/cfg/limits`, `system/cfg/protection`,
+/// `system/cfg/sampling`, `system/cfg/mqtt` and `system/cfg/diagnostics`. Each part is a
+/// complete `cfg/bulk` document; together they cover every setting. Rejected as busy rather than
+/// sent partially when the status queue can't take all of them.
+fn apply_get(_: CfgTarget, _: &[u8]) -> Result<(), CommandResult> {
+    if SYSTEM_MESSAGE_CHANNEL.free_capacity() < CFG_GET_PARTS {
+        return Err(CommandResult::Busy);
+    }
+
+    for (topic, config) in BulkConfig::snapshot(&settings::get()) {
+        let mut json = [0u8; MAX_SYSTEM_MESSAGE_LEN];
+        let len = serde_json_core::to_slice(&config, &mut json).map_err(|err| {
+            log::error!("Cannot serialize {}: {:?}", topic, err);
+            CommandResult::Failed
+        })?;
+        publish_system_message(topic, &json[..len]);
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WifiConfig<'a> {
+    ssid: &'a str,
+    password: &'a str,
+}
+
+/// Value: `{"ssid":"..","password":".."}`. The device moves to the new network and keeps it only
+/// if it connects; the outcome is published to `system/wifi`.
+fn apply_wifi(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let (config, _) = serde_json_core::from_slice::<WifiConfig>(value)
+        .map_err(|_| CommandResult::InvalidPayload)?;
+    let credentials =
+        WifiCredentials::new(config.ssid, config.password).ok_or(CommandResult::InvalidValue)?;
+
+    WIFI_CREDENTIALS_CHANNEL
+        .try_send(credentials)
+        .map_err(|_| CommandResult::Busy)
+}
+
+/// Value: the new name, up to 32 ASCII letters, digits, `-`, `_` or `.`. Stored in flash and
+/// echoed to `system/device-name`.
+fn apply_device_name(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let name = core::str::from_utf8(value).map_err(|_| CommandResult::InvalidPayload)?;
+    if !device_name::is_valid(name) {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    device_name::store(name).map_err(|err| {
+        log::error!("Cannot store device name: {:?}", err);
+        CommandResult::Failed
+    })?;
+    log::info!("Device name set to {}", name);
+    publish_system_message("device-name", name.as_bytes());
+    Ok(())
+}
+
+fn apply_log_level(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    let value = core::str::from_utf8(value).map_err(|_| CommandResult::InvalidPayload)?;
+    let level = logger::parse_level(value).ok_or(CommandResult::InvalidValue)?;
+
+    logger::set_level(level);
+    log::info!("Log level set to {}", level);
+    publish_system_message("log-level", level.as_str().as_bytes());
+    Ok(())
+}
+
+/// Takes `1` as the value so a stray empty publish can't reboot the device.
+fn apply_reboot(_: CfgTarget, value: &[u8]) -> Result<(), CommandResult> {
+    if value[0] != 1 {
+        return Err(CommandResult::InvalidValue);
+    }
+
+    system::request_reboot();
+    Ok(())
+}
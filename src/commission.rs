@@ -0,0 +1,166 @@
+//! Production test: runs each charge channel in turn at the configured power into a test load
+//! and checks that its INA226 measures the matching current.
+//!
+//! Results go to `commission/ch<n>` as they come in and to `commission/summary` at the end. The
+//! other channels are held at the lowest allowed limit while one is under test, and the
+//! requested limits are restored afterwards.
+
+use core::{cell::Cell, fmt::Write};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::String;
+
+use crate::{bus::publish_status, helper::abs, settings, snapshot};
+
+/// Time for the sink to renegotiate and the load to settle after the limits change.
+const SETTLE_TIME: Duration = Duration::from_secs(5);
+/// Readings of the channel under test are averaged over this long.
+const MEASURE_TIME: Duration = Duration::from_secs(3);
+const MEASURE_INTERVAL: Duration = Duration::from_millis(500);
+
+static REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static RUNNING: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+pub fn running() -> bool {
+    RUNNING.lock(|running| running.get())
+}
+
+/// Starts a test run. Returns `false` if one is already in progress.
+pub fn start() -> bool {
+    let started = RUNNING.lock(|running| !running.replace(true));
+    if started {
+        REQUEST.signal(());
+    }
+    started
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Verdict {
+    Pass,
+    Fail,
+    /// The channel produced no readings during the test.
+    Offline,
+}
+
+impl Verdict {
+    fn name(self) -> &'static str {
+        match self {
+            Verdict::Pass => "pass",
+            Verdict::Fail => "fail",
+            Verdict::Offline => "offline",
+        }
+    }
+}
+
+#[embassy_executor::task]
+pub async fn task() {
+    loop {
+        REQUEST.wait().await;
+
+        let requested_limits = settings::get().limit_watts;
+        log::info!("Commission test started");
+
+        let mut verdicts = [Verdict::Offline; 4];
+        for (index, verdict) in verdicts.iter_mut().enumerate() {
+            *verdict = test_channel(index).await;
+        }
+
+        settings::update(|settings| settings.limit_watts = requested_limits);
+        RUNNING.lock(|running| running.set(false));
+
+        let mut json = String::<64>::new();
+        json.push_str("{\"channels\":[").ok();
+        for (index, verdict) in verdicts.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            write!(json, "{}\"{}\"", separator, verdict.name()).ok();
+        }
+        json.push_str("]}").ok();
+        log::info!("Commission test done: {}", json);
+        publish_status("commission/summary", json.as_bytes());
+    }
+}
+
+async fn test_channel(index: usize) -> Verdict {
+    let settings = settings::get();
+    settings::update(|settings| {
+        for (channel, limit) in settings.limit_watts.iter_mut().enumerate() {
+            *limit = if channel == index {
+                settings.commission_watts
+            } else {
+                settings.min_limit_watts
+            };
+        }
+    });
+
+    Timer::after(SETTLE_TIME).await;
+
+    let mut samples = 0u32;
+    let mut amps = 0.0;
+    let mut millivolts = 0.0;
+    let mut limit_watts = 0;
+    let started_at = Instant::now();
+    let mut last_at = started_at;
+    while started_at.elapsed() < MEASURE_TIME {
+        Timer::after(MEASURE_INTERVAL).await;
+
+        let Some(sample) = snapshot::snapshot().channels[index] else {
+            continue;
+        };
+        if sample.at <= last_at {
+            continue;
+        }
+        last_at = sample.at;
+        samples += 1;
+        amps += sample.value.amps;
+        millivolts += sample.value.millivolts;
+        // Read back from the SW3526, so clamping and throttling are accounted for.
+        limit_watts = sample.value.limit_watts;
+    }
+
+    let mut topic = String::<24>::new();
+    write!(topic, "commission/ch{}", index).ok();
+
+    if samples == 0 || millivolts <= 0.0 {
+        log::warn!("Commission ch{}: no readings", index);
+        publish_status(&topic, b"{\"verdict\":\"offline\"}");
+        return Verdict::Offline;
+    }
+
+    let amps = amps / samples as f64;
+    let millivolts = millivolts / samples as f64;
+    let expected_amps = limit_watts as f64 / (millivolts / 1000.0);
+    let tolerance = expected_amps * settings.commission_tolerance_percent as f64 / 100.0;
+    let verdict = if abs(amps - expected_amps) <= tolerance {
+        Verdict::Pass
+    } else {
+        Verdict::Fail
+    };
+
+    log::info!(
+        "Commission ch{}: {:.3}A at {:.0}mV, expected {:.3}A: {}",
+        index,
+        amps,
+        millivolts,
+        expected_amps,
+        verdict.name()
+    );
+
+    let mut json = String::<128>::new();
+    write!(
+        json,
+        "{{\"verdict\":\"{}\",\"watts\":{},\"millivolts\":{:.0},\"amps\":{:.3},\"expected_amps\":{:.3}}}",
+        verdict.name(),
+        limit_watts,
+        millivolts,
+        amps,
+        expected_amps
+    )
+    .ok();
+    publish_status(&topic, json.as_bytes());
+
+    verdict
+}
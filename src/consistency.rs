@@ -0,0 +1,117 @@
+//! Cross-checks the charge channels against the input: everything the channels deliver comes
+//! through the protector, so their summed output power has to stay between the input power and
+//! what the bucks' efficiency leaves of it. A reading outside that range points at a calibration
+//! or wiring fault that the per-sensor checks can't see.
+// With `no_protector` there is no input reading to compare against and the task isn't spawned.
+#![cfg_attr(no_protector, allow(dead_code))]
+
+use core::fmt::Write;
+
+use embassy_time::{Duration, Instant, Ticker};
+use heapless::{String, Vec};
+
+use crate::{bus::publish_system_message, settings, snapshot};
+
+const CHECK_PERIOD: Duration = Duration::from_secs(10);
+
+/// Readings older than this are left out of a check.
+const MAX_SAMPLE_AGE: Duration = Duration::from_secs(5);
+
+/// Below this input power the ratio is dominated by quiescent draw and sensor offsets.
+const MIN_INPUT_WATTS: f64 = 5.0;
+
+/// Lowest plausible conversion efficiency of the charge channels, in percent.
+const MIN_EFFICIENCY_PERCENT: f64 = 80.0;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Finding {
+    ok: bool,
+    /// Channels whose reading alone explains an output above the input.
+    suspects: Vec<u8, 4>,
+}
+
+/// Checks once per period and publishes `system/consistency` when the verdict changes, e.g.
+/// `{"ok":false,"input_watts":40.1,"output_watts":52.3,"suspects":[2]}`.
+#[embassy_executor::task]
+pub async fn task() {
+    let mut ticker = Ticker::every(CHECK_PERIOD);
+    let mut last: Option<Finding> = None;
+
+    loop {
+        ticker.next().await;
+
+        let tolerance_percent = settings::get().consistency_tolerance_percent;
+        if tolerance_percent == 0 {
+            last = None;
+            continue;
+        }
+
+        let snapshot = snapshot::snapshot();
+        let now = Instant::now();
+        let fresh = |at: Instant| now - at <= MAX_SAMPLE_AGE;
+
+        let Some(input) = snapshot.protector.filter(|sample| fresh(sample.at)) else {
+            continue;
+        };
+        let input_watts = input.value.watts;
+        if input_watts < MIN_INPUT_WATTS {
+            continue;
+        }
+
+        let mut outputs = [0.0; 4];
+        for (output, sample) in outputs.iter_mut().zip(snapshot.channels) {
+            if let Some(sample) = sample.filter(|sample| fresh(sample.at)) {
+                *output = sample.value.watts;
+            }
+        }
+        let output_watts: f64 = outputs.iter().sum();
+
+        let tolerance = tolerance_percent as f64 / 100.0;
+        let max_watts = input_watts * (1.0 + tolerance);
+        let min_watts = input_watts * (MIN_EFFICIENCY_PERCENT / 100.0 - tolerance);
+        let in_range = |watts: f64| (min_watts..=max_watts).contains(&watts);
+
+        let mut finding = Finding {
+            ok: in_range(output_watts),
+            suspects: Vec::new(),
+        };
+        if output_watts > max_watts {
+            for (index, watts) in outputs.iter().enumerate() {
+                if in_range(output_watts - watts) {
+                    finding.suspects.push(index as u8).ok();
+                }
+            }
+        }
+
+        if last.as_ref() == Some(&finding) {
+            continue;
+        }
+
+        if finding.ok {
+            log::info!("Channel output consistent with input again");
+        } else {
+            log::warn!(
+                "Channels deliver {:.1}W from {:.1}W input, suspects: {:?}",
+                output_watts,
+                input_watts,
+                finding.suspects
+            );
+        }
+
+        let mut json = String::<128>::new();
+        write!(
+            json,
+            "{{\"ok\":{},\"input_watts\":{:.1},\"output_watts\":{:.1},\"suspects\":[",
+            finding.ok, input_watts, output_watts
+        )
+        .ok();
+        for (index, suspect) in finding.suspects.iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            write!(json, "{}{}", separator, suspect).ok();
+        }
+        json.push_str("]}").ok();
+        publish_system_message("consistency", json.as_bytes());
+
+        last = Some(finding);
+    }
+}
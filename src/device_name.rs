@@ -0,0 +1,63 @@
+//! Friendly name of the desk, e.g. `office-desk`, so dashboards watching several desks can tell
+//! them apart. It goes into `system/boot` and `system/heartbeat`.
+//!
+//! Stored in flash; without a stored name the desk is `power-desk-` plus the last three bytes of
+//! its MAC address.
+
+use core::{cell::RefCell, fmt::Write};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use esp_hal::efuse::Efuse;
+use heapless::String;
+
+use crate::storage::{self, Slot, StorageError};
+
+pub const MAX_NAME_LEN: usize = 32;
+
+pub type DeviceName = String<MAX_NAME_LEN>;
+
+static NAME: Mutex<CriticalSectionRawMutex, RefCell<DeviceName>> =
+    Mutex::new(RefCell::new(String::new()));
+
+/// ASCII letters, digits, `-`, `_` and `.` only, so the name can be written into JSON and
+/// topics as is.
+pub fn is_valid(name: &str) -> bool {
+    (1..=MAX_NAME_LEN).contains(&name.len())
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.'))
+}
+
+/// The name without a stored one. Stays the same when the desk is renamed, so it also serves as
+/// a stable id.
+pub fn default_name() -> DeviceName {
+    let [_, _, _, d, e, f] = Efuse::get_mac_address();
+    let mut name = DeviceName::new();
+    write!(name, "power-desk-{:02x}{:02x}{:02x}", d, e, f).ok();
+    name
+}
+
+/// Reads the stored name, falling back to the MAC-derived one. Call once at start-up.
+pub fn load() {
+    let mut record = [0u8; MAX_NAME_LEN];
+    let stored = storage::read(Slot::DeviceName, &mut record)
+        .and_then(|len| core::str::from_utf8(&record[..len]).ok())
+        .filter(|name| is_valid(name))
+        .and_then(|name| DeviceName::try_from(name).ok());
+    let name = stored.unwrap_or_else(default_name);
+
+    log::info!("Device name: {}", name);
+    NAME.lock(|current| *current.borrow_mut() = name);
+}
+
+pub fn get() -> DeviceName {
+    NAME.lock(|name| name.borrow().clone())
+}
+
+/// Stores a name checked with [`is_valid`] and uses it from now on.
+pub fn store(name: &str) -> Result<(), StorageError> {
+    let name = DeviceName::try_from(name).map_err(|_| StorageError::TooLong)?;
+    storage::write(Slot::DeviceName, name.as_bytes())?;
+    NAME.lock(|current| *current.borrow_mut() = name);
+    Ok(())
+}
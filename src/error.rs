@@ -1,8 +1,22 @@
 use embedded_hal_async::i2c;
-
+use sw3526::OperationError;
 
 #[derive(Debug)]
 pub(crate) enum ChargeChannelError<I2cErr: i2c::Error> {
     I2CError(I2cErr),
     SW3526Error(sw3526::OperationError<I2cErr>),
-}
\ No newline at end of file
+    /// A device operation didn't finish within `channel_op_timeout_ms`.
+    Timeout,
+    /// A mux read back a different port than was just selected; the chip index.
+    MuxMismatch(u8),
+}
+
+impl<I2cErr: i2c::Error> ChargeChannelError<I2cErr> {
+    /// The I2C error behind this one, if any.
+    pub fn i2c_error(&self) -> Option<&I2cErr> {
+        match self {
+            Self::I2CError(err) | Self::SW3526Error(OperationError::I2CError(err)) => Some(err),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Optional cooling fan on GPIO6, PWM-driven by the LEDC when built with the `fan` feature.
+//!
+//! The protector sets the duty every cycle from the board temperature: off below
+//! `fan_start_celsius`, then rising linearly from [`MIN_DUTY_PERCENT`] to 100% at
+//! `fan_full_celsius`. While the sensors hold VIN cut for over-temperature it runs at 100% so the
+//! residual heat clears sooner. Once running it only stops [`STOP_HYSTERESIS_CELSIUS`] below the
+//! start temperature, so it doesn't buzz on and off around it.
+
+// With `no_protector` nothing drives the fan.
+#![cfg_attr(no_protector, allow(dead_code))]
+
+use esp_hal::{
+    gpio::GpioPin,
+    ledc::{
+        channel::{Channel, ChannelIFace},
+        LowSpeed,
+    },
+};
+#[cfg(feature = "fan")]
+use esp_hal::{
+    ledc::{
+        channel,
+        timer::{self, Timer, TimerIFace},
+        LSGlobalClkSource, Ledc,
+    },
+    peripherals::LEDC,
+    prelude::*,
+};
+#[cfg(feature = "fan")]
+use static_cell::StaticCell;
+
+pub type FanGpio = GpioPin<6>;
+
+type FanPwm = Channel<'static, LowSpeed, FanGpio>;
+
+/// The PWM frequency of 4-pin fans, above the audible range.
+#[cfg(feature = "fan")]
+const PWM_FREQUENCY_KHZ: u32 = 25;
+
+/// Lowest duty a running fan gets; most fans stall below it.
+const MIN_DUTY_PERCENT: u8 = 20;
+
+/// Degrees below `fan_start_celsius` the temperature has to fall before a running fan stops.
+const STOP_HYSTERESIS_CELSIUS: f32 = 3.0;
+
+#[cfg(feature = "fan")]
+static TIMER: StaticCell<Timer<'static, LowSpeed>> = StaticCell::new();
+
+pub struct Fan {
+    pwm: Option<FanPwm>,
+    duty_percent: u8,
+}
+
+impl Fan {
+    /// No fan fitted: the duty stays at 0.
+    pub const fn none() -> Self {
+        Self {
+            pwm: None,
+            duty_percent: 0,
+        }
+    }
+
+    /// Sets up the PWM output, stopped. Falls back to [`Fan::none`] if the LEDC rejects the
+    /// configuration.
+    #[cfg(feature = "fan")]
+    pub fn new(ledc: LEDC, pin: FanGpio) -> Self {
+        let mut ledc = Ledc::new(ledc);
+        ledc.set_global_slow_clock(LSGlobalClkSource::APBClk);
+
+        let pwm_timer = TIMER.init(ledc.get_timer::<LowSpeed>(timer::Number::Timer0));
+        if let Err(err) = pwm_timer.configure(timer::config::Config {
+            duty: timer::config::Duty::Duty10Bit,
+            clock_source: timer::LSClockSource::APBClk,
+            frequency: PWM_FREQUENCY_KHZ.kHz(),
+        }) {
+            log::error!("Cannot configure the fan timer: {:?}", err);
+            return Self::none();
+        }
+
+        let mut pwm = ledc.get_channel(channel::Number::Channel0, pin);
+        if let Err(err) = pwm.configure(channel::config::Config {
+            timer: &*pwm_timer,
+            duty_pct: 0,
+            pin_config: channel::config::PinConfig::PushPull,
+        }) {
+            log::error!("Cannot configure the fan output: {:?}", err);
+            return Self::none();
+        }
+
+        Self {
+            pwm: Some(pwm),
+            duty_percent: 0,
+        }
+    }
+
+    /// Sets the duty for `temperature` and returns it. `over_temperature` is set while the
+    /// sensors hold VIN cut.
+    pub fn update(
+        &mut self,
+        temperature: f32,
+        start_celsius: f32,
+        full_celsius: f32,
+        over_temperature: bool,
+    ) -> u8 {
+        let Some(pwm) = &self.pwm else {
+            return 0;
+        };
+
+        let stop_celsius = if self.duty_percent > 0 {
+            start_celsius - STOP_HYSTERESIS_CELSIUS
+        } else {
+            start_celsius
+        };
+        let duty_percent = if over_temperature || temperature >= full_celsius {
+            100
+        } else if temperature < stop_celsius {
+            0
+        } else {
+            let fraction =
+                ((temperature - start_celsius) / (full_celsius - start_celsius)).clamp(0.0, 1.0);
+            MIN_DUTY_PERCENT + (fraction * (100 - MIN_DUTY_PERCENT) as f32) as u8
+        };
+
+        if duty_percent != self.duty_percent {
+            match pwm.set_duty(duty_percent) {
+                Ok(()) => self.duty_percent = duty_percent,
+                Err(err) => log::warn!("Cannot set the fan duty: {:?}", err),
+            }
+        }
+        self.duty_percent
+    }
+}
@@ -0,0 +1,154 @@
+//! Home Assistant MQTT discovery: one retained `homeassistant/sensor/<id>/<object>/config`
+//! message per sensor, pointing Home Assistant at the JSON series topics. `<id>` is the default
+//! device name, `power-desk-` plus the last three bytes of the MAC address, so it stays put when
+//! the device is renamed.
+//!
+//! Resent after every connect, so the sensors come back after the broker lost its retained
+//! messages. Availability follows `system/status`.
+
+use core::fmt::Write;
+
+use heapless::String;
+
+use crate::{device_name, system::FIRMWARE_VERSION};
+
+const DISCOVERY_PREFIX: &str = "homeassistant/sensor/";
+
+pub type DiscoveryTopic = String<64>;
+pub type DiscoveryConfig = String<512>;
+
+struct Sensor {
+    object: &'static str,
+    name: &'static str,
+    template: &'static str,
+    unit: Option<&'static str>,
+    device_class: &'static str,
+    /// The states of an `enum` sensor, as a JSON array.
+    options: Option<&'static str>,
+}
+
+const CHANNEL_SENSORS: [Sensor; 3] = [
+    Sensor {
+        object: "voltage",
+        name: "Voltage",
+        template: "{{ (value_json.millivolts / 1000) | round(3) }}",
+        unit: Some("V"),
+        device_class: "voltage",
+        options: None,
+    },
+    Sensor {
+        object: "current",
+        name: "Current",
+        template: "{{ value_json.amps | round(3) }}",
+        unit: Some("A"),
+        device_class: "current",
+        options: None,
+    },
+    Sensor {
+        object: "power",
+        name: "Power",
+        template: "{{ value_json.watts | round(2) }}",
+        unit: Some("W"),
+        device_class: "power",
+        options: None,
+    },
+];
+
+#[cfg(not(no_protector))]
+const PROTECTOR_SENSORS: [Sensor; 3] = [
+    Sensor {
+        object: "temperature_0",
+        name: "Temperature 0",
+        template: "{{ value_json.temperature_0 | round(1) }}",
+        unit: Some("°C"),
+        device_class: "temperature",
+        options: None,
+    },
+    Sensor {
+        object: "temperature_1",
+        name: "Temperature 1",
+        template: "{{ value_json.temperature_1 | round(1) }}",
+        unit: Some("°C"),
+        device_class: "temperature",
+        options: None,
+    },
+    Sensor {
+        object: "vin_status",
+        name: "VIN status",
+        template: "{{ ['normal', 'shutdown', 'protection'][value_json.vin_status] }}",
+        unit: None,
+        device_class: "enum",
+        options: Some(r#"["normal","shutdown","protection"]"#),
+    },
+];
+
+/// Discovery messages for every sensor; `prefix` is the device's topic prefix and
+/// `availability_topic` the full `system/status` topic.
+pub fn configs<'a>(
+    prefix: &'a str,
+    availability_topic: &'a str,
+) -> impl Iterator<Item = (DiscoveryTopic, DiscoveryConfig)> + 'a {
+    let id = device_name::default_name();
+    let name = device_name::get();
+
+    let channels = (0..4u8).flat_map(|index| {
+        CHANNEL_SENSORS
+            .iter()
+            .map(move |sensor| (Some(index), sensor))
+    });
+    #[cfg(not(no_protector))]
+    let sensors = channels.chain(PROTECTOR_SENSORS.iter().map(|sensor| (None, sensor)));
+    #[cfg(no_protector)]
+    let sensors = channels;
+
+    sensors.map(move |(channel, sensor)| {
+        let mut object = String::<16>::new();
+        match channel {
+            Some(index) => write!(object, "ch{}_{}", index, sensor.object).ok(),
+            None => write!(object, "{}", sensor.object).ok(),
+        };
+
+        let mut topic = DiscoveryTopic::new();
+        write!(topic, "{}{}/{}/config", DISCOVERY_PREFIX, id, object).ok();
+
+        let mut json = DiscoveryConfig::new();
+        match channel {
+            Some(index) => write!(
+                json,
+                "{{\"name\":\"CH{} {}\",\"stat_t\":\"{}ch{}/series/json\"",
+                index, sensor.name, prefix, index
+            ),
+            None => write!(
+                json,
+                "{{\"name\":\"{}\",\"stat_t\":\"{}protector/json\"",
+                sensor.name, prefix
+            ),
+        }
+        .ok();
+        write!(
+            json,
+            ",\"uniq_id\":\"{}_{}\",\"val_tpl\":\"{}\",\"dev_cla\":\"{}\"",
+            id, object, sensor.template, sensor.device_class
+        )
+        .ok();
+        if let Some(options) = sensor.options {
+            write!(json, ",\"ops\":{}", options).ok();
+        }
+        if let Some(unit) = sensor.unit {
+            write!(
+                json,
+                ",\"unit_of_meas\":\"{}\",\"stat_cla\":\"measurement\"",
+                unit
+            )
+            .ok();
+        }
+        write!(
+            json,
+            ",\"avty_t\":\"{}\",\"dev\":{{\"ids\":[\"{}\"],\"name\":\"{}\",\"mdl\":\"Power Desk\",\"sw\":\"{}\"}}}}",
+            availability_topic, id, name, FIRMWARE_VERSION
+        )
+        .ok();
+
+        (topic, json)
+    })
+}
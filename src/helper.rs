@@ -0,0 +1,265 @@
+use embassy_time::{Duration, Instant};
+
+/// Moving average over the last `window` samples, with `window` adjustable at runtime up to `N`.
+pub struct MovingAverage<const N: usize> {
+    samples: [f64; N],
+    window: usize,
+    next: usize,
+    len: usize,
+}
+
+impl<const N: usize> MovingAverage<N> {
+    pub const fn new(window: usize) -> Self {
+        let window = if window == 0 {
+            1
+        } else if window > N {
+            N
+        } else {
+            window
+        };
+
+        Self {
+            samples: [0.0; N],
+            window,
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.window
+    }
+
+    /// Changes the window length (clamped to `1..=N`) and drops the collected samples.
+    pub fn set_window(&mut self, window: usize) {
+        self.window = window.clamp(1, N);
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.next = 0;
+        self.len = 0;
+    }
+
+    /// Adds a sample and returns the average of the samples currently in the window.
+    pub fn push(&mut self, value: f64) -> f64 {
+        self.samples[self.next] = value;
+        self.next = (self.next + 1) % self.window;
+        self.len = (self.len + 1).min(self.window);
+
+        self.samples[..self.len].iter().sum::<f64>() / self.len as f64
+    }
+}
+
+pub fn abs(value: f64) -> f64 {
+    if value < 0.0 {
+        -value
+    } else {
+        value
+    }
+}
+
+/// Power differences below this are never flagged as a mismatch between the INA226 power
+/// register and V×I, in watts.
+pub const POWER_MISMATCH_FLOOR_WATTS: f64 = 0.5;
+
+/// Returns `true` when the magnitudes of `a` and `b` differ by more than `percent` of the larger
+/// one. Differences up to `floor` are ignored so readings near zero don't flap.
+pub fn diverges(a: f64, b: f64, percent: u8, floor: f64) -> bool {
+    let (a, b) = (abs(a), abs(b));
+    let diff = abs(a - b);
+
+    diff > floor && diff * 100.0 > a.max(b) * percent as f64
+}
+
+/// Threshold comparison with hysteresis: `value` has to reach `threshold` to switch on, and
+/// fall below `release_percent` of it to switch off again. `on` is the previous result.
+pub fn exceeds_with_hysteresis(on: bool, value: f64, threshold: f64, release_percent: u8) -> bool {
+    if on {
+        value >= threshold * release_percent as f64 / 100.0
+    } else {
+        value >= threshold
+    }
+}
+
+/// How often [`MissedReads`] reports.
+const MISSED_READ_REPORT_PERIOD: Duration = Duration::from_secs(60);
+
+/// Counts INA226 current and power reads that returned no value, so the rate of held-over
+/// readings can be logged instead of going unnoticed.
+pub struct MissedReads {
+    samples: u32,
+    amps: u32,
+    watts: u32,
+    since: Instant,
+}
+
+impl MissedReads {
+    pub const fn new() -> Self {
+        Self {
+            samples: 0,
+            amps: 0,
+            watts: 0,
+            since: Instant::from_ticks(0),
+        }
+    }
+
+    /// Records one sample. Once per report period, returns `(samples, missed current reads,
+    /// missed power reads)` if any read was missed, and starts counting afresh.
+    pub fn record(&mut self, amps_missed: bool, watts_missed: bool) -> Option<(u32, u32, u32)> {
+        self.samples += 1;
+        self.amps += amps_missed as u32;
+        self.watts += watts_missed as u32;
+
+        if self.since.elapsed() < MISSED_READ_REPORT_PERIOD {
+            return None;
+        }
+
+        let report = (self.samples, self.amps, self.watts);
+        *self = Self {
+            since: Instant::now(),
+            ..Self::new()
+        };
+        (report.1 > 0 || report.2 > 0).then_some(report)
+    }
+}
+
+/// CRC-16/CCITT-FALSE.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Minimum number of readings in one noise estimate.
+const NOISE_WINDOW: u32 = 32;
+/// Minimum time between two averaging changes, so every step is judged on settled readings.
+const NOISE_ADJUST_INTERVAL: Duration = Duration::from_secs(30);
+/// Differences this many times the target are load changes, not noise.
+const NOISE_STEP_REJECT: f64 = 20.0;
+
+/// Picks the INA226 averaging step that keeps current noise under a target.
+///
+/// Noise is estimated from the differences between consecutive readings, which cancels out
+/// slow load changes. The step goes up while the noise is above the target and down only once
+/// it is below a quarter of it, so a step down (at most 4× fewer samples, i.e. twice the noise)
+/// can't push it back over and the tuning settles.
+pub struct NoiseTuner {
+    last: Option<f64>,
+    sum_squares: f64,
+    count: u32,
+    since: Instant,
+}
+
+impl NoiseTuner {
+    pub fn new() -> Self {
+        Self {
+            last: None,
+            sum_squares: 0.0,
+            count: 0,
+            since: Instant::now(),
+        }
+    }
+
+    /// Starts over, e.g. after the averaging changed.
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Adds a reading and returns `(step, noise)` once a window is complete and `step` should
+    /// change, with the estimated noise in the reading's unit.
+    pub fn push(&mut self, value: f64, step: u8, max_step: u8, target: f64) -> Option<(u8, f64)> {
+        let last = self.last.replace(value)?;
+        let diff = value - last;
+        if abs(diff) > target * NOISE_STEP_REJECT {
+            return None;
+        }
+        self.sum_squares += diff * diff;
+        self.count += 1;
+
+        if self.count < NOISE_WINDOW || self.since.elapsed() < NOISE_ADJUST_INTERVAL {
+            return None;
+        }
+
+        // The difference of two readings carries the noise of both.
+        let noise = sqrt(self.sum_squares / self.count as f64 / 2.0);
+        self.sum_squares = 0.0;
+        self.count = 0;
+        self.since = Instant::now();
+
+        if noise > target && step < max_step {
+            Some((step + 1, noise))
+        } else if noise < target / 4.0 && step > 0 {
+            Some((step - 1, noise))
+        } else {
+            None
+        }
+    }
+}
+
+/// I²t protection in the manner of a slow-blow fuse. Current above the rated one heats it by
+/// `(I² - rated²)·dt`, current below cools it by the same law, and it blows once the heat
+/// exceeds the limit. A blown fuse stays blown until reset.
+pub struct SoftFuse {
+    heat: f64,
+    blown: bool,
+}
+
+impl SoftFuse {
+    pub const fn new() -> Self {
+        Self {
+            heat: 0.0,
+            blown: false,
+        }
+    }
+
+    /// Accumulated over-energy, in A²s.
+    pub fn heat(&self) -> f64 {
+        self.heat
+    }
+
+    pub fn blown(&self) -> bool {
+        self.blown
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Adds `secs` at `amps`. Returns `true` if this blew the fuse.
+    pub fn record(&mut self, amps: f64, secs: f64, rated_amps: f64, limit: f64) -> bool {
+        if self.blown {
+            return false;
+        }
+
+        self.heat = (self.heat + (amps * amps - rated_amps * rated_amps) * secs).max(0.0);
+        self.blown = self.heat > limit;
+        self.blown
+    }
+}
+
+/// Square root by Newton's method; `core` has no `f64::sqrt`.
+fn sqrt(value: f64) -> f64 {
+    if value <= 0.0 {
+        return 0.0;
+    }
+
+    let mut root = if value > 1.0 { value } else { 1.0 };
+    for _ in 0..64 {
+        let next = (root + value / root) / 2.0;
+        if next >= root {
+            break;
+        }
+        root = next;
+    }
+    root
+}
@@ -0,0 +1,200 @@
+//! The most recent samples of every series, kept in RAM so a consumer that wasn't subscribed
+//! during an event can still fetch its lead-up with the `history` command.
+//!
+//! A dump goes to `history/protector` or `history/ch<n>` as a run of messages
+//! `{"part":0,"parts":8,"now":123456,"samples":[[..],..]}`, oldest samples first. `now` is the
+//! uptime in ms when the part was sent, to relate the sample times to. Each sample is an array:
+//!
+//! - protector: `[uptime_ms, millivolts, amps, watts, temperature_0, temperature_1, vin_status]`
+//! - charge channel: `[uptime_ms, millivolts, amps, watts, limit_watts]`
+
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Write,
+};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::Instant;
+use heapless::{HistoryBuffer, String};
+
+use crate::{
+    bus::{send_status, ChargeChannelSeriesItem, ProtectorSeriesItem, MAX_SYSTEM_TOPIC_LEN},
+    settings,
+};
+
+/// Samples kept per series, the upper bound of `history_len`. About 2.5kB for the protector and
+/// 1.8kB per channel.
+pub const HISTORY_CAPACITY: usize = 60;
+
+/// Samples per message, so the worst case stays within a system message.
+const PROTECTOR_SAMPLES_PER_PART: usize = 3;
+const CHANNEL_SAMPLES_PER_PART: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+struct ProtectorSample {
+    at: Instant,
+    millivolts: f32,
+    amps: f32,
+    watts: f32,
+    temperature_0: f32,
+    temperature_1: f32,
+    vin_status: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChannelSample {
+    at: Instant,
+    millivolts: f32,
+    amps: f32,
+    watts: f32,
+    limit_watts: u8,
+}
+
+trait Sample {
+    fn write_json(&self, json: &mut String<256>);
+}
+
+impl Sample for ProtectorSample {
+    fn write_json(&self, json: &mut String<256>) {
+        write!(
+            json,
+            "[{},{:.0},{:.3},{:.2},{:.1},{:.1},{}]",
+            self.at.as_millis(),
+            self.millivolts,
+            self.amps,
+            self.watts,
+            self.temperature_0,
+            self.temperature_1,
+            self.vin_status
+        )
+        .ok();
+    }
+}
+
+impl Sample for ChannelSample {
+    fn write_json(&self, json: &mut String<256>) {
+        write!(
+            json,
+            "[{},{:.0},{:.3},{:.2},{}]",
+            self.at.as_millis(),
+            self.millivolts,
+            self.amps,
+            self.watts,
+            self.limit_watts
+        )
+        .ok();
+    }
+}
+
+type History<T> = HistoryBuffer<T, HISTORY_CAPACITY>;
+
+const NO_CHANNEL_HISTORY: History<ChannelSample> = HistoryBuffer::new();
+
+static PROTECTOR: Mutex<CriticalSectionRawMutex, RefCell<History<ProtectorSample>>> =
+    Mutex::new(RefCell::new(HistoryBuffer::new()));
+
+static CHANNELS: Mutex<CriticalSectionRawMutex, RefCell<[History<ChannelSample>; 4]>> =
+    Mutex::new(RefCell::new([NO_CHANNEL_HISTORY; 4]));
+
+pub fn record_protector(value: &ProtectorSeriesItem) {
+    let sample = ProtectorSample {
+        at: Instant::now(),
+        millivolts: value.millivolts as f32,
+        amps: value.amps as f32,
+        watts: value.watts as f32,
+        temperature_0: value.temperature_0,
+        temperature_1: value.temperature_1,
+        vin_status: value.vin_status.into(),
+    };
+    PROTECTOR.lock(|history| history.borrow_mut().write(sample));
+}
+
+pub fn record_charge_channel(index: usize, value: &ChargeChannelSeriesItem) {
+    let sample = ChannelSample {
+        at: Instant::now(),
+        millivolts: value.millivolts as f32,
+        amps: value.amps as f32,
+        watts: value.watts as f32,
+        limit_watts: value.limit_watts,
+    };
+    CHANNELS.lock(|channels| channels.borrow_mut()[index].write(sample));
+}
+
+/// Series a dump is taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Protector,
+    Channel(u8),
+}
+
+static REQUEST: Signal<CriticalSectionRawMutex, Source> = Signal::new();
+static RUNNING: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Starts a dump of `source`. Returns `false` if one is already in progress.
+pub fn request(source: Source) -> bool {
+    let started = RUNNING.lock(|running| !running.replace(true));
+    if started {
+        REQUEST.signal(source);
+    }
+    started
+}
+
+#[embassy_executor::task]
+pub async fn task() {
+    loop {
+        let source = REQUEST.wait().await;
+        let len = settings::get().history_len as usize;
+
+        let mut topic = String::<MAX_SYSTEM_TOPIC_LEN>::new();
+        match source {
+            Source::Protector => {
+                topic.push_str("history/protector").ok();
+                let history = PROTECTOR.lock(|history| history.borrow().clone());
+                dump(&topic, &history, len, PROTECTOR_SAMPLES_PER_PART).await;
+            }
+            Source::Channel(index) => {
+                write!(topic, "history/ch{}", index).ok();
+                let history = CHANNELS.lock(|channels| channels.borrow()[index as usize].clone());
+                dump(&topic, &history, len, CHANNEL_SAMPLES_PER_PART).await;
+            }
+        }
+
+        RUNNING.lock(|running| running.set(false));
+    }
+}
+
+/// Sends the last `len` samples of `history`, waiting for room in the status queue rather than
+/// dropping parts.
+async fn dump<T: Sample>(topic: &str, history: &History<T>, len: usize, per_part: usize) {
+    let skip = history.len().saturating_sub(len);
+    let count = history.len() - skip;
+    let parts = count.div_ceil(per_part).max(1);
+    log::info!("Dumping {} samples of {} in {} parts", count, topic, parts);
+
+    let mut samples = history.oldest_ordered().skip(skip);
+    for part in 0..parts {
+        let mut json = String::<256>::new();
+        write!(
+            json,
+            "{{\"part\":{},\"parts\":{},\"now\":{},\"samples\":[",
+            part,
+            parts,
+            Instant::now().as_millis()
+        )
+        .ok();
+        for index in 0..per_part {
+            let Some(sample) = samples.next() else {
+                break;
+            };
+            if index > 0 {
+                json.push(',').ok();
+            }
+            sample.write_json(&mut json);
+        }
+        json.push_str("]}").ok();
+        send_status(topic, json.as_bytes()).await;
+    }
+}
@@ -0,0 +1,326 @@
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Write,
+};
+
+use embassy_futures::yield_now;
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+};
+use embassy_time::{Duration, Instant};
+use embedded_hal_async::i2c::{self, ErrorKind, ErrorType, I2c, Operation, SevenBitAddress};
+use heapless::String;
+
+use crate::{bus::publish_system_message, i2c_mux};
+
+/// How often the bus-wait statistics are published to `system/i2c-wait`.
+const WAIT_REPORT_PERIOD: Duration = Duration::from_secs(60);
+/// Minimum time between two `system/i2c-errors` reports.
+const ERROR_REPORT_PERIOD: Duration = Duration::from_secs(10);
+
+/// `High` transactions a waiting `Normal` one lets go first. After that it is due, and `High`
+/// ones wait for it in turn, so a steady stream of protection reads can't starve telemetry.
+const MAX_HIGH_AHEAD: u32 = 4;
+
+/// Who gets the bus first when several devices are waiting for it.
+#[derive(Debug, Clone, Copy)]
+pub enum Priority {
+    /// Protection reads and output force-offs. They go ahead of every `Normal` transaction that
+    /// hasn't started yet.
+    High = 0,
+    /// Telemetry and housekeeping.
+    Normal = 1,
+}
+
+/// `High` transactions currently waiting for the bus.
+static HIGH_WAITING: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<u8>> =
+    blocking_mutex::Mutex::new(Cell::new(0));
+
+fn high_waiting() -> bool {
+    HIGH_WAITING.lock(|waiting| waiting.get() > 0)
+}
+
+/// `High` transactions that got the bus since boot, wrapping.
+static HIGH_STARTED: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<u32>> =
+    blocking_mutex::Mutex::new(Cell::new(0));
+
+fn high_started() -> u32 {
+    HIGH_STARTED.lock(|started| started.get())
+}
+
+/// `Normal` transactions that have let [`MAX_HIGH_AHEAD`] `High` ones go first.
+static NORMAL_DUE: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<u8>> =
+    blocking_mutex::Mutex::new(Cell::new(0));
+
+fn normal_due() -> bool {
+    NORMAL_DUE.lock(|due| due.get() > 0)
+}
+
+/// Counts a `Normal` transaction as due until dropped, like [`HighWaiting`].
+struct NormalDue;
+
+impl NormalDue {
+    fn new() -> Self {
+        NORMAL_DUE.lock(|due| due.set(due.get() + 1));
+        Self
+    }
+}
+
+impl Drop for NormalDue {
+    fn drop(&mut self) {
+        NORMAL_DUE.lock(|due| due.set(due.get() - 1));
+    }
+}
+
+/// Counts a `High` transaction as waiting until dropped, so a wait cancelled by a timeout is
+/// released too.
+struct HighWaiting;
+
+impl HighWaiting {
+    fn new() -> Self {
+        HIGH_WAITING.lock(|waiting| waiting.set(waiting.get() + 1));
+        Self
+    }
+}
+
+impl Drop for HighWaiting {
+    fn drop(&mut self) {
+        HIGH_WAITING.lock(|waiting| waiting.set(waiting.get() - 1));
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct WaitStats {
+    count: u32,
+    total: Duration,
+    max: Duration,
+}
+
+impl WaitStats {
+    const fn new() -> Self {
+        Self {
+            count: 0,
+            total: Duration::from_ticks(0),
+            max: Duration::from_ticks(0),
+        }
+    }
+
+    fn record(&mut self, wait: Duration) {
+        self.count += 1;
+        self.total += wait;
+        self.max = self.max.max(wait);
+    }
+
+    fn write_json(&self, json: &mut String<128>, name: &str) {
+        let avg_us = match self.count {
+            0 => 0,
+            count => self.total.as_micros() / count as u64,
+        };
+        write!(
+            json,
+            "\"{}\":{{\"count\":{},\"avg_us\":{},\"max_us\":{}}}",
+            name,
+            self.count,
+            avg_us,
+            self.max.as_micros()
+        )
+        .ok();
+    }
+}
+
+struct WaitReport {
+    stats: [WaitStats; 2],
+    since: Instant,
+}
+
+static WAITS: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<WaitReport>> =
+    blocking_mutex::Mutex::new(RefCell::new(WaitReport {
+        stats: [WaitStats::new(); 2],
+        since: Instant::from_ticks(0),
+    }));
+
+/// Records how long a transaction waited for the bus and publishes
+/// `{"protector":{..},"telemetry":{..}}` once per report period.
+fn record_wait(priority: Priority, wait: Duration) {
+    let due = WAITS.lock(|report| {
+        let mut report = report.borrow_mut();
+        report.stats[priority as usize].record(wait);
+        if report.since.elapsed() < WAIT_REPORT_PERIOD {
+            return None;
+        }
+
+        let stats = report.stats;
+        report.stats = [WaitStats::new(); 2];
+        report.since = Instant::now();
+        Some(stats)
+    });
+
+    if let Some([high, normal]) = due {
+        let mut json = String::<128>::new();
+        json.push('{').ok();
+        high.write_json(&mut json, "protector");
+        json.push(',').ok();
+        normal.write_json(&mut json, "telemetry");
+        json.push('}').ok();
+        publish_system_message("i2c-wait", json.as_bytes());
+    }
+}
+
+/// Kinds of I2C failure, which call for different responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Arbitration lost, a bus error or an overrun: the bus itself was disturbed, whichever
+    /// device was addressed. The controller resets itself after these; re-initializing the
+    /// device would not help.
+    Bus = 0,
+    /// The addressed device didn't acknowledge: it is missing, reset or stuck.
+    Device = 1,
+    /// Anything else the controller reports, e.g. a timeout.
+    Other = 2,
+}
+
+impl ErrorClass {
+    pub fn of(err: &impl i2c::Error) -> Self {
+        match err.kind() {
+            ErrorKind::Bus | ErrorKind::ArbitrationLoss | ErrorKind::Overrun => ErrorClass::Bus,
+            ErrorKind::NoAcknowledge(_) => ErrorClass::Device,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+struct ErrorReport {
+    /// Failed transactions since boot, by [`ErrorClass`].
+    counts: [u32; 3],
+    published: [u32; 3],
+    published_at: Instant,
+    /// Mux ports selected when the last error happened, to tell which device it came from.
+    mux: [Option<u8>; 2],
+}
+
+static ERRORS: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<ErrorReport>> =
+    blocking_mutex::Mutex::new(RefCell::new(ErrorReport {
+        counts: [0; 3],
+        published: [0; 3],
+        published_at: Instant::from_ticks(0),
+        mux: [None; 2],
+    }));
+
+/// Counts a failed transaction and publishes the totals since boot as
+/// `{"bus":n,"device":n,"other":n,"mux":[p,p]}`, at most once per report period, with the mux
+/// ports (`null` for none) selected at the last error. Counts that came in
+/// since the last report go out with the next transaction after the period, failed or not.
+fn record_error(class: Option<ErrorClass>) {
+    let due = ERRORS.lock(|report| {
+        let mut report = report.borrow_mut();
+        if let Some(class) = class {
+            report.counts[class as usize] += 1;
+            report.mux = i2c_mux::selected();
+        }
+        if report.counts == report.published || report.published_at.elapsed() < ERROR_REPORT_PERIOD
+        {
+            return None;
+        }
+
+        report.published = report.counts;
+        report.published_at = Instant::now();
+        Some((report.counts, report.mux))
+    });
+
+    if let Some(([bus, device, other], mux)) = due {
+        log::warn!(
+            "I2C errors since boot: {} bus, {} device, {} other, last with mux ports {:?}",
+            bus,
+            device,
+            other,
+            mux
+        );
+        let mut json = String::<96>::new();
+        write!(
+            json,
+            "{{\"bus\":{},\"device\":{},\"other\":{},\"mux\":[",
+            bus, device, other
+        )
+        .ok();
+        for (chip, port) in mux.iter().enumerate() {
+            let separator = if chip == 0 { "" } else { "," };
+            match port {
+                Some(port) => write!(json, "{}{}", separator, port).ok(),
+                None => write!(json, "{}null", separator).ok(),
+            };
+        }
+        json.push_str("]}").ok();
+        publish_system_message("i2c-errors", json.as_bytes());
+    }
+}
+
+/// Device on the shared I2C bus, replacing `I2cDevice` so protection reads aren't stuck behind
+/// a queue of telemetry reads.
+///
+/// A transaction in progress is never interrupted, but a `Normal` device steps aside while any
+/// `High` one is waiting, so a protection read waits for at most one transaction. Unless a
+/// `Normal` transaction has already let [`MAX_HIGH_AHEAD`] of them go first: then it is next.
+pub struct SharedI2c<'a, BUS> {
+    bus: &'a Mutex<CriticalSectionRawMutex, BUS>,
+    priority: Priority,
+}
+
+impl<'a, BUS> SharedI2c<'a, BUS> {
+    pub fn new(bus: &'a Mutex<CriticalSectionRawMutex, BUS>, priority: Priority) -> Self {
+        Self { bus, priority }
+    }
+}
+
+impl<BUS: ErrorType> ErrorType for SharedI2c<'_, BUS> {
+    type Error = BUS::Error;
+}
+
+impl<BUS: I2c> I2c for SharedI2c<'_, BUS> {
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let requested_at = Instant::now();
+        // Held until the transaction is done, so `High` ones keep waiting for it.
+        let mut due = None;
+
+        let mut bus = match self.priority {
+            Priority::High => {
+                let _waiting = HighWaiting::new();
+                loop {
+                    while normal_due() {
+                        yield_now().await;
+                    }
+                    let bus = self.bus.lock().await;
+                    if !normal_due() {
+                        HIGH_STARTED.lock(|started| started.set(started.get().wrapping_add(1)));
+                        break bus;
+                    }
+                }
+            }
+            Priority::Normal => {
+                let first_high = high_started();
+                loop {
+                    if due.is_none() && high_started().wrapping_sub(first_high) >= MAX_HIGH_AHEAD {
+                        due = Some(NormalDue::new());
+                    }
+                    if due.is_none() && high_waiting() {
+                        yield_now().await;
+                        continue;
+                    }
+                    let bus = self.bus.lock().await;
+                    if due.is_some() || !high_waiting() {
+                        break bus;
+                    }
+                }
+            }
+        };
+
+        record_wait(self.priority, requested_at.elapsed());
+        let result = bus.transaction(address, operations).await;
+        record_error(result.as_ref().err().map(ErrorClass::of));
+        result
+    }
+}
@@ -1,6 +1,72 @@
+use core::{cell::Cell, fmt::Write};
+
+use embassy_futures::yield_now;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    mutex::{self, MutexGuard},
+};
 use embedded_hal_async::i2c;
+use heapless::String;
 use pca9546a::{Channel, PCA9546A};
 
+use crate::{bus::publish_system_message, error::ChargeChannelError, i2c_bus::Priority};
+
+/// Held from selecting ports until done with the selected device, so two users of the muxes
+/// can't switch ports under each other.
+static PORTS: mutex::Mutex<CriticalSectionRawMutex, ()> = mutex::Mutex::new(());
+
+/// A `High` user is waiting for [`PORTS`]; `Normal` ones let it go first.
+static HIGH_WAITING: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+fn high_waiting() -> bool {
+    HIGH_WAITING.lock(|waiting| waiting.get())
+}
+
+/// Sets [`HIGH_WAITING`] until dropped, so a cancelled wait clears it too.
+struct HighWaiting;
+
+impl HighWaiting {
+    fn new() -> Self {
+        HIGH_WAITING.lock(|waiting| waiting.set(true));
+        Self
+    }
+}
+
+impl Drop for HighWaiting {
+    fn drop(&mut self) {
+        HIGH_WAITING.lock(|waiting| waiting.set(false));
+    }
+}
+
+/// The ports selected by [`I2cMux::set_channel`]; they stay selected while this is held.
+pub type PortsGuard = MutexGuard<'static, CriticalSectionRawMutex, ()>;
+
+/// Port each chip was last confirmed to have selected; `None` when none is selected, the chip is
+/// offline, or the last selection didn't take.
+static SELECTED: Mutex<CriticalSectionRawMutex, Cell<[Option<u8>; 2]>> =
+    Mutex::new(Cell::new([None; 2]));
+
+pub fn selected() -> [Option<u8>; 2] {
+    SELECTED.lock(|selected| selected.get())
+}
+
+fn record_selected(chip: usize, port: Option<u8>) {
+    SELECTED.lock(|selected| {
+        let mut ports = selected.get();
+        ports[chip] = port;
+        selected.set(ports);
+    });
+}
+
+/// Only ports 0 and 1 of each chip are wired.
+fn port_channel(port: Option<u8>) -> Channel {
+    match port {
+        Some(0) => Channel::Ch0,
+        Some(1) => Channel::Ch1,
+        _ => Channel::None,
+    }
+}
+
 pub enum ChargeChannelIndex {
     Ch0 = 0,
     Ch1 = 1,
@@ -13,6 +79,7 @@ pub struct I2cMux<I2C> {
     mux_1: PCA9546A<I2C>,
     mux_0_online: bool,
     mux_1_online: bool,
+    priority: Priority,
 }
 
 impl<I2C, E> I2cMux<I2C>
@@ -20,12 +87,13 @@ where
     I2C: i2c::I2c<Error = E> + 'static,
     E: i2c::Error + 'static,
 {
-    pub fn new(mux_0: PCA9546A<I2C>, mux_1: PCA9546A<I2C>) -> Self {
+    pub fn new(mux_0: PCA9546A<I2C>, mux_1: PCA9546A<I2C>, priority: Priority) -> Self {
         Self {
             mux_0,
             mux_1,
             mux_0_online: false,
             mux_1_online: false,
+            priority,
         }
     }
 
@@ -55,43 +123,77 @@ where
         }
     }
 
-    async fn set_channels_if_online(
+    /// Selects the ports and reads every selection back, so a chip that silently fails to
+    /// switch is caught before a read goes to the wrong device. Costs one extra read per chip.
+    async fn set_ports_if_online(
         &mut self,
-        mux_0_channel: Channel,
-        mux_1_channel: Channel,
-    ) -> Result<(), E> {
-        if self.mux_0_online {
-            self.mux_0.set_channel(mux_0_channel).await?;
-        }
+        ports: [Option<u8>; 2],
+    ) -> Result<(), ChargeChannelError<E>> {
+        let online = self.online();
+        let muxes = [&mut self.mux_0, &mut self.mux_1];
+        for (chip, (mux, port)) in muxes.into_iter().zip(ports).enumerate() {
+            record_selected(chip, None);
+            if !online[chip] {
+                continue;
+            }
 
-        if self.mux_1_online {
-            self.mux_1.set_channel(mux_1_channel).await?;
+            mux.set_channel(port_channel(port))
+                .await
+                .map_err(ChargeChannelError::I2CError)?;
+            let selected = mux
+                .get_channel()
+                .await
+                .map_err(ChargeChannelError::I2CError)?;
+            if selected != port_channel(port) {
+                log::error!("mux#{} did not switch to port {:?}", chip, port);
+                publish_mux_mismatch(chip, port);
+                return Err(ChargeChannelError::MuxMismatch(chip as u8));
+            }
+            record_selected(chip, port);
         }
 
         Ok(())
     }
 
-    pub async fn set_channel(&mut self, channel: ChargeChannelIndex) -> Result<(), E> {
-        match channel {
-            ChargeChannelIndex::Ch0 => {
-                self.set_channels_if_online(Channel::Ch0, Channel::None)
-                    .await?;
-            }
-            ChargeChannelIndex::Ch1 => {
-                self.set_channels_if_online(Channel::None, Channel::Ch1)
-                    .await?;
-            }
-            ChargeChannelIndex::Ch2 => {
-                self.set_channels_if_online(Channel::Ch1, Channel::None)
-                    .await?;
-            }
-            ChargeChannelIndex::Ch3 => {
-                self.set_channels_if_online(Channel::None, Channel::Ch0)
-                    .await?;
+    /// Selects the channel's ports, which stay selected until the returned guard is dropped.
+    pub async fn set_channel(
+        &mut self,
+        channel: ChargeChannelIndex,
+    ) -> Result<PortsGuard, ChargeChannelError<E>> {
+        // Port to select on each chip, `None` to deselect all of its ports.
+        let ports = match channel {
+            ChargeChannelIndex::Ch0 => [Some(0), None],
+            ChargeChannelIndex::Ch1 => [None, Some(1)],
+            ChargeChannelIndex::Ch2 => [Some(1), None],
+            ChargeChannelIndex::Ch3 => [None, Some(0)],
+        };
+
+        let guard = self.lock_ports().await;
+        self.set_ports_if_online(ports).await?;
+        Ok(guard)
+    }
+
+    async fn lock_ports(&self) -> PortsGuard {
+        match self.priority {
+            // Only the force-off refresher, so there is never more than one waiting.
+            Priority::High => {
+                let _waiting = HighWaiting::new();
+                PORTS.lock().await
             }
+            Priority::Normal => loop {
+                while high_waiting() {
+                    yield_now().await;
+                }
+                let guard = PORTS.lock().await;
+                if !high_waiting() {
+                    break guard;
+                }
+            },
         }
+    }
 
-        Ok(())
+    pub fn online(&self) -> [bool; 2] {
+        [self.mux_0_online, self.mux_1_online]
     }
 
     pub fn get_channel_available(&mut self, channel: ChargeChannelIndex) -> bool {
@@ -103,3 +205,13 @@ where
         }
     }
 }
+
+/// Publishes `{"chip":n,"port":p}` to `system/mux-mismatch`, with `port` `null` for a deselect.
+fn publish_mux_mismatch(chip: usize, port: Option<u8>) {
+    let mut json = String::<32>::new();
+    match port {
+        Some(port) => write!(json, "{{\"chip\":{},\"port\":{}}}", chip, port).ok(),
+        None => write!(json, "{{\"chip\":{},\"port\":null}}", chip).ok(),
+    };
+    publish_system_message("mux-mismatch", json.as_bytes());
+}
@@ -0,0 +1,97 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use embedded_hal_async::i2c::{ErrorType, I2c, Operation, SevenBitAddress};
+
+/// Tracing switches itself off after this long even if transactions remain, so a forgotten
+/// trace can't keep flooding the log.
+const MAX_TRACE_DURATION: Duration = Duration::from_secs(60);
+
+struct Trace {
+    address: SevenBitAddress,
+    remaining: u8,
+    until: Instant,
+}
+
+static TRACE: Mutex<CriticalSectionRawMutex, RefCell<Option<Trace>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Logs the next `count` transactions with the device at `address`.
+pub fn start(address: SevenBitAddress, count: u8) {
+    log::info!("Tracing {} I2C transactions with 0x{:02x}", count, address);
+    TRACE.lock(|trace| {
+        *trace.borrow_mut() = Some(Trace {
+            address,
+            remaining: count,
+            until: Instant::now() + MAX_TRACE_DURATION,
+        })
+    });
+}
+
+pub fn stop() {
+    TRACE.lock(|trace| *trace.borrow_mut() = None);
+}
+
+/// Whether this transaction with `address` should be logged; uses up one from the budget.
+fn take(address: SevenBitAddress) -> bool {
+    TRACE.lock(|trace| {
+        let mut trace = trace.borrow_mut();
+        let Some(state) = trace.as_mut() else {
+            return false;
+        };
+
+        if Instant::now() > state.until {
+            log::info!("I2C trace of 0x{:02x} expired", state.address);
+            *trace = None;
+            return false;
+        }
+        if state.address != address {
+            return false;
+        }
+
+        state.remaining -= 1;
+        if state.remaining == 0 {
+            *trace = None;
+        }
+        true
+    })
+}
+
+/// Pass-through I2C device that logs its transactions while a trace for the target address is
+/// active. Costs one lock per transaction otherwise.
+pub struct TracedI2c<I2C> {
+    inner: I2C,
+}
+
+impl<I2C> TracedI2c<I2C> {
+    pub fn new(inner: I2C) -> Self {
+        Self { inner }
+    }
+}
+
+impl<I2C: ErrorType> ErrorType for TracedI2c<I2C> {
+    type Error = I2C::Error;
+}
+
+impl<I2C: I2c> I2c for TracedI2c<I2C> {
+    async fn transaction(
+        &mut self,
+        address: SevenBitAddress,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let result = self.inner.transaction(address, operations).await;
+
+        if take(address) {
+            for operation in operations.iter() {
+                match operation {
+                    Operation::Write(bytes) => log::info!("i2c 0x{:02x} W {:02x?}", address, bytes),
+                    Operation::Read(bytes) => log::info!("i2c 0x{:02x} R {:02x?}", address, bytes),
+                }
+            }
+            log::info!("i2c 0x{:02x} -> {:?}", address, result);
+        }
+
+        result
+    }
+}
@@ -0,0 +1,319 @@
+//! Lifetime energy delivered by each charge channel, the desk's odometer. Unlike the peaks it has
+//! no reset command; only erasing the flash clears it. Next to it runs a session counter per
+//! channel, the trip meter, which `energy-reset` zeroes and which starts from 0 at boot.
+//!
+//! Kept in RAM and saved every [`SAVE_PERIOD`] and before a controlled reboot, so up to one
+//! period of energy is lost on power loss. Each save appends a fixed-size record to a log spanning
+//! two slots; a save cut short leaves the previous record intact. A slot is only erased once the
+//! log has moved on to the other one, and then in the background, so a save is a plain flash
+//! write. Published to `system/lifetime` as
+//! `{"total":12.345,"channels":[1.234,..],"session":[0.123,..]}`, in Wh.
+
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Write,
+};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Ticker};
+use heapless::String;
+
+use crate::{
+    bus::{publish_system_message, ChargeChannelSeriesItem},
+    helper::crc16,
+    storage::{self, Slot, StorageError},
+};
+
+const CHANNELS: usize = 4;
+
+/// With [`RECORDS_PER_SLOT`] records per slot, each slot is erased about every 17 hours, so the
+/// flash's rated 100k erase cycles last well over a century.
+const SAVE_PERIOD: Duration = Duration::from_secs(5 * 60);
+const PUBLISH_PERIOD: Duration = Duration::from_secs(60);
+
+/// Samples further apart than this aren't integrated: the channel was offline in between.
+const MAX_SAMPLE_GAP: Duration = Duration::from_secs(5);
+
+const SLOTS: [Slot; 2] = [Slot::Lifetime0, Slot::Lifetime1];
+
+/// `[seq: u32][watt_hours: f64; 4][crc16: u16]`, little-endian, padded with `0xff` to whole
+/// flash words.
+const PAYLOAD_LEN: usize = size_of::<u32>() + CHANNELS * size_of::<f64>();
+const RECORD_LEN: usize = (PAYLOAD_LEN + size_of::<u16>()).next_multiple_of(4);
+const RECORDS_PER_SLOT: usize = storage::SLOT_SIZE as usize / RECORD_LEN;
+
+struct Odometer {
+    watt_hours: [f64; CHANNELS],
+    session_watt_hours: [f64; CHANNELS],
+    last_samples: [Option<Instant>; CHANNELS],
+    /// Sequence number of the last saved record.
+    seq: u32,
+    saved: [f64; CHANNELS],
+}
+
+/// Where the log continues.
+#[derive(Debug, Clone, Copy)]
+struct Log {
+    /// The slot being filled and the index of the next record in it. `RECORDS_PER_SLOT` once
+    /// it is full, or holds something other than records after the last one.
+    slot: usize,
+    next: usize,
+    /// The other slot is erased, so the log can move on without an erase.
+    spare_erased: bool,
+}
+
+impl Log {
+    fn spare(&self) -> usize {
+        (self.slot + 1) % SLOTS.len()
+    }
+
+    fn append(&mut self, record: &[u8; RECORD_LEN]) -> Result<(), StorageError> {
+        if self.next == RECORDS_PER_SLOT {
+            // Normally done by `prepare_spare` long before.
+            if !self.spare_erased {
+                storage::erase(SLOTS[self.spare()])?;
+            }
+            self.slot = self.spare();
+            self.next = 0;
+            self.spare_erased = false;
+        }
+
+        // Skipped even if the write fails: it may have left the bytes half programmed.
+        let offset = (self.next * RECORD_LEN) as u32;
+        self.next += 1;
+        storage::program(SLOTS[self.slot], offset, record)
+    }
+}
+
+static ODOMETER: Mutex<CriticalSectionRawMutex, RefCell<Odometer>> =
+    Mutex::new(RefCell::new(Odometer {
+        watt_hours: [0.0; CHANNELS],
+        session_watt_hours: [0.0; CHANNELS],
+        last_samples: [None; CHANNELS],
+        seq: 0,
+        saved: [0.0; CHANNELS],
+    }));
+
+static LOG: Mutex<CriticalSectionRawMutex, Cell<Log>> = Mutex::new(Cell::new(Log {
+    slot: 0,
+    next: RECORDS_PER_SLOT,
+    spare_erased: false,
+}));
+
+fn encode(seq: u32, watt_hours: &[f64; CHANNELS]) -> [u8; RECORD_LEN] {
+    let mut record = [0xffu8; RECORD_LEN];
+    record[..4].copy_from_slice(&seq.to_le_bytes());
+    for (value, bytes) in watt_hours
+        .iter()
+        .zip(record[4..PAYLOAD_LEN].chunks_exact_mut(8))
+    {
+        bytes.copy_from_slice(&value.to_le_bytes());
+    }
+    let crc = crc16(&record[..PAYLOAD_LEN]);
+    record[PAYLOAD_LEN..PAYLOAD_LEN + 2].copy_from_slice(&crc.to_le_bytes());
+    record
+}
+
+/// `None` for anything but an intact record.
+fn decode(record: &[u8; RECORD_LEN]) -> Option<(u32, [f64; CHANNELS])> {
+    let crc = u16::from_le_bytes([record[PAYLOAD_LEN], record[PAYLOAD_LEN + 1]]);
+    if crc != crc16(&record[..PAYLOAD_LEN]) {
+        return None;
+    }
+
+    let seq = u32::from_le_bytes(record[..4].try_into().unwrap());
+    let mut watt_hours = [0.0; CHANNELS];
+    for (value, bytes) in watt_hours
+        .iter_mut()
+        .zip(record[4..PAYLOAD_LEN].chunks_exact(8))
+    {
+        let stored = f64::from_le_bytes(bytes.try_into().unwrap());
+        if stored.is_finite() && stored >= 0.0 {
+            *value = stored;
+        }
+    }
+    Some((seq, watt_hours))
+}
+
+/// Loads the newest intact record of the log, finds where the log continues and publishes the
+/// counters. Call once at boot.
+pub fn load() {
+    let mut newest: Option<(usize, usize, u32, [f64; CHANNELS])> = None;
+    // Index after the last record in each slot that isn't erased.
+    let mut used = [0usize; 2];
+    for (slot_index, slot) in SLOTS.iter().enumerate() {
+        for index in 0..RECORDS_PER_SLOT {
+            let mut record = [0u8; RECORD_LEN];
+            if storage::read_raw(*slot, (index * RECORD_LEN) as u32, &mut record).is_err() {
+                used[slot_index] = index + 1;
+                continue;
+            }
+            if record.iter().all(|&byte| byte == 0xff) {
+                continue;
+            }
+            used[slot_index] = index + 1;
+
+            let Some((seq, watt_hours)) = decode(&record) else {
+                continue;
+            };
+            let is_newer = newest.map_or(true, |(_, _, newest_seq, _)| {
+                (seq.wrapping_sub(newest_seq) as i32) > 0
+            });
+            if is_newer {
+                newest = Some((slot_index, index, seq, watt_hours));
+            }
+        }
+    }
+
+    let (slot, next) = match newest {
+        Some((slot, index, seq, watt_hours)) => {
+            ODOMETER.lock(|odometer| {
+                let mut odometer = odometer.borrow_mut();
+                odometer.watt_hours = watt_hours;
+                odometer.saved = watt_hours;
+                odometer.seq = seq;
+            });
+            log::info!("Lifetime energy loaded from record {}", seq);
+            (slot, index + 1)
+        }
+        None => {
+            log::info!("No stored lifetime energy");
+            (0, 0)
+        }
+    };
+    let mut log = Log {
+        slot,
+        next,
+        spare_erased: false,
+    };
+    if used[slot] > next {
+        // A torn write or leftovers after the newest record; start afresh in the other slot.
+        log.next = RECORDS_PER_SLOT;
+    }
+    log.spare_erased = used[log.spare()] == 0;
+    LOG.lock(|value| value.set(log));
+
+    publish();
+}
+
+/// Adds the energy since the channel's previous sample.
+pub fn record_charge_channel(index: usize, value: &ChargeChannelSeriesItem) {
+    let now = Instant::now();
+    ODOMETER.lock(|odometer| {
+        let mut odometer = odometer.borrow_mut();
+        if let Some(last) = odometer.last_samples[index].replace(now) {
+            let elapsed = now.saturating_duration_since(last);
+            if elapsed <= MAX_SAMPLE_GAP {
+                let watt_hours =
+                    value.watts.max(0.0) * elapsed.as_micros() as f64 / 3_600_000_000.0;
+                odometer.watt_hours[index] += watt_hours;
+                odometer.session_watt_hours[index] += watt_hours;
+            }
+        }
+    });
+}
+
+/// Zeroes the session counter of one channel, or of all with `None`, and publishes the result.
+/// A sample recorded concurrently lands either before the reset or after it, never half in both.
+pub fn reset_session(index: Option<usize>) {
+    ODOMETER.lock(|odometer| {
+        let mut odometer = odometer.borrow_mut();
+        match index {
+            Some(index) => odometer.session_watt_hours[index] = 0.0,
+            None => odometer.session_watt_hours = [0.0; CHANNELS],
+        }
+    });
+    publish();
+}
+
+/// Appends a record to the log if anything changed since the last one. Also called by
+/// `system::reboot_task` right before a controlled reboot.
+pub fn save() {
+    let Some((seq, watt_hours)) = ODOMETER.lock(|odometer| {
+        let odometer = odometer.borrow();
+        (odometer.watt_hours != odometer.saved)
+            .then(|| (odometer.seq.wrapping_add(1), odometer.watt_hours))
+    }) else {
+        return;
+    };
+
+    // Flash access stays out of the critical section.
+    let mut log = LOG.lock(|log| log.get());
+    let result = log.append(&encode(seq, &watt_hours));
+    LOG.lock(|value| value.set(log));
+
+    match result {
+        Ok(()) => ODOMETER.lock(|odometer| {
+            let mut odometer = odometer.borrow_mut();
+            odometer.saved = watt_hours;
+            odometer.seq = seq;
+        }),
+        Err(err) => log::error!("Cannot save lifetime energy: {:?}", err),
+    }
+}
+
+/// Erases the slot the log moves to next, once the current one holds the newest record, so
+/// neither a save nor the one before a reboot has to wait for an erase.
+fn prepare_spare() {
+    let mut log = LOG.lock(|log| log.get());
+    if log.spare_erased {
+        return;
+    }
+
+    match storage::erase(SLOTS[log.spare()]) {
+        Ok(()) => {
+            log.spare_erased = true;
+            LOG.lock(|value| value.set(log));
+        }
+        Err(err) => log::error!("Cannot erase lifetime slot: {:?}", err),
+    }
+}
+
+fn publish() {
+    let (watt_hours, session_watt_hours) = ODOMETER.lock(|odometer| {
+        let odometer = odometer.borrow();
+        (odometer.watt_hours, odometer.session_watt_hours)
+    });
+
+    let mut json = String::<192>::new();
+    write!(
+        json,
+        "{{\"total\":{:.3},\"channels\":[",
+        watt_hours.iter().sum::<f64>()
+    )
+    .ok();
+    for (index, value) in watt_hours.iter().enumerate() {
+        if index > 0 {
+            json.push(',').ok();
+        }
+        write!(json, "{:.3}", value).ok();
+    }
+    json.push_str("],\"session\":[").ok();
+    for (index, value) in session_watt_hours.iter().enumerate() {
+        if index > 0 {
+            json.push(',').ok();
+        }
+        write!(json, "{:.3}", value).ok();
+    }
+    json.push_str("]}").ok();
+
+    publish_system_message("lifetime", json.as_bytes());
+}
+
+#[embassy_executor::task]
+pub async fn task() {
+    let mut ticker = Ticker::every(PUBLISH_PERIOD);
+    let mut saved_at = Instant::now();
+
+    loop {
+        ticker.next().await;
+        publish();
+
+        if saved_at.elapsed() >= SAVE_PERIOD {
+            saved_at = Instant::now();
+            save();
+        } else {
+            prepare_spare();
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use core::{cell::Cell, str::FromStr};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Console logger whose level can be changed at runtime with [`set_level`].
+///
+/// `esp_println`'s logger bakes the `ESP_LOG` filter in at build time, which only allows lowering
+/// the verbosity later on. This one reads the same `ESP_LOG` spec, e.g.
+/// `info,power_desk::mqtt=debug,esp_wifi=warn`: its bare level is the global level at boot, and
+/// [`set_level`] replaces that one only. A module directive keeps deciding for the targets it
+/// prefixes; the longest matching one wins.
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let max = module_level(metadata.target()).unwrap_or_else(level);
+        metadata.level() <= max
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            esp_println::println!("{} - {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// `ESP_LOG` as set at build time.
+const FILTER_SPEC: &str = match option_env!("ESP_LOG") {
+    Some(spec) => spec,
+    None => "",
+};
+
+/// The global level, as opposed to the module directives of [`FILTER_SPEC`].
+static LEVEL: Mutex<CriticalSectionRawMutex, Cell<LevelFilter>> =
+    Mutex::new(Cell::new(LevelFilter::Info));
+
+/// The directives of [`FILTER_SPEC`] as `(module, level)`, with no module for the bare level. A
+/// module without a level gets every level, and what follows a `/` is ignored, as in
+/// `esp_println`. Invalid directives are skipped.
+fn directives() -> impl Iterator<Item = (Option<&'static str>, LevelFilter)> {
+    let modules = FILTER_SPEC.split('/').next().unwrap_or_default();
+    modules
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| !directive.is_empty())
+        .filter_map(|directive| match directive.split_once('=') {
+            None => match LevelFilter::from_str(directive) {
+                Ok(level) => Some((None, level)),
+                Err(_) => Some((Some(directive), LevelFilter::Trace)),
+            },
+            Some((module, level)) if level.trim().is_empty() => {
+                Some((Some(module.trim()), LevelFilter::Trace))
+            }
+            Some((module, level)) => Some((
+                Some(module.trim()),
+                LevelFilter::from_str(level.trim()).ok()?,
+            )),
+        })
+}
+
+/// Level of the longest module directive prefixing `target`, if any.
+fn module_level(target: &str) -> Option<LevelFilter> {
+    directives()
+        .filter_map(|(module, level)| Some((module?, level)))
+        .filter(|(module, _)| target.starts_with(module))
+        .max_by_key(|(module, _)| module.len())
+        .map(|(_, level)| level)
+}
+
+/// The bare level of `ESP_LOG`, `info` when it isn't set. A spec of module directives only
+/// leaves every other module off, as it does with `esp_println`.
+fn default_level() -> LevelFilter {
+    directives()
+        .filter(|(module, _)| module.is_none())
+        .map(|(_, level)| level)
+        .max()
+        .unwrap_or(if FILTER_SPEC.is_empty() {
+            LevelFilter::Info
+        } else {
+            LevelFilter::Off
+        })
+}
+
+/// `log` drops records above its max level before asking the logger, so that has to let
+/// through the most verbose module directive too.
+fn max_level(level: LevelFilter) -> LevelFilter {
+    directives()
+        .filter(|(module, _)| module.is_some())
+        .map(|(_, level)| level)
+        .fold(level, LevelFilter::max)
+}
+
+pub fn init() {
+    let level = default_level();
+    LEVEL.lock(|current| current.set(level));
+    unsafe {
+        log::set_logger_racy(&LOGGER).unwrap();
+        log::set_max_level_racy(max_level(level));
+    }
+}
+
+/// Parses a level name (`off`, `error`, `warn`, `info`, `debug`, `trace`; case-insensitive).
+pub fn parse_level(value: &str) -> Option<LevelFilter> {
+    LevelFilter::from_str(value.trim()).ok()
+}
+
+/// Sets the global level; modules with their own `ESP_LOG` directive keep theirs.
+pub fn set_level(level: LevelFilter) {
+    LEVEL.lock(|current| current.set(level));
+    log::set_max_level(max_level(level));
+}
+
+pub fn level() -> LevelFilter {
+    LEVEL.lock(|current| current.get())
+}
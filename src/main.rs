@@ -4,12 +4,12 @@
 #![feature(impl_trait_in_assoc_type)]
 
 use embassy_executor::Spawner;
-use embassy_net::{Config, Stack, StackResources};
+use embassy_net::{Stack, StackResources};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
 use embassy_time::{Duration, Timer};
 use esp_backtrace as _;
 use esp_hal::{
-    gpio::{Flex, Io, Level, Pull},
+    gpio::{Flex, Io},
     i2c::I2c,
     prelude::*,
     rng::Rng,
@@ -23,13 +23,37 @@ use mqtt::mqtt_task;
 use static_cell::make_static;
 use wifi::{connection, get_ip_addr, net_task};
 
+mod board;
+mod burn_in;
 mod bus;
+mod calibration;
 mod charge_channel;
+mod command;
+mod commission;
+mod consistency;
+mod device_name;
 mod error;
+mod fan;
+#[cfg(feature = "ha-discovery")]
+mod ha_discovery;
 mod helper;
+mod history;
+mod i2c_bus;
 mod i2c_mux;
+mod i2c_trace;
+mod lifetime;
+mod logger;
 mod mqtt;
+mod mqtt_wire;
+mod peaks;
 mod protector;
+mod schema;
+mod settings;
+mod snapshot;
+mod storage;
+mod system;
+mod telemetry_layout;
+mod watchdog;
 mod wifi;
 
 extern crate alloc;
@@ -37,35 +61,35 @@ use esp_alloc as _;
 
 #[main]
 async fn main(spawner: Spawner) {
-    esp_println::logger::init_logger_from_env();
+    logger::init();
 
     log::info!("starting");
 
     esp_alloc::heap_allocator!(72 * 1024);
 
-    let peripherals = esp_hal::init(esp_hal::Config::default());
+    system::init_boot_report();
+
+    let mut hal_config = esp_hal::Config::default();
+    watchdog::configure(&mut hal_config);
+    let peripherals = esp_hal::init(hal_config);
+
+    calibration::load();
+    device_name::load();
+    lifetime::load();
 
     let io: Io = Io::new(peripherals.GPIO, peripherals.IO_MUX);
 
     let systimer = SystemTimer::new(peripherals.SYSTIMER).split::<Target>();
     esp_hal_embassy::init(systimer.alarm0);
+    // Feeds the RWDT armed by `esp_hal::init` once main first yields.
+    spawner.spawn(watchdog::task()).ok();
     let timg0 = TimerGroup::new(peripherals.TIMG0);
 
-    let vin_ctl_pin = io.pins.gpio7;
+    // VIN stays cut until the check below has passed.
+    let vin_ctl_pin: system::VinCtlGpio = io.pins.gpio7;
     let mut vin_ctl_pin = Flex::new(vin_ctl_pin);
 
-    vin_ctl_pin.set_as_open_drain(Pull::None);
-    vin_ctl_pin.set_low();
-
-    log::info!("vin_ctl_pin: {:?}", vin_ctl_pin.get_level());
-
-    if matches!(vin_ctl_pin.get_level(), Level::High) {
-        log::error!("vin_ctl_pin cannot set to low");
-
-        Timer::after_millis(5000).await;
-        return;
-    }
-    vin_ctl_pin.set_as_input(Pull::None);
+    system::drive_vin_ctl(&mut vin_ctl_pin, true);
 
     // Wi-Fi
 
@@ -79,7 +103,7 @@ async fn main(spawner: Spawner) {
     let wifi = peripherals.WIFI;
     let (wifi_interface, controller) =
         esp_wifi::wifi::new_with_mode(&init, wifi, WifiStaDevice).unwrap();
-    let config = Config::dhcpv4(Default::default());
+    let config = wifi::net_config();
     let seed = 1234; // very random, very secure seed
 
     // Init network stack
@@ -100,10 +124,34 @@ async fn main(spawner: Spawner) {
     spawner.spawn(get_ip_addr(&stack)).ok();
 
     spawner.spawn(mqtt_task(&stack)).ok();
-
-    spawner.spawn(protector::task(i2c_mutex, vin_ctl_pin)).ok();
+    spawner.spawn(system::reboot_task()).ok();
+
+    // Runs after the network tasks are spawned, so a failed check can be reported over MQTT.
+    system::check_vin_ctl(&mut vin_ctl_pin).await;
+    system::drive_vin_ctl(&mut vin_ctl_pin, false);
+
+    // Boards without the input protection stage build with `--cfg no_protector`. VIN is then
+    // left permanently enabled after the startup check above: there is no over-temperature
+    // shutdown and no remote `vin-status` control, so the upstream supply must provide its own
+    // protection.
+    #[cfg(all(not(no_protector), feature = "fan"))]
+    let fan = fan::Fan::new(peripherals.LEDC, io.pins.gpio6);
+    #[cfg(all(not(no_protector), not(feature = "fan")))]
+    let fan = fan::Fan::none();
+    #[cfg(not(no_protector))]
+    spawner.spawn(protector::task(i2c_mutex, vin_ctl_pin, fan)).ok();
+    #[cfg(not(no_protector))]
+    spawner.spawn(protector::offline_guard_task()).ok();
+    #[cfg(not(no_protector))]
+    spawner.spawn(consistency::task()).ok();
 
     spawner.spawn(charge_channel::task(i2c_mutex)).ok();
+    spawner.spawn(charge_channel::force_off_task(i2c_mutex)).ok();
+    spawner.spawn(commission::task()).ok();
+    spawner.spawn(burn_in::task()).ok();
+    spawner.spawn(history::task()).ok();
+    spawner.spawn(lifetime::task()).ok();
+    spawner.spawn(calibration::task()).ok();
 
     loop {
         Timer::after(Duration::from_millis(5_000)).await;
@@ -1,8 +1,16 @@
-use embassy_futures::select::{select, select3, select4, Either, Either3, Either4};
-use embassy_net::{tcp::TcpSocket, IpAddress, IpEndpoint, Stack};
-use embassy_time::{Duration, Ticker, Timer};
+use core::{
+    cell::RefCell,
+    fmt::{Display, Write},
+    future::poll_fn,
+};
+
+use embassy_futures::select::{select, select4, Either, Either4};
+use embassy_net::{dns::DnsQueryType, tcp::TcpSocket, IpAddress, IpEndpoint, Stack};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use esp_hal::efuse::Efuse;
 use esp_wifi::wifi::{WifiDevice, WifiStaDevice};
-use heapless::{String, Vec};
+use heapless::{Deque, String, Vec};
 use rust_mqtt::{
     client::{client::MqttClient, client_config::ClientConfig},
     packet::v5::{publish_packet::QualityOfService, reason_codes::ReasonCode},
@@ -10,14 +18,548 @@ use rust_mqtt::{
 };
 use static_cell::make_static;
 
-use crate::bus::{
-    ChargeChannelSeriesItem, ProtectorSeriesItem, WiFiConnectStatus,
-    CHARGE_CHANNEL_SERIES_ITEM_CHANNELS, PROTECTOR_SERIES_ITEM_CHANNEL, VIN_STATUS_CFG_CHANNEL,
-    WIFI_CONNECT_STATUS,
+#[cfg(feature = "json-payload")]
+use crate::bus::{JsonPayload, MAX_JSON_PAYLOAD_LEN};
+#[cfg(feature = "ha-discovery")]
+use crate::ha_discovery;
+use crate::{
+    bus::{
+        ChargeChannelSeriesItem, CommandAck, ProtectorSeriesItem, SystemMessage, WiFiConnectStatus,
+        CHARGE_CHANNEL_SERIES_ITEM_CHANNELS, CMD_ACK_CHANNEL, MAX_CFG_FIELD_LEN,
+        MAX_SYSTEM_MESSAGE_LEN, MAX_SYSTEM_TOPIC_LEN, PROTECTOR_SERIES_ITEM_CHANNEL,
+        SYSTEM_MESSAGE_CHANNEL, WIFI_CONNECT_STATUS, WIFI_LINK_INFO,
+    },
+    command, device_name,
+    mqtt_wire::{
+        self, copy_payload, prepend_age, push_replay_suffix, set_charge_channel_topic,
+        set_protector_topic, SerializeError,
+    },
+    schema,
+    settings::{self, Stream, MQTT_KEEP_ALIVE_SECS},
+    system,
+};
+
+/// Every topic lives under `power-desk/<mac>/`, the MAC as 12 hex digits, so desks sharing a
+/// broker don't overwrite each other; see [`topic_prefix`].
+const MQTT_TOPIC_ROOT: &str = "power-desk/";
+/// Commands arrive on `<prefix>cfg/<field>`.
+const MQTT_CFG_TOPIC: &str = "cfg/";
+const MQTT_CFG_ACK_TOPIC: &str = "cfg-ack/";
+const MQTT_SYSTEM_MQTT_TOPIC: &str = "system/mqtt";
+const MQTT_SYSTEM_BOOT_TOPIC: &str = "system/boot";
+const MQTT_SYSTEM_CAPABILITIES_TOPIC: &str = "system/capabilities";
+const MQTT_SYSTEM_STATUS_TOPIC: &str = "system/status";
+const MQTT_SYSTEM_RECONNECTS_TOPIC: &str = "system/reconnects";
+const MQTT_SYSTEM_HEARTBEAT_TOPIC: &str = "system/heartbeat";
+const MQTT_SCHEMA_TOPIC: &str = "schema";
+const MQTT_WIFI_TOPIC: &str = "wifi";
+
+/// Size of the buffer outgoing messages are serialized into.
+#[cfg(not(feature = "json-payload"))]
+const SEND_BUFFER_LEN: usize = MAX_SYSTEM_MESSAGE_LEN;
+#[cfg(feature = "json-payload")]
+const SEND_BUFFER_LEN: usize = if MAX_JSON_PAYLOAD_LEN > MAX_SYSTEM_MESSAGE_LEN {
+    MAX_JSON_PAYLOAD_LEN
+} else {
+    MAX_SYSTEM_MESSAGE_LEN
 };
 
-const MQTT_TOPIC_PREFIX: &str = "power-desk/test/";
-const MQTT_CFG_TOPIC_PREFIX: &str = "power-desk/test/cfg/#";
+/// Fits the longest `schema/<layout>` document with its topic.
+#[cfg(not(feature = "json-payload"))]
+const MQTT_TX_BUFFER_SIZE: usize = 576;
+/// Fits a JSON series payload with its topic.
+#[cfg(feature = "json-payload")]
+const MQTT_TX_BUFFER_SIZE: usize = 640;
+/// PUBLISH header, topic and property lengths and packet id, at their longest.
+const MQTT_PUBLISH_OVERHEAD: usize = 16;
+
+const _: () = assert!(
+    // 64 for the topic buffer.
+    schema::MAX_LAYOUT_JSON_LEN + 64 + MQTT_PUBLISH_OVERHEAD <= MQTT_TX_BUFFER_SIZE,
+    "a schema layout doesn't fit the MQTT send buffer"
+);
+const _: () = assert!(
+    system::MAX_CAPABILITIES_JSON_LEN + 64 + MQTT_PUBLISH_OVERHEAD <= MQTT_TX_BUFFER_SIZE,
+    "the capabilities document doesn't fit the MQTT send buffer"
+);
+/// Also bounds incoming commands, so it has to fit a `cfg/bulk` document.
+const MQTT_RX_BUFFER_SIZE: usize = 512;
+
+const MQTT_BROKER: &str = env!("MQTT_BROKER");
+const MQTT_BROKER_SECONDARY: Option<&str> = option_env!("MQTT_BROKER_SECONDARY");
+const MQTT_DEFAULT_PORT: u16 = 1883;
+
+/// Consecutive connect failures before switching to the other broker.
+const MAX_BROKER_FAILURES: u8 = 3;
+/// How long to stay on the secondary broker before probing the primary again.
+const PRIMARY_RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Pause before subscribing again on the same connection after the broker refused.
+const SUBSCRIBE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Series samples kept while disconnected, across all series.
+const REPLAY_CAPACITY: usize = 16;
+
+/// `power-desk/<mac>/`.
+type TopicPrefix = String<24>;
+
+static TOPIC_PREFIX: Mutex<CriticalSectionRawMutex, RefCell<TopicPrefix>> =
+    Mutex::new(RefCell::new(String::new()));
+
+/// The device's topic prefix, built from the MAC address on first use.
+fn topic_prefix() -> TopicPrefix {
+    TOPIC_PREFIX.lock(|prefix| {
+        let mut prefix = prefix.borrow_mut();
+        if prefix.is_empty() {
+            let [a, b, c, d, e, f] = Efuse::get_mac_address();
+            write!(
+                prefix,
+                "{}{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}/",
+                MQTT_TOPIC_ROOT, a, b, c, d, e, f
+            )
+            .ok();
+        }
+        prefix.clone()
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Broker {
+    host: &'static str,
+    port: u16,
+}
+
+impl Broker {
+    /// Parses `host[:port]`, defaulting to the standard MQTT port. `host` is an IPv4 address or
+    /// a hostname to resolve through DNS.
+    fn parse(value: &'static str) -> Option<Self> {
+        let (host, port) = match value.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().ok()?),
+            None => (value, MQTT_DEFAULT_PORT),
+        };
+
+        if host.is_empty() {
+            return None;
+        }
+
+        Some(Self { host, port })
+    }
+
+    /// The endpoint of a broker given by its IPv4 address.
+    fn literal_endpoint(&self) -> Option<IpEndpoint> {
+        let mut octets = [0u8; 4];
+        let mut parts = self.host.split('.');
+        for octet in octets.iter_mut() {
+            *octet = parts.next()?.parse().ok()?;
+        }
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let address = IpAddress::v4(octets[0], octets[1], octets[2], octets[3]);
+        Some(IpEndpoint::new(address, self.port))
+    }
+
+    async fn resolve(
+        &self,
+        stack: &Stack<WifiDevice<'static, WifiStaDevice>>,
+    ) -> Option<IpEndpoint> {
+        if let Some(endpoint) = self.literal_endpoint() {
+            return Some(endpoint);
+        }
+
+        match stack.dns_query(self.host, DnsQueryType::A).await {
+            Ok(addresses) => {
+                let endpoint = IpEndpoint::new(*addresses.first()?, self.port);
+                log::info!("Resolved {} to {}", self.host, endpoint);
+                Some(endpoint)
+            }
+            Err(err) => {
+                log::warn!("Cannot resolve {}: {:?}", self.host, err);
+                None
+            }
+        }
+    }
+}
+
+impl Display for Broker {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// Picks the broker to connect to. Without a secondary broker this always yields the primary.
+struct BrokerSelector {
+    brokers: Vec<Broker, 2>,
+    active: usize,
+    failures: u8,
+    connected_at: Instant,
+    /// Address of the active broker, kept until a connect to it fails so a changed address is
+    /// picked up on the next attempt.
+    resolved: Option<IpEndpoint>,
+}
+
+impl BrokerSelector {
+    fn new() -> Self {
+        let mut brokers = Vec::new();
+        for value in [Some(MQTT_BROKER), MQTT_BROKER_SECONDARY]
+            .into_iter()
+            .flatten()
+        {
+            match Broker::parse(value) {
+                Some(broker) => brokers.push(broker).unwrap(),
+                None => log::error!("Invalid MQTT broker: {:?}", value),
+            }
+        }
+
+        Self {
+            brokers,
+            active: 0,
+            failures: 0,
+            connected_at: Instant::now(),
+            resolved: None,
+        }
+    }
+
+    fn current(&self) -> Option<Broker> {
+        self.brokers.get(self.active).copied()
+    }
+
+    /// Endpoint of the active broker, resolving its hostname unless cached.
+    async fn endpoint(
+        &mut self,
+        stack: &Stack<WifiDevice<'static, WifiStaDevice>>,
+    ) -> Option<IpEndpoint> {
+        if self.resolved.is_none() {
+            self.resolved = self.current()?.resolve(stack).await;
+        }
+        self.resolved
+    }
+
+    fn role(&self) -> &'static str {
+        if self.active == 0 {
+            "primary"
+        } else {
+            "secondary"
+        }
+    }
+
+    fn on_connect_failed(&mut self) {
+        self.resolved = None;
+        self.failures += 1;
+        if self.failures >= MAX_BROKER_FAILURES && self.brokers.len() > 1 {
+            self.active = (self.active + 1) % self.brokers.len();
+            self.failures = 0;
+            log::warn!("Failing over to {} MQTT broker", self.role());
+        }
+    }
+
+    fn on_connected(&mut self) {
+        self.failures = 0;
+        self.connected_at = Instant::now();
+    }
+
+    /// Returns `true` when connected to the secondary long enough that the primary should be
+    /// probed again. The probe gets a single attempt before falling back to the secondary.
+    fn should_retry_primary(&mut self) -> bool {
+        if self.active == 0 || self.connected_at.elapsed() < PRIMARY_RETRY_INTERVAL {
+            return false;
+        }
+
+        self.active = 0;
+        self.failures = MAX_BROKER_FAILURES - 1;
+        self.resolved = None;
+        true
+    }
+}
+
+/// First and longest backoff between failed connection attempts.
+const BACKOFF_FLOOR: Duration = Duration::from_millis(500);
+const BACKOFF_CAP: Duration = Duration::from_secs(30);
+/// A connection that lasted this long resets the backoff to the floor; a shorter one counts as
+/// another failure, so a broker that drops every connection right away isn't hammered either.
+const BACKOFF_RESET_AFTER: Duration = Duration::from_secs(60);
+
+/// Spaces out connection attempts. Each attempt waits until the backoff, or
+/// `mqtt_reconnect_min_secs` if longer, has passed since the previous one, plus a random share
+/// of `mqtt_reconnect_spread_secs`, so a fleet that lost its access point together doesn't hit
+/// the broker all at once. The backoff doubles from [`BACKOFF_FLOOR`] up to [`BACKOFF_CAP`] with
+/// every failed attempt, ±25% at random.
+struct ReconnectPacer {
+    last_attempt: Option<Instant>,
+    /// Attempts since the last successful connect.
+    attempts: u32,
+    total_attempts: u32,
+    /// Doublings of the backoff, i.e. attempts since the last connection that lasted.
+    backoff_steps: u32,
+    connected_at: Option<Instant>,
+    /// xorshift state, seeded from the MAC address so devices spread differently.
+    random: u32,
+}
+
+impl ReconnectPacer {
+    fn new() -> Self {
+        let [_, _, a, b, c, d] = Efuse::get_mac_address();
+        Self {
+            last_attempt: None,
+            attempts: 0,
+            total_attempts: 0,
+            backoff_steps: 0,
+            connected_at: None,
+            random: u32::from_le_bytes([a, b, c, d]) | 1,
+        }
+    }
+
+    fn next_random(&mut self) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+
+    fn backoff(&mut self) -> Duration {
+        // The first attempt after a connection goes straight out; the floor applies from the
+        // one after.
+        let steps = self.backoff_steps.saturating_sub(1).min(16);
+        let backoff = (BACKOFF_FLOOR * (1u32 << steps)).min(BACKOFF_CAP);
+        let jitter = 75 + self.next_random() % 51;
+        backoff * jitter / 100
+    }
+
+    async fn wait(&mut self) {
+        let settings = settings::get();
+        let min_interval = Duration::from_secs(settings.mqtt_reconnect_min_secs as u64);
+        let spread_ms = settings.mqtt_reconnect_spread_secs as u32 * 1000;
+
+        if let Some(connected_at) = self.connected_at.take() {
+            if connected_at.elapsed() >= BACKOFF_RESET_AFTER {
+                self.backoff_steps = 0;
+            }
+        }
+        let interval = min_interval.max(self.backoff());
+
+        let mut delay = match self.last_attempt {
+            Some(last_attempt) => interval.checked_sub(last_attempt.elapsed()),
+            None => None,
+        }
+        .unwrap_or(Duration::from_ticks(0));
+        if spread_ms > 0 {
+            delay += Duration::from_millis((self.next_random() % spread_ms) as u64);
+        }
+
+        if delay.as_millis() > 0 {
+            log::info!("Next MQTT connection attempt in {}ms", delay.as_millis());
+            Timer::after(delay).await;
+        }
+
+        self.last_attempt = Some(Instant::now());
+        self.attempts += 1;
+        self.total_attempts += 1;
+        self.backoff_steps += 1;
+    }
+
+    /// Publishes `{"attempts":<until this connect>,"total":<since boot>}` to `system/reconnects`
+    /// and starts counting afresh.
+    async fn report_connected(&mut self, client: &mut Client<'_, '_>, topic_name: &mut String<64>) {
+        let mut json = String::<48>::new();
+        write!(
+            json,
+            "{{\"attempts\":{},\"total\":{}}}",
+            self.attempts, self.total_attempts
+        )
+        .ok();
+        self.attempts = 0;
+        self.connected_at = Some(Instant::now());
+
+        if let Err(err) = publish_system(
+            client,
+            topic_name,
+            MQTT_SYSTEM_RECONNECTS_TOPIC,
+            json.as_bytes(),
+        )
+        .await
+        {
+            log::warn!("Cannot publish reconnect attempts: {:?}", err);
+        }
+    }
+}
+
+/// Publishes `{"seq":n,"uptime":secs}` to `system/heartbeat` every `heartbeat_interval_secs`,
+/// regardless of telemetry, so consumers can tell a quiet device from a silent one. Not
+/// retained; the will message covers a dropped connection.
+struct Heartbeat {
+    seq: u32,
+    last_at: Instant,
+}
+
+impl Heartbeat {
+    fn new() -> Self {
+        Self {
+            seq: 0,
+            last_at: Instant::now(),
+        }
+    }
+
+    /// Waits until the next heartbeat is due; forever while heartbeats are disabled.
+    async fn due(&self) {
+        match settings::get().heartbeat_interval_secs {
+            0 => core::future::pending().await,
+            secs => Timer::at(self.last_at + Duration::from_secs(secs as u64)).await,
+        }
+    }
+
+    async fn publish(
+        &mut self,
+        client: &mut Client<'_, '_>,
+        topic_name: &mut String<64>,
+    ) -> Result<(), ReasonCode> {
+        self.last_at = Instant::now();
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut json = String::<96>::new();
+        write!(
+            json,
+            "{{\"name\":\"{}\",\"seq\":{},\"uptime\":{}}}",
+            device_name::get(),
+            self.seq,
+            self.last_at.as_secs()
+        )
+        .ok();
+
+        if set_topic(topic_name, &[MQTT_SYSTEM_HEARTBEAT_TOPIC]).is_err() {
+            return Err(ReasonCode::TopicNameInvalid);
+        }
+        client
+            .send_message(topic_name, json.as_bytes(), QualityOfService::QoS0, false)
+            .await
+    }
+}
+
+/// A series sample that couldn't be published when it was taken, with the time it was taken
+/// off its series channel.
+#[derive(Debug, Clone, Copy)]
+enum Replayed {
+    Protector(Instant, ProtectorSeriesItem),
+    ChargeChannel(Instant, u8, ChargeChannelSeriesItem),
+}
+
+impl Replayed {
+    fn at(&self) -> Instant {
+        match *self {
+            Replayed::Protector(at, _) | Replayed::ChargeChannel(at, _, _) => at,
+        }
+    }
+}
+
+/// The most recent series samples taken while disconnected, published once the connection is
+/// back.
+///
+/// Best effort: only the last [`REPLAY_CAPACITY`] samples are kept, the oldest dropped first,
+/// and nothing survives a reboot. Draining the series channels while offline also keeps the
+/// producers from blocking on a full channel. Replayed samples go newest first to the series
+/// topic plus [`mqtt_wire::REPLAY_TOPIC_SUFFIX`], e.g. `ch0/series/replay`, never retained, so
+/// consumers of the live topics aren't handed stale data; `seq` tells where each belongs. Each
+/// payload carries the sample's age on publishing, see [`prepend_age`], so consumers can place
+/// it in time.
+struct Replay {
+    samples: Deque<Replayed, REPLAY_CAPACITY>,
+}
+
+impl Replay {
+    fn new() -> Self {
+        Self {
+            samples: Deque::new(),
+        }
+    }
+
+    fn stash(&mut self, sample: Replayed) {
+        if self.samples.is_full() {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample).ok();
+    }
+
+    /// Moves whatever is waiting in the series channels into the queue.
+    fn collect(&mut self) {
+        while let Ok(value) = PROTECTOR_SERIES_ITEM_CHANNEL.try_receive() {
+            self.stash(Replayed::Protector(Instant::now(), value));
+        }
+        for (index, channel) in CHARGE_CHANNEL_SERIES_ITEM_CHANNELS.iter().enumerate() {
+            while let Ok(value) = channel.try_receive() {
+                self.stash(Replayed::ChargeChannel(Instant::now(), index as u8, value));
+            }
+        }
+    }
+
+    /// Keeps collecting samples as they come in, for as long as it is polled.
+    async fn collect_forever(&mut self) {
+        loop {
+            self.collect();
+            match select(
+                PROTECTOR_SERIES_ITEM_CHANNEL.receive(),
+                receive_charge_channel(0),
+            )
+            .await
+            {
+                Either::First(value) => self.stash(Replayed::Protector(Instant::now(), value)),
+                Either::Second((index, value)) => {
+                    self.stash(Replayed::ChargeChannel(Instant::now(), index as u8, value))
+                }
+            }
+        }
+    }
+
+    /// Publishes the queued samples, newest first. A sample that fails to send is put back for
+    /// the next connection.
+    async fn publish(
+        &mut self,
+        client: &mut Client<'_, '_>,
+        topic_name: &mut String<64>,
+        msg_buffer: &mut [u8],
+    ) -> Result<(), ReasonCode> {
+        if !self.samples.is_empty() {
+            log::info!("Replaying {} series samples", self.samples.len());
+        }
+
+        while let Some(sample) = self.samples.pop_back() {
+            let serialized = match sample {
+                Replayed::Protector(_, value) => serialize_protector(value, topic_name, msg_buffer),
+                Replayed::ChargeChannel(_, index, value) => {
+                    serialize_charge_channel_series_item(value, topic_name, msg_buffer, index)
+                }
+            }
+            .and_then(|(size, _, _)| {
+                push_replay_suffix(topic_name)?;
+                prepend_age(msg_buffer, size, sample.at().elapsed().as_millis())
+            });
+            let size = match serialized {
+                Ok(size) => size,
+                Err(err) => {
+                    log::error!("Dropping replay for {:?}: {:?}", topic_name, err);
+                    continue;
+                }
+            };
+
+            match client
+                .send_message(
+                    topic_name,
+                    &msg_buffer[..size],
+                    QualityOfService::QoS0,
+                    false,
+                )
+                .await
+            {
+                Ok(_) | Err(ReasonCode::NoMatchingSubscribers) => {}
+                Err(err) => {
+                    self.samples.push_back(sample).ok();
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
 
 #[embassy_executor::task]
 pub async fn mqtt_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>) {
@@ -25,100 +567,248 @@ pub async fn mqtt_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>
 
     log::info!("start mqtt task");
 
-    let mqtt_tx = make_static!([0u8; 128]);
-    let mqtt_rx = make_static!([0u8; 128]);
+    // Every `make_static!` panics when reached a second time, so they all stay out here: the
+    // reconnect loop below reborrows these buffers on each attempt, and the task never returns
+    // to be spawned again.
+    let mqtt_tx = make_static!([0u8; MQTT_TX_BUFFER_SIZE]);
+    let mqtt_rx = make_static!([0u8; MQTT_RX_BUFFER_SIZE]);
     let socket_tx = make_static!([0u8; 1024]);
     let socket_rx = make_static!([0u8; 1024]);
-    let topics = make_static!(Vec::<&str, 1>::from_slice(&[MQTT_CFG_TOPIC_PREFIX]).unwrap());
 
-    let send_message_buffer: &mut [u8] = make_static!([0u8; 128]);
+    let prefix = topic_prefix();
+    log::info!("MQTT topic prefix: {}", prefix);
+    // `<prefix>system/status`, which the broker sets to `offline` when the connection drops
+    // without a disconnect.
+    let will_topic = make_static!(String::<48>::new());
+    write!(will_topic, "{}{}", prefix, MQTT_SYSTEM_STATUS_TOPIC).ok();
+    let will_topic: &'static str = will_topic;
+    let cfg_topic = make_static!(String::<32>::new());
+    write!(cfg_topic, "{}{}#", prefix, MQTT_CFG_TOPIC).ok();
+    let cfg_topic: &'static str = cfg_topic;
+    let topics = make_static!(Vec::<&str, 1>::from_slice(&[cfg_topic]).unwrap());
+    // `power-desk-<mac>`, unique per device so the broker never mixes up two desks' sessions.
+    let client_id = make_static!(String::<32>::new());
+    let mac = &prefix[MQTT_TOPIC_ROOT.len()..prefix.len() - 1];
+    write!(client_id, "power-desk-{}", mac).ok();
+    let client_id: &'static str = client_id;
+
+    let send_message_buffer: &mut [u8] = make_static!([0u8; SEND_BUFFER_LEN]);
     let send_topic = make_static!(String::<64>::new());
 
+    // The longest topics the serialize helpers build. Anything that still doesn't fit is
+    // dropped with an error instead of panicking.
+    debug_assert!(
+        prefix.capacity() + MQTT_CFG_ACK_TOPIC.len() + MAX_CFG_FIELD_LEN <= send_topic.capacity()
+    );
+    debug_assert!(prefix.capacity() + MAX_SYSTEM_TOPIC_LEN <= send_topic.capacity());
+
+    let mut brokers = BrokerSelector::new();
+    let mut boot_report_published = false;
+    let mut published_schema = None;
+    let mut next_channel = 0;
+    let mut pacer = ReconnectPacer::new();
+    let mut heartbeat = Heartbeat::new();
+    let mut replay = Replay::new();
+
     loop {
-        let mut ticker = Ticker::every(Duration::from_secs(5));
+        replay.collect();
+        select(pacer.wait(), replay.collect_forever()).await;
 
-        let address = IpAddress::v4(192, 168, 31, 11);
+        let mut ping_interval_secs = settings::get().mqtt_ping_interval_secs;
+        let mut ticker = Ticker::every(Duration::from_secs(ping_interval_secs as u64));
 
-        let remote_endpoint = IpEndpoint::new(address, 1883);
+        let Some(broker) = brokers.current() else {
+            log::error!("No valid MQTT broker configured");
+            Timer::after_secs(60).await;
+            continue;
+        };
+        let Some(remote_endpoint) = brokers.endpoint(stack).await else {
+            log::error!("Cannot resolve MQTT broker {}", broker);
+            brokers.on_connect_failed();
+            continue;
+        };
 
         let mut socket = TcpSocket::new(&stack, socket_rx, socket_tx);
-        socket.set_timeout(Some(embassy_time::Duration::from_secs(10)));
+        socket.set_timeout(Some(Duration::from_secs(
+            settings::get().socket_timeout_secs as u64,
+        )));
 
-        socket
-            .connect(remote_endpoint)
-            .await
-            .expect("Cannot connect");
+        if let Err(err) = socket.connect(remote_endpoint).await {
+            log::error!("Cannot connect to {}: {:?}", broker, err);
+            brokers.on_connect_failed();
+            continue;
+        }
 
         let mut config = ClientConfig::new(
             rust_mqtt::client::client_config::MqttVersion::MQTTv5,
             CountingRng(20000),
         );
         config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
-        config.add_client_id("");
-        config.max_packet_size = 100;
+        config.add_client_id(client_id);
+        config.max_packet_size = MQTT_RX_BUFFER_SIZE as u32;
+        config.keep_alive = MQTT_KEEP_ALIVE_SECS;
+        config.add_will(will_topic, b"offline", true);
 
-        let mut client = MqttClient::<_, 5, _>::new(socket, mqtt_tx, 128, mqtt_rx, 128, config);
+        let mut client = MqttClient::<_, 5, _>::new(
+            socket,
+            mqtt_tx,
+            MQTT_TX_BUFFER_SIZE,
+            mqtt_rx,
+            MQTT_RX_BUFFER_SIZE,
+            config,
+        );
 
         match client.connect_to_broker().await {
             Ok(_) => {
-                log::info!("Connected");
+                log::info!("Connected to {} broker {}", brokers.role(), broker);
+                brokers.on_connected();
+                system::record_broker_contact();
+                pacer.report_connected(&mut client, send_topic).await;
             }
             Err(err) => {
                 log::error!("Cannot connect: {:?}", err);
-                Timer::after_millis(1000).await;
+                brokers.on_connect_failed();
                 continue;
             }
         }
 
-        match client.subscribe_to_topics(topics).await {
+        // A refused subscription is retried on this connection; a network error means the
+        // connection is gone and only a reconnect helps.
+        let mut subscribed = client.subscribe_to_topics(topics).await;
+        for attempt in 1..=settings::get().mqtt_subscribe_retries {
+            match subscribed {
+                Err(err) if err != ReasonCode::NetworkError => {
+                    log::warn!("Cannot subscribe: {:?}, retry {}", err, attempt);
+                    Timer::after(SUBSCRIBE_RETRY_DELAY).await;
+                    subscribed = client.subscribe_to_topics(topics).await;
+                }
+                _ => break,
+            }
+        }
+        match subscribed {
             Ok(_) => {
                 log::info!("Subscribed");
             }
             Err(err) => {
                 log::error!("Cannot subscribe: {:?}", err);
-                Timer::after_millis(1000).await;
                 continue;
             }
         }
 
+        if let Err(err) =
+            publish_system(&mut client, send_topic, MQTT_SYSTEM_STATUS_TOPIC, b"online").await
+        {
+            log::warn!("Cannot publish online status: {:?}", err);
+        }
+
+        let mut active_broker = String::<64>::new();
+        write!(active_broker, "{} {}", brokers.role(), broker).ok();
+        if let Err(err) = publish_system(
+            &mut client,
+            send_topic,
+            MQTT_SYSTEM_MQTT_TOPIC,
+            active_broker.as_bytes(),
+        )
+        .await
+        {
+            log::warn!("Cannot publish active broker: {:?}", err);
+        }
+
+        // Fixed per build, but resent on every connect in case the broker lost its retained
+        // messages.
+        let capabilities = system::capabilities_json();
+        if let Err(err) = publish_system(
+            &mut client,
+            send_topic,
+            MQTT_SYSTEM_CAPABILITIES_TOPIC,
+            capabilities.as_bytes(),
+        )
+        .await
+        {
+            log::warn!("Cannot publish capabilities: {:?}", err);
+        }
+
+        if !boot_report_published {
+            boot_report_published = publish_boot_report(&mut client, send_topic).await;
+        }
+        publish_schema(&mut client, send_topic, &mut published_schema).await;
+        #[cfg(feature = "ha-discovery")]
+        publish_ha_discovery(&mut client, &prefix, will_topic).await;
+
+        replay.collect();
+        if let Err(err) = replay
+            .publish(&mut client, send_topic, send_message_buffer)
+            .await
+        {
+            log::error!("Cannot replay series samples: {:?}", err);
+            continue;
+        }
+
         loop {
-            let ticker_future = ticker.next();
+            let ticker_future = select(ticker.next(), heartbeat.due());
             let recv_future = client.receive_message();
-            let send_future = next_message(send_topic, send_message_buffer);
+            let send_future = next_message(send_topic, send_message_buffer, &mut next_channel);
+            let reboot_future = system::reboot_pending();
+
+            match select4(ticker_future, recv_future, send_future, reboot_future).await {
+                Either4::First(Either::Second(_)) => {
+                    if let Err(err) = heartbeat.publish(&mut client, send_topic).await {
+                        log::error!("Heartbeat error: {:?}", err);
+                        break;
+                    }
+                }
+                Either4::First(Either::First(_)) => {
+                    let settings = settings::get();
+                    if settings.mqtt_ping_interval_secs != ping_interval_secs {
+                        ping_interval_secs = settings.mqtt_ping_interval_secs;
+                        ticker = Ticker::every(Duration::from_secs(ping_interval_secs as u64));
+                        log::info!("MQTT ping interval: {}s", ping_interval_secs);
+                    }
+
+                    if !boot_report_published {
+                        boot_report_published = publish_boot_report(&mut client, send_topic).await;
+                    }
+                    publish_schema(&mut client, send_topic, &mut published_schema).await;
+                    if let Err(err) = publish_wifi_link(&mut client, send_topic).await {
+                        log::warn!("Cannot publish Wi-Fi link: {:?}", err);
+                    }
+
+                    if brokers.should_retry_primary() {
+                        log::info!("Probing primary MQTT broker");
+                        client.disconnect().await.ok();
+                        break;
+                    }
 
-            match select3(ticker_future, recv_future, send_future).await {
-                Either3::First(_) => {
                     match client.send_ping().await {
-                        Ok(_) => log::info!("Ping success"),
+                        // `send_ping` only returns `Ok` on the PINGRESP.
+                        Ok(_) => {
+                            log::info!("Ping success");
+                            system::record_broker_contact();
+                        }
                         Err(_) => {
                             log::error!("Ping error");
                             break;
                         }
                     };
                 }
-                Either3::Second(msg) => {
+                Either4::Second(msg) => {
                     ticker.reset();
                     match msg {
                         Ok(msg) => {
+                            system::record_broker_contact();
                             let (topic_name, message) = msg;
 
-                            if !topic_name.starts_with(
-                                &MQTT_CFG_TOPIC_PREFIX[..MQTT_CFG_TOPIC_PREFIX.len() - 1],
-                            ) {
+                            let Some(field) = topic_name
+                                .strip_prefix(prefix.as_str())
+                                .and_then(|topic| topic.strip_prefix(MQTT_CFG_TOPIC))
+                            else {
                                 log::warn!("Invalid topic: {:?}", topic_name);
-                                break;
-                            }
-
-                            let field = &topic_name[(MQTT_CFG_TOPIC_PREFIX.len() - 1)..];
+                                continue;
+                            };
 
-                            match field {
-                                "vin-status" => {
-                                    VIN_STATUS_CFG_CHANNEL.send(message[0].into()).await
-                                }
-                                _ => {
-                                    log::warn!("Invalid field: {:?}", field);
-                                    break;
-                                }
+                            let ack = command::handle(field, message);
+                            if CMD_ACK_CHANNEL.try_send(ack).is_err() {
+                                log::warn!("cfg ack queue full, dropping ack for {:?}", field);
                             }
                         }
                         Err(mqtt_error) => {
@@ -127,7 +817,7 @@ pub async fn mqtt_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>
                         }
                     };
                 }
-                Either3::Third((topic_name, message, qos, retain)) => {
+                Either4::Third((topic_name, message, qos, retain, sample)) => {
                     match client.send_message(topic_name, &message, qos, retain).await {
                         Ok(_) => {}
                         Err(err) => {
@@ -137,16 +827,178 @@ pub async fn mqtt_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>
                                 continue;
                             }
 
+                            if let Some(sample) = sample {
+                                replay.stash(sample);
+                            }
                             break;
                         }
                     }
                 }
+                Either4::Fourth(_) => {
+                    disconnect_for_reboot(&mut client, send_topic, send_message_buffer).await;
+                    system::ready_to_reboot();
+                    core::future::pending::<()>().await;
+                }
             };
         }
     }
 }
 
-type NextMessageInfo<'a> = (&'a String<64>, &'a [u8], QualityOfService, bool);
+type Client<'a, 'b> = MqttClient<'a, TcpSocket<'b>, 5, CountingRng>;
+
+/// Publishes a retained message under `<prefix><sub_topic>`, e.g. `system/boot`.
+async fn publish_system(
+    client: &mut Client<'_, '_>,
+    topic_name: &mut String<64>,
+    sub_topic: &str,
+    payload: &[u8],
+) -> Result<(), ReasonCode> {
+    if set_topic(topic_name, &[sub_topic]).is_err() {
+        log::error!("Topic {:?} too long", sub_topic);
+        return Err(ReasonCode::TopicNameInvalid);
+    }
+
+    client
+        .send_message(topic_name, payload, QualityOfService::QoS0, true)
+        .await
+}
+
+/// Publishes the link quality to `wifi` every ping interval, not retained, e.g.
+/// `{"rssi":-61,"channel":6,"bssid":"a0:b1:c2:d3:e4:f5"}`, as last refreshed by the Wi-Fi
+/// connection task. Nothing is sent while it has no link to report.
+async fn publish_wifi_link(
+    client: &mut Client<'_, '_>,
+    topic_name: &mut String<64>,
+) -> Result<(), ReasonCode> {
+    let Some(link) = *WIFI_LINK_INFO.lock().await else {
+        return Ok(());
+    };
+
+    let [a, b, c, d, e, f] = link.bssid;
+    let mut json = String::<80>::new();
+    write!(
+        json,
+        "{{\"rssi\":{},\"channel\":{},\"bssid\":\"{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}\"}}",
+        link.rssi, link.channel, a, b, c, d, e, f
+    )
+    .ok();
+
+    if set_topic(topic_name, &[MQTT_WIFI_TOPIC]).is_err() {
+        return Err(ReasonCode::TopicNameInvalid);
+    }
+    client
+        .send_message(topic_name, json.as_bytes(), QualityOfService::QoS0, false)
+        .await
+}
+
+/// Publishes the boot report once every task has recorded its init result. Returns whether it
+/// was published.
+async fn publish_boot_report(client: &mut Client<'_, '_>, topic_name: &mut String<64>) -> bool {
+    let report = system::boot_report();
+    if !report.is_complete() {
+        return false;
+    }
+
+    let json = report.to_json();
+    match publish_system(client, topic_name, MQTT_SYSTEM_BOOT_TOPIC, json.as_bytes()).await {
+        Ok(_) => {
+            log::info!("Boot report published");
+            true
+        }
+        Err(err) => {
+            log::warn!("Cannot publish boot report: {:?}", err);
+            false
+        }
+    }
+}
+
+/// Publishes `schema` and the `schema/<layout>` documents when the set of active streams changed
+/// since `published` (or was never published), and records the revision once all went out.
+async fn publish_schema(
+    client: &mut Client<'_, '_>,
+    topic_name: &mut String<64>,
+    published: &mut Option<u32>,
+) {
+    let Some((revision, streams)) = schema::streams_json() else {
+        return;
+    };
+    if *published == Some(revision) {
+        return;
+    }
+
+    // The layouts only change with the firmware, but are cheap enough to resend with the streams
+    // so a consumer never sees one without the other.
+    for (name, version, fields) in schema::LAYOUTS {
+        let mut layout_topic = String::<32>::new();
+        write!(layout_topic, "{}/{}", MQTT_SCHEMA_TOPIC, name).ok();
+        let json = schema::layout_json(version, fields);
+        if let Err(err) = publish_system(client, topic_name, &layout_topic, json.as_bytes()).await {
+            log::warn!("Cannot publish {} layout: {:?}", name, err);
+            return;
+        }
+    }
+
+    match publish_system(client, topic_name, MQTT_SCHEMA_TOPIC, streams.as_bytes()).await {
+        Ok(_) => {
+            log::info!("Schema revision {} published", revision);
+            *published = Some(revision);
+        }
+        Err(err) => log::warn!("Cannot publish schema: {:?}", err),
+    }
+}
+
+/// Publishes the Home Assistant discovery messages, retained.
+#[cfg(feature = "ha-discovery")]
+async fn publish_ha_discovery(client: &mut Client<'_, '_>, prefix: &str, will_topic: &str) {
+    for (topic, config) in ha_discovery::configs(prefix, will_topic) {
+        if let Err(err) = client
+            .send_message(&topic, config.as_bytes(), QualityOfService::QoS0, true)
+            .await
+        {
+            log::warn!("Cannot publish discovery for {}: {:?}", topic, err);
+            return;
+        }
+    }
+    log::info!("Home Assistant discovery published");
+}
+
+/// Best-effort goodbye before a controlled reboot: flushes pending acks, publishes `offline` to
+/// `system/status` and sends DISCONNECT so the broker drops the session right away. The reboot
+/// task bounds the time spent here.
+async fn disconnect_for_reboot(
+    client: &mut Client<'_, '_>,
+    topic_name: &mut String<64>,
+    msg_buffer: &mut [u8],
+) {
+    while let Ok(ack) = CMD_ACK_CHANNEL.try_receive() {
+        if let Ok((size, qos, retain)) = serialize_command_ack(ack, topic_name, msg_buffer) {
+            client
+                .send_message(topic_name, &msg_buffer[..size], qos, retain)
+                .await
+                .ok();
+        }
+    }
+
+    if let Err(err) = publish_system(client, topic_name, MQTT_SYSTEM_STATUS_TOPIC, b"offline").await
+    {
+        log::warn!("Cannot publish offline status: {:?}", err);
+    }
+
+    match client.disconnect().await {
+        Ok(_) => log::info!("Disconnected from MQTT broker"),
+        Err(err) => log::warn!("Cannot disconnect cleanly: {:?}", err),
+    }
+}
+
+/// Topic, payload, QoS and retain flag, plus the sample for series messages so it can be
+/// replayed if sending fails.
+type NextMessageInfo<'a> = (
+    &'a String<64>,
+    &'a [u8],
+    QualityOfService,
+    bool,
+    Option<Replayed>,
+);
 
 pub async fn waiting_wifi_connected() {
     loop {
@@ -164,85 +1016,150 @@ pub async fn waiting_wifi_connected() {
     }
 }
 
+/// Waits for the next message to publish.
+///
+/// Acks, system messages and protector telemetry are served first, in that order, on purpose:
+/// they are low rate, and the first two answer the user while the protector carries the input
+/// readings. The charge channels are served round-robin starting after `next_channel`, so a
+/// busy channel can't starve the ones after it. Messages that don't fit the buffers are logged
+/// and skipped.
 pub async fn next_message<'a>(
     topic_name: &'a mut String<64>,
     msg_buffer: &'a mut [u8],
+    next_channel: &mut usize,
 ) -> NextMessageInfo<'a> {
-    let protector_future = PROTECTOR_SERIES_ITEM_CHANNEL.receive();
-
-    let ch0_future = CHARGE_CHANNEL_SERIES_ITEM_CHANNELS[0].receive();
-    let ch1_future = CHARGE_CHANNEL_SERIES_ITEM_CHANNELS[1].receive();
-    let ch2_future = CHARGE_CHANNEL_SERIES_ITEM_CHANNELS[2].receive();
-    let ch3_future = CHARGE_CHANNEL_SERIES_ITEM_CHANNELS[3].receive();
-
-    let channels_future = select4(ch0_future, ch1_future, ch2_future, ch3_future);
+    loop {
+        let ack_future = CMD_ACK_CHANNEL.receive();
+        let system_future = SYSTEM_MESSAGE_CHANNEL.receive();
+        let protector_future = PROTECTOR_SERIES_ITEM_CHANNEL.receive();
+        let channels_future = receive_charge_channel(*next_channel);
 
-    match select(protector_future, channels_future).await {
-        Either::First(value) => serialize_protector(value, topic_name, msg_buffer),
-        Either::Second(channels) => match channels {
-            Either4::First(ch) => {
-                serialize_charge_channel_series_item(ch, topic_name, msg_buffer, 0)
-            }
-            Either4::Second(ch) => {
-                serialize_charge_channel_series_item(ch, topic_name, msg_buffer, 1)
+        let mut sample = None;
+        let serialized = match select4(ack_future, system_future, protector_future, channels_future)
+            .await
+        {
+            Either4::First(ack) => serialize_command_ack(ack, topic_name, msg_buffer),
+            Either4::Second(message) => serialize_system_message(message, topic_name, msg_buffer),
+            Either4::Third(value) => {
+                sample = Some(Replayed::Protector(Instant::now(), value));
+                serialize_protector(value, topic_name, msg_buffer)
             }
-            Either4::Third(ch) => {
-                serialize_charge_channel_series_item(ch, topic_name, msg_buffer, 2)
+            Either4::Fourth((index, value)) => {
+                *next_channel =
+                    mqtt_wire::next_channel(index, CHARGE_CHANNEL_SERIES_ITEM_CHANNELS.len());
+                sample = Some(Replayed::ChargeChannel(Instant::now(), index as u8, value));
+                serialize_charge_channel_series_item(value, topic_name, msg_buffer, index as u8)
             }
-            Either4::Fourth(ch) => {
-                serialize_charge_channel_series_item(ch, topic_name, msg_buffer, 3)
+        };
+
+        match serialized {
+            Ok((size, qos, retain)) => {
+                return (topic_name, &msg_buffer[..size], qos, retain, sample)
             }
-        },
+            Err(err) => log::error!("Dropping message for {:?}: {:?}", topic_name, err),
+        }
     }
 }
 
-fn get_channel_str(ch: u8) -> &'static str {
-    match ch {
-        0 => "ch0",
-        1 => "ch1",
-        2 => "ch2",
-        3 => "ch3",
-        _ => "unknown",
-    }
+/// Receives from the first ready charge channel, checking them in order from `start`; see
+/// [`mqtt_wire::poll_channels`].
+///
+/// Only the charge channels take turns. In [`next_message`] acks, system messages and the
+/// protector series still go ahead of them, on purpose: acks and system messages answer the
+/// user, and the protector carries the input readings protection is judged on. All three are
+/// low rate, so they can delay the channels but not starve them.
+async fn receive_charge_channel(start: usize) -> (usize, ChargeChannelSeriesItem) {
+    let channels = &CHARGE_CHANNEL_SERIES_ITEM_CHANNELS;
+
+    poll_fn(|cx| {
+        mqtt_wire::poll_channels(start, channels.len(), |index| {
+            channels[index].poll_receive(cx)
+        })
+    })
+    .await
+}
+
+/// QoS and retain flag configured for `stream`.
+fn delivery(stream: Stream) -> (QualityOfService, bool) {
+    let delivery = settings::get().delivery(stream);
+    let qos = match delivery.qos {
+        0 => QualityOfService::QoS0,
+        _ => QualityOfService::QoS1,
+    };
+
+    (qos, delivery.retain)
+}
+
+/// Payload length, QoS and retain flag of a message serialized into the send buffers.
+type Serialized = Result<(usize, QualityOfService, bool), SerializeError>;
+
+/// Sets `topic_name` to the device prefix followed by `parts`.
+fn set_topic(topic_name: &mut String<64>, parts: &[&str]) -> Result<(), SerializeError> {
+    mqtt_wire::set_topic(topic_name, &topic_prefix(), parts)
+}
+
+#[cfg(feature = "json-payload")]
+fn json_payload(
+    json: serde_json_core::ser::Result<JsonPayload>,
+) -> Result<JsonPayload, SerializeError> {
+    json.map_err(|_| SerializeError::PayloadTooLong)
 }
 
 #[inline(always)]
-fn serialize_charge_channel_series_item<'a>(
+fn serialize_charge_channel_series_item(
     value: ChargeChannelSeriesItem,
-    topic_name: &'a mut String<64>,
-    msg_buffer: &'a mut [u8],
+    topic_name: &mut String<64>,
+    msg_buffer: &mut [u8],
     ch: u8,
-) -> NextMessageInfo<'a> {
-    let channel_name = get_channel_str(ch);
-    topic_name.clear();
-    topic_name.push_str(MQTT_TOPIC_PREFIX).unwrap();
-    topic_name.push_str(channel_name).unwrap();
-    topic_name.push_str("/series").unwrap();
-    let message = value.to_bytes();
-    let message = message.as_slice();
-    let size = message.len();
-    msg_buffer[..size].copy_from_slice(message);
-    let qos = QualityOfService::QoS0;
-    let retain = false;
+) -> Serialized {
+    set_charge_channel_topic(topic_name, &topic_prefix(), ch)?;
+    #[cfg(not(feature = "json-payload"))]
+    let size = copy_payload(msg_buffer, &value.to_bytes())?;
+    #[cfg(feature = "json-payload")]
+    let size = copy_payload(msg_buffer, json_payload(value.to_json())?.as_bytes())?;
+    let (qos, retain) = delivery(Stream::ChargeChannel);
 
-    (topic_name, &msg_buffer[..size], qos, retain)
+    Ok((size, qos, retain))
 }
 
 #[inline(always)]
-fn serialize_protector<'a>(
+fn serialize_protector(
     value: ProtectorSeriesItem,
-    topic_name: &'a mut String<64>,
-    msg_buffer: &'a mut [u8],
-) -> NextMessageInfo<'a> {
-    topic_name.clear();
-    topic_name.push_str(MQTT_TOPIC_PREFIX).unwrap();
-    topic_name.push_str("protector").unwrap();
-    let message = value.to_bytes();
-    let message = message.as_slice();
-    let size = message.len();
-    msg_buffer[..size].copy_from_slice(message);
-    let qos = QualityOfService::QoS0;
-    let retain = false;
-
-    (topic_name, &msg_buffer[..size], qos, retain)
+    topic_name: &mut String<64>,
+    msg_buffer: &mut [u8],
+) -> Serialized {
+    set_protector_topic(topic_name, &topic_prefix())?;
+    #[cfg(not(feature = "json-payload"))]
+    let size = copy_payload(msg_buffer, &value.to_bytes())?;
+    #[cfg(feature = "json-payload")]
+    let size = copy_payload(msg_buffer, json_payload(value.to_json())?.as_bytes())?;
+    let (qos, retain) = delivery(Stream::Protector);
+
+    Ok((size, qos, retain))
+}
+
+#[inline(always)]
+fn serialize_command_ack(
+    value: CommandAck,
+    topic_name: &mut String<64>,
+    msg_buffer: &mut [u8],
+) -> Serialized {
+    set_topic(topic_name, &[MQTT_CFG_ACK_TOPIC, &value.field])?;
+    let size = copy_payload(msg_buffer, &value.to_bytes())?;
+    let (qos, retain) = delivery(Stream::CommandAck);
+
+    Ok((size, qos, retain))
+}
+
+#[inline(always)]
+fn serialize_system_message(
+    value: SystemMessage,
+    topic_name: &mut String<64>,
+    msg_buffer: &mut [u8],
+) -> Serialized {
+    set_topic(topic_name, &[&value.topic])?;
+    let size = copy_payload(msg_buffer, &value.payload)?;
+    let (qos, retain) = delivery(Stream::Status);
+
+    Ok((size, qos, retain))
 }
@@ -0,0 +1,152 @@
+//! How outgoing MQTT messages are laid out: their topics, their payloads in the send buffer and
+//! the age put in front of replayed samples. Also the order the charge channels are served in.
+//!
+//! The MQTT task does the sending; this only fills buffers and picks the next channel.
+//! `tools/host-tests` includes this file on the host and tests it there, so keep it to `core`,
+//! `heapless` and [`telemetry_layout`].
+//!
+//! [`telemetry_layout`]: crate::telemetry_layout
+
+#[cfg(feature = "json-payload")]
+use core::fmt::Write;
+use core::task::Poll;
+
+use heapless::String;
+
+#[cfg(not(feature = "json-payload"))]
+use crate::telemetry_layout::{self, ByteOrder};
+
+/// Appended to the series topics, so binary and JSON consumers never see each other's payloads.
+pub const SERIES_FORMAT_SUFFIX: &str = if cfg!(feature = "json-payload") {
+    "/json"
+} else {
+    ""
+};
+/// Appended to the series topic of a sample published after a reconnect.
+pub const REPLAY_TOPIC_SUFFIX: &str = "/replay";
+
+/// A message that doesn't fit the send buffers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerializeError {
+    TopicTooLong,
+    PayloadTooLong,
+}
+
+/// Sets `topic_name` to `prefix` followed by `parts`.
+pub fn set_topic(
+    topic_name: &mut String<64>,
+    prefix: &str,
+    parts: &[&str],
+) -> Result<(), SerializeError> {
+    topic_name.clear();
+    topic_name
+        .push_str(prefix)
+        .map_err(|_| SerializeError::TopicTooLong)?;
+    for part in parts {
+        topic_name
+            .push_str(part)
+            .map_err(|_| SerializeError::TopicTooLong)?;
+    }
+    Ok(())
+}
+
+/// `protector`, plus [`SERIES_FORMAT_SUFFIX`].
+pub fn set_protector_topic(
+    topic_name: &mut String<64>,
+    prefix: &str,
+) -> Result<(), SerializeError> {
+    set_topic(topic_name, prefix, &["protector", SERIES_FORMAT_SUFFIX])
+}
+
+/// `ch<n>/series`, plus [`SERIES_FORMAT_SUFFIX`].
+pub fn set_charge_channel_topic(
+    topic_name: &mut String<64>,
+    prefix: &str,
+    ch: u8,
+) -> Result<(), SerializeError> {
+    set_topic(
+        topic_name,
+        prefix,
+        &[channel_name(ch), "/series", SERIES_FORMAT_SUFFIX],
+    )
+}
+
+/// Turns a series topic set above into its [`REPLAY_TOPIC_SUFFIX`] counterpart.
+pub fn push_replay_suffix(topic_name: &mut String<64>) -> Result<(), SerializeError> {
+    topic_name
+        .push_str(REPLAY_TOPIC_SUFFIX)
+        .map_err(|_| SerializeError::TopicTooLong)
+}
+
+fn channel_name(ch: u8) -> &'static str {
+    match ch {
+        0 => "ch0",
+        1 => "ch1",
+        2 => "ch2",
+        3 => "ch3",
+        _ => "unknown",
+    }
+}
+
+/// Polls `count` charge channels in order from `start`, wrapping around, and returns the first
+/// ready one with its index.
+pub fn poll_channels<T>(
+    start: usize,
+    count: usize,
+    mut poll: impl FnMut(usize) -> Poll<T>,
+) -> Poll<(usize, T)> {
+    for offset in 0..count {
+        let index = (start + offset) % count;
+        if let Poll::Ready(value) = poll(index) {
+            return Poll::Ready((index, value));
+        }
+    }
+    Poll::Pending
+}
+
+/// Where [`poll_channels`] starts after serving `index`: just past it, so every ready channel
+/// gets its turn before `index` is served again.
+pub fn next_channel(index: usize, count: usize) -> usize {
+    (index + 1) % count
+}
+
+/// Copies `payload` to the start of `msg_buffer` and returns its length.
+pub fn copy_payload(msg_buffer: &mut [u8], payload: &[u8]) -> Result<usize, SerializeError> {
+    msg_buffer
+        .get_mut(..payload.len())
+        .ok_or(SerializeError::PayloadTooLong)?
+        .copy_from_slice(payload);
+    Ok(payload.len())
+}
+
+/// Puts `age_ms` in front of the `size` bytes serialized into `msg_buffer` and returns the new
+/// size. Binary payloads get a `u32` of milliseconds in the telemetry byte order, JSON objects an
+/// `age_ms` member as their first. Ages past `u32::MAX` are capped.
+pub fn prepend_age(
+    msg_buffer: &mut [u8],
+    size: usize,
+    age_ms: u64,
+) -> Result<usize, SerializeError> {
+    let age_ms = age_ms.min(u32::MAX as u64) as u32;
+    #[cfg(not(feature = "json-payload"))]
+    let (prefix, replaced) = match telemetry_layout::byte_order() {
+        ByteOrder::Little => (age_ms.to_le_bytes(), 0),
+        ByteOrder::Big => (age_ms.to_be_bytes(), 0),
+    };
+    // Replaces the opening brace.
+    #[cfg(feature = "json-payload")]
+    let (prefix, replaced) = {
+        let mut prefix = String::<24>::new();
+        write!(prefix, "{{\"age_ms\":{},", age_ms).ok();
+        (prefix, 1)
+    };
+
+    let prefix: &[u8] = prefix.as_ref();
+    let total = prefix.len() + size - replaced;
+    if total > msg_buffer.len() {
+        return Err(SerializeError::PayloadTooLong);
+    }
+    msg_buffer.copy_within(replaced..size, prefix.len());
+    msg_buffer[..prefix.len()].copy_from_slice(prefix);
+    Ok(total)
+}
@@ -0,0 +1,142 @@
+use core::{cell::RefCell, fmt::Write};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use heapless::String;
+
+use crate::bus::{publish_status, ChargeChannelSeriesItem, ProtectorSeriesItem};
+
+/// Minimum time between two peak publications of the same source. A peak climbs on nearly every
+/// sample while a load ramps up, and the status queue is shared with acks and contracts.
+const PUBLISH_PERIOD: Duration = Duration::from_secs(10);
+
+const CHANNEL_FIELDS: [&str; 2] = ["watts", "amps"];
+const PROTECTOR_FIELDS: [&str; 3] = ["temperature", "amps", "watts"];
+
+/// Highest value seen since boot or the last reset, with the time it was seen.
+#[derive(Debug, Clone, Copy)]
+pub struct Peak {
+    pub value: f64,
+    pub at: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PeakSet<const N: usize> {
+    peaks: [Option<Peak>; N],
+    changed: bool,
+    published_at: Option<Instant>,
+}
+
+impl<const N: usize> PeakSet<N> {
+    const fn new() -> Self {
+        Self {
+            peaks: [None; N],
+            changed: false,
+            published_at: None,
+        }
+    }
+
+    fn record(&mut self, values: [f64; N], at: Instant) {
+        for (peak, value) in self.peaks.iter_mut().zip(values) {
+            if peak.map_or(true, |peak| value > peak.value) {
+                *peak = Some(Peak { value, at });
+                self.changed = true;
+            }
+        }
+    }
+
+    /// Returns the peaks when they changed and the last publication is old enough.
+    fn take_due(&mut self, now: Instant) -> Option<[Option<Peak>; N]> {
+        let due = self
+            .published_at
+            .map_or(true, |published_at| now - published_at >= PUBLISH_PERIOD);
+        if !self.changed || !due {
+            return None;
+        }
+
+        self.changed = false;
+        self.published_at = Some(now);
+        Some(self.peaks)
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+static CHANNELS: Mutex<CriticalSectionRawMutex, RefCell<[PeakSet<2>; 4]>> =
+    Mutex::new(RefCell::new([PeakSet::new(); 4]));
+
+static PROTECTOR: Mutex<CriticalSectionRawMutex, RefCell<PeakSet<3>>> =
+    Mutex::new(RefCell::new(PeakSet::new()));
+
+/// Tracks the output power and current of a channel, publishing `ch<n>/peaks` when they rise.
+pub fn record_charge_channel(index: usize, value: &ChargeChannelSeriesItem) {
+    let now = Instant::now();
+    let due = CHANNELS.lock(|channels| {
+        let peaks = &mut channels.borrow_mut()[index];
+        peaks.record([value.watts, value.amps], now);
+        peaks.take_due(now)
+    });
+
+    if let Some(peaks) = due {
+        publish_channel(index, &peaks);
+    }
+}
+
+/// Tracks the hotter of the two temperatures and the input current and power, publishing
+/// `protector/peaks` when they rise.
+#[cfg_attr(no_protector, allow(dead_code))]
+pub fn record_protector(value: &ProtectorSeriesItem) {
+    let now = Instant::now();
+    let temperature = value.temperature_0.max(value.temperature_1) as f64;
+    let due = PROTECTOR.lock(|protector| {
+        let mut peaks = protector.borrow_mut();
+        peaks.record([temperature, value.raw_amps, value.watts], now);
+        peaks.take_due(now)
+    });
+
+    if let Some(peaks) = due {
+        publish("protector/peaks", &PROTECTOR_FIELDS, &peaks);
+    }
+}
+
+pub fn reset_charge_channel(index: usize) {
+    CHANNELS.lock(|channels| channels.borrow_mut()[index].reset());
+    publish_channel(index, &[None; 2]);
+}
+
+pub fn reset_protector() {
+    PROTECTOR.lock(|protector| protector.borrow_mut().reset());
+    publish("protector/peaks", &PROTECTOR_FIELDS, &[None; 3]);
+}
+
+fn publish_channel(index: usize, peaks: &[Option<Peak>; 2]) {
+    let mut topic = String::<16>::new();
+    write!(topic, "ch{}/peaks", index).ok();
+    publish(&topic, &CHANNEL_FIELDS, peaks);
+}
+
+/// Publishes `{"<field>":{"value":..,"uptime_ms":..},...}`, with `null` for fields that have no
+/// reading since the last reset.
+fn publish<const N: usize>(topic: &str, fields: &[&str; N], peaks: &[Option<Peak>; N]) {
+    let mut json = String::<192>::new();
+    for (index, (field, peak)) in fields.iter().zip(peaks).enumerate() {
+        let separator = if index == 0 { "{" } else { "," };
+        match peak {
+            Some(peak) => write!(
+                json,
+                "{}\"{}\":{{\"value\":{:.3},\"uptime_ms\":{}}}",
+                separator,
+                field,
+                peak.value,
+                peak.at.as_millis()
+            )
+            .ok(),
+            None => write!(json, "{}\"{}\":null", separator, field).ok(),
+        };
+    }
+    json.push('}').ok();
+
+    publish_status(topic, json.as_bytes());
+}
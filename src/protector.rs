@@ -1,40 +1,141 @@
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
-use embassy_futures::select::{select3, Either3};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Ticker};
+// With `no_protector` the task is never spawned; only the shared types are used.
+#![cfg_attr(no_protector, allow(dead_code))]
+
+use core::{cell::Cell, fmt::Write};
+
+use embassy_futures::select::{select4, Either4};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+};
+use embassy_time::{Duration, Instant, Ticker};
 use embedded_hal_async::i2c::I2c;
 use esp_hal::{
-    gpio::{AnyPin, Flex, Level, Pull},
+    gpio::{AnyPin, Flex},
     peripherals::I2C0,
     Async,
 };
 use gx21m15::{Gx21m15, Gx21m15Config, OsFailQueueSize};
+use heapless::String;
 use ina226::INA226;
 
-use crate::bus::{
-    ProtectorSeriesItem, ProtectorSeriesItemChannel, PROTECTOR_SERIES_ITEM_CHANNEL,
-    VIN_STATUS_CFG_CHANNEL,
+#[cfg(not(no_protector))]
+use crate::snapshot;
+use crate::{
+    board::{GX21M15_ADDRESSES, PROTECTOR_INA226_ADDRESS},
+    bus::{
+        publish_system_message, ProtectorSeriesItem, ProtectorSeriesItemChannel, VinAction,
+        PROTECTOR_CFG_CHANNEL, PROTECTOR_SERIES_ITEM_CHANNEL, VIN_STATUS_CFG_CHANNEL,
+    },
+    calibration::{self, CalibrationConfig, PROTECTOR_DEVICE},
+    fan::Fan,
+    helper::{diverges, MissedReads, MovingAverage, POWER_MISMATCH_FLOOR_WATTS},
+    history,
+    i2c_bus::{Priority, SharedI2c},
+    i2c_trace::TracedI2c,
+    peaks, schema,
+    settings::{self, Settings},
+    system,
 };
 
 const MAX_FAIL_TIMES: u8 = 3;
 
+/// Upper bound of the input current averaging window, in samples.
+pub const MAX_CURRENT_AVG_WINDOW: usize = 8;
+
+/// Runtime configuration commands for the protector, sent through [`PROTECTOR_CFG_CHANNEL`].
+#[derive(Debug, Clone, Copy)]
+pub enum ProtectorCommand {
+    /// Number of samples (`1..=MAX_CURRENT_AVG_WINDOW`) averaged into the reported input
+    /// current. `1` disables averaging.
+    CurrentAvgWindow(u8),
+}
+
+/// Input current magnitude below which the direction is reported as idle, in amps.
+const IDLE_CURRENT_AMPS: f64 = 0.05;
+
+/// Default temperature at which the sensors' OS output cuts VIN in hardware; see
+/// `temperature_shutdown_celsius`.
+pub const OVER_TEMPERATURE_SHUTDOWN_CELSIUS: u8 = 70;
+
+/// Degrees below `thermal_throttle_celsius` the temperature has to fall before throttling ends.
+const THROTTLE_RELEASE_CELSIUS: f32 = 2.0;
+
+/// Consecutive samples the input current has to exceed `over_current_milliamps` before VIN is
+/// cut, so a single noisy reading doesn't trip it.
+const OVER_CURRENT_SAMPLES: u8 = 3;
+
+/// Longest a `vin-override` may hold VIN, in seconds.
+pub const MAX_VIN_OVERRIDE_SECS: u16 = 3600;
+
+/// How often a running VIN override publishes its remaining time.
+const VIN_OVERRIDE_REPORT_PERIOD: Duration = Duration::from_secs(10);
+
+/// Cap on every charge channel's output limit while the board is hot, set by the protector.
+static THERMAL_LIMIT_WATTS: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<u8>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+
+/// The output limit every charge channel is held to because of temperature, if any.
+pub fn thermal_limit_watts() -> Option<u8> {
+    THERMAL_LIMIT_WATTS.lock(|limit| limit.get())
+}
+
+/// Share of `input_power_limit_watts` the input has to fall below before the channels' limits
+/// are raised again, in percent.
+const INPUT_LIMIT_RELEASE_PERCENT: f64 = 85.0;
+
+/// Minimum time between two reductions of the input power throttle. Every change of the output
+/// limit makes the SW3526 renegotiate, so the loop waits for the sinks to settle first.
+const INPUT_LIMIT_LOWER_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Minimum time between two steps back up, slower than going down so it doesn't oscillate.
+const INPUT_LIMIT_RAISE_INTERVAL: Duration = Duration::from_secs(10);
+
+const INPUT_LIMIT_RAISE_STEP_WATTS: u8 = 2;
+
+/// Cap on every charge channel's output limit that keeps the input under
+/// `input_power_limit_watts`, set by the protector.
+static INPUT_LIMIT_WATTS: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<u8>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+
+/// The output limit every charge channel is held to because of the input power ceiling, if any.
+pub fn input_limit_watts() -> Option<u8> {
+    INPUT_LIMIT_WATTS.lock(|limit| limit.get())
+}
+
+/// VIN was cut for over-current and stays cut until `vin-status` turns it back on. Set and
+/// cleared by the protector; read by the commands that would turn VIN on.
+static OVER_CURRENT_LATCHED: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<bool>> =
+    blocking_mutex::Mutex::new(Cell::new(false));
+
+/// Whether an over-current trip is holding VIN off.
+pub fn over_current_latched() -> bool {
+    OVER_CURRENT_LATCHED.lock(|latched| latched.get())
+}
+
+fn set_over_current_latched(latched: bool) {
+    OVER_CURRENT_LATCHED.lock(|cell| cell.set(latched));
+}
+
 #[embassy_executor::task]
 pub async fn task(
     i2c_mutex: &'static Mutex<CriticalSectionRawMutex, esp_hal::i2c::I2c<'static, I2C0, Async>>,
     vin_ctl_pin: Flex<'static, AnyPin>,
+    fan: Fan,
 ) {
-    let i2c_dev = I2cDevice::new(i2c_mutex);
-    let sensor_0 = Gx21m15::new(i2c_dev, 0x49);
-    let i2c_dev = I2cDevice::new(i2c_mutex);
-    let sensor_1 = Gx21m15::new(i2c_dev, 0x48);
-    let i2c_dev = I2cDevice::new(i2c_mutex);
-    let ina226 = INA226::new(i2c_dev, 0x43);
+    let i2c_dev = TracedI2c::new(SharedI2c::new(i2c_mutex, Priority::High));
+    let sensor_0 = Gx21m15::new(i2c_dev, GX21M15_ADDRESSES[0]);
+    let i2c_dev = TracedI2c::new(SharedI2c::new(i2c_mutex, Priority::High));
+    let sensor_1 = Gx21m15::new(i2c_dev, GX21M15_ADDRESSES[1]);
+    let i2c_dev = TracedI2c::new(SharedI2c::new(i2c_mutex, Priority::High));
+    let ina226 = INA226::new(i2c_dev, PROTECTOR_INA226_ADDRESS);
 
     let mut protector = Protector::new(
         sensor_0,
         sensor_1,
         ina226,
         vin_ctl_pin,
+        fan,
         &PROTECTOR_SERIES_ITEM_CHANNEL,
     );
 
@@ -49,27 +150,36 @@ pub async fn task(
         // init
         if let Err(err) = protector.init().await {
             log::error!("Failed to init protector: {:?}", err);
+            system::record_protector_online(false);
+            schema::record_protector(false);
             continue;
         }
+        system::record_protector_online(true);
+        schema::record_protector(true);
 
         // run
         while fail_times < MAX_FAIL_TIMES {
             ticker.next().await;
 
+            protector.update_vin_override();
+            protector.apply_pending_vin();
+
             let receive_vin_state_cfg = VIN_STATUS_CFG_CHANNEL.receive();
+            let receive_protector_cfg = PROTECTOR_CFG_CHANNEL.receive();
 
-            let future = select3(
+            let future = select4(
                 ticker.next(),
                 protector.run_task_once(),
                 receive_vin_state_cfg,
+                receive_protector_cfg,
             )
             .await;
             match future {
-                Either3::First(_) => {
+                Either4::First(_) => {
                     log::warn!("read temperature time out");
                     continue;
                 }
-                Either3::Second(res) => match res {
+                Either4::Second(res) => match res {
                     Ok(_) => {}
                     Err(err) => {
                         fail_times += 1;
@@ -77,14 +187,8 @@ pub async fn task(
                         continue;
                     }
                 },
-                Either3::Third(res) => match res {
-                    VinState::Normal => {
-                        protector.turn_on_vin();
-                    }
-                    _ => {
-                        protector.turn_off_vin();
-                    }
-                },
+                Either4::Third(action) => protector.apply_vin_action(action),
+                Either4::Fourth(cmd) => protector.apply_command(cmd),
             }
 
             fail_times = 0;
@@ -92,21 +196,69 @@ pub async fn task(
     }
 }
 
-#[derive(Debug)]
+/// Cuts VIN once the device has been unable to report for `offline_shutdown_secs`, for
+/// setups that must not run unattended. Counting starts at the first connection to the broker,
+/// and the guard trips once per outage: a VIN turned back on stays on until the broker has
+/// answered again and is lost again. VIN stays off until a `vin-status` command turns it back
+/// on; the state is published to `system/offline-shutdown` once back online.
+#[embassy_executor::task]
+pub async fn offline_guard_task() {
+    let mut ticker = Ticker::every(Duration::from_secs(1));
+    // The contact the last trip followed, so one outage trips once.
+    let mut tripped_after: Option<Instant> = None;
+
+    loop {
+        ticker.next().await;
+
+        let limit_secs = settings::get().offline_shutdown_secs;
+        let Some(last_contact) = system::last_broker_contact() else {
+            continue;
+        };
+        if limit_secs == 0
+            || tripped_after == Some(last_contact)
+            || last_contact.elapsed().as_secs() < limit_secs as u64
+        {
+            continue;
+        }
+
+        // Never wait on the queue here; a full one is retried on the next tick.
+        if VIN_STATUS_CFG_CHANNEL.try_send(VinAction::Cut).is_err() {
+            log::warn!("VIN queue full, offline shutdown deferred");
+            continue;
+        }
+
+        log::warn!("Offline for {}s, cutting VIN", limit_secs);
+        tripped_after = Some(last_contact);
+        system::set_offline_shutdown(true);
+        publish_system_message("offline-shutdown", b"1");
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct TemperatureConfig {
     hysteresis: f32,
     over_shutdown: f32,
+    fan_start: f32,
+    fan_full: f32,
 }
 
-impl Default for TemperatureConfig {
-    fn default() -> Self {
+impl TemperatureConfig {
+    fn from_settings(settings: &Settings) -> Self {
         Self {
-            hysteresis: 60.0,
-            over_shutdown: 70.0,
+            hysteresis: settings.temperature_hysteresis_celsius,
+            over_shutdown: settings.temperature_shutdown_celsius,
+            fan_start: settings.fan_start_celsius as f32,
+            fan_full: settings.fan_full_celsius as f32,
         }
     }
 }
 
+impl Default for TemperatureConfig {
+    fn default() -> Self {
+        Self::from_settings(&Settings::DEFAULT)
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum VinState {
@@ -125,26 +277,165 @@ impl From<VinState> for u8 {
     }
 }
 
-impl From<u8> for VinState {
-    fn from(vin_state: u8) -> Self {
+impl TryFrom<u8> for VinState {
+    type Error = u8;
+
+    fn try_from(vin_state: u8) -> Result<Self, Self::Error> {
         match vin_state {
-            0 => Self::Normal,
-            1 => Self::Shutdown,
-            2 => Self::Protection,
-            _ => unreachable!(),
+            0 => Ok(Self::Normal),
+            1 => Ok(Self::Shutdown),
+            2 => Ok(Self::Protection),
+            _ => Err(vin_state),
+        }
+    }
+}
+
+/// Which protection rules are active, one bit each, carried in the protector telemetry and
+/// published by name to `system/protection` when they change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectionReasons(u16);
+
+impl ProtectionReasons {
+    pub const NONE: Self = Self(0);
+    /// VIN switched off by `vin-status`, `all-off` or the end of an override.
+    pub const VIN_OFF: Self = Self(1 << 0);
+    /// The board's own protection cut VIN.
+    pub const HARDWARE: Self = Self(1 << 1);
+    /// Output limits capped by thermal throttling.
+    pub const THERMAL: Self = Self(1 << 2);
+    /// VIN cut after being offline for `offline_shutdown_secs`.
+    pub const OFFLINE: Self = Self(1 << 3);
+    /// A `vin-override` is running.
+    pub const OVERRIDE: Self = Self(1 << 4);
+    /// At least one channel's soft fuse is blown.
+    pub const FUSE: Self = Self(1 << 5);
+    /// Every output held off by `all-off`.
+    pub const OUTPUTS_OFF: Self = Self(1 << 6);
+    /// Current flowing back into the supply.
+    pub const REVERSE_CURRENT: Self = Self(1 << 7);
+    /// Output limits capped to keep the input under `input_power_limit_watts`.
+    pub const INPUT_POWER: Self = Self(1 << 8);
+    /// VIN cut by the input over-current protection, until a `vin-status` command turns it on.
+    pub const OVER_CURRENT: Self = Self(1 << 9);
+
+    /// Names in bit order.
+    const NAMES: [&'static str; 10] = [
+        "vin-off",
+        "hardware",
+        "thermal",
+        "offline",
+        "override",
+        "fuse",
+        "outputs-off",
+        "reverse-current",
+        "input-power",
+        "over-current",
+    ];
+
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    pub const fn contains(self, reason: Self) -> bool {
+        self.0 & reason.0 == reason.0
+    }
+
+    fn set(&mut self, reason: Self, active: bool) {
+        if active {
+            self.0 |= reason.0;
+        } else {
+            self.0 &= !reason.0;
+        }
+    }
+
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        Self::NAMES
+            .into_iter()
+            .enumerate()
+            .filter(move |(bit, _)| self.0 & (1 << bit) != 0)
+            .map(|(_, name)| name)
+    }
+}
+
+/// Sensor config with the OS output in comparator mode, active low, and the given fail-queue
+/// size (a validated `os_fail_queue_size`).
+fn gx21m15_config(os_fail_queue: u8) -> Gx21m15Config {
+    let size = match os_fail_queue {
+        1 => OsFailQueueSize::One,
+        2 => OsFailQueueSize::Two,
+        8 => OsFailQueueSize::Eight,
+        _ => OsFailQueueSize::Four,
+    };
+
+    let mut config = Gx21m15Config::new();
+    config
+        .set_os_fail_queue_size(size)
+        .set_os_mode(false)
+        .set_os_polarity(false)
+        .set_shutdown(false);
+    config
+}
+
+/// Direction of the input current as seen by the board.
+///
+/// The input INA226 is wired with its shunt reversed relative to the charge channels, so the
+/// protector negates its reading: positive `amps`/`watts` always mean power flowing from the
+/// supply into the board (`Forward`), the same sign convention the charge channels use for
+/// power delivered to their load. `Reverse` means current is being pushed back into the supply,
+/// which should never happen in normal operation and is flagged as a potential fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CurrentDirection {
+    Idle = 0,
+    Forward = 1,
+    Reverse = 2,
+}
+
+impl CurrentDirection {
+    fn from_amps(amps: f64) -> Self {
+        if amps > IDLE_CURRENT_AMPS {
+            Self::Forward
+        } else if amps < -IDLE_CURRENT_AMPS {
+            Self::Reverse
+        } else {
+            Self::Idle
         }
     }
 }
 
+/// A `vin-override` in progress.
+#[derive(Debug, Clone, Copy)]
+struct VinOverride {
+    state: VinState,
+    until: Instant,
+    /// Whether VIN was set to off before the override, and goes back to off after it.
+    restore_shutdown: bool,
+    reported_at: Instant,
+}
+
 struct Protector<'a, I2C> {
     gx21m15_0: Gx21m15<I2C>,
     gx21m15_1: Gx21m15<I2C>,
     ina226: INA226<I2C>,
     vin_ctl_pin: Flex<'a, AnyPin>,
+    fan: Fan,
     temperature_config: TemperatureConfig,
+    calibration_config: CalibrationConfig,
     temperature_channel: &'a ProtectorSeriesItemChannel,
     current_state: ProtectorSeriesItem,
+    current_average: MovingAverage<MAX_CURRENT_AVG_WINDOW>,
+    missed_reads: MissedReads,
     shutdown: bool,
+    /// When VIN last switched, for `vin_min_on_secs`/`vin_min_off_secs`.
+    vin_switched_at: Instant,
+    /// A switch held back by the minimum on/off time: `true` to cut VIN.
+    pending_shutdown: Option<bool>,
+    vin_override: Option<VinOverride>,
+    input_limit_changed_at: Instant,
+    /// OS fail-queue size the sensors were last configured with.
+    os_fail_queue_size: u8,
+    /// Samples in a row the input current has been over `over_current_milliamps`.
+    over_current_samples: u8,
 }
 
 impl<'a, I2C, E> Protector<'a, I2C>
@@ -157,6 +448,7 @@ where
         gx21m15_1: Gx21m15<I2C>,
         ina226: INA226<I2C>,
         vin_ctl_pin: Flex<'a, AnyPin>,
+        fan: Fan,
         temperature_channel: &'a ProtectorSeriesItemChannel,
     ) -> Self {
         Self::new_with_config(
@@ -164,8 +456,10 @@ where
             gx21m15_1,
             ina226,
             vin_ctl_pin,
+            fan,
             temperature_channel,
             TemperatureConfig::default(),
+            CalibrationConfig::default(),
         )
     }
 
@@ -175,31 +469,37 @@ where
         ina226: INA226<I2C>,
 
         vin_ctl_pin: Flex<'a, AnyPin>,
+        fan: Fan,
         temperature_channel: &'a ProtectorSeriesItemChannel,
         config: TemperatureConfig,
+        calibration_config: CalibrationConfig,
     ) -> Self {
         Self {
             gx21m15_0,
             gx21m15_1,
             ina226,
             vin_ctl_pin,
+            fan,
             temperature_config: config,
+            calibration_config,
             temperature_channel,
             current_state: ProtectorSeriesItem::default(),
+            current_average: MovingAverage::new(settings::get().current_avg_window as usize),
+            missed_reads: MissedReads::new(),
             shutdown: false,
+            vin_switched_at: Instant::from_ticks(0),
+            pending_shutdown: None,
+            vin_override: None,
+            input_limit_changed_at: Instant::now(),
+            os_fail_queue_size: 0,
+            over_current_samples: 0,
         }
     }
 
     async fn init(&mut self) -> Result<(), E> {
         macro_rules! init_gx21m15 {
             ($gx21m15:expr) => {{
-                let mut config = Gx21m15Config::new();
-
-                config
-                    .set_os_fail_queue_size(OsFailQueueSize::Four)
-                    .set_os_mode(false)
-                    .set_os_polarity(false)
-                    .set_shutdown(false);
+                let config = gx21m15_config(os_fail_queue);
 
                 match $gx21m15.set_config(&config).await {
                     Ok(_) => {
@@ -241,8 +541,10 @@ where
             }};
         }
 
+        let os_fail_queue = settings::get().os_fail_queue_size;
         init_gx21m15!(self.gx21m15_0);
         init_gx21m15!(self.gx21m15_1);
+        self.set_os_fail_queue(os_fail_queue);
 
         self.init_ina226().await?;
 
@@ -258,33 +560,141 @@ where
         };
 
         self.ina226.set_configuration(&config).await?;
-        self.ina226.callibrate(0.01, 5.0).await?;
+        self.ina226
+            .callibrate(
+                self.calibration_config.shunt_ohms,
+                self.calibration_config.max_current_amps,
+            )
+            .await?;
+
+        Ok(())
+    }
 
+    /// Rewrites the config register of both sensors with a new OS fail-queue size.
+    async fn apply_os_fail_queue(&mut self, size: u8) -> Result<(), E> {
+        let config = gx21m15_config(size);
+        self.gx21m15_0.set_config(&config).await?;
+        self.gx21m15_1.set_config(&config).await?;
+        self.set_os_fail_queue(size);
         Ok(())
     }
 
+    /// Takes a new temperature config, reprogramming the over-temperature thresholds of both
+    /// sensors if they changed. If either write fails the config isn't recorded, so the next
+    /// cycle tries again.
+    async fn apply_temperature_config(&mut self, config: TemperatureConfig) -> Result<(), E> {
+        if config.hysteresis != self.temperature_config.hysteresis
+            || config.over_shutdown != self.temperature_config.over_shutdown
+        {
+            for sensor in [&mut self.gx21m15_0, &mut self.gx21m15_1] {
+                sensor.set_temperature_hysteresis(config.hysteresis).await?;
+                sensor
+                    .set_temperature_over_shutdown(config.over_shutdown)
+                    .await?;
+            }
+            log::info!(
+                "Temperature over shutdown: {:.1}°C, hysteresis: {:.1}°C",
+                config.over_shutdown,
+                config.hysteresis
+            );
+        }
+        self.temperature_config = config;
+        Ok(())
+    }
+
+    /// Records the fail-queue size the sensors run with and publishes it to
+    /// `system/os-fail-queue` as `{"size":<n>}`.
+    fn set_os_fail_queue(&mut self, size: u8) {
+        log::info!("OS fail queue: {}", size);
+        self.os_fail_queue_size = size;
+
+        let mut json = String::<16>::new();
+        write!(json, "{{\"size\":{}}}", size).ok();
+        publish_system_message("os-fail-queue", json.as_bytes());
+    }
+
     pub async fn run_task_once(&mut self) -> Result<(), E> {
+        let settings = settings::get();
+
+        if settings.os_fail_queue_size != self.os_fail_queue_size {
+            self.apply_os_fail_queue(settings.os_fail_queue_size)
+                .await?;
+        }
+
+        let temperature_config = TemperatureConfig::from_settings(&settings);
+        if temperature_config != self.temperature_config {
+            self.apply_temperature_config(temperature_config).await?;
+        }
+
         self.current_state.temperature_0 = self.gx21m15_0.get_temperature().await?;
         self.current_state.temperature_1 = self.gx21m15_1.get_temperature().await?;
 
-        self.current_state.millivolts = self.ina226.bus_voltage_millivolts().await?;
-        match self.ina226.current_amps().await? {
-            Some(amps) => {
-                self.current_state.amps = -amps;
-            }
-            None => {
-                log::info!("Failed to read input current");
-            }
+        self.update_thermal_limit(&settings);
+
+        let calibration = calibration::get(PROTECTOR_DEVICE);
+
+        self.current_state.millivolts =
+            calibration.millivolts(self.ina226.bus_voltage_millivolts().await?);
+        let amps = self.ina226.current_amps().await?;
+        if let Some(amps) = amps {
+            let amps = calibration.amps(amps);
+            self.current_state.raw_amps = -amps;
+            self.current_state.amps = self.current_average.push(-amps);
         }
-        match self.ina226.power_watts().await? {
-            Some(watts) => {
-                self.current_state.watts = watts;
-            }
-            None => {
-                log::info!("Failed to read input power");
-            }
+        self.current_state.amps_stale = amps.is_none();
+        let direction = CurrentDirection::from_amps(self.current_state.amps);
+        if direction == CurrentDirection::Reverse
+            && self.current_state.direction != CurrentDirection::Reverse
+        {
+            log::warn!(
+                "Reverse input current detected: {:.3}A",
+                self.current_state.amps
+            );
+        }
+        self.current_state.direction = direction;
+
+        let watts = self.ina226.power_watts().await?;
+        if let Some(watts) = watts {
+            let watts = calibration.watts(watts);
+            // The power register is unsigned; carry over the sign of the current read this cycle.
+            // The averaged current lags behind it and may still have the old sign.
+            self.current_state.watts = if self.current_state.raw_amps < 0.0 {
+                -watts
+            } else {
+                watts
+            };
+        }
+        self.current_state.watts_stale = watts.is_none();
+
+        if let Some((samples, amps, watts)) = self.missed_reads.record(
+            self.current_state.amps_stale,
+            self.current_state.watts_stale,
+        ) {
+            log::warn!(
+                "Protector: INA226 returned no current in {} and no power in {} of {} samples",
+                amps,
+                watts,
+                samples
+            );
         }
 
+        if settings.power_cross_check {
+            self.current_state.computed_watts =
+                self.current_state.millivolts / 1000.0 * self.current_state.raw_amps;
+            self.current_state.power_mismatch = diverges(
+                self.current_state.watts,
+                self.current_state.computed_watts,
+                settings.power_mismatch_percent,
+                POWER_MISMATCH_FLOOR_WATTS,
+            );
+        } else {
+            self.current_state.computed_watts = 0.0;
+            self.current_state.power_mismatch = false;
+        }
+
+        self.update_input_limit(&settings);
+        self.update_over_current(&settings);
+
         log::info!(
             "get level: {:?}, get output level: {:?}",
             self.vin_ctl_pin.get_level(),
@@ -292,28 +702,476 @@ where
         );
         self.current_state.vin_status = if self.shutdown {
             VinState::Shutdown
-        } else if matches!(self.vin_ctl_pin.get_level(), Level::High) {
-            VinState::Normal
-        } else {
+        } else if self.vin_ctl_pin.get_level() == system::vin_ctl_cut_level() {
+            // Cut without us asking: the board's own protection pulled the line.
             VinState::Protection
+        } else {
+            VinState::Normal
         };
 
+        // The sensors only pull the line when too hot, so a hardware cut means over-temperature.
+        let temperature = settings.temperature_reduction.reduce(
+            self.current_state.temperature_0,
+            self.current_state.temperature_1,
+        );
+        self.current_state.fan_duty = self.fan.update(
+            temperature,
+            self.temperature_config.fan_start,
+            self.temperature_config.fan_full,
+            matches!(self.current_state.vin_status, VinState::Protection),
+        );
+
+        self.update_protection_reasons();
+
+        #[cfg(not(no_protector))]
+        snapshot::record_protector(self.current_state);
+        peaks::record_protector(&self.current_state);
+        history::record_protector(&self.current_state);
         self.temperature_channel.send(self.current_state).await;
+        self.current_state.seq = self.current_state.seq.wrapping_add(1);
 
         Ok(())
     }
 
-    pub fn turn_off_vin(&mut self) {
-        log::info!("turn_off_vin");
+    /// Keeps the input power under `input_power_limit_watts` by capping every charge channel's
+    /// output limit. Over the ceiling the cap is scaled down by the excess, as far as
+    /// `min_limit_watts`; once the input is comfortably below it again the cap is raised step by
+    /// step and lifted when it reaches `max_limit_watts`. Changes are published to
+    /// `system/input-power-limit`.
+    fn update_input_limit(&mut self, settings: &Settings) {
+        let ceiling = settings.input_power_limit_watts as f64;
+        let watts = self.current_state.watts;
+        let current = input_limit_watts();
+
+        let limit = if settings.input_power_limit_watts == 0 {
+            None
+        } else if self.current_state.watts_stale {
+            return;
+        } else if watts > ceiling {
+            if self.input_limit_changed_at.elapsed() < INPUT_LIMIT_LOWER_INTERVAL {
+                return;
+            }
+            let cap = current.unwrap_or(settings.max_limit_watts);
+            let scaled = ((cap as f64 * ceiling / watts) as u8).min(cap.saturating_sub(1));
+            Some(scaled.max(settings.min_limit_watts))
+        } else {
+            match current {
+                Some(cap)
+                    if watts < ceiling * INPUT_LIMIT_RELEASE_PERCENT / 100.0
+                        && self.input_limit_changed_at.elapsed() >= INPUT_LIMIT_RAISE_INTERVAL =>
+                {
+                    let raised = cap.saturating_add(INPUT_LIMIT_RAISE_STEP_WATTS);
+                    (raised < settings.max_limit_watts).then_some(raised)
+                }
+                _ => current,
+            }
+        };
+
+        if limit == current {
+            return;
+        }
+        INPUT_LIMIT_WATTS.lock(|value| value.set(limit));
+        self.input_limit_changed_at = Instant::now();
+
+        let mut json = String::<96>::new();
+        match limit {
+            Some(limit) => {
+                log::warn!(
+                    "Input {:.1}W over {}W, output limits capped at {}W",
+                    watts,
+                    settings.input_power_limit_watts,
+                    limit
+                );
+                write!(
+                    json,
+                    "{{\"limited\":true,\"input_watts\":{:.1},\"ceiling_watts\":{},\"limit_watts\":{}}}",
+                    watts, settings.input_power_limit_watts, limit
+                )
+                .ok();
+            }
+            None => {
+                log::info!("Input power ceiling released at {:.1}W", watts);
+                write!(
+                    json,
+                    "{{\"limited\":false,\"input_watts\":{:.1},\"ceiling_watts\":{}}}",
+                    watts, settings.input_power_limit_watts
+                )
+                .ok();
+            }
+        }
+        publish_system_message("input-power-limit", json.as_bytes());
+    }
+
+    /// Derives the active protection rules from the state just read, so the mask always agrees
+    /// with `vin_status` in the same sample, and publishes them to `system/protection` on change,
+    /// e.g. `{"mask":36,"reasons":["thermal","fuse"]}`.
+    fn update_protection_reasons(&mut self) {
+        let offline = system::offline_shutdown();
+        let holds = system::output_holds();
+        let vin_status = self.current_state.vin_status;
+
+        let mut reasons = ProtectionReasons::NONE;
+        reasons.set(
+            ProtectionReasons::VIN_OFF,
+            matches!(vin_status, VinState::Shutdown)
+                && !offline
+                && !over_current_latched()
+                && self.vin_override.is_none(),
+        );
+        reasons.set(
+            ProtectionReasons::HARDWARE,
+            matches!(vin_status, VinState::Protection),
+        );
+        reasons.set(ProtectionReasons::THERMAL, thermal_limit_watts().is_some());
+        reasons.set(ProtectionReasons::OFFLINE, offline);
+        reasons.set(ProtectionReasons::OVERRIDE, self.vin_override.is_some());
+        reasons.set(ProtectionReasons::FUSE, holds.fuse_blown.contains(&true));
+        reasons.set(ProtectionReasons::OUTPUTS_OFF, holds.all_off);
+        reasons.set(
+            ProtectionReasons::REVERSE_CURRENT,
+            self.current_state.direction == CurrentDirection::Reverse,
+        );
+        reasons.set(
+            ProtectionReasons::INPUT_POWER,
+            input_limit_watts().is_some(),
+        );
+        reasons.set(ProtectionReasons::OVER_CURRENT, over_current_latched());
+
+        if reasons == self.current_state.protection {
+            return;
+        }
+        self.current_state.protection = reasons;
+
+        let mut json = String::<160>::new();
+        write!(json, "{{\"mask\":{},\"reasons\":[", reasons.bits()).ok();
+        for (index, name) in reasons.names().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            write!(json, "{}\"{}\"", separator, name).ok();
+        }
+        json.push_str("]}").ok();
+        log::info!("Protection: {}", json);
+        publish_system_message("protection", json.as_bytes());
+    }
+
+    /// Load shedding ahead of the hardware cut-off: above `thermal_throttle_celsius` the charge
+    /// channels' output limits are lowered step by step, from the configured maximum at that
+    /// temperature down to the minimum just before `temperature_shutdown_celsius`. VIN
+    /// is only cut if the temperature keeps climbing regardless. Changes are published to
+    /// `system/throttle`.
+    fn update_thermal_limit(&mut self, settings: &Settings) {
+        let threshold = settings.thermal_throttle_celsius as f32;
+        let temperature = settings.temperature_reduction.reduce(
+            self.current_state.temperature_0,
+            self.current_state.temperature_1,
+        );
+        let release = match thermal_limit_watts() {
+            Some(_) => threshold - THROTTLE_RELEASE_CELSIUS,
+            None => threshold,
+        };
+
+        let limit = if settings.thermal_throttle_celsius == 0 || temperature < release {
+            None
+        } else {
+            let span = settings.temperature_shutdown_celsius - threshold;
+            let fraction = ((temperature - threshold) / span).clamp(0.0, 1.0);
+            let range = (settings.max_limit_watts - settings.min_limit_watts) as f32;
+            Some(settings.max_limit_watts - (fraction * range) as u8)
+        };
+
+        if limit == thermal_limit_watts() {
+            return;
+        }
+        THERMAL_LIMIT_WATTS.lock(|current| current.set(limit));
+
+        let mut json = String::<128>::new();
+        match limit {
+            Some(limit) => {
+                log::warn!("{:.1}°C, output limits capped at {}W", temperature, limit);
+                write!(
+                    json,
+                    "{{\"throttled\":true,\"temperature\":{:.1},\"limit_watts\":{},\"channels\":[",
+                    temperature, limit
+                )
+                .ok();
+                for channel in 0..settings.limit_watts.len() {
+                    let separator = if channel == 0 { "" } else { "," };
+                    let capped = settings.effective_limit_watts(channel) > limit;
+                    write!(json, "{}{}", separator, capped).ok();
+                }
+                json.push_str("]}").ok();
+            }
+            None => {
+                log::info!("{:.1}°C, output limits restored", temperature);
+                write!(
+                    json,
+                    "{{\"throttled\":false,\"temperature\":{:.1}}}",
+                    temperature
+                )
+                .ok();
+            }
+        }
+        publish_system_message("throttle", json.as_bytes());
+    }
+
+    /// Cuts VIN once the averaged input current has been over `over_current_milliamps` for
+    /// [`OVER_CURRENT_SAMPLES`] samples in a row. The cut skips the minimum on time and ends any
+    /// override, and it latches: VIN stays off until a `vin-status` command turns it back on.
+    /// Published to `system/over-current` as `{"amps":6.120,"limit_amps":5.000}`.
+    fn update_over_current(&mut self, settings: &Settings) {
+        if over_current_latched() || self.current_state.amps_stale {
+            return;
+        }
+        let limit_amps = settings.over_current_milliamps as f64 / 1000.0;
+        if settings.over_current_milliamps == 0 || self.current_state.amps <= limit_amps {
+            self.over_current_samples = 0;
+            return;
+        }
+
+        self.over_current_samples += 1;
+        if self.over_current_samples < OVER_CURRENT_SAMPLES {
+            return;
+        }
+        self.over_current_samples = 0;
+        set_over_current_latched(true);
+
+        log::error!(
+            "Input over-current: {:.3}A over {:.3}A, cutting VIN",
+            self.current_state.amps,
+            limit_amps
+        );
+        if self.vin_override.take().is_some() {
+            self.publish_vin_override(Some("over-current"));
+        }
+        self.cut_vin();
+
+        let mut json = String::<48>::new();
+        write!(
+            json,
+            "{{\"amps\":{:.3},\"limit_amps\":{:.3}}}",
+            self.current_state.amps, limit_amps
+        )
+        .ok();
+        publish_system_message("over-current", json.as_bytes());
+    }
+
+    /// Applies a config command and records the value in the settings, so `cfg/get` reports
+    /// what the protector runs with.
+    pub fn apply_command(&mut self, cmd: ProtectorCommand) {
+        match cmd {
+            ProtectorCommand::CurrentAvgWindow(window) => {
+                self.current_average.set_window(window as usize);
+                settings::update(|settings| settings.current_avg_window = window);
+                log::info!("current average window: {}", self.current_average.window());
+            }
+        }
+    }
+
+    fn apply_vin_action(&mut self, action: VinAction) {
+        match action {
+            VinAction::Set(VinState::Normal) if over_current_latched() => {
+                log::warn!("VIN turn-on refused: over-current latched");
+            }
+            VinAction::Rearm => {
+                if over_current_latched() {
+                    log::info!("Over-current latch cleared");
+                    set_over_current_latched(false);
+                }
+                self.apply_vin_action(VinAction::Set(VinState::Normal));
+            }
+            VinAction::Set(state) => {
+                // A manual setting becomes what the override would have returned to.
+                if self.vin_override.is_some() {
+                    self.end_vin_override("replaced", false);
+                }
+                self.set_vin(state);
+            }
+            VinAction::Cut => {
+                if self.vin_override.take().is_some() {
+                    self.publish_vin_override(Some("offline"));
+                }
+                self.cut_vin();
+            }
+            VinAction::Override(_, 0) => {
+                if self.vin_override.is_some() {
+                    self.end_vin_override("cancelled", false);
+                }
+            }
+            VinAction::Override(VinState::Normal, _) if over_current_latched() => {
+                log::warn!("VIN override refused: over-current latched");
+            }
+            VinAction::Override(state, secs) => {
+                let restore_shutdown = match self.vin_override {
+                    Some(vin_override) => vin_override.restore_shutdown,
+                    // A deferred switch is what VIN was meant to be.
+                    None => self.pending_shutdown.unwrap_or(self.shutdown),
+                };
+                log::warn!("VIN overridden to {:?} for {}s", state, secs);
+                self.vin_override = Some(VinOverride {
+                    state,
+                    until: Instant::now() + Duration::from_secs(secs as u64),
+                    restore_shutdown,
+                    reported_at: Instant::now(),
+                });
+                self.set_vin(state);
+                self.publish_vin_override(None);
+            }
+        }
+    }
+
+    /// Ends the override once its time is up, or early if it holds VIN on while the board's
+    /// protection has cut it, the board runs hot enough to be throttled, or current flows back
+    /// into the supply. Otherwise publishes the remaining time every report period.
+    fn update_vin_override(&mut self) {
+        let Some(vin_override) = self.vin_override else {
+            return;
+        };
+
+        if Instant::now() >= vin_override.until {
+            self.end_vin_override("expired", false);
+            return;
+        }
 
-        self.shutdown = true;
-        self.vin_ctl_pin.set_as_open_drain(Pull::None);
-        self.vin_ctl_pin.set_low();
+        if matches!(vin_override.state, VinState::Normal) {
+            let danger = if matches!(self.current_state.vin_status, VinState::Protection) {
+                Some("protection")
+            } else if thermal_limit_watts().is_some() {
+                Some("temperature")
+            } else if self.current_state.direction == CurrentDirection::Reverse {
+                Some("reverse-current")
+            } else {
+                None
+            };
+            if let Some(reason) = danger {
+                self.end_vin_override(reason, true);
+                return;
+            }
+        }
+
+        if vin_override.reported_at.elapsed() >= VIN_OVERRIDE_REPORT_PERIOD {
+            self.publish_vin_override(None);
+        }
     }
 
-    pub fn turn_on_vin(&mut self) {
-        log::info!("turn_on_vin");
-        self.shutdown = false;
-        self.vin_ctl_pin.set_as_input(Pull::None);
+    /// Returns VIN to the state the override would restore. With `hazard`, a cut it returns to
+    /// is made at once instead of waiting out the minimum on time.
+    fn end_vin_override(&mut self, reason: &str, hazard: bool) {
+        let Some(vin_override) = self.vin_override.take() else {
+            return;
+        };
+
+        log::warn!("VIN override ended: {}", reason);
+        if hazard && vin_override.restore_shutdown {
+            self.cut_vin();
+        } else {
+            self.switch_vin(vin_override.restore_shutdown);
+        }
+        self.publish_vin_override(Some(reason));
+    }
+
+    /// Publishes `system/vin-override`: `{"state":1,"remaining":42}` while an override runs,
+    /// `{"state":null,"remaining":0,"reason":"expired"}` once it ended.
+    fn publish_vin_override(&mut self, ended: Option<&str>) {
+        let mut json = String::<64>::new();
+        if let Some(reason) = ended {
+            write!(
+                json,
+                "{{\"state\":null,\"remaining\":0,\"reason\":\"{}\"}}",
+                reason
+            )
+            .ok();
+        } else if let Some(vin_override) = self.vin_override.as_mut() {
+            let remaining = vin_override.until.saturating_duration_since(Instant::now());
+            write!(
+                json,
+                "{{\"state\":{},\"remaining\":{}}}",
+                u8::from(vin_override.state),
+                remaining.as_secs()
+            )
+            .ok();
+            vin_override.reported_at = Instant::now();
+        } else {
+            return;
+        }
+        publish_system_message("vin-override", json.as_bytes());
+    }
+
+    fn set_vin(&mut self, state: VinState) {
+        self.switch_vin(!matches!(state, VinState::Normal));
+    }
+
+    /// Cuts VIN (`shutdown`) or lets it through, unless it switched less than the configured
+    /// minimum on/off time ago. Then the switch is deferred, published to `system/vin-deferred`
+    /// as `{"state":1,"remaining":3}`, and made by [`Self::apply_pending_vin`] once the time is
+    /// up. A later request replaces a deferred one. Cuts made for protection go through
+    /// [`Self::cut_vin`] instead.
+    fn switch_vin(&mut self, shutdown: bool) {
+        if shutdown == self.shutdown {
+            self.pending_shutdown = None;
+            self.drive_vin(shutdown);
+            return;
+        }
+
+        let settings = settings::get();
+        let min_secs = if self.shutdown {
+            settings.vin_min_off_secs
+        } else {
+            settings.vin_min_on_secs
+        };
+        let ready_at = self.vin_switched_at + Duration::from_secs(min_secs as u64);
+        if Instant::now() < ready_at {
+            if self.pending_shutdown != Some(shutdown) {
+                let remaining = ready_at.saturating_duration_since(Instant::now());
+                log::warn!(
+                    "VIN {} deferred by {}s",
+                    if shutdown { "cut" } else { "on" },
+                    remaining.as_secs()
+                );
+                let mut json = String::<48>::new();
+                write!(
+                    json,
+                    "{{\"state\":{},\"remaining\":{}}}",
+                    shutdown as u8,
+                    remaining.as_secs()
+                )
+                .ok();
+                publish_system_message("vin-deferred", json.as_bytes());
+            }
+            self.pending_shutdown = Some(shutdown);
+            return;
+        }
+
+        self.pending_shutdown = None;
+        self.vin_switched_at = Instant::now();
+        self.drive_vin(shutdown);
+    }
+
+    /// Cuts VIN at once, past the minimum on time, and drops any deferred switch. VIN still has
+    /// to stay off for the minimum off time before it comes back.
+    fn cut_vin(&mut self) {
+        self.pending_shutdown = None;
+        if !self.shutdown {
+            self.vin_switched_at = Instant::now();
+        }
+        self.drive_vin(true);
+    }
+
+    /// Makes a switch deferred by [`Self::switch_vin`] once the minimum time is up.
+    fn apply_pending_vin(&mut self) {
+        if let Some(shutdown) = self.pending_shutdown {
+            self.switch_vin(shutdown);
+        }
+    }
+
+    fn drive_vin(&mut self, shutdown: bool) {
+        log::info!(
+            "{}",
+            if shutdown {
+                "turn_off_vin"
+            } else {
+                "turn_on_vin"
+            }
+        );
+        self.shutdown = shutdown;
+        system::drive_vin_ctl(&mut self.vin_ctl_pin, shutdown);
     }
 }
@@ -0,0 +1,135 @@
+//! Retained description of the telemetry this device publishes, so a consumer can configure
+//! itself from what it receives on `schema/#`:
+//!
+//! - `schema`: `{"revision":n,"streams":[{"topic":"ch0/series","layout":"charge-channel"},..]}`,
+//!   listing only the series that are currently being published.
+//! - `schema/<layout>`: `{"version":n,"size":bytes,"byte_order":"little"|"big","fields":
+//!   "name:type,.."}` for every layout in this firmware, with the fields in wire order. Reserved
+//!   bytes are given as `reserved[n]`.
+
+use core::{cell::RefCell, fmt::Write};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::String;
+
+use crate::{
+    charge_channel::ChargeChannelOnlineStatus,
+    telemetry_layout::{
+        self, Field, CHARGE_CHANNEL_SERIES_ITEM, CHARGE_CHANNEL_SERIES_VERSION,
+        PROTECTOR_SERIES_ITEM, PROTECTOR_SERIES_VERSION,
+    },
+};
+
+pub const PROTECTOR_LAYOUT: &str = "protector";
+pub const CHARGE_CHANNEL_LAYOUT: &str = "charge-channel";
+
+/// Every layout, as `(name, version, fields)`.
+pub const LAYOUTS: [(&str, u8, &[Field]); 2] = [
+    (
+        PROTECTOR_LAYOUT,
+        PROTECTOR_SERIES_VERSION,
+        PROTECTOR_SERIES_ITEM,
+    ),
+    (
+        CHARGE_CHANNEL_LAYOUT,
+        CHARGE_CHANNEL_SERIES_VERSION,
+        CHARGE_CHANNEL_SERIES_ITEM,
+    ),
+];
+
+/// Which series are being published. `None` until the owning task has finished its init.
+#[derive(Debug, Clone, Copy)]
+struct Streams {
+    protector: Option<bool>,
+    channels: Option<[bool; 4]>,
+    /// Bumped on every change, so the MQTT task knows when to republish.
+    revision: u32,
+}
+
+static STREAMS: Mutex<CriticalSectionRawMutex, RefCell<Streams>> =
+    Mutex::new(RefCell::new(Streams {
+        protector: None,
+        channels: None,
+        revision: 0,
+    }));
+
+/// A channel only publishes its series while both of its chips are online.
+pub fn record_channels(channels: [ChargeChannelOnlineStatus; 4]) {
+    let active = channels.map(|status| status == ChargeChannelOnlineStatus::Online);
+    STREAMS.lock(|streams| {
+        let mut streams = streams.borrow_mut();
+        if streams.channels != Some(active) {
+            streams.channels = Some(active);
+            streams.revision += 1;
+        }
+    });
+}
+
+#[cfg_attr(no_protector, allow(dead_code))]
+pub fn record_protector(online: bool) {
+    STREAMS.lock(|streams| {
+        let mut streams = streams.borrow_mut();
+        if streams.protector != Some(online) {
+            streams.protector = Some(online);
+            streams.revision += 1;
+        }
+    });
+}
+
+/// The current revision and its `schema` document, or `None` while a task is still in init.
+pub fn streams_json() -> Option<(u32, String<320>)> {
+    let streams = STREAMS.lock(|streams| *streams.borrow());
+    let channels = streams.channels?;
+    let protector = if cfg!(no_protector) {
+        false
+    } else {
+        streams.protector?
+    };
+
+    let mut json = String::new();
+    write!(json, "{{\"revision\":{},\"streams\":[", streams.revision).ok();
+    let mut separator = "";
+    if protector {
+        write!(
+            json,
+            "{{\"topic\":\"protector\",\"layout\":\"{}\"}}",
+            PROTECTOR_LAYOUT
+        )
+        .ok();
+        separator = ",";
+    }
+    for (index, _) in channels.iter().enumerate().filter(|(_, active)| **active) {
+        write!(
+            json,
+            "{}{{\"topic\":\"ch{}/series\",\"layout\":\"{}\"}}",
+            separator, index, CHARGE_CHANNEL_LAYOUT
+        )
+        .ok();
+        separator = ",";
+    }
+    json.push_str("]}").ok();
+
+    Some((streams.revision, json))
+}
+
+/// Longest `schema/<layout>` document of all [`LAYOUTS`].
+pub const MAX_LAYOUT_JSON_LEN: usize = {
+    let mut max = 0;
+    let mut index = 0;
+    while index < LAYOUTS.len() {
+        let len = telemetry_layout::layout_json_len(LAYOUTS[index].2);
+        if len > max {
+            max = len;
+        }
+        index += 1;
+    }
+    max
+};
+
+/// The `schema/<layout>` document for `fields`, one of the [`LAYOUTS`]; sized for the longest
+/// of them, so it is never cut short.
+pub fn layout_json(version: u8, fields: &[Field]) -> String<MAX_LAYOUT_JSON_LEN> {
+    let mut json = String::new();
+    telemetry_layout::write_layout_json(&mut json, version, fields).ok();
+    json
+}
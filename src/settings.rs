@@ -0,0 +1,429 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::{
+    history::HISTORY_CAPACITY,
+    protector::{MAX_CURRENT_AVG_WINDOW, OVER_TEMPERATURE_SHUTDOWN_CELSIUS},
+};
+
+/// Keep-alive advertised to the MQTT broker. The broker drops the session after 1.5× this
+/// without any packet from us.
+pub const MQTT_KEEP_ALIVE_SECS: u16 = 60;
+
+/// Output limit range the SW3526 accepts, in watts.
+pub const SW3526_MIN_LIMIT_WATTS: u8 = 12;
+pub const SW3526_MAX_LIMIT_WATTS: u8 = 71;
+
+/// Highest over-temperature cut-off the sensors may be programmed with, in °C; the board's
+/// parts aren't rated much beyond it.
+const MAX_TEMPERATURE_SHUTDOWN_CELSIUS: f32 = 100.0;
+
+/// Highest input over-current threshold, the full scale of the protector's INA226 calibration.
+const MAX_OVER_CURRENT_MILLIAMPS: u16 = 5000;
+
+/// PD profiles in [`Settings::pd_profiles`], one bit each.
+pub const PD_PROFILE_9V: u8 = 1 << 0;
+pub const PD_PROFILE_12V: u8 = 1 << 1;
+pub const PD_PROFILE_15V: u8 = 1 << 2;
+pub const PD_PROFILE_20V: u8 = 1 << 3;
+pub const PD_PROFILE_PPS0: u8 = 1 << 4;
+pub const PD_PROFILE_PPS1: u8 = 1 << 5;
+pub const PD_PROFILES_ALL: u8 = 0x3f;
+
+/// Highest INA226 averaging step (256 samples) for the charge channels: at 588µs per bus and
+/// shunt conversion that is ~300ms, which still fits the 1s sample period.
+pub const MAX_INA226_AVG_STEP: u8 = 5;
+
+/// Upper bound of a channel's active-current threshold, the INA226 calibration range.
+pub const MAX_CHANNEL_ACTIVE_MILLIAMPS: u16 = 5000;
+
+/// Kinds of message the MQTT task publishes, indexing [`Settings::delivery`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Protector = 0,
+    ChargeChannel = 1,
+    CommandAck = 2,
+    Status = 3,
+}
+
+impl TryFrom<u8> for Stream {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Stream::Protector),
+            1 => Ok(Stream::ChargeChannel),
+            2 => Ok(Stream::CommandAck),
+            3 => Ok(Stream::Status),
+            _ => Err(value),
+        }
+    }
+}
+
+/// How the protector's two temperature readings are combined into the one that thermal
+/// throttling and the fan act on. Both readings are still published as they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemperatureReduction {
+    /// The hotter sensor; the safe choice when either may sit at the hot spot.
+    Max = 0,
+    /// The average of both, for layouts where they measure the same area.
+    Mean = 1,
+    Sensor0 = 2,
+    Sensor1 = 3,
+}
+
+impl TemperatureReduction {
+    pub fn reduce(self, temperature_0: f32, temperature_1: f32) -> f32 {
+        match self {
+            TemperatureReduction::Max => temperature_0.max(temperature_1),
+            TemperatureReduction::Mean => (temperature_0 + temperature_1) / 2.0,
+            TemperatureReduction::Sensor0 => temperature_0,
+            TemperatureReduction::Sensor1 => temperature_1,
+        }
+    }
+}
+
+impl TryFrom<u8> for TemperatureReduction {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TemperatureReduction::Max),
+            1 => Ok(TemperatureReduction::Mean),
+            2 => Ok(TemperatureReduction::Sensor0),
+            3 => Ok(TemperatureReduction::Sensor1),
+            _ => Err(value),
+        }
+    }
+}
+
+/// QoS level and retain flag for the messages of one [`Stream`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delivery {
+    pub qos: u8,
+    pub retain: bool,
+}
+
+impl Delivery {
+    const fn new(qos: u8, retain: bool) -> Self {
+        Self { qos, retain }
+    }
+}
+
+/// Runtime-tunable parameters shared by all tasks.
+///
+/// Config commands update this store; tasks read a copy at the start of each cycle, so a change
+/// takes effect on the next iteration of the owning task.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    /// Samples averaged into the protector's input current, `1..=MAX_CURRENT_AVG_WINDOW`.
+    pub current_avg_window: u8,
+    /// Relative difference, in percent, between the INA226 power register and V×I above which
+    /// `power_mismatch` is flagged.
+    pub power_mismatch_percent: u8,
+    /// Report the power computed as V×I next to the INA226 power register and check the two
+    /// against each other. Off, only the register value is reported.
+    pub power_cross_check: bool,
+    /// Per-channel output current at which a channel counts as delivering power. This is the
+    /// single definition of channel activity; see `ChargeChannel::is_active`.
+    pub channel_active_milliamps: [u16; 4],
+    /// Interval between MQTT pings on an idle connection. Must stay below
+    /// [`MQTT_KEEP_ALIVE_SECS`] and the socket timeout, or the link is torn down between pings.
+    pub mqtt_ping_interval_secs: u16,
+    /// TCP socket timeout: the connection is dropped when nothing is received for this long.
+    /// Raise it together with the ping interval on high-latency links. Applied on the next
+    /// (re)connect.
+    pub socket_timeout_secs: u16,
+    /// Interval of the `system/heartbeat` liveness message. 0 disables it.
+    pub heartbeat_interval_secs: u16,
+    /// Minimum time between two broker connection attempts, whether the previous one failed or
+    /// an established connection dropped.
+    pub mqtt_reconnect_min_secs: u16,
+    /// Upper bound of a random delay added to every connection attempt, so devices that lose
+    /// the network together don't reconnect in lockstep. 0 disables it.
+    pub mqtt_reconnect_spread_secs: u16,
+    /// Times a refused subscription is retried on the same connection before it is torn down
+    /// and the broker reconnected.
+    pub mqtt_subscribe_retries: u8,
+    /// Time allowed for one device's reads in a charge channel cycle (the INA226 and the
+    /// SW3526 each get this much). A device that exceeds it is skipped for that cycle instead
+    /// of stalling the other channels.
+    pub channel_op_timeout_ms: u16,
+    /// INA226 averaging of the charge channels, as the register's step: 0 = 1 sample, then 4,
+    /// 16, 64, 128 and 256 samples. Ignored while auto-tuning is on.
+    pub ina226_avg_step: u8,
+    /// Noise target of the averaging auto-tuning, in µA of current noise. Each channel raises
+    /// its averaging until its current noise stays below this; 0 uses `ina226_avg_step`.
+    pub ina226_noise_target_microamps: u16,
+    /// Consecutive SW3526 timeouts after which the chip is marked offline and re-initialized,
+    /// instead of the channel carrying on with charger data it can no longer refresh.
+    pub sw3526_timeout_limit: u8,
+    /// Per-channel output limit as requested. What gets programmed is clamped to
+    /// `min_limit_watts..=max_limit_watts`; see [`Settings::effective_limit_watts`].
+    pub limit_watts: [u8; 4],
+    /// Bounds every output limit is clamped to before it reaches the SW3526, so automation
+    /// can't program a value the hardware shouldn't run at.
+    pub min_limit_watts: u8,
+    pub max_limit_watts: u8,
+    /// Per-channel PD profiles the SW3526 offers, a mask of the `PD_PROFILE_*` bits; a cleared
+    /// bit withdraws that profile. 5V is always offered. Applied on the channel's next cycle,
+    /// so a negotiation under way isn't interrupted.
+    pub pd_profiles: [u8; 4],
+    /// Cut VIN after being unable to reach the broker for this long. 0 keeps the desk running
+    /// regardless of connectivity.
+    pub offline_shutdown_secs: u16,
+    /// Time VIN has to stay on, and off, before it may switch again, so chattering conditions
+    /// or a flood of commands can't toggle the MOSFET rapidly. A switch requested sooner is
+    /// deferred until the time is up. Cuts made for protection, by the over-current trip, the
+    /// offline guard or an override ended by a hazard, skip the on time. 0 disables the
+    /// respective limit.
+    pub vin_min_on_secs: u16,
+    pub vin_min_off_secs: u16,
+    /// Sample the charge channels as soon as their INA226 flags a finished conversion instead of
+    /// once per second. Gives faster readings at the cost of a busier I2C bus and more telemetry.
+    pub poll_conversion_ready: bool,
+    /// Samples per series a `history` dump returns, up to `HISTORY_CAPACITY`; see `history`.
+    pub history_len: u8,
+    /// How each [`Stream`] is published. Retaining the telemetry series gives a new subscriber
+    /// the current state right away, at the cost of the broker rewriting a message per topic on
+    /// every sample; see [`is_valid_delivery`] for what QoS 1 costs.
+    pub delivery: [Delivery; 4],
+    /// Current a channel may carry indefinitely before its soft fuse starts heating up; see
+    /// `SoftFuse`. 0 disables the fuse.
+    pub fuse_rated_milliamps: u16,
+    /// Over-energy in A²s above the rated current at which a channel's soft fuse blows.
+    pub fuse_limit_amp2_secs: u16,
+    /// Board temperature in °C above which the charge channels' output limits are lowered to
+    /// shed heat before the hardware cuts VIN. 0, the default, disables throttling.
+    pub thermal_throttle_celsius: u8,
+    /// How the two board temperatures are combined for thermal throttling and the fan, the
+    /// hotter one by default. The GX21M15 sensors' own OS outputs still cut VIN when either one
+    /// alone overheats.
+    pub temperature_reduction: TemperatureReduction,
+    /// Consecutive over-temperature readings the GX21M15 sensors need before their OS output
+    /// cuts VIN: 1, 2, 4 or 8. More rides out noise, fewer reacts sooner.
+    pub os_fail_queue_size: u8,
+    /// Temperature in °C at which the GX21M15 sensors' OS output cuts VIN, and the one either
+    /// sensor has to fall below before it lets VIN back on. Reprogrammed into both sensors on
+    /// the protector's next cycle.
+    pub temperature_shutdown_celsius: f32,
+    pub temperature_hysteresis_celsius: f32,
+    /// Board temperature in °C at which the cooling fan starts, and the one it reaches full
+    /// speed at; see `fan`. Ignored without the `fan` feature.
+    pub fan_start_celsius: u8,
+    pub fan_full_celsius: u8,
+    /// Input current in mA above which VIN is cut and held off until a `vin-status` command; see
+    /// `Protector::update_over_current`. 0 disables the protection.
+    pub over_current_milliamps: u16,
+    /// Input power the supply can deliver, in watts. Above it the charge channels' output
+    /// limits are lowered until the input falls back below it, instead of overloading the
+    /// adapter. 0 disables the ceiling.
+    pub input_power_limit_watts: u16,
+    /// Output limit a channel is run at during the commission test; see `commission`.
+    pub commission_watts: u8,
+    /// How far, in percent, the measured current may be off the expected one in the
+    /// commission test.
+    pub commission_tolerance_percent: u8,
+    /// How far, in percent of the input power, the channels' summed output may stray outside
+    /// the expected efficiency range before `system/consistency` flags it. 0 disables the check.
+    pub consistency_tolerance_percent: u8,
+}
+
+impl Settings {
+    pub const DEFAULT: Self = Self {
+        current_avg_window: 3,
+        power_mismatch_percent: 10,
+        power_cross_check: false,
+        channel_active_milliamps: [100; 4],
+        mqtt_ping_interval_secs: 5,
+        socket_timeout_secs: 10,
+        heartbeat_interval_secs: 30,
+        mqtt_reconnect_min_secs: 1,
+        mqtt_reconnect_spread_secs: 0,
+        mqtt_subscribe_retries: 2,
+        channel_op_timeout_ms: 1000,
+        ina226_avg_step: 1,
+        ina226_noise_target_microamps: 0,
+        sw3526_timeout_limit: 3,
+        limit_watts: [65; 4],
+        min_limit_watts: SW3526_MIN_LIMIT_WATTS,
+        max_limit_watts: 65,
+        pd_profiles: [PD_PROFILES_ALL; 4],
+        offline_shutdown_secs: 0,
+        vin_min_on_secs: 0,
+        vin_min_off_secs: 0,
+        poll_conversion_ready: false,
+        history_len: 30,
+        delivery: [
+            Delivery::new(0, false),
+            Delivery::new(0, false),
+            Delivery::new(0, false),
+            Delivery::new(0, true),
+        ],
+        fuse_rated_milliamps: 0,
+        fuse_limit_amp2_secs: 20,
+        thermal_throttle_celsius: 0,
+        temperature_reduction: TemperatureReduction::Max,
+        os_fail_queue_size: 4,
+        temperature_shutdown_celsius: OVER_TEMPERATURE_SHUTDOWN_CELSIUS as f32,
+        temperature_hysteresis_celsius: 60.0,
+        fan_start_celsius: 40,
+        fan_full_celsius: 55,
+        over_current_milliamps: 0,
+        input_power_limit_watts: 0,
+        commission_watts: 20,
+        commission_tolerance_percent: 10,
+        consistency_tolerance_percent: 15,
+    };
+
+    pub fn delivery(&self, stream: Stream) -> Delivery {
+        self.delivery[stream as usize]
+    }
+
+    pub fn effective_limit_watts(&self, channel: usize) -> u8 {
+        self.limit_watts[channel].clamp(self.min_limit_watts, self.max_limit_watts)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+static SETTINGS: Mutex<CriticalSectionRawMutex, RefCell<Settings>> =
+    Mutex::new(RefCell::new(Settings::DEFAULT));
+
+pub fn get() -> Settings {
+    SETTINGS.lock(|settings| *settings.borrow())
+}
+
+pub fn update(f: impl FnOnce(&mut Settings)) {
+    SETTINGS.lock(|settings| f(&mut settings.borrow_mut()))
+}
+
+pub fn is_valid_current_avg_window(window: u8) -> bool {
+    (1..=MAX_CURRENT_AVG_WINDOW).contains(&(window as usize))
+}
+
+pub fn is_valid_power_mismatch_percent(percent: u8) -> bool {
+    (1..=100).contains(&percent)
+}
+
+pub fn is_valid_channel_active_milliamps(milliamps: u16) -> bool {
+    (1..=MAX_CHANNEL_ACTIVE_MILLIAMPS).contains(&milliamps)
+}
+
+/// The ping interval has to be shorter than both the keep-alive and the socket timeout, so a
+/// healthy but idle connection is never dropped.
+pub fn is_valid_mqtt_timing(ping_interval_secs: u16, socket_timeout_secs: u16) -> bool {
+    ping_interval_secs >= 1
+        && ping_interval_secs < MQTT_KEEP_ALIVE_SECS
+        && ping_interval_secs < socket_timeout_secs
+        && socket_timeout_secs <= 300
+}
+
+pub fn is_valid_heartbeat_interval_secs(secs: u16) -> bool {
+    secs == 0 || (5..=3600).contains(&secs)
+}
+
+pub fn is_valid_mqtt_reconnect(min_secs: u16, spread_secs: u16) -> bool {
+    (1..=600).contains(&min_secs) && spread_secs <= 600
+}
+
+/// The MQTT client supports QoS 0 and 1 only. It also waits for the PUBACK of every QoS 1
+/// message before sending anything else, and drops commands that arrive in the meantime, so the
+/// charge channel series can't use QoS 1 at the polled sample rate. Every stream fits the
+/// transmit buffer at either level.
+pub fn is_valid_delivery(delivery: &[Delivery; 4], poll_conversion_ready: bool) -> bool {
+    delivery.iter().all(|delivery| delivery.qos <= 1)
+        && !(poll_conversion_ready && delivery[Stream::ChargeChannel as usize].qos > 0)
+}
+
+pub fn is_valid_mqtt_subscribe_retries(retries: u8) -> bool {
+    retries <= 10
+}
+
+pub fn is_valid_channel_op_timeout_ms(timeout_ms: u16) -> bool {
+    (50..=5000).contains(&timeout_ms)
+}
+
+pub fn is_valid_ina226_avg_step(step: u8) -> bool {
+    step <= MAX_INA226_AVG_STEP
+}
+
+/// Anything below the current LSB (~150µA) could never be reached.
+pub fn is_valid_ina226_noise_target_microamps(microamps: u16) -> bool {
+    microamps == 0 || (200..=50_000).contains(&microamps)
+}
+
+pub fn is_valid_sw3526_timeout_limit(limit: u8) -> bool {
+    (1..=60).contains(&limit)
+}
+
+pub fn is_valid_limit_watts(watts: u8) -> bool {
+    (SW3526_MIN_LIMIT_WATTS..=SW3526_MAX_LIMIT_WATTS).contains(&watts)
+}
+
+pub fn is_valid_pd_profiles(profiles: u8) -> bool {
+    profiles & !PD_PROFILES_ALL == 0
+}
+
+pub fn is_valid_fuse(rated_milliamps: u16, limit_amp2_secs: u16) -> bool {
+    (rated_milliamps == 0 || (100..=MAX_CHANNEL_ACTIVE_MILLIAMPS).contains(&rated_milliamps))
+        && (1..=1000).contains(&limit_amp2_secs)
+}
+
+/// Leaves at least 5°C of throttling range below the hardware cut-off.
+pub fn is_valid_thermal_throttle_celsius(celsius: u8, shutdown_celsius: f32) -> bool {
+    celsius == 0 || (40.0..=shutdown_celsius - 5.0).contains(&(celsius as f32))
+}
+
+/// The cut-off has to stay reachable and below what the parts are rated for, and VIN can only
+/// come back on if the hysteresis is the lower of the two.
+pub fn is_valid_temperature_limits(hysteresis_celsius: f32, shutdown_celsius: f32) -> bool {
+    (45.0..=MAX_TEMPERATURE_SHUTDOWN_CELSIUS).contains(&shutdown_celsius)
+        && (30.0..shutdown_celsius).contains(&hysteresis_celsius)
+}
+
+/// Leaves the fan a ramp of at least 5°C.
+pub fn is_valid_fan_celsius(start_celsius: u8, full_celsius: u8) -> bool {
+    (25..=90).contains(&start_celsius) && (start_celsius + 5..=95).contains(&full_celsius)
+}
+
+pub fn is_valid_history_len(len: u8) -> bool {
+    (1..=HISTORY_CAPACITY).contains(&(len as usize))
+}
+
+pub fn is_valid_os_fail_queue_size(size: u8) -> bool {
+    matches!(size, 1 | 2 | 4 | 8)
+}
+
+/// Within the input INA226's calibrated range, and above what the board draws at idle.
+pub fn is_valid_over_current_milliamps(milliamps: u16) -> bool {
+    milliamps == 0 || (500..=MAX_OVER_CURRENT_MILLIAMPS).contains(&milliamps)
+}
+
+/// Below 20W the channels' minimum limits alone could exceed the ceiling.
+pub fn is_valid_input_power_limit_watts(watts: u16) -> bool {
+    watts == 0 || (20..=300).contains(&watts)
+}
+
+pub fn is_valid_commission_tolerance_percent(percent: u8) -> bool {
+    (1..=50).contains(&percent)
+}
+
+pub fn is_valid_consistency_tolerance_percent(percent: u8) -> bool {
+    percent <= 50
+}
+
+/// Kept short, since a deferred switch may be a cut-off.
+pub fn is_valid_vin_min_secs(secs: u16) -> bool {
+    secs <= 60
+}
+
+/// Short timeouts would trip on an ordinary reconnect.
+pub fn is_valid_offline_shutdown_secs(secs: u16) -> bool {
+    secs == 0 || (30..=3600).contains(&secs)
+}
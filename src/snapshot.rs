@@ -0,0 +1,58 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+use crate::bus::{ChargeChannelSeriesItem, ProtectorSeriesItem};
+
+/// A reading together with the time it was taken.
+#[derive(Debug, Clone, Copy)]
+pub struct Sample<T> {
+    pub value: T,
+    pub at: Instant,
+}
+
+/// Latest reading of every sensor, taken under one lock so readers get a coherent view.
+///
+/// The series channels hand each reading to exactly one consumer; these cells keep the most
+/// recent one around for anyone who needs the current state instead. `None` until the sensor
+/// has produced its first reading.
+#[derive(Debug, Clone, Copy)]
+pub struct Snapshot {
+    pub protector: Option<Sample<ProtectorSeriesItem>>,
+    pub channels: [Option<Sample<ChargeChannelSeriesItem>>; 4],
+}
+
+impl Snapshot {
+    const fn new() -> Self {
+        Self {
+            protector: None,
+            channels: [None; 4],
+        }
+    }
+}
+
+static LATEST: Mutex<CriticalSectionRawMutex, RefCell<Snapshot>> =
+    Mutex::new(RefCell::new(Snapshot::new()));
+
+/// Without the protector nothing records it, and `protector` stays `None`.
+#[cfg(not(no_protector))]
+pub fn record_protector(value: ProtectorSeriesItem) {
+    let sample = Sample {
+        value,
+        at: Instant::now(),
+    };
+    LATEST.lock(|latest| latest.borrow_mut().protector = Some(sample));
+}
+
+pub fn record_charge_channel(index: usize, value: ChargeChannelSeriesItem) {
+    let sample = Sample {
+        value,
+        at: Instant::now(),
+    };
+    LATEST.lock(|latest| latest.borrow_mut().channels[index] = Some(sample));
+}
+
+pub fn snapshot() -> Snapshot {
+    LATEST.lock(|latest| *latest.borrow())
+}
@@ -0,0 +1,137 @@
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::{FlashStorage, FlashStorageError};
+
+use crate::helper::crc16;
+
+/// Start of the flash region used for records: the `nvs` partition of the default partition
+/// table, which nothing else in this firmware touches. It is six sectors long, so six slots at
+/// most.
+const STORAGE_OFFSET: u32 = 0x9000;
+/// Each slot gets its own sector, so rewriting one record never disturbs another.
+pub const SLOT_SIZE: u32 = FlashStorage::SECTOR_SIZE;
+
+const RECORD_MAGIC: u16 = 0x5044;
+const HEADER_LEN: usize = 4;
+const CRC_LEN: usize = 2;
+
+pub const MAX_RECORD_LEN: usize = 256;
+
+/// Fixed location of each persisted record.
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+pub enum Slot {
+    Calibration = 0,
+    Board = 1,
+    Wifi = 2,
+    DeviceName = 3,
+    /// Together one log of fixed-size records, accessed with [`read_raw`], [`program`] and
+    /// [`erase`] rather than as single records; see [`crate::lifetime`].
+    Lifetime0 = 4,
+    Lifetime1 = 5,
+}
+
+impl Slot {
+    fn offset(self) -> u32 {
+        STORAGE_OFFSET + self as u32 * SLOT_SIZE
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// A record longer than [`MAX_RECORD_LEN`], or raw access past the end of the slot.
+    TooLong,
+    Flash(FlashStorageError),
+}
+
+/// Record layout: `[magic: u16][len: u16][payload][crc16(payload): u16]`, little-endian.
+///
+/// Reads the record in `slot` into `buffer` and returns its length. An erased slot, a
+/// corrupted record or one larger than `buffer` all read as `None`.
+pub fn read(slot: Slot, buffer: &mut [u8]) -> Option<usize> {
+    let mut flash = FlashStorage::new();
+
+    let mut header = [0u8; HEADER_LEN];
+    flash.read(slot.offset(), &mut header).ok()?;
+    if u16::from_le_bytes([header[0], header[1]]) != RECORD_MAGIC {
+        return None;
+    }
+
+    let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    if len > MAX_RECORD_LEN || len > buffer.len() {
+        return None;
+    }
+
+    let mut crc = [0u8; CRC_LEN];
+    flash
+        .read(slot.offset() + HEADER_LEN as u32, &mut buffer[..len])
+        .ok()?;
+    flash
+        .read(slot.offset() + (HEADER_LEN + len) as u32, &mut crc)
+        .ok()?;
+    if u16::from_le_bytes(crc) != crc16(&buffer[..len]) {
+        log::warn!("Corrupted record in {:?}", slot);
+        return None;
+    }
+
+    Some(len)
+}
+
+/// Replaces the record in `slot`. Blocks for the sector erase, typically tens of milliseconds.
+pub fn write(slot: Slot, payload: &[u8]) -> Result<(), StorageError> {
+    if payload.len() > MAX_RECORD_LEN {
+        return Err(StorageError::TooLong);
+    }
+
+    let mut record = [0u8; HEADER_LEN + MAX_RECORD_LEN + CRC_LEN];
+    record[..2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+    record[2..HEADER_LEN].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+    record[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+    let end = HEADER_LEN + payload.len();
+    record[end..end + CRC_LEN].copy_from_slice(&crc16(payload).to_le_bytes());
+
+    FlashStorage::new()
+        .write(slot.offset(), &record[..end + CRC_LEN])
+        .map_err(StorageError::Flash)
+}
+
+fn check_raw(offset: u32, len: usize) -> Result<(), StorageError> {
+    if offset as usize + len > SLOT_SIZE as usize {
+        return Err(StorageError::TooLong);
+    }
+    Ok(())
+}
+
+/// Reads raw bytes at `offset` within `slot`, for slots that hold a log instead of one record.
+/// Offset and length must be multiples of 4.
+pub fn read_raw(slot: Slot, offset: u32, buffer: &mut [u8]) -> Result<(), StorageError> {
+    check_raw(offset, buffer.len())?;
+    embedded_storage::nor_flash::ReadNorFlash::read(
+        &mut FlashStorage::new(),
+        slot.offset() + offset,
+        buffer,
+    )
+    .map_err(StorageError::Flash)
+}
+
+/// Writes `bytes` at `offset` within `slot` without erasing first, so the bytes must still be
+/// erased (`0xff`). Takes microseconds where [`write`] takes an erase. Offset and length must be
+/// multiples of 4.
+pub fn program(slot: Slot, offset: u32, bytes: &[u8]) -> Result<(), StorageError> {
+    check_raw(offset, bytes.len())?;
+    embedded_storage::nor_flash::NorFlash::write(
+        &mut FlashStorage::new(),
+        slot.offset() + offset,
+        bytes,
+    )
+    .map_err(StorageError::Flash)
+}
+
+/// Erases all of `slot` to `0xff`. Blocks for the sector erase, typically tens of milliseconds.
+pub fn erase(slot: Slot) -> Result<(), StorageError> {
+    embedded_storage::nor_flash::NorFlash::erase(
+        &mut FlashStorage::new(),
+        slot.offset(),
+        slot.offset() + SLOT_SIZE,
+    )
+    .map_err(StorageError::Flash)
+}
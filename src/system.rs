@@ -0,0 +1,419 @@
+use core::{
+    cell::{Cell, RefCell},
+    fmt::Write,
+};
+
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_hal::{
+    gpio::{Flex, GpioPin, Level, Pull},
+    reset::{get_reset_reason, software_reset, SocResetReason},
+};
+use heapless::String;
+
+use crate::{
+    bus::publish_system_message,
+    charge_channel::ChargeChannelOnlineStatus,
+    device_name, lifetime, logger,
+    telemetry_layout::{self, CHARGE_CHANNEL_SERIES_VERSION, PROTECTOR_SERIES_VERSION},
+};
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// One-shot summary of the boot, published retained to `system/boot`.
+///
+/// Fields are `None` until the owning task has recorded them. `seq_start` tells consumers that
+/// the `seq` of every telemetry series started over from that value with this boot.
+#[derive(Debug, Clone, Copy)]
+pub struct BootReport {
+    pub reset_reason: Option<SocResetReason>,
+    pub heap_free: usize,
+    pub mux_online: Option<[bool; 2]>,
+    pub channels: Option<[ChargeChannelOnlineStatus; 4]>,
+    pub protector_online: Option<bool>,
+}
+
+impl BootReport {
+    const fn new() -> Self {
+        Self {
+            reset_reason: None,
+            heap_free: 0,
+            mux_online: None,
+            channels: None,
+            protector_online: None,
+        }
+    }
+
+    /// All tasks have reported their init results.
+    pub fn is_complete(&self) -> bool {
+        self.mux_online.is_some()
+            && self.channels.is_some()
+            && (cfg!(no_protector) || self.protector_online.is_some())
+    }
+
+    pub fn to_json(&self) -> String<256> {
+        let mut json = String::new();
+
+        write!(
+            json,
+            "{{\"name\":\"{}\",\"version\":\"{}\",\"heap_free\":{},\"log_level\":\"{}\",\"seq_start\":0",
+            device_name::get(),
+            FIRMWARE_VERSION,
+            self.heap_free,
+            logger::level()
+        )
+        .ok();
+
+        match self.reset_reason {
+            Some(reason) => write!(json, ",\"reset_reason\":\"{:?}\"", reason).ok(),
+            None => write!(json, ",\"reset_reason\":null").ok(),
+        };
+
+        match self.mux_online {
+            Some([mux_0, mux_1]) => write!(json, ",\"mux\":[{},{}]", mux_0, mux_1).ok(),
+            None => write!(json, ",\"mux\":null").ok(),
+        };
+
+        match self.channels {
+            Some(channels) => {
+                write!(json, ",\"channels\":[").ok();
+                for (index, status) in channels.iter().enumerate() {
+                    let separator = if index == 0 { "" } else { "," };
+                    write!(json, "{}{}", separator, *status as u8).ok();
+                }
+                write!(json, "]").ok()
+            }
+            None => write!(json, ",\"channels\":null").ok(),
+        };
+
+        match self.protector_online {
+            Some(online) => write!(json, ",\"protector\":{}}}", online).ok(),
+            None => write!(json, ",\"protector\":null}}").ok(),
+        };
+
+        json
+    }
+}
+
+/// Length of the `system/capabilities` document at its longest: every value at its widest
+/// spelling, `false` for each flag and three-digit series versions.
+pub const MAX_CAPABILITIES_JSON_LEN: usize = concat!(
+    r#"{"version":"","protector":false,"mux":[false,false],"#,
+    r#""telemetry":{"payload":"binary","byte_order":"little","protector":255,"charge_channel":255},"#,
+    r#""vin_ctl":{"cut_level":"high","drive":"open-drain","check":"retry"},"#,
+    r#""secondary_broker":false,"ha_discovery":false,"fan":false}"#
+)
+.len()
+    + FIRMWARE_VERSION.len();
+
+/// The `VIN_CHECK` reaction this build uses. Anything unknown falls back to `halt`.
+fn vin_check_mode() -> &'static str {
+    match option_env!("VIN_CHECK") {
+        Some("retry") => "retry",
+        Some("warn") => "warn",
+        _ => "halt",
+    }
+}
+
+/// What this build supports, published retained to `system/capabilities` so a consumer can
+/// adapt to firmware built with different hardware flags and build-time options. Everything in
+/// it is fixed at compile time, e.g.
+/// `{"version":"0.1.0","protector":true,"mux":[true,true],"telemetry":{"payload":"binary",
+/// "byte_order":"little","protector":3,"charge_channel":3},"vin_ctl":{"cut_level":"low","drive":"open-drain",
+/// "check":"halt"},"secondary_broker":false,"ha_discovery":false,"fan":false}`. The telemetry
+/// `byte_order` also applies to the `ch<n>/contract` payload.
+pub fn capabilities_json() -> String<MAX_CAPABILITIES_JSON_LEN> {
+    let level_name = |level: Level| match level {
+        Level::Low => "low",
+        Level::High => "high",
+    };
+
+    let mut json = String::new();
+    write!(
+        json,
+        "{{\"version\":\"{}\",\"protector\":{},\"mux\":[{},{}]",
+        FIRMWARE_VERSION,
+        cfg!(not(no_protector)),
+        cfg!(not(no_mux_0)),
+        cfg!(not(no_mux_1))
+    )
+    .ok();
+    write!(
+        json,
+        ",\"telemetry\":{{\"payload\":\"{}\",\"byte_order\":\"{}\",\"protector\":{},\"charge_channel\":{}}}",
+        if cfg!(feature = "json-payload") {
+            "json"
+        } else {
+            "binary"
+        },
+        telemetry_layout::byte_order().name(),
+        PROTECTOR_SERIES_VERSION,
+        CHARGE_CHANNEL_SERIES_VERSION
+    )
+    .ok();
+    write!(
+        json,
+        ",\"vin_ctl\":{{\"cut_level\":\"{}\",\"drive\":\"{}\",\"check\":\"{}\"}}",
+        level_name(vin_ctl_cut_level()),
+        match option_env!("VIN_CTL_DRIVE") {
+            Some("push-pull") => "push-pull",
+            _ => "open-drain",
+        },
+        vin_check_mode()
+    )
+    .ok();
+    write!(
+        json,
+        ",\"secondary_broker\":{},\"ha_discovery\":{},\"fan\":{}}}",
+        option_env!("MQTT_BROKER_SECONDARY").is_some(),
+        cfg!(feature = "ha-discovery"),
+        cfg!(feature = "fan")
+    )
+    .ok();
+    json
+}
+
+static BOOT_REPORT: Mutex<CriticalSectionRawMutex, RefCell<BootReport>> =
+    Mutex::new(RefCell::new(BootReport::new()));
+
+/// Records the chip-level boot info. Call once, right after the heap is set up.
+pub fn init_boot_report() {
+    BOOT_REPORT.lock(|report| {
+        let mut report = report.borrow_mut();
+        report.reset_reason = get_reset_reason();
+        report.heap_free = esp_alloc::HEAP.free();
+    });
+}
+
+pub fn record_mux_online(mux_online: [bool; 2]) {
+    BOOT_REPORT.lock(|report| report.borrow_mut().mux_online = Some(mux_online));
+}
+
+pub fn record_channels(channels: [ChargeChannelOnlineStatus; 4]) {
+    BOOT_REPORT.lock(|report| report.borrow_mut().channels = Some(channels));
+}
+
+pub fn record_protector_online(online: bool) {
+    BOOT_REPORT.lock(|report| report.borrow_mut().protector_online = Some(online));
+}
+
+pub fn boot_report() -> BootReport {
+    BOOT_REPORT.lock(|report| *report.borrow())
+}
+
+/// Last time the broker answered us; `None` until the first connection, so a desk that never
+/// had a broker isn't treated as offline.
+static LAST_BROKER_CONTACT: Mutex<CriticalSectionRawMutex, Cell<Option<Instant>>> =
+    Mutex::new(Cell::new(None));
+
+/// VIN is off because the device was offline for too long; see `offline_shutdown_secs`.
+#[cfg_attr(no_protector, allow(dead_code))]
+static OFFLINE_SHUTDOWN: Mutex<CriticalSectionRawMutex, Cell<bool>> = Mutex::new(Cell::new(false));
+
+/// Called by the MQTT task whenever something arrives from the broker: a CONNACK, a PINGRESP or
+/// a message.
+pub fn record_broker_contact() {
+    LAST_BROKER_CONTACT.lock(|last| last.set(Some(Instant::now())));
+}
+
+/// When the broker last answered, `None` if it never did since boot.
+#[cfg_attr(no_protector, allow(dead_code))]
+pub fn last_broker_contact() -> Option<Instant> {
+    LAST_BROKER_CONTACT.lock(|last| last.get())
+}
+
+#[cfg_attr(no_protector, allow(dead_code))]
+pub fn offline_shutdown() -> bool {
+    OFFLINE_SHUTDOWN.lock(|shutdown| shutdown.get())
+}
+
+#[cfg_attr(no_protector, allow(dead_code))]
+pub fn set_offline_shutdown(shutdown: bool) {
+    OFFLINE_SHUTDOWN.lock(|value| value.set(shutdown));
+}
+
+/// Charge channel outputs held off by the charge channel task: every one of them after
+/// `all-off`, and each channel whose soft fuse is blown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputHolds {
+    pub all_off: bool,
+    pub fuse_blown: [bool; 4],
+}
+
+static OUTPUT_HOLDS: Mutex<CriticalSectionRawMutex, Cell<OutputHolds>> =
+    Mutex::new(Cell::new(OutputHolds {
+        all_off: false,
+        fuse_blown: [false; 4],
+    }));
+
+pub fn record_output_holds(holds: OutputHolds) {
+    OUTPUT_HOLDS.lock(|value| value.set(holds));
+}
+
+#[cfg_attr(no_protector, allow(dead_code))]
+pub fn output_holds() -> OutputHolds {
+    OUTPUT_HOLDS.lock(|holds| holds.get())
+}
+
+/// Interval between fault reports (and re-checks with `VIN_CHECK=retry`) while the VIN control
+/// check fails.
+const VIN_CHECK_FAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Level of the VIN control line that cuts VIN. Build-time `VIN_CTL_ACTIVE_LEVEL`, `low` unless
+/// set to `high`.
+pub fn vin_ctl_cut_level() -> Level {
+    match option_env!("VIN_CTL_ACTIVE_LEVEL") {
+        Some("high") => Level::High,
+        _ => Level::Low,
+    }
+}
+
+/// The GPIO the VIN control line is wired to.
+pub type VinCtlGpio = GpioPin<7>;
+
+/// Safe state on a crash: VIN is cut, which takes every charge channel output down with it.
+///
+/// esp-backtrace calls this first thing on a panic or CPU exception, before printing the
+/// backtrace and halting, so power doesn't stay on under firmware that no longer watches it.
+/// The protector task owns the pin but never runs again, so it is taken over here; nothing in
+/// this path allocates, locks or waits. It also applies to `no_protector` builds.
+#[no_mangle]
+fn custom_pre_backtrace() {
+    // SAFETY: the other instance belongs to a task that will not be polled again.
+    let mut pin = Flex::new(unsafe { VinCtlGpio::steal() });
+    drive_vin_ctl(&mut pin, true);
+}
+
+/// Drives the VIN control line to cut VIN or let it through.
+///
+/// By default the line is open-drain with a pull-up on the board: it is only ever pulled low and
+/// otherwise left floating, so the board's protection stage can pull it low too. With the
+/// build-time `VIN_CTL_DRIVE=push-pull` both levels are driven, for boards with a gate driver.
+pub fn drive_vin_ctl(pin: &mut Flex<'_>, cut: bool) {
+    let level = if cut {
+        vin_ctl_cut_level()
+    } else {
+        !vin_ctl_cut_level()
+    };
+
+    match (option_env!("VIN_CTL_DRIVE"), level) {
+        (Some("push-pull"), level) => {
+            pin.set_level(level);
+            pin.set_as_output();
+        }
+        (_, Level::Low) => {
+            pin.set_as_open_drain(Pull::None);
+            pin.set_low();
+        }
+        (_, Level::High) => pin.set_as_input(Pull::None),
+    }
+}
+
+/// Level the VIN control line must read back while VIN is cut. Build-time
+/// `VIN_CTL_EXPECTED_LEVEL`, the cut level unless set.
+fn vin_ctl_expected_level() -> Level {
+    match option_env!("VIN_CTL_EXPECTED_LEVEL") {
+        Some("high") => Level::High,
+        Some("low") => Level::Low,
+        _ => vin_ctl_cut_level(),
+    }
+}
+
+/// Boot-time check of the VIN control line. Call with VIN cut through [`drive_vin_ctl`].
+///
+/// The line has to read back the cut level. If it doesn't, it is stuck or not wired and VIN
+/// could not be cut on over-temperature, so the desk must not start. Boards that read back
+/// something else can set `VIN_CTL_EXPECTED_LEVEL` and choose the reaction with the build-time
+/// `VIN_CHECK`:
+/// - `halt` (default): stay in a fault state, logging and publishing `system/fault` periodically.
+/// - `retry`: like `halt`, but re-check each time and continue booting once the check passes.
+/// - `warn`: report the fault once and continue booting.
+///
+/// With `VIN_CTL_DRIVE=push-pull` the check is skipped: the pin would only read back the level
+/// the MCU drives itself, which says nothing about the line.
+pub async fn check_vin_ctl(pin: &mut Flex<'_>) {
+    if option_env!("VIN_CTL_DRIVE") == Some("push-pull") {
+        log::warn!("VIN control check skipped: the line is driven push-pull");
+        return;
+    }
+
+    let mode = vin_check_mode();
+    let expected = vin_ctl_expected_level();
+    let mut failed = false;
+
+    loop {
+        let level = pin.get_level();
+        log::info!("vin_ctl_pin: {:?}", level);
+
+        if level == expected {
+            if failed {
+                log::info!("VIN control check passed");
+                publish_system_message("fault", b"");
+            }
+            return;
+        }
+
+        log::error!(
+            "VIN control line reads {:?} instead of {:?}; VIN cannot be cut",
+            level,
+            expected
+        );
+        // Every round, not just once: the first reports may be dropped before MQTT is up.
+        publish_system_message("fault", b"vin-ctl");
+        failed = true;
+
+        match mode {
+            "warn" => return,
+            "retry" => Timer::after(VIN_CHECK_FAULT_INTERVAL).await,
+            _ => loop {
+                Timer::after(VIN_CHECK_FAULT_INTERVAL).await;
+                log::error!("Halted: VIN control check failed");
+                publish_system_message("fault", b"vin-ctl");
+            },
+        }
+    }
+}
+
+/// Upper bound on the time spent saying goodbye to the broker before a controlled reboot, so a
+/// hung socket can't block it.
+pub const REBOOT_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+static REBOOT_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static REBOOT_PREPARE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static REBOOT_READY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Starts a controlled reboot. Every planned restart should go through here rather than calling
+/// `software_reset` directly, so the MQTT session is closed cleanly first.
+pub fn request_reboot() {
+    REBOOT_REQUEST.signal(());
+}
+
+/// Resolves once a controlled reboot is pending. Only the MQTT task waits on this.
+pub async fn reboot_pending() {
+    REBOOT_PREPARE.wait().await
+}
+
+/// Called by the MQTT task once it has disconnected from the broker.
+pub fn ready_to_reboot() {
+    REBOOT_READY.signal(());
+}
+
+#[embassy_executor::task]
+pub async fn reboot_task() {
+    REBOOT_REQUEST.wait().await;
+
+    log::warn!("Reboot requested");
+    lifetime::save();
+    REBOOT_PREPARE.signal(());
+    if with_timeout(REBOOT_GRACE_PERIOD, REBOOT_READY.wait())
+        .await
+        .is_err()
+    {
+        log::warn!("MQTT did not disconnect in time, rebooting anyway");
+    }
+
+    software_reset();
+}
@@ -0,0 +1,251 @@
+//! Wire layout of the binary telemetry series, as plain data.
+//!
+//! The firmware checks it against the encoders at compile time and publishes it as the
+//! `schema/<layout>` documents written here; `tools/telemetry-schema` includes this file on the
+//! host and prints it, so downstream decoders can be generated from the same source, and
+//! `tools/host-tests` tests it. Keep it free of dependencies so all of them can use it.
+
+// The firmware reads nothing but the sizes, offsets and names.
+#![allow(dead_code)]
+
+use core::fmt::{self, Write};
+
+/// Byte order of the multi-byte fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    pub const fn name(self) -> &'static str {
+        match self {
+            ByteOrder::Little => "little",
+            ByteOrder::Big => "big",
+        }
+    }
+}
+
+/// Build-time `TELEMETRY_BYTE_ORDER`, `little` unless set to `big`. The series and the binary
+/// `ch<n>/contract` payload are both encoded in it. The host tool picks up the same variable from
+/// `.cargo/config.toml`, so its output matches the firmware built from the same tree.
+pub fn byte_order() -> ByteOrder {
+    match option_env!("TELEMETRY_BYTE_ORDER") {
+        Some("big") => ByteOrder::Big,
+        _ => ByteOrder::Little,
+    }
+}
+
+/// Encoding of one field. Multi-byte fields are in [`byte_order`]; `Bool` is one byte, 0 or 1.
+#[derive(Debug, Clone, Copy)]
+pub enum FieldType {
+    U8,
+    Bool,
+    U16,
+    U32,
+    F32,
+    F64,
+    /// Unused bytes, always zero.
+    Reserved(usize),
+}
+
+impl FieldType {
+    pub const fn size(self) -> usize {
+        match self {
+            FieldType::U8 | FieldType::Bool => 1,
+            FieldType::U16 => 2,
+            FieldType::U32 | FieldType::F32 => 4,
+            FieldType::F64 => 8,
+            FieldType::Reserved(len) => len,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            FieldType::U8 => "u8",
+            FieldType::Bool => "bool",
+            FieldType::U16 => "u16",
+            FieldType::U32 => "u32",
+            FieldType::F32 => "f32",
+            FieldType::F64 => "f64",
+            FieldType::Reserved(_) => "reserved",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    pub name: &'static str,
+    pub ty: FieldType,
+}
+
+const fn field(name: &'static str, ty: FieldType) -> Field {
+    Field { name, ty }
+}
+
+/// Bumped whenever a layout below changes, so consumers can tell which one they are decoding.
+pub const PROTECTOR_SERIES_VERSION: u8 = 6;
+pub const CHARGE_CHANNEL_SERIES_VERSION: u8 = 6;
+
+/// Payload of the `protector` topic.
+pub const PROTECTOR_SERIES_ITEM: &[Field] = &[
+    field("temperature_0", FieldType::F32),
+    field("temperature_1", FieldType::F32),
+    field("millivolts", FieldType::F64),
+    field("amps", FieldType::F64),
+    field("watts", FieldType::F64),
+    field("vin_status", FieldType::U8),
+    field("direction", FieldType::U8),
+    field("power_mismatch", FieldType::Bool),
+    field("amps_stale", FieldType::Bool),
+    field("watts_stale", FieldType::Bool),
+    // Bitmask of the active protection rules, bits as listed in `system/protection`.
+    field("protection", FieldType::U16),
+    // Per-topic sample counter from 0 at boot, wrapping; see `seq_start` in `system/boot`.
+    field("seq", FieldType::U16),
+    // Cooling fan duty in percent, 0 on boards without the `fan` feature.
+    field("fan_duty", FieldType::U8),
+    // Instantaneous input current; `amps` is averaged over `current_avg_window` samples.
+    field("raw_amps", FieldType::F64),
+    // V×I, 0 unless `power_cross_check` is on.
+    field("computed_watts", FieldType::F64),
+];
+
+/// Payload of the `ch<n>/series` topics.
+pub const CHARGE_CHANNEL_SERIES_ITEM: &[Field] = &[
+    field("millivolts", FieldType::F64),
+    field("amps", FieldType::F64),
+    field("watts", FieldType::F64),
+    field("protocol", FieldType::U8),
+    field("system_status", FieldType::U8),
+    field("abnormal_case", FieldType::U8),
+    field("buck_output_millivolts", FieldType::U16),
+    field("buck_output_limit_milliamps", FieldType::U16),
+    field("limit_watts", FieldType::U8),
+    field("requested_limit_watts", FieldType::U8),
+    field("power_mismatch", FieldType::Bool),
+    field("active", FieldType::Bool),
+    field("sample_interval_ms", FieldType::U32),
+    field("sample_overrun", FieldType::Bool),
+    field("amps_stale", FieldType::Bool),
+    field("watts_stale", FieldType::Bool),
+    field("buck_output_milliamps", FieldType::U16),
+    field("seq", FieldType::U16),
+    field("shunt_microvolts", FieldType::F64),
+    // 1 INA226 online, 2 SW3526 online, 3 both; the readings are only valid at 3.
+    field("online_status", FieldType::U8),
+    // V×I, 0 unless `power_cross_check` is on.
+    field("computed_watts", FieldType::F64),
+    // The payload length is derived from the in-memory size of the three SW3526 status
+    // structs, which is larger than the one byte each is encoded to.
+    field("_reserved", FieldType::Reserved(5)),
+];
+
+/// Writes the `schema/<layout>` document for `fields`, e.g. `{"version":6,"size":58,
+/// "byte_order":"little","fields":"temperature_0:f32,temperature_1:f32,.."}`.
+pub fn write_layout_json(out: &mut impl Write, version: u8, fields: &[Field]) -> fmt::Result {
+    write!(
+        out,
+        "{{\"version\":{},\"size\":{},\"byte_order\":\"{}\",\"fields\":\"",
+        version,
+        byte_size(fields),
+        byte_order().name()
+    )?;
+    for (index, field) in fields.iter().enumerate() {
+        let separator = if index == 0 { "" } else { "," };
+        match field.ty {
+            FieldType::Reserved(len) => write!(
+                out,
+                "{}{}:{}[{}]",
+                separator,
+                field.name,
+                field.ty.name(),
+                len
+            )?,
+            ty => write!(out, "{}{}:{}", separator, field.name, ty.name())?,
+        }
+    }
+    out.write_str("\"}")
+}
+
+/// Length of the [`write_layout_json`] document for `fields` at its longest, i.e. with a
+/// three-digit version and the longer byte order.
+pub const fn layout_json_len(fields: &[Field]) -> usize {
+    let mut len = r#"{"version":255,"size":,"byte_order":"little","fields":""}"#.len()
+        + digits(byte_size(fields));
+    let mut index = 0;
+    while index < fields.len() {
+        let field = fields[index];
+        len += field.name.len() + ":".len() + field.ty.name().len();
+        if let FieldType::Reserved(reserved) = field.ty {
+            len += "[]".len() + digits(reserved);
+        }
+        if index > 0 {
+            len += ",".len();
+        }
+        index += 1;
+    }
+    len
+}
+
+const fn digits(mut value: usize) -> usize {
+    let mut digits = 1;
+    while value >= 10 {
+        value /= 10;
+        digits += 1;
+    }
+    digits
+}
+
+pub const fn byte_size(fields: &[Field]) -> usize {
+    let mut size = 0;
+    let mut index = 0;
+    while index < fields.len() {
+        size += fields[index].ty.size();
+        index += 1;
+    }
+    size
+}
+
+/// Offset of the field called `name`. Fails to compile in a const context if there is none.
+pub const fn offset(fields: &[Field], name: &str) -> usize {
+    let end = index_of(fields, name);
+    let mut offset = 0;
+    let mut index = 0;
+    while index < end {
+        offset += fields[index].ty.size();
+        index += 1;
+    }
+    offset
+}
+
+/// Size of the field called `name`; see [`offset`].
+pub const fn size(fields: &[Field], name: &str) -> usize {
+    fields[index_of(fields, name)].ty.size()
+}
+
+const fn index_of(fields: &[Field], name: &str) -> usize {
+    let mut index = 0;
+    while index < fields.len() {
+        if str_eq(fields[index].name, name) {
+            return index;
+        }
+        index += 1;
+    }
+    panic!("no such telemetry field");
+}
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut index = 0;
+    while index < a.len() {
+        if a[index] != b[index] {
+            return false;
+        }
+        index += 1;
+    }
+    true
+}
@@ -0,0 +1,63 @@
+//! Hardware watchdog: the RTC watchdog (RWDT) resets the chip unless it is fed within its
+//! timeout. [`task`] feeds it every [`feed_interval`], so it only fires when the executor stops
+//! running tasks altogether, e.g. a task that spins without awaiting or an interrupt storm. A
+//! task stuck in an await still lets the others run and isn't caught by it.
+//!
+//! The reset shows up as the reset reason in `system/boot`.
+
+use embassy_time::{Duration, Ticker};
+use esp_hal::{config::WatchdogStatus, rtc_cntl::Rwdt};
+
+const DEFAULT_FEED_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Feeds the timeout spans at least, so a single late one doesn't reset the chip.
+const MIN_FEEDS_PER_TIMEOUT: u32 = 3;
+
+/// Build-time `RWDT_FEED_MS`, 1000 unless set to another non-zero value.
+pub fn feed_interval() -> Duration {
+    option_env!("RWDT_FEED_MS")
+        .and_then(|millis| millis.parse().ok())
+        .filter(|millis| *millis > 0)
+        .map(Duration::from_millis)
+        .unwrap_or(DEFAULT_FEED_INTERVAL)
+}
+
+/// Build-time `RWDT_TIMEOUT_SECS`, 10 unless set. It runs from `esp_hal::init` on, so it has to
+/// cover the blocking start-up before [`task`] is spawned.
+pub fn timeout() -> Duration {
+    let requested = option_env!("RWDT_TIMEOUT_SECS")
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs);
+    derive_timeout(requested, feed_interval())
+}
+
+/// The requested timeout, or the default, stretched to [`MIN_FEEDS_PER_TIMEOUT`] feeds.
+fn derive_timeout(requested: Option<Duration>, feed_interval: Duration) -> Duration {
+    requested
+        .unwrap_or(DEFAULT_TIMEOUT)
+        .max(feed_interval * MIN_FEEDS_PER_TIMEOUT)
+}
+
+/// Enables the RWDT in the HAL config, with [`timeout`].
+pub fn configure(config: &mut esp_hal::Config) {
+    let timeout = esp_hal::time::Duration::micros(timeout().as_micros());
+    config.watchdog.rwdt = WatchdogStatus::Enabled(timeout);
+}
+
+#[embassy_executor::task]
+pub async fn task() {
+    log::info!(
+        "RWDT armed with a {}ms timeout, fed every {}ms",
+        timeout().as_millis(),
+        feed_interval().as_millis()
+    );
+
+    // The HAL already configured it; this handle only writes the feed register.
+    let mut rwdt = Rwdt::default();
+    let mut ticker = Ticker::every(feed_interval());
+
+    loop {
+        rwdt.feed();
+        ticker.next().await;
+    }
+}
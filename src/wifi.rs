@@ -1,42 +1,249 @@
-use embassy_net::{Stack, StaticConfigV4};
+use core::fmt::Write;
 
-use crate::bus::{WiFiConnectStatus, WIFI_CONNECT_STATUS};
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, Stack, StaticConfigV4};
+
+use crate::{
+    bus::{
+        publish_system_message, WiFiConnectStatus, WiFiLinkInfo, WIFI_CONNECT_STATUS,
+        WIFI_CREDENTIALS_CHANNEL, WIFI_LINK_INFO,
+    },
+    storage::{self, Slot, StorageError},
+};
+use embassy_futures::select::{select3, Either3};
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Timer};
+use embassy_time::{with_timeout, Duration, Timer};
 use esp_backtrace as _;
 use esp_wifi::wifi::{
-    ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiStaDevice,
-    WifiState,
+    ClientConfiguration, Configuration, ScanConfig, WifiController, WifiDevice, WifiEvent,
+    WifiStaDevice, WifiState,
 };
+use heapless::{String, Vec};
 
 const SSID: &str = env!("SSID");
 const PASSWORD: &str = env!("PASSWORD");
 
+/// Time the new network gets to accept us before switching back to the previous one.
+const SWITCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the link quality is refreshed while connected. Each refresh is a short scan of the
+/// network's SSID, so this is kept well apart.
+const LINK_INFO_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest stored credentials record: both strings at full length plus their length bytes.
+const CREDENTIALS_RECORD_LEN: usize = 1 + 32 + 1 + 64;
+
+/// Network the station connects to. The password is never logged.
+#[derive(Clone)]
+pub struct WifiCredentials {
+    pub ssid: String<32>,
+    pub password: String<64>,
+}
+
+impl WifiCredentials {
+    /// The credentials the firmware was built with.
+    fn built_in() -> Self {
+        Self {
+            ssid: SSID.try_into().unwrap(),
+            password: PASSWORD.try_into().unwrap(),
+        }
+    }
+
+    /// The SSID must not be empty; the password is empty for an open network, otherwise a
+    /// WPA passphrase (8..=63 characters) or a 64-digit hex key.
+    pub fn new(ssid: &str, password: &str) -> Option<Self> {
+        if ssid.is_empty() || !(password.is_empty() || (8..=64).contains(&password.len())) {
+            return None;
+        }
+
+        Some(Self {
+            ssid: ssid.try_into().ok()?,
+            password: password.try_into().ok()?,
+        })
+    }
+
+    fn client_configuration(&self) -> Configuration {
+        Configuration::Client(ClientConfiguration {
+            ssid: self.ssid.clone(),
+            password: self.password.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// `[ssid len][ssid][password len][password]`
+    fn to_bytes(&self) -> Vec<u8, CREDENTIALS_RECORD_LEN> {
+        let mut bytes = Vec::new();
+        bytes.push(self.ssid.len() as u8).ok();
+        bytes.extend_from_slice(self.ssid.as_bytes()).ok();
+        bytes.push(self.password.len() as u8).ok();
+        bytes.extend_from_slice(self.password.as_bytes()).ok();
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&ssid_len, rest) = bytes.split_first()?;
+        let ssid = rest.get(..ssid_len as usize)?;
+        let (&password_len, rest) = rest[ssid_len as usize..].split_first()?;
+        let password = rest.get(..password_len as usize)?;
+        if rest.len() != password_len as usize {
+            return None;
+        }
+
+        Self::new(
+            core::str::from_utf8(ssid).ok()?,
+            core::str::from_utf8(password).ok()?,
+        )
+    }
+}
+
+/// The credentials stored by a `wifi` command, or the built-in ones if none are stored.
+fn load_credentials() -> WifiCredentials {
+    let mut record = [0u8; CREDENTIALS_RECORD_LEN];
+    storage::read(Slot::Wifi, &mut record)
+        .and_then(|len| WifiCredentials::from_bytes(&record[..len]))
+        .unwrap_or_else(WifiCredentials::built_in)
+}
+
+fn store_credentials(credentials: &WifiCredentials) -> Result<(), StorageError> {
+    storage::write(Slot::Wifi, &credentials.to_bytes())
+}
+
+fn parse_ipv4(text: &str) -> Option<Ipv4Address> {
+    let mut octets = [0u8; 4];
+    let mut parts = text.trim().split('.');
+    for octet in octets.iter_mut() {
+        *octet = parts.next()?.parse().ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Ipv4Address::new(octets[0], octets[1], octets[2], octets[3]))
+}
+
+/// `192.168.1.50/24`
+fn parse_ipv4_cidr(text: &str) -> Option<Ipv4Cidr> {
+    let (address, prefix_len) = text.split_once('/')?;
+    let prefix_len = prefix_len.parse().ok().filter(|len| *len <= 32)?;
+    Some(Ipv4Cidr::new(parse_ipv4(address)?, prefix_len))
+}
+
+/// The build-time static IPv4 setup, if any: `STATIC_IP` as `192.168.1.50/24`, with an optional
+/// `STATIC_GATEWAY` and `STATIC_DNS`, up to three comma-separated addresses. An invalid
+/// `STATIC_IP` falls back to DHCP; an invalid gateway or DNS server is left out.
+pub fn static_config() -> Option<StaticConfigV4> {
+    let text = option_env!("STATIC_IP")?;
+    let Some(address) = parse_ipv4_cidr(text) else {
+        log::error!("Invalid STATIC_IP {}, using DHCP", text);
+        return None;
+    };
+
+    let gateway = option_env!("STATIC_GATEWAY").and_then(|text| {
+        let gateway = parse_ipv4(text);
+        if gateway.is_none() {
+            log::error!("Invalid STATIC_GATEWAY {}", text);
+        }
+        gateway
+    });
+
+    let mut dns_servers = Vec::new();
+    for text in option_env!("STATIC_DNS").unwrap_or("").split(',') {
+        if text.trim().is_empty() {
+            continue;
+        }
+        match parse_ipv4(text) {
+            Some(server) => {
+                if dns_servers.push(server).is_err() {
+                    log::error!("More than three STATIC_DNS servers, using the first three");
+                    break;
+                }
+            }
+            None => log::error!("Invalid STATIC_DNS server {}", text),
+        }
+    }
+
+    Some(StaticConfigV4 {
+        address,
+        gateway,
+        dns_servers,
+    })
+}
+
+/// The network stack's IPv4 config: [`static_config`] if one is set, DHCP otherwise.
+pub fn net_config() -> Config {
+    match static_config() {
+        Some(config) => Config::ipv4_static(config),
+        None => Config::dhcpv4(Default::default()),
+    }
+}
+
+/// Updates [`WIFI_LINK_INFO`] from a scan for the network's SSID. esp-wifi has no safe way to
+/// read the associated AP's record, so with several APs sharing the SSID this may report another
+/// one than the station is on. Keeps the previous value if the scan finds nothing.
+async fn refresh_link_info(controller: &mut WifiController<'static>, ssid: &str) {
+    let config = ScanConfig {
+        ssid: Some(ssid),
+        ..Default::default()
+    };
+    match controller.scan_with_config::<1>(config).await {
+        Ok((aps, _)) => {
+            if let Some(ap) = aps.first() {
+                *WIFI_LINK_INFO.lock().await = Some(WiFiLinkInfo {
+                    rssi: ap.signal_strength,
+                    channel: ap.channel,
+                    bssid: ap.bssid,
+                });
+            }
+        }
+        Err(err) => log::warn!("Cannot scan for Wi-Fi link info: {:?}", err),
+    }
+}
+
 // global variable ip address
 pub static NETWORK_CONFIG: Mutex<CriticalSectionRawMutex, Option<StaticConfigV4>> =
     Mutex::new(None);
 
 #[embassy_executor::task]
 pub async fn connection(mut controller: WifiController<'static>) {
+    let mut credentials = load_credentials();
+
     log::info!("start connection task");
-    log::info!("SSID : {}", SSID);
+    log::info!("SSID : {}", credentials.ssid);
     log::info!("Device capabilities: {:?}", controller.get_capabilities());
     loop {
         match esp_wifi::wifi::get_wifi_state() {
             WifiState::StaConnected => {
-                // wait until we're no longer connected
-                controller.wait_for_event(WifiEvent::StaDisconnected).await;
-                Timer::after(Duration::from_millis(5000)).await
+                // wait until we're no longer connected, or asked to move to another network
+                match select3(
+                    controller.wait_for_event(WifiEvent::StaDisconnected),
+                    WIFI_CREDENTIALS_CHANNEL.receive(),
+                    Timer::after(LINK_INFO_INTERVAL),
+                )
+                .await
+                {
+                    Either3::First(_) => {
+                        *WIFI_LINK_INFO.lock().await = None;
+                        Timer::after(Duration::from_millis(5000)).await
+                    }
+                    Either3::Second(new_credentials) => {
+                        *WIFI_LINK_INFO.lock().await = None;
+                        credentials =
+                            switch_network(&mut controller, credentials, new_credentials).await;
+                        if matches!(controller.is_connected(), Ok(true)) {
+                            refresh_link_info(&mut controller, &credentials.ssid).await;
+                        }
+                        continue;
+                    }
+                    Either3::Third(_) => {
+                        refresh_link_info(&mut controller, &credentials.ssid).await;
+                        continue;
+                    }
+                }
             }
             _ => {}
         }
         if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: SSID.try_into().unwrap(),
-                password: PASSWORD.try_into().unwrap(),
-                ..Default::default()
-            });
-            controller.set_configuration(&client_config).unwrap();
+            controller
+                .set_configuration(&credentials.client_configuration())
+                .unwrap();
             log::info!("Starting wifi");
             controller.start().await.unwrap();
             log::info!("Wifi started!");
@@ -44,7 +251,10 @@ pub async fn connection(mut controller: WifiController<'static>) {
         log::info!("About to connect...");
 
         match controller.connect().await {
-            Ok(_) => log::info!("Wifi connected!"),
+            Ok(_) => {
+                log::info!("Wifi connected!");
+                refresh_link_info(&mut controller, &credentials.ssid).await;
+            }
             Err(e) => {
                 log::info!("Failed to connect to wifi: {e:?}");
                 Timer::after(Duration::from_millis(5000)).await
@@ -53,14 +263,76 @@ pub async fn connection(mut controller: WifiController<'static>) {
     }
 }
 
+/// Moves the station to `new`, keeping it only if the connection succeeds within
+/// [`SWITCH_TIMEOUT`]. Returns the credentials in use afterwards. New credentials are stored only
+/// once they have worked, and the outcome is published to `system/wifi`.
+async fn switch_network(
+    controller: &mut WifiController<'static>,
+    current: WifiCredentials,
+    new: WifiCredentials,
+) -> WifiCredentials {
+    log::info!("Switching Wi-Fi to SSID {}", new.ssid);
+    controller.disconnect().await.ok();
+
+    let connected = controller
+        .set_configuration(&new.client_configuration())
+        .is_ok()
+        && matches!(
+            with_timeout(SWITCH_TIMEOUT, controller.connect()).await,
+            Ok(Ok(_))
+        );
+
+    let (in_use, stored) = if connected {
+        log::info!("Wi-Fi switched to SSID {}", new.ssid);
+        let stored = store_credentials(&new);
+        if let Err(err) = &stored {
+            log::error!("Cannot store Wi-Fi credentials: {:?}", err);
+        }
+        (new, stored.is_ok())
+    } else {
+        log::warn!(
+            "Cannot connect to SSID {}, back to {}",
+            new.ssid,
+            current.ssid
+        );
+        controller.disconnect().await.ok();
+        controller
+            .set_configuration(&current.client_configuration())
+            .ok();
+        // The connection loop reconnects from here.
+        (current, false)
+    };
+
+    let mut json = String::<128>::new();
+    json.push_str("{\"ssid\":\"").ok();
+    for c in in_use.ssid.chars() {
+        if c == '"' || c == '\\' {
+            json.push('\\').ok();
+        }
+        json.push(c).ok();
+    }
+    write!(
+        json,
+        "\",\"switched\":{},\"stored\":{}}}",
+        connected, stored
+    )
+    .ok();
+    publish_system_message("wifi", json.as_bytes());
+
+    in_use
+}
+
 #[embassy_executor::task]
 pub async fn get_ip_addr(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>) {
+    // A static address holds as soon as the link is up, without asking the stack.
+    let static_config = static_config();
+
     loop {
         let mut network_config_guard = NETWORK_CONFIG.lock().await;
         if stack.is_link_up() && network_config_guard.is_none() {
             log::info!("Waiting to get IP address...");
             loop {
-                if let Some(config) = stack.config_v4() {
+                if let Some(config) = static_config.clone().or_else(|| stack.config_v4()) {
                     log::info!("Got IP: {}", config.address);
                     *network_config_guard = Some(config);
 
@@ -0,0 +1,16 @@
+//! Host tests for the firmware modules that don't depend on the hardware. The firmware only
+//! builds for the ESP32-C3, so these modules are included from `src/` here and tested from
+//! `tests/`:
+//!
+//! ```sh
+//! cd tools/host-tests
+//! RUSTFLAGS= cargo +stable test --target "$(rustc +stable -vV | sed -n 's/host: //p')"
+//! RUSTFLAGS= cargo +stable test --target "$(rustc +stable -vV | sed -n 's/host: //p')" --features json-payload
+//! ```
+
+#![no_std]
+
+#[path = "../../../src/mqtt_wire.rs"]
+pub mod mqtt_wire;
+#[path = "../../../src/telemetry_layout.rs"]
+pub mod telemetry_layout;
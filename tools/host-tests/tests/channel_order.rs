@@ -0,0 +1,70 @@
+//! The order `next_message` serves the charge channels in, with mock channels standing in for
+//! the series channels.
+
+use std::{collections::VecDeque, task::Poll};
+
+use host_tests::mqtt_wire::{next_channel, poll_channels};
+
+const CHANNELS: usize = 4;
+
+/// Serves the mock channels the way `next_message` does, at most `limit` samples, and returns
+/// the channel of each in order. With `refill`, a channel gets a new sample whenever one is
+/// taken, as if its producer kept it full.
+fn serve(mut queues: [VecDeque<u32>; CHANNELS], limit: usize, refill: bool) -> Vec<usize> {
+    let mut served = Vec::new();
+    let mut start = 0;
+
+    while served.len() < limit {
+        let poll = poll_channels(start, CHANNELS, |index| match queues[index].pop_front() {
+            Some(sample) => Poll::Ready(sample),
+            None => Poll::Pending,
+        });
+        let Poll::Ready((index, _)) = poll else {
+            break;
+        };
+        served.push(index);
+        start = next_channel(index, CHANNELS);
+        if refill {
+            queues[index].push_back(0);
+        }
+    }
+    served
+}
+
+fn queues(samples: [usize; CHANNELS]) -> [VecDeque<u32>; CHANNELS] {
+    samples.map(|count| (0..count as u32).collect())
+}
+
+#[test]
+fn no_channel_starves_when_all_are_ready() {
+    let served = serve(queues([1; CHANNELS]), 400, true);
+
+    assert_eq!(served.len(), 400);
+    for ch in 0..CHANNELS {
+        let count = served.iter().filter(|&&index| index == ch).count();
+        assert_eq!(count, 100, "ch{} served {} times", ch, count);
+    }
+    // Strictly in turn.
+    for (turn, index) in served.iter().enumerate() {
+        assert_eq!(*index, turn % CHANNELS);
+    }
+}
+
+#[test]
+fn busy_channel_does_not_hold_back_a_late_one() {
+    // ch0 has a backlog, ch3 a single sample.
+    let served = serve(queues([100, 0, 0, 1]), 200, false);
+    let ch3 = served.iter().position(|&index| index == 3).unwrap();
+    assert_eq!(ch3, 1, "ch3 went after {} samples of ch0", ch3);
+}
+
+#[test]
+fn idle_channels_are_skipped() {
+    let served = serve(queues([3, 0, 3, 0]), 10, false);
+    assert_eq!(served, [0, 2, 0, 2, 0, 2]);
+}
+
+#[test]
+fn nothing_ready_is_pending() {
+    assert!(poll_channels(2, CHANNELS, |_| Poll::<()>::Pending).is_pending());
+}
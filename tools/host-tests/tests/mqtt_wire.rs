@@ -0,0 +1,138 @@
+use heapless::String;
+use host_tests::mqtt_wire::{
+    copy_payload, prepend_age, push_replay_suffix, set_charge_channel_topic, set_protector_topic,
+    set_topic, SerializeError, SERIES_FORMAT_SUFFIX,
+};
+
+const PREFIX: &str = "power-desk/0123456789ab/";
+
+fn expected_topic(parts: &[&str]) -> std::string::String {
+    let mut topic = std::string::String::from(PREFIX);
+    for part in parts {
+        topic.push_str(part);
+    }
+    topic
+}
+
+#[test]
+fn protector_topic() {
+    let mut topic = String::new();
+    set_protector_topic(&mut topic, PREFIX).unwrap();
+    assert_eq!(
+        topic.as_str(),
+        expected_topic(&["protector", SERIES_FORMAT_SUFFIX])
+    );
+}
+
+#[test]
+fn charge_channel_topics() {
+    let mut topic = String::new();
+    for (ch, name) in ["ch0", "ch1", "ch2", "ch3"].into_iter().enumerate() {
+        set_charge_channel_topic(&mut topic, PREFIX, ch as u8).unwrap();
+        assert_eq!(
+            topic.as_str(),
+            expected_topic(&[name, "/series", SERIES_FORMAT_SUFFIX])
+        );
+    }
+}
+
+#[test]
+fn replay_topic_follows_the_series_topic() {
+    let mut topic = String::new();
+    set_charge_channel_topic(&mut topic, PREFIX, 2).unwrap();
+    push_replay_suffix(&mut topic).unwrap();
+    assert_eq!(
+        topic.as_str(),
+        expected_topic(&["ch2/series", SERIES_FORMAT_SUFFIX, "/replay"])
+    );
+}
+
+#[test]
+fn topic_fills_buffer_exactly() {
+    let mut topic = String::new();
+    let fits = "x".repeat(64 - PREFIX.len());
+
+    set_topic(&mut topic, PREFIX, &[&fits]).unwrap();
+    assert_eq!(topic.len(), 64);
+    assert_eq!(
+        set_topic(&mut topic, PREFIX, &[&fits, "x"]),
+        Err(SerializeError::TopicTooLong)
+    );
+    // No room left for the replay suffix either.
+    set_topic(&mut topic, PREFIX, &[&fits]).unwrap();
+    assert_eq!(
+        push_replay_suffix(&mut topic),
+        Err(SerializeError::TopicTooLong)
+    );
+}
+
+#[test]
+fn payload_fills_buffer_exactly() {
+    let payload = [0x5a; 58];
+    let mut buffer = [0xff; 64];
+
+    assert_eq!(copy_payload(&mut buffer[..58], &payload), Ok(58));
+    assert_eq!(buffer[..58], payload);
+    // Nothing is written past the payload.
+    assert!(buffer[58..].iter().all(|&byte| byte == 0xff));
+}
+
+#[test]
+fn payload_one_byte_too_long_is_rejected() {
+    let mut buffer = [0; 57];
+    assert_eq!(
+        copy_payload(&mut buffer, &[0x5a; 58]),
+        Err(SerializeError::PayloadTooLong)
+    );
+}
+
+#[cfg(not(feature = "json-payload"))]
+#[test]
+fn replay_payload_starts_with_age() {
+    use host_tests::telemetry_layout::{byte_order, ByteOrder};
+
+    let payload = [0x5a; 58];
+    let mut buffer = [0; 62];
+    let size = copy_payload(&mut buffer, &payload).unwrap();
+
+    assert_eq!(prepend_age(&mut buffer, size, 0x0102_0304), Ok(62));
+    let age = match byte_order() {
+        ByteOrder::Little => [0x04, 0x03, 0x02, 0x01],
+        ByteOrder::Big => [0x01, 0x02, 0x03, 0x04],
+    };
+    assert_eq!(buffer[..4], age);
+    assert_eq!(buffer[4..], payload);
+}
+
+#[cfg(not(feature = "json-payload"))]
+#[test]
+fn replay_age_is_capped() {
+    let mut buffer = [0; 5];
+    assert_eq!(prepend_age(&mut buffer, 1, u64::MAX), Ok(5));
+    assert_eq!(buffer[..4], [0xff; 4]);
+}
+
+#[cfg(feature = "json-payload")]
+#[test]
+fn replay_payload_starts_with_age() {
+    let payload = br#"{"seq":7}"#;
+    let mut buffer = [0; 64];
+    let size = copy_payload(&mut buffer, payload).unwrap();
+
+    let size = prepend_age(&mut buffer, size, 1500).unwrap();
+    assert_eq!(&buffer[..size], br#"{"age_ms":1500,"seq":7}"#);
+}
+
+#[test]
+fn replay_age_needs_room() {
+    let payload = br#"{"seq":7}"#;
+    let mut buffer = [0; 9];
+    let size = copy_payload(&mut buffer, payload).unwrap();
+
+    assert_eq!(
+        prepend_age(&mut buffer, size, 1),
+        Err(SerializeError::PayloadTooLong)
+    );
+    // Left as it was.
+    assert_eq!(&buffer, payload);
+}
@@ -0,0 +1,52 @@
+use host_tests::telemetry_layout::{
+    byte_order, byte_size, layout_json_len, write_layout_json, ByteOrder, Field,
+    CHARGE_CHANNEL_SERIES_ITEM, PROTECTOR_SERIES_ITEM,
+};
+
+const LAYOUTS: [&[Field]; 2] = [PROTECTOR_SERIES_ITEM, CHARGE_CHANNEL_SERIES_ITEM];
+
+fn layout_json(version: u8, fields: &[Field]) -> String {
+    let mut json = String::new();
+    write_layout_json(&mut json, version, fields).unwrap();
+    json
+}
+
+#[test]
+fn layout_json_fits_its_bound() {
+    for fields in LAYOUTS {
+        for version in [0, 9, 10, 255] {
+            let json = layout_json(version, fields);
+            assert!(json.len() <= layout_json_len(fields), "{}", json);
+        }
+        // The bound is exact for a three-digit version and the longer byte order.
+        if byte_order() == ByteOrder::Little {
+            assert_eq!(layout_json(255, fields).len(), layout_json_len(fields));
+        }
+    }
+}
+
+#[test]
+fn layout_json_lists_every_field() {
+    for fields in LAYOUTS {
+        let json = layout_json(6, fields);
+        let listed = json
+            .split("\"fields\":\"")
+            .nth(1)
+            .and_then(|rest| rest.strip_suffix("\"}"))
+            .unwrap();
+        let names: Vec<_> = listed
+            .split(',')
+            .map(|field| field.split(':').next().unwrap())
+            .collect();
+        let expected: Vec<_> = fields.iter().map(|field| field.name).collect();
+        assert_eq!(names, expected);
+        assert!(json.contains(&format!("\"size\":{},", byte_size(fields))));
+    }
+}
+
+#[test]
+fn layout_json_does_not_overflow_silently() {
+    // The firmware writes into a fixed buffer; a short one has to fail, not truncate quietly.
+    let mut json = heapless::String::<64>::new();
+    assert!(write_layout_json(&mut json, 6, PROTECTOR_SERIES_ITEM).is_err());
+}
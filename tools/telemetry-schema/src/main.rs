@@ -0,0 +1,59 @@
+//! Prints the wire layout of the binary telemetry series as JSON, for generating decoders.
+//!
+//! The firmware's build settings target the ESP32-C3, so build this one for the host:
+//!
+//! ```sh
+//! cd tools/telemetry-schema
+//! RUSTFLAGS= cargo +stable run --target "$(rustc +stable -vV | sed -n 's/host: //p')"
+//! ```
+
+#[path = "../../../src/telemetry_layout.rs"]
+mod telemetry_layout;
+
+use telemetry_layout::{
+    byte_order, Field, CHARGE_CHANNEL_SERIES_ITEM, CHARGE_CHANNEL_SERIES_VERSION,
+    PROTECTOR_SERIES_ITEM, PROTECTOR_SERIES_VERSION,
+};
+
+fn series_json(name: &str, topic: &str, version: u8, fields: &[Field]) -> String {
+    let mut offset = 0;
+    let mut entries = Vec::new();
+    for field in fields {
+        entries.push(format!(
+            "{{\"name\":\"{}\",\"type\":\"{}\",\"offset\":{},\"size\":{}}}",
+            field.name,
+            field.ty.name(),
+            offset,
+            field.ty.size()
+        ));
+        offset += field.ty.size();
+    }
+
+    format!(
+        "\"{}\":{{\"topic\":\"{}\",\"version\":{},\"endianness\":\"{}\",\"size\":{},\"fields\":[{}]}}",
+        name,
+        topic,
+        version,
+        byte_order().name(),
+        offset,
+        entries.join(",")
+    )
+}
+
+fn main() {
+    println!(
+        "{{{},{}}}",
+        series_json(
+            "protector",
+            "protector",
+            PROTECTOR_SERIES_VERSION,
+            PROTECTOR_SERIES_ITEM
+        ),
+        series_json(
+            "charge_channel",
+            "ch<n>/series",
+            CHARGE_CHANNEL_SERIES_VERSION,
+            CHARGE_CHANNEL_SERIES_ITEM
+        )
+    );
+}